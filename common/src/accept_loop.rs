@@ -0,0 +1,122 @@
+use crate::listener::{Listener, Stream};
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Pause after a failed accept, so running out of file descriptors does not
+/// turn into a busy loop.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(10);
+
+/// A source of connections: a bound [`Listener`] or, in tests, in-memory
+/// streams.
+pub trait Accept {
+    type Io: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// The next connection, `None` once no more will come.
+    fn accept(&self) -> impl Future<Output = Option<io::Result<Self::Io>>> + Send;
+}
+
+impl Accept for Listener {
+    type Io = Stream;
+
+    async fn accept(&self) -> Option<io::Result<Stream>> {
+        Some(Listener::accept(self).await)
+    }
+}
+
+/// Accepts connections until `listener` is closed, serving each on its own
+/// task with `handle`. A failed accept is logged and retried; `server` names
+/// the listener in that log line.
+pub async fn serve<A, F, Fut>(listener: A, server: &'static str, handle: F)
+where
+    A: Accept,
+    F: Fn(A::Io) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    while let Some(accepted) = listener.accept().await {
+        match accepted {
+            Ok(io) => {
+                tokio::spawn(handle(io));
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, server, "Failed to accept connection");
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream};
+    use tokio::sync::{mpsc, Mutex};
+
+    /// Hands out whatever the test pushes, closing once the sender is gone.
+    struct ChannelListener(Mutex<mpsc::UnboundedReceiver<io::Result<DuplexStream>>>);
+
+    impl Accept for ChannelListener {
+        type Io = DuplexStream;
+
+        async fn accept(&self) -> Option<io::Result<DuplexStream>> {
+            self.0.lock().await.recv().await
+        }
+    }
+
+    fn channel_listener() -> (mpsc::UnboundedSender<io::Result<DuplexStream>>, ChannelListener) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (sender, ChannelListener(Mutex::new(receiver)))
+    }
+
+    /// Answers every line with its uppercase copy.
+    async fn shout(io: DuplexStream) {
+        let mut lines = BufReader::new(io);
+        let mut line = String::new();
+        while lines.read_line(&mut line).await.unwrap() > 0 {
+            lines.get_mut().write_all(line.to_uppercase().as_bytes()).await.unwrap();
+            line.clear();
+        }
+    }
+
+    async fn connect(sender: &mpsc::UnboundedSender<io::Result<DuplexStream>>) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(1024);
+        sender.send(Ok(server)).unwrap();
+        client
+    }
+
+    async fn round_trip(client: &mut DuplexStream, line: &str) -> String {
+        client.write_all(line.as_bytes()).await.unwrap();
+        let mut reply = vec![0; line.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_connections_concurrently() {
+        let (sender, listener) = channel_listener();
+        let server = tokio::spawn(serve(listener, "test", shout));
+
+        let mut first = connect(&sender).await;
+        let mut second = connect(&sender).await;
+        // The first connection stays open while the second is served.
+        assert_eq!(round_trip(&mut second, "two\n").await, "TWO\n");
+        assert_eq!(round_trip(&mut first, "one\n").await, "ONE\n");
+
+        drop(sender);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_accepting_after_an_error() {
+        let (sender, listener) = channel_listener();
+        let server = tokio::spawn(serve(listener, "test", shout));
+
+        sender.send(Err(io::Error::from_raw_os_error(24))).unwrap();
+        let mut client = connect(&sender).await;
+        assert_eq!(round_trip(&mut client, "still here\n").await, "STILL HERE\n");
+
+        drop(sender);
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+//! Which build is running: git sha, profile, enabled features and start
+//! time. The values are recorded by each binary's `build.rs` through
+//! [`crate::build_script::emit`] and read by [`build_info!`](crate::build_info!)
+//! where it expands, so they describe that binary rather than this crate.
+
+use std::fmt::Write as _;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What [`build_info!`](crate::build_info!) captured at compile time.
+#[derive(Debug, Clone, Copy)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub profile: &'static str,
+    /// Comma separated, empty without features.
+    pub features: &'static str,
+}
+
+/// The calling crate's [`BuildInfo`].
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::build_info::BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: env!("BUILD_GIT_SHA"),
+            profile: env!("BUILD_PROFILE"),
+            features: env!("BUILD_FEATURES"),
+        }
+    };
+}
+
+static STARTED: OnceLock<SystemTime> = OnceLock::new();
+
+/// Records the start time; called first thing in `main`.
+pub fn mark_started() {
+    STARTED.get_or_init(SystemTime::now);
+}
+
+impl BuildInfo {
+    /// `{"binary":…,"version":…,"gitSha":…,"profile":…,"features":[…],"startedAt":…,"uptimeSecs":…}`,
+    /// with `startedAt` in unix seconds.
+    pub fn json(&self, binary: &str) -> String {
+        let started = *STARTED.get_or_init(SystemTime::now);
+        let started_at = started.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let uptime = started.elapsed().unwrap_or_default().as_secs();
+
+        let mut out = String::with_capacity(256);
+        let _ = write!(
+            out,
+            r#"{{"binary":"{}","version":"{}","gitSha":"{}","profile":"{}","features":["#,
+            binary, self.version, self.git_sha, self.profile,
+        );
+        for (i, feature) in self.features.split(',').filter(|f| !f.is_empty()).enumerate() {
+            let _ = write!(out, r#"{}"{}""#, if i == 0 { "" } else { "," }, feature);
+        }
+        let _ = write!(out, r#"],"startedAt":{},"uptimeSecs":{}}}"#, started_at, uptime);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_lists_each_feature() {
+        let info = BuildInfo {
+            version: "0.1.0",
+            git_sha: "abc123",
+            profile: "release",
+            features: "shm-transport,tls",
+        };
+        let json = info.json("worker");
+        assert!(json.starts_with(
+            r#"{"binary":"worker","version":"0.1.0","gitSha":"abc123","profile":"release","features":["shm-transport","tls"],"startedAt":"#
+        ));
+
+        let none = BuildInfo { features: "", ..info };
+        assert!(none.json("worker").contains(r#""features":[],"#));
+    }
+}
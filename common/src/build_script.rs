@@ -0,0 +1,57 @@
+//! Used from each binary's `build.rs` to record build metadata for
+//! [`build_info!`](crate::build_info!): the git sha (`GIT_SHA`, else
+//! `git rev-parse`), the profile and the enabled features.
+
+use std::process::Command;
+
+/// Prints the `cargo:` lines for the package whose build script calls it.
+pub fn emit() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+
+    let sha = std::env::var("GIT_SHA")
+        .ok()
+        .filter(|sha| !sha.is_empty())
+        .or_else(git_sha)
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=BUILD_GIT_SHA={}", sha);
+
+    let profile = std::env::var("PROFILE").unwrap_or_default();
+    println!("cargo:rustc-env=BUILD_PROFILE={}", profile);
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(name, _)| name.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+}
+
+fn git_sha() -> Option<String> {
+    let output = git(&["rev-parse", "--short=12", "HEAD"])?;
+
+    // A commit moves the branch HEAD points to, not HEAD itself, so watch
+    // the branch ref too, and `packed-refs` for when it is packed.
+    watch_git_path("HEAD");
+    if let Some(branch) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        watch_git_path(branch.trim());
+        watch_git_path("packed-refs");
+    }
+    Some(output.trim().to_string())
+}
+
+/// Cargo reruns the script on every build for a path that does not exist,
+/// so only existing ones are watched.
+fn watch_git_path(name: &str) {
+    if let Some(path) = git(&["rev-parse", "--git-path", name])
+        && std::path::Path::new(path.trim()).exists()
+    {
+        println!("cargo:rerun-if-changed={}", path.trim());
+    }
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
@@ -0,0 +1,17 @@
+//! Code shared by the gateway, worker and load balancer binaries.
+
+#[cfg(feature = "runtime")]
+pub mod accept_loop;
+#[cfg(feature = "runtime")]
+pub mod build_info;
+pub mod build_script;
+#[cfg(feature = "runtime")]
+pub mod listener;
+#[cfg(feature = "runtime")]
+pub mod logging;
+#[cfg(feature = "lz4")]
+pub mod lz4;
+#[cfg(feature = "redis-summary")]
+pub mod redis_summary;
+#[cfg(feature = "runtime")]
+pub mod startup;
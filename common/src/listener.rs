@@ -0,0 +1,184 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UnixListener, UnixStream};
+
+/// Where HTTP connections are accepted, parsed from `unix:///path`,
+/// `tcp://host:port` or a bare socket path.
+#[derive(Debug, Clone)]
+pub enum ListenAddr {
+    Unix(String),
+    Tcp(SocketAddr),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            return addr
+                .parse()
+                .map(ListenAddr::Tcp)
+                .map_err(|e| format!("invalid tcp listen address {}: {}", addr, e));
+        }
+
+        let path = s.strip_prefix("unix://").unwrap_or(s);
+        if path.is_empty() {
+            return Err("empty unix socket path".to_string());
+        }
+        Ok(ListenAddr::Unix(path.to_string()))
+    }
+}
+
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    /// Binds `addr` for clients. A stale unix socket file is replaced and
+    /// made world writable; TCP sockets set `SO_REUSEPORT` so several
+    /// processes can share a port without a load balancer in front.
+    pub fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        Self::bind_with_mode(addr, 0o666)
+    }
+
+    /// Like [`Listener::bind`], but a unix socket only lets its owner and
+    /// group in, for admin endpoints.
+    pub fn bind_admin(addr: &ListenAddr) -> io::Result<Self> {
+        Self::bind_with_mode(addr, 0o660)
+    }
+
+    fn bind_with_mode(addr: &ListenAddr, mode: u32) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Unix(path) => {
+                if std::fs::metadata(path).is_ok() {
+                    std::fs::remove_file(path)?;
+                }
+
+                let listener = UnixListener::bind(path)?;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+                Ok(Listener::Unix(listener))
+            }
+            ListenAddr::Tcp(addr) => {
+                let socket = match addr {
+                    SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                    SocketAddr::V6(_) => TcpSocket::new_v6()?,
+                };
+                socket.set_reuseaddr(true)?;
+                socket.set_reuseport(true)?;
+                socket.bind(*addr)?;
+                Ok(Listener::Tcp(socket.listen(16 * 1024)?))
+            }
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Unix(listener) => listener.accept().await.map(|(s, _)| Stream::Unix(s)),
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                stream.set_nodelay(true)?;
+                Ok(Stream::Tcp(stream))
+            }
+        }
+    }
+}
+
+/// A connection accepted by [`Listener`].
+pub enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    /// The remote address of a TCP connection.
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        match self {
+            Stream::Unix(_) => None,
+            Stream::Tcp(s) => s.peer_addr().ok().map(|addr| addr.ip()),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Unix(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            Stream::Tcp(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Unix(s) => s.is_write_vectored(),
+            Stream::Tcp(s) => s.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tcp(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket_mode(bind: fn(&ListenAddr) -> io::Result<Listener>, name: &str) -> u32 {
+        let path = std::env::temp_dir().join(format!("common-{}-{}.sock", name, std::process::id()));
+        let addr = ListenAddr::Unix(path.to_str().unwrap().to_string());
+        let _listener = bind(&addr).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        std::fs::remove_file(&path).unwrap();
+        mode
+    }
+
+    #[tokio::test]
+    async fn admin_sockets_are_not_world_writable() {
+        assert_eq!(socket_mode(Listener::bind, "api"), 0o666);
+        assert_eq!(socket_mode(Listener::bind_admin, "admin"), 0o660);
+    }
+}
@@ -0,0 +1,175 @@
+//! Logging setup shared by the binaries.
+//!
+//! `RUST_LOG` takes `EnvFilter` directives such as `warn,worker::store=debug`
+//! and `LOG_FORMAT=json` switches to one JSON object per line. The filter can
+//! be replaced while running with [`set_filter`], and `SIGUSR2` toggles
+//! between the configured filter and `debug` (see [`watch_sigusr2`]).
+
+use std::fmt::Write as _;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+/// Filter used for a `SIGUSR2` bump.
+const BUMPED_FILTER: &str = "debug";
+
+struct Logging {
+    filter: reload::Handle<EnvFilter, Registry>,
+    /// Directives the process started with, restored by [`set_filter`] with
+    /// an empty string and by the second `SIGUSR2`.
+    configured: String,
+}
+
+static LOGGING: OnceLock<Logging> = OnceLock::new();
+
+/// Installs the global subscriber. `default_filter` applies when `RUST_LOG`
+/// is unset or invalid.
+pub fn init(default_filter: &str) {
+    let configured = std::env::var("RUST_LOG")
+        .ok()
+        .filter(|directives| EnvFilter::try_new(directives).is_ok())
+        .unwrap_or_else(|| default_filter.to_string());
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(&configured));
+
+    let json = std::env::var("LOG_FORMAT").is_ok_and(|format| format == "json");
+    let registry = tracing_subscriber::registry().with(filter);
+    let result = if json {
+        registry.with(fmt::layer().event_format(JsonFormat)).try_init()
+    } else {
+        registry.with(fmt::layer().with_ansi(false)).try_init()
+    };
+
+    if result.is_ok() {
+        let _ = LOGGING.set(Logging { filter: handle, configured });
+    }
+}
+
+/// Replaces the active filter; an empty string restores the configured one.
+pub fn set_filter(directives: &str) -> Result<String, String> {
+    let logging = LOGGING.get().ok_or("logging is not initialised")?;
+    let directives = match directives.trim() {
+        "" => logging.configured.as_str(),
+        directives => directives,
+    };
+
+    let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+    logging.filter.reload(filter).map_err(|e| e.to_string())?;
+    tracing::warn!(filter = directives, "Log filter changed");
+    Ok(directives.to_string())
+}
+
+/// Toggles between the configured filter and `debug` on every `SIGUSR2`.
+pub async fn watch_sigusr2() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut usr2 = match signal(SignalKind::user_defined2()) {
+        Ok(usr2) => usr2,
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to install SIGUSR2 handler");
+            return;
+        }
+    };
+
+    let mut bumped = false;
+    while usr2.recv().await.is_some() {
+        bumped = !bumped;
+        let _ = set_filter(if bumped { BUMPED_FILTER } else { "" });
+    }
+}
+
+/// One JSON object per event: `ts` (unix seconds), `level`, `target` and the
+/// event's fields, `message` included.
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, _ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> std::fmt::Result {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let metadata = event.metadata();
+
+        let mut line = String::with_capacity(256);
+        let _ = write!(line, r#"{{"ts":{:.6},"level":"{}","target":"#, ts, metadata.level());
+        push_json_str(&mut line, metadata.target());
+        event.record(&mut JsonFields(&mut line));
+        line.push('}');
+
+        writeln!(writer, "{}", line)
+    }
+}
+
+struct JsonFields<'a>(&'a mut String);
+
+impl JsonFields<'_> {
+    fn key(&mut self, field: &Field) {
+        self.0.push(',');
+        push_json_str(self.0, field.name());
+        self.0.push(':');
+    }
+}
+
+impl Visit for JsonFields<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.key(field);
+        if value.is_finite() {
+            let _ = write!(self.0, "{}", value);
+        } else {
+            self.0.push_str("null");
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.key(field);
+        let _ = write!(self.0, "{}", value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.key(field);
+        let _ = write!(self.0, "{}", value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.key(field);
+        let _ = write!(self.0, "{}", value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.key(field);
+        push_json_str(self.0, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.key(field);
+        push_json_str(self.0, &format!("{:?}", value));
+    }
+}
+
+fn push_json_str(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -0,0 +1,196 @@
+//! LZ4 frames between the gateway and workers that negotiate compression.
+//! The gateway writes independent 64 KiB blocks without checksums, the
+//! socket being reliable; the worker accepts any frame the reference `lz4`
+//! tool writes, dictionaries aside, verifying whatever checksums it carries.
+
+use lz4_flex::frame::{BlockMode, BlockSize, FrameDecoder, FrameEncoder, FrameInfo};
+use std::io::{Read, Write};
+
+pub use lz4_flex::frame::Error as FrameError;
+
+const MAGIC: [u8; 4] = 0x184D_2204u32.to_le_bytes();
+
+#[derive(Debug)]
+pub enum Lz4Error {
+    NotAFrame,
+    Corrupt(FrameError),
+    /// Decompressing would exceed the allowed size.
+    TooLarge,
+}
+
+impl std::fmt::Display for Lz4Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Lz4Error::NotAFrame => write!(f, "Not an LZ4 frame"),
+            Lz4Error::Corrupt(e) => write!(f, "Corrupt LZ4 frame: {}", e),
+            Lz4Error::TooLarge => write!(f, "LZ4 frame decompresses beyond the size limit"),
+        }
+    }
+}
+
+impl std::error::Error for Lz4Error {}
+
+/// The LZ4 frame holding `input`.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let info = FrameInfo::new()
+        .block_size(BlockSize::Max64KB)
+        .block_mode(BlockMode::Independent);
+    let mut encoder = FrameEncoder::with_frame_info(info, Vec::with_capacity(input.len() / 2 + 16));
+    encoder.write_all(input).expect("writing to a Vec cannot fail");
+    encoder.finish().expect("writing to a Vec cannot fail")
+}
+
+/// Appends the content of `frame` to `out`, failing once it grows past
+/// `limit` bytes. On failure `out` is left as it was.
+pub fn decompress(frame: &[u8], limit: usize, out: &mut Vec<u8>) -> Result<(), Lz4Error> {
+    if !frame.starts_with(&MAGIC) {
+        return Err(Lz4Error::NotAFrame);
+    }
+
+    let start = out.len();
+    let read = FrameDecoder::new(frame).take(limit as u64 + 1).read_to_end(out);
+    match read {
+        Ok(len) if len <= limit => Ok(()),
+        Ok(_) => {
+            out.truncate(start);
+            Err(Lz4Error::TooLarge)
+        }
+        Err(e) => {
+            out.truncate(start);
+            Err(Lz4Error::Corrupt(e.into()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Four payment lines, as written by `lz4 -BD -BX --content-size`:
+    /// linked blocks with block and content checksums.
+    const FRAME: &[u8] = &[
+        0x04, 0x22, 0x4d, 0x18, 0x7c, 0x40, 0x20, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x76, 0x61,
+        0x00, 0x00, 0x00, 0xff, 0x39, 0x7b, 0x22, 0x63, 0x6f, 0x72, 0x72, 0x65, 0x6c, 0x61, 0x74, 0x69,
+        0x6f, 0x6e, 0x49, 0x64, 0x22, 0x3a, 0x22, 0x34, 0x61, 0x37, 0x39, 0x30, 0x31, 0x62, 0x38, 0x2d,
+        0x37, 0x64, 0x32, 0x36, 0x2d, 0x34, 0x64, 0x39, 0x64, 0x2d, 0x61, 0x61, 0x31, 0x39, 0x2d, 0x34,
+        0x64, 0x63, 0x31, 0x63, 0x37, 0x63, 0x66, 0x36, 0x30, 0x62, 0x30, 0x22, 0x2c, 0x22, 0x61, 0x6d,
+        0x6f, 0x75, 0x6e, 0x74, 0x22, 0x3a, 0x31, 0x39, 0x2e, 0x39, 0x30, 0x7d, 0x0a, 0x48, 0x00, 0x22,
+        0x1f, 0x31, 0x48, 0x00, 0x34, 0x1f, 0x32, 0x48, 0x00, 0x34, 0x19, 0x33, 0x48, 0x00, 0x50, 0x2e,
+        0x39, 0x30, 0x7d, 0x0a, 0xa2, 0x43, 0x96, 0xe1, 0x00, 0x00, 0x00, 0x00, 0x51, 0x51, 0xb0, 0xe3,
+    ];
+
+    fn payments() -> Vec<u8> {
+        (0..4)
+            .flat_map(|i| {
+                format!(r#"{{"correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b{}","amount":19.90}}"#, i)
+                    .into_bytes()
+                    .into_iter()
+                    .chain([b'\n'])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decodes_frames_written_by_the_reference_implementation() {
+        let mut out = b"kept".to_vec();
+        decompress(FRAME, 1024, &mut out).unwrap();
+        assert_eq!(&out[..4], b"kept");
+        assert_eq!(out[4..], payments());
+    }
+
+    #[test]
+    fn rejects_corrupt_and_oversized_frames() {
+        let mut corrupt = FRAME.to_vec();
+        corrupt[40] ^= 1;
+        let mut out = b"kept".to_vec();
+        assert!(matches!(
+            decompress(&corrupt, 1024, &mut out),
+            Err(Lz4Error::Corrupt(FrameError::BlockChecksumError))
+        ));
+        assert_eq!(out, b"kept");
+        assert!(matches!(decompress(&FRAME[..60], 1024, &mut Vec::new()), Err(Lz4Error::Corrupt(_))));
+        assert!(matches!(decompress(b"{\"amount\":1}", 1024, &mut Vec::new()), Err(Lz4Error::NotAFrame)));
+        assert!(matches!(decompress(FRAME, 100, &mut out), Err(Lz4Error::TooLarge)));
+        assert_eq!(out, b"kept");
+    }
+
+    #[test]
+    fn rejects_matches_reaching_before_the_frame() {
+        let mut frame = vec![0x04, 0x22, 0x4d, 0x18, 0x60, 0x40, 0x82];
+        // "a", then a match one byte further back than the output holds.
+        frame.extend_from_slice(&[0x04, 0x00, 0x00, 0x00, 0x10, b'a', 0x02, 0x00]);
+        frame.extend_from_slice(&[0x00; 4]);
+        assert!(matches!(decompress(&frame, 1024, &mut b"kept".to_vec()), Err(Lz4Error::Corrupt(_))));
+    }
+
+    /// Pseudo-random bytes, which do not compress.
+    fn noise(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frames_grow_by_at_most_their_headers() {
+        const BLOCK_SIZE: usize = 64 * 1024;
+        for len in [1, BLOCK_SIZE - 1, BLOCK_SIZE, BLOCK_SIZE + 1, 3 * BLOCK_SIZE + 17] {
+            let input = noise(len, len as u64 + 1);
+            let blocks = len.div_ceil(BLOCK_SIZE);
+            let frame = compress(&input);
+            assert!(frame.len() <= 7 + 4 * blocks + len + 4, "{} bytes became {}", len, frame.len());
+        }
+    }
+
+    /// Random bytes, which do not compress, and repetitive ones, which
+    /// do, spanning several 64 KiB blocks.
+    fn contents() -> impl Strategy<Value = Vec<u8>> {
+        prop_oneof![
+            prop::collection::vec(any::<u8>(), 0..200_000),
+            prop::collection::vec(0u8..4, 0..200_000),
+            (prop::collection::vec(any::<u8>(), 1..100), 0usize..3_000).prop_map(|(unit, n)| unit.repeat(n)),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn decodes_what_it_encodes(input in contents()) {
+            let frame = compress(&input);
+            let mut out = Vec::new();
+            decompress(&frame, input.len(), &mut out).unwrap();
+            prop_assert!(out == input);
+            if !input.is_empty() {
+                let err = decompress(&frame, input.len() - 1, &mut Vec::new());
+                prop_assert!(matches!(err, Err(Lz4Error::TooLarge)));
+            }
+        }
+
+        #[test]
+        fn malformed_frames_stay_within_the_limit(
+            input in contents(),
+            flips in prop::collection::vec((any::<prop::sample::Index>(), 1u8..=255), 1..8),
+            cut in any::<prop::sample::Index>(),
+            limit in 0usize..300_000,
+        ) {
+            let mut frame = compress(&input);
+            for (at, mask) in flips {
+                let at = at.index(frame.len());
+                frame[at] ^= mask;
+            }
+            let cut = cut.index(frame.len() + 1);
+            for frame in [&frame[..], &frame[..cut]] {
+                let mut out = b"kept".to_vec();
+                let _ = decompress(frame, limit, &mut out);
+                prop_assert!(out.starts_with(b"kept"));
+                prop_assert!(out.len() <= 4 + limit);
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! The payment counters the worker and gateway keep in Redis, so the
+//! summary can be served without Postgres. Each processor's hash is
+//! bucketed by `requested_at` in milliseconds, with a `<ms>:n` field
+//! counting requests and a `<ms>:a` field summing the amount in cents.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+
+/// Hash holding a processor's totals, suffixed with the processor name.
+pub const KEY_PREFIX: &str = "payments-summary:";
+
+/// Set of the correlationIds already counted.
+pub const RECORDED_KEY: &str = "payments-summary-recorded";
+
+/// Counts each payment of `ARGV` (correlationId, index of its processor's
+/// key in `KEYS`, millisecond bucket, cents) unless its correlationId is
+/// already in the set at `KEYS[1]`, so recording a payment twice is
+/// harmless. Returns how many were counted. See [`record_args`].
+pub const RECORD_SCRIPT: &str = r#"
+local counted = 0
+for i = 1, #ARGV, 4 do
+  if redis.call('SADD', KEYS[1], ARGV[i]) == 1 then
+    local key = KEYS[tonumber(ARGV[i + 1])]
+    redis.call('HINCRBY', key, ARGV[i + 2] .. ':n', 1)
+    redis.call('HINCRBY', key, ARGV[i + 2] .. ':a', ARGV[i + 3])
+    counted = counted + 1
+  end
+end
+return counted
+"#;
+
+/// The hash holding `processor`'s totals.
+pub fn key(processor: impl Display) -> String {
+    format!("{}{}", KEY_PREFIX, processor)
+}
+
+/// `KEYS` and `ARGV` of [`RECORD_SCRIPT`] for `(correlationId, processor,
+/// unix millis, cents)` payments, naming each processor's key once.
+pub fn record_args<I, P>(payments: impl IntoIterator<Item = (I, P, i64, i64)>) -> (Vec<String>, Vec<String>)
+where
+    I: Display,
+    P: Display,
+{
+    let mut keys = vec![RECORDED_KEY.to_string()];
+    let mut key_index: HashMap<String, usize> = HashMap::new();
+    let mut args = Vec::new();
+    for (correlation_id, processor, millis, cents) in payments {
+        let index = *key_index.entry(key(processor)).or_insert_with_key(|key| {
+            keys.push(key.clone());
+            keys.len()
+        });
+        args.extend([correlation_id.to_string(), index.to_string(), millis.to_string(), cents.to_string()]);
+    }
+    (keys, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_args_name_each_processor_key_once() {
+        let (keys, args) = record_args([
+            ("a", "default", 1_700_000_000_000, 1990),
+            ("b", "fallback", 1_700_000_000_001, 50),
+            ("c", "default", 1_700_000_000_002, 100),
+        ]);
+
+        assert_eq!(keys, [RECORDED_KEY, "payments-summary:default", "payments-summary:fallback"]);
+        assert_eq!(
+            args,
+            [
+                "a", "2", "1700000000000", "1990", //
+                "b", "3", "1700000000001", "50", //
+                "c", "2", "1700000000002", "100",
+            ]
+        );
+    }
+
+    #[test]
+    fn nothing_to_record_names_only_the_recorded_set() {
+        let (keys, args) = record_args(std::iter::empty::<(&str, &str, i64, i64)>());
+        assert_eq!(keys, [RECORDED_KEY]);
+        assert!(args.is_empty());
+    }
+}
@@ -0,0 +1,75 @@
+//! Where startup is: `connecting-db`, then `warming`, then `ready`. Each
+//! step is logged and `/readyz` answers from the current phase.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+#[cfg(feature = "postgres")]
+use std::time::Duration;
+
+/// Pause between checks while waiting for Postgres.
+#[cfg(feature = "postgres")]
+const DATABASE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// Waiting for the database to answer; skipped without one.
+    ConnectingDb = 1,
+    /// Connecting to peers and filling caches.
+    Warming = 2,
+    /// Serving.
+    Ready = 3,
+}
+
+impl Phase {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::ConnectingDb => "connecting-db",
+            Phase::Warming => "warming",
+            Phase::Ready => "ready",
+        }
+    }
+}
+
+/// `0` until the first [`enter`].
+static PHASE: AtomicU8 = AtomicU8::new(0);
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// Moves startup on to `phase`. Phases only go forward; entering an earlier
+/// or the current one does nothing.
+pub fn enter(phase: Phase) {
+    let started = *STARTED.get_or_init(Instant::now);
+    if PHASE.fetch_max(phase as u8, Ordering::Relaxed) < phase as u8 {
+        tracing::warn!(phase = phase.as_str(), elapsed_ms = started.elapsed().as_millis() as u64, "Startup phase");
+    }
+}
+
+pub fn current() -> Phase {
+    match PHASE.load(Ordering::Relaxed) {
+        3 => Phase::Ready,
+        2 => Phase::Warming,
+        _ => Phase::ConnectingDb,
+    }
+}
+
+/// Polls Postgres until it answers or `timeout` passes. Returns whether
+/// it answered.
+#[cfg(feature = "postgres")]
+pub async fn wait_for_database(dbpool: &deadpool_postgres::Pool, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let error = match dbpool.get().await {
+            Ok(client) => match client.simple_query("SELECT 1").await {
+                Ok(_) => return true,
+                Err(e) => e.to_string(),
+            },
+            Err(e) => e.to_string(),
+        };
+        if tokio::time::Instant::now() >= deadline {
+            tracing::error!(error, "Database did not answer in time");
+            return false;
+        }
+        tracing::info!(error, "Waiting for the database");
+        tokio::time::sleep(DATABASE_POLL_INTERVAL).await;
+    }
+}
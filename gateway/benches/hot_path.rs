@@ -0,0 +1,126 @@
+//! Criterion benchmarks for the work every request does before it leaves
+//! the gateway: reading and stamping a payment, handing it to the worker
+//! socket, and serializing a summary.
+//!
+//! `cargo bench --bench hot_path`
+
+// Its unit tests are not run here, leaving their imports unused.
+#[allow(unused_imports)]
+#[path = "../src/api.rs"]
+mod api;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/publisher.rs"]
+mod publisher;
+
+use api::{PaymentBody, ProcessorSummary, Summary};
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use http_body_util::{BodyExt, Full};
+use publisher::{stamp_message, Publisher};
+use rust_decimal::Decimal;
+use std::hint::black_box;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::net::UnixListener;
+use tokio::runtime::Runtime;
+
+const PAYMENT: &[u8] = br#"{"correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3","amount":19.90}"#;
+
+/// Concurrent publishers racing for the connection pool.
+const PUBLISHERS: usize = 32;
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn payment_body(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("payment_body");
+    group.throughput(Throughput::Elements(1));
+
+    group.bench_function("collect_validate_stamp", |b| {
+        b.to_async(&rt).iter_batched(
+            || Full::new(Bytes::from_static(PAYMENT)),
+            |body| async move {
+                let body = body.collect().await.unwrap().to_bytes();
+                let payment = serde_json::from_slice::<PaymentBody>(&body).unwrap();
+                let stamped = stamp_message(&payment.canonical().unwrap(), "bench", None, None);
+                black_box((stamped, payment.correlation_id.len()))
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+/// Accepts worker connections and discards whatever is written to them.
+async fn sink(listener: UnixListener) {
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            return;
+        };
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 64 * 1024];
+            while matches!(stream.read(&mut buf).await, Ok(n) if n > 0) {}
+        });
+    }
+}
+
+fn publish_contention(c: &mut Criterion) {
+    let rt = runtime();
+    let socket = std::env::temp_dir().join(format!("gateway-bench-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket);
+
+    let publisher = rt.block_on(async {
+        tokio::spawn(sink(UnixListener::bind(&socket).unwrap()));
+        Arc::new(Publisher::new(socket.to_string_lossy().into_owned(), 64).await.unwrap())
+    });
+
+    let mut group = c.benchmark_group("publish");
+    group.throughput(Throughput::Elements(PUBLISHERS as u64));
+
+    group.bench_function("contended", |b| {
+        b.to_async(&rt).iter(|| {
+            let publisher = publisher.clone();
+            async move {
+                let mut publishes = tokio::task::JoinSet::new();
+                for _ in 0..PUBLISHERS {
+                    let publisher = publisher.clone();
+                    publishes.spawn(async move { publisher.publish(PAYMENT).await });
+                }
+                while let Some(result) = publishes.join_next().await {
+                    result.unwrap().unwrap();
+                }
+            }
+        })
+    });
+
+    group.finish();
+    let _ = std::fs::remove_file(&socket);
+}
+
+fn summary_serialization(c: &mut Criterion) {
+    let processor = |requests: i64, cents: i64| ProcessorSummary {
+        total_requests: requests,
+        total_amount: Decimal::new(cents, 2),
+    };
+
+    c.bench_function("summary/serialize", |b| {
+        b.iter(|| {
+            let summary = Summary {
+                default: Some(processor(black_box(15_734), 31_310_660)),
+                fallback: Some(processor(black_box(1_206), 2_399_940)),
+                last_requested_at: None,
+            };
+            black_box(serde_json::to_vec(&summary).unwrap())
+        })
+    });
+}
+
+criterion_group!(benches, payment_body, publish_contention, summary_serialization);
+criterion_main!(benches);
@@ -0,0 +1,86 @@
+//! Compares issuing the summary statements one after the other with sending
+//! them pipelined on one connection, as the gateway does for `meta=true`.
+//!
+//! `POSTGRES_URL=... cargo bench --bench summary_pipelining`
+
+#[path = "../src/summary_queries.rs"]
+mod summary_queries;
+
+use bytes::BytesMut;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+use tokio_postgres::{Client, NoTls, Statement};
+
+const ITERATIONS: u32 = 2_000;
+
+/// Stand-in for the gateway's processor filter; always bound as NULL.
+#[derive(Debug)]
+struct ServiceType;
+
+impl ToSql for ServiceType {
+    fn to_sql(&self, _ty: &Type, _out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        Ok(IsNull::Yes)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        ty.name() == "service_type"
+    }
+
+    to_sql_checked!();
+}
+
+/// The unfiltered summary: no window, processor or run.
+const NO_FILTER: [&(dyn ToSql + Sync); 4] = [
+    &None::<time::PrimitiveDateTime>,
+    &None::<time::PrimitiveDateTime>,
+    &None::<ServiceType>,
+    &None::<&str>,
+];
+
+async fn sequential(client: &Client, totals: &Statement, last: &Statement) -> Result<(), tokio_postgres::Error> {
+    client.query(totals, &NO_FILTER).await?;
+    client.query_one(last, &NO_FILTER).await?;
+    Ok(())
+}
+
+async fn pipelined(client: &Client, totals: &Statement, last: &Statement) -> Result<(), tokio_postgres::Error> {
+    tokio::try_join!(client.query(totals, &NO_FILTER), client.query_one(last, &NO_FILTER))?;
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let Ok(url) = std::env::var("POSTGRES_URL") else {
+        eprintln!("POSTGRES_URL is not set, skipping");
+        return Ok(());
+    };
+
+    let (client, connection) = tokio_postgres::connect(&url, NoTls).await?;
+    tokio::spawn(connection);
+
+    let totals = client.prepare(summary_queries::TOTALS).await?;
+    let last = client.prepare(summary_queries::LAST_REQUESTED_AT).await?;
+
+    // Warm up caches and the connection before measuring.
+    for _ in 0..100 {
+        sequential(&client, &totals, &last).await?;
+    }
+
+    let mut elapsed = [Duration::ZERO; 2];
+    for _ in 0..ITERATIONS {
+        let started = Instant::now();
+        sequential(&client, &totals, &last).await?;
+        elapsed[0] += started.elapsed();
+
+        let started = Instant::now();
+        pipelined(&client, &totals, &last).await?;
+        elapsed[1] += started.elapsed();
+    }
+
+    for (name, total) in ["sequential", "pipelined"].iter().zip(elapsed) {
+        println!("{:<10} {:>8.1} µs/summary", name, total.as_secs_f64() * 1e6 / f64::from(ITERATIONS));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,5 @@
+//! Records build metadata for `common::build_info!`.
+
+fn main() {
+    common::build_script::emit();
+}
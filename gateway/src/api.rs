@@ -0,0 +1,132 @@
+//! Request and response bodies of the public HTTP API. Kept apart from the
+//! handlers so the hot path benchmark works on the same types.
+
+use rust_decimal::Decimal;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+
+/// The fields of a payment body the gateway reads itself; the rest of the
+/// validation is left to the worker.
+#[derive(Deserialize)]
+pub struct PaymentBody<'a> {
+    #[serde(rename = "correlationId", borrow)]
+    pub correlation_id: &'a str,
+    #[serde(default)]
+    pub amount: Option<Amount>,
+}
+
+impl PaymentBody<'_> {
+    /// The body published to the worker, with the amount in canonical form,
+    /// or `None` when there is no amount to put in it.
+    pub fn canonical(&self) -> Option<Vec<u8>> {
+        let Some(Amount::Valid(amount)) = self.amount else { return None };
+        // A borrowed `correlation_id` had no escapes, so it is written back as is.
+        Some(format!(r#"{{"correlationId":"{}","amount":{}}}"#, self.correlation_id, amount).into_bytes())
+    }
+}
+
+/// `amount` as clients send it, a JSON number or a numeric string such as
+/// `"19.90"`, normalized to two decimal places.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Amount {
+    Valid(Decimal),
+    /// Not a number, or finer than a cent.
+    Invalid,
+}
+
+impl Amount {
+    fn parse(value: &str) -> Self {
+        match Decimal::from_str_exact(value.trim()) {
+            Ok(amount) => Self::normalize(amount),
+            Err(_) => Self::Invalid,
+        }
+    }
+
+    fn normalize(amount: Decimal) -> Self {
+        let mut amount = amount.normalize();
+        if amount.scale() > 2 {
+            return Self::Invalid;
+        }
+        amount.rescale(2);
+        Self::Valid(amount)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct AmountVisitor;
+
+        impl Visitor<'_> for AmountVisitor {
+            type Value = Amount;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number or a numeric string")
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Amount, E> {
+                Ok(Amount::normalize(Decimal::from(value)))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Amount, E> {
+                Ok(Amount::normalize(Decimal::from(value)))
+            }
+
+            // The shortest representation that reads back as the same
+            // float, so `19.9` is 19.9 and not its binary expansion.
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<Amount, E> {
+                Ok(Amount::parse(&value.to_string()))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Amount, E> {
+                Ok(Amount::parse(value))
+            }
+        }
+
+        deserializer.deserialize_any(AmountVisitor)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ProcessorSummary {
+    #[serde(rename = "totalRequests")]
+    pub total_requests: i64,
+    #[serde(rename = "totalAmount")]
+    pub total_amount: Decimal,
+}
+
+/// A processor is omitted when the summary was filtered to the other one.
+#[derive(Deserialize, Serialize)]
+pub struct Summary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<ProcessorSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<ProcessorSummary>,
+    /// Only with `meta=true` and the Postgres backend.
+    #[serde(rename = "lastRequestedAt", skip_serializing_if = "Option::is_none")]
+    pub last_requested_at: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amounts_are_read_from_numbers_and_strings() {
+        let amount = |body| serde_json::from_str::<PaymentBody>(body).unwrap().amount;
+        let cents = |cents| Some(Amount::Valid(Decimal::new(cents, 2)));
+        assert_eq!(amount(r#"{"correlationId":"a","amount":19.9}"#), cents(1990));
+        assert_eq!(amount(r#"{"correlationId":"a","amount":"19.90"}"#), cents(1990));
+        assert_eq!(amount(r#"{"correlationId":"a","amount":" 19.900 "}"#), cents(1990));
+        assert_eq!(amount(r#"{"correlationId":"a","amount":20}"#), cents(2000));
+        assert_eq!(amount(r#"{"correlationId":"a","amount":"19.999"}"#), Some(Amount::Invalid));
+        assert_eq!(amount(r#"{"correlationId":"a","amount":"abc"}"#), Some(Amount::Invalid));
+        assert_eq!(amount(r#"{"correlationId":"a"}"#), None);
+    }
+
+    #[test]
+    fn canonical_body_carries_a_two_decimal_number() {
+        let body = serde_json::from_str::<PaymentBody>(r#"{"correlationId":"a","amount":"19.9","extra":1}"#).unwrap();
+        assert_eq!(body.canonical().unwrap(), br#"{"correlationId":"a","amount":19.90}"#);
+    }
+}
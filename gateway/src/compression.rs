@@ -0,0 +1,67 @@
+use async_compression::tokio::write::{GzipEncoder, ZlibEncoder};
+use hyper::header::{HeaderMap, ACCEPT_ENCODING};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Content codings the gateway can apply to response bodies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    /// HTTP's `deflate` is the zlib format, not raw deflate.
+    Deflate,
+}
+
+impl Encoding {
+    /// Picks a coding from `Accept-Encoding`, preferring gzip. Codings
+    /// listed with `q=0` are refused and `*` stands for gzip.
+    pub fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let mut deflate = false;
+
+        for value in headers.get_all(ACCEPT_ENCODING) {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+
+            for item in value.split(',') {
+                let mut parts = item.split(';').map(str::trim);
+                let coding = parts.next().unwrap_or_default();
+                let refused = parts
+                    .filter_map(|p| p.strip_prefix("q="))
+                    .any(|q| q.parse::<f32>().is_ok_and(|q| q <= 0.0));
+                if refused {
+                    continue;
+                }
+
+                if coding.eq_ignore_ascii_case("gzip") || coding == "*" {
+                    return Some(Encoding::Gzip);
+                }
+                if coding.eq_ignore_ascii_case("deflate") {
+                    deflate = true;
+                }
+            }
+        }
+
+        deflate.then_some(Encoding::Deflate)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Compresses `body` through the async encoder for this coding.
+    pub async fn compress(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        let out = Vec::with_capacity(body.len() / 2 + 64);
+        match self {
+            Encoding::Gzip => Ok(write_all(GzipEncoder::new(out), body).await?.into_inner()),
+            Encoding::Deflate => Ok(write_all(ZlibEncoder::new(out), body).await?.into_inner()),
+        }
+    }
+}
+
+async fn write_all<E: AsyncWrite + Unpin>(mut encoder: E, body: &[u8]) -> std::io::Result<E> {
+    encoder.write_all(body).await?;
+    encoder.shutdown().await?;
+    Ok(encoder)
+}
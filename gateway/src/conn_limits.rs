@@ -0,0 +1,180 @@
+//! Limits on client connections, so keep-alive connections a load tool
+//! leaks do not pin memory for the rest of the run: an idle timeout closing
+//! connections that stopped sending, and a cap on the connections a single
+//! TCP peer may hold open.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+
+/// Connections open per peer address.
+pub struct PeerLimit {
+    max: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl PeerLimit {
+    pub fn new(max: usize) -> Self {
+        Self { max, open: Mutex::new(HashMap::new()) }
+    }
+
+    /// Counts a connection from `peer` until the slot is dropped, or
+    /// returns `None` when the peer already has `max` open.
+    pub fn acquire(self: &Arc<Self>, peer: IpAddr) -> Option<PeerSlot> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(peer).or_default();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(PeerSlot { limit: Arc::clone(self), peer })
+    }
+}
+
+pub struct PeerSlot {
+    limit: Arc<PeerLimit>,
+    peer: IpAddr,
+}
+
+impl Drop for PeerSlot {
+    fn drop(&mut self) {
+        let mut open = self.limit.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.peer) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.peer);
+            }
+        }
+    }
+}
+
+/// When a connection last read or wrote anything.
+pub struct Activity {
+    opened: Instant,
+    last_ms: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self { opened: Instant::now(), last_ms: AtomicU64::new(0) }
+    }
+
+    fn touch(&self) {
+        self.last_ms.store(self.opened.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Resolves once the connection has gone `timeout` without any I/O.
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let last = self.opened + Duration::from_millis(self.last_ms.load(Ordering::Relaxed));
+            if last.elapsed() >= timeout {
+                return;
+            }
+            tokio::time::sleep_until(last + timeout).await;
+        }
+    }
+}
+
+/// A stream recording its I/O in an [`Activity`].
+pub struct Tracked<S> {
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+impl<S> Tracked<S> {
+    pub fn new(inner: S, activity: Arc<Activity>) -> Self {
+        Self { inner, activity }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            this.activity.touch();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll
+            && n > 0
+        {
+            this.activity.touch();
+        }
+        poll
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(n)) = poll
+            && n > 0
+        {
+            this.activity.touch();
+        }
+        poll
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn caps_connections_per_peer() {
+        let limit = Arc::new(PeerLimit::new(2));
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let first = limit.acquire(peer).unwrap();
+        let _second = limit.acquire(peer).unwrap();
+        assert!(limit.acquire(peer).is_none());
+        assert!(limit.acquire("10.0.0.2".parse().unwrap()).is_some());
+
+        drop(first);
+        assert!(limit.acquire(peer).is_some());
+    }
+
+    #[tokio::test]
+    async fn idle_counts_from_the_last_io() {
+        let activity = Arc::new(Activity::new());
+        let (client, _server) = tokio::io::duplex(64);
+        let mut stream = Tracked::new(client, Arc::clone(&activity));
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        stream.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let started = Instant::now();
+        activity.idle(Duration::from_millis(100)).await;
+        // Counted from the write, not from when the stream was opened.
+        assert!(started.elapsed() >= Duration::from_millis(90));
+    }
+}
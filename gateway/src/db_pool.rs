@@ -0,0 +1,210 @@
+//! The summary and lookup connection pool. Deadpool drops connections it
+//! finds closed, but while Postgres is down or restarting every request
+//! still waits out a connect attempt of its own, piling up on the pool and
+//! answering 503 one by one. After enough failures in a row a breaker opens
+//! instead: requests are refused at once for a cooldown, the pool is
+//! rebuilt from scratch, and then a single request probes whether the
+//! database is back.
+
+use crate::error::HandlerError;
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, PoolError, RecyclingMethod, Status};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio_postgres::NoTls;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    /// Failures in a row that open the breaker
+    /// (`GATEWAY_DB_BREAKER_FAILURES`, `0` disables it).
+    pub failures: u32,
+    /// How long requests are refused before a probe
+    /// (`GATEWAY_DB_BREAKER_COOLDOWN_MS`).
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed,
+    /// Refusing requests until `retry_at`.
+    Open { retry_at: Instant },
+    /// One request is trying the database; another may once `retry_at`
+    /// passes without an answer.
+    Probing { retry_at: Instant },
+}
+
+struct Breaker {
+    state: State,
+    failures: u32,
+}
+
+pub struct DbPool {
+    pg_config: tokio_postgres::Config,
+    size: usize,
+    wait_timeout: Option<Duration>,
+    breaker_config: Option<BreakerConfig>,
+    pool: RwLock<Pool>,
+    breaker: Mutex<Breaker>,
+    rebuilds: AtomicU64,
+}
+
+impl DbPool {
+    pub fn new(
+        pg_config: tokio_postgres::Config,
+        size: usize,
+        wait_timeout: Option<Duration>,
+        breaker_config: Option<BreakerConfig>,
+    ) -> Self {
+        let pool = build(&pg_config, size, wait_timeout);
+        Self {
+            pg_config,
+            size,
+            wait_timeout,
+            breaker_config,
+            pool: RwLock::new(pool),
+            breaker: Mutex::new(Breaker { state: State::Closed, failures: 0 }),
+            rebuilds: AtomicU64::new(0),
+        }
+    }
+
+    /// A pooled connection, or [`HandlerError::DatabaseUnavailable`] at once
+    /// while the breaker is open.
+    pub async fn get(&self) -> Result<Object, HandlerError> {
+        self.admit()?;
+        let pool = self.pool.read().unwrap().clone();
+        let client = pool.get().await;
+        self.record(client.as_ref().err());
+        Ok(client?)
+    }
+
+    /// What every pooled connection is opened with.
+    pub fn pg_config(&self) -> &tokio_postgres::Config {
+        &self.pg_config
+    }
+
+    /// The current pool, bypassing the breaker.
+    pub fn pool(&self) -> Pool {
+        self.pool.read().unwrap().clone()
+    }
+
+    pub fn status(&self) -> Status {
+        self.pool.read().unwrap().status()
+    }
+
+    /// `closed`, `open` or `probing`.
+    pub fn breaker_state(&self) -> &'static str {
+        match self.breaker.lock().unwrap().state {
+            State::Closed => "closed",
+            State::Open { .. } => "open",
+            State::Probing { .. } => "probing",
+        }
+    }
+
+    /// Times the pool was replaced after the breaker opened.
+    pub fn rebuilds(&self) -> u64 {
+        self.rebuilds.load(Ordering::Relaxed)
+    }
+
+    fn admit(&self) -> Result<(), HandlerError> {
+        let Some(config) = self.breaker_config else {
+            return Ok(());
+        };
+        let mut breaker = self.breaker.lock().unwrap();
+        let now = Instant::now();
+        match breaker.state {
+            State::Closed => Ok(()),
+            State::Open { retry_at } | State::Probing { retry_at } if now < retry_at => {
+                Err(HandlerError::DatabaseUnavailable)
+            }
+            _ => {
+                breaker.state = State::Probing { retry_at: now + config.cooldown };
+                tracing::warn!("Probing the database");
+                Ok(())
+            }
+        }
+    }
+
+    /// Counts the outcome of asking the pool for a connection. Waiting too
+    /// long for a free connection, the only timeout the pool is built with,
+    /// means it is busy rather than that the database is gone, so it is not
+    /// counted.
+    fn record(&self, error: Option<&PoolError>) {
+        let Some(config) = self.breaker_config else {
+            return;
+        };
+        let mut breaker = self.breaker.lock().unwrap();
+        let Some(error) = error else {
+            if breaker.state != State::Closed {
+                tracing::warn!("Database is back, breaker closed");
+            }
+            *breaker = Breaker { state: State::Closed, failures: 0 };
+            return;
+        };
+        if matches!(error, PoolError::Timeout(_)) {
+            return;
+        }
+
+        breaker.failures += 1;
+        let reopen = matches!(breaker.state, State::Probing { .. })
+            || (breaker.state == State::Closed && breaker.failures >= config.failures);
+        if reopen {
+            breaker.state = State::Open { retry_at: Instant::now() + config.cooldown };
+            tracing::warn!(
+                failures = breaker.failures,
+                error = %error,
+                cooldown_ms = config.cooldown.as_millis() as u64,
+                "Database pool failing, breaker open, rebuilding the pool"
+            );
+            drop(breaker);
+            self.rebuild();
+        }
+    }
+
+    /// Replaces the pool, closing the old one's connections once the
+    /// requests holding them are done.
+    fn rebuild(&self) {
+        let pool = build(&self.pg_config, self.size, self.wait_timeout);
+        let old = std::mem::replace(&mut *self.pool.write().unwrap(), pool);
+        old.close();
+        self.rebuilds.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn build(pg_config: &tokio_postgres::Config, size: usize, wait_timeout: Option<Duration>) -> Pool {
+    let mgr = Manager::from_config(
+        pg_config.clone(),
+        NoTls,
+        ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        },
+    );
+    Pool::builder(mgr)
+        .max_size(size)
+        .wait_timeout(wait_timeout)
+        .runtime(deadpool_postgres::Runtime::Tokio1)
+        .build()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn breaker_opens_after_failures_and_probes_after_the_cooldown() {
+        // Nothing listens on port 1, so every connect is refused.
+        let pg_config = "host=127.0.0.1 port=1 user=postgres".parse().unwrap();
+        let breaker = BreakerConfig { failures: 2, cooldown: Duration::from_millis(50) };
+        let db = DbPool::new(pg_config, 1, None, Some(breaker));
+
+        for _ in 0..2 {
+            assert!(matches!(db.get().await, Err(HandlerError::Pool(_))));
+        }
+        assert_eq!((db.breaker_state(), db.rebuilds()), ("open", 1));
+        assert!(matches!(db.get().await, Err(HandlerError::DatabaseUnavailable)));
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(matches!(db.get().await, Err(HandlerError::Pool(_))));
+        assert_eq!((db.breaker_state(), db.rebuilds()), ("open", 2));
+    }
+}
@@ -0,0 +1,97 @@
+use crate::worker_summary::WorkerSummaryError;
+use hyper::StatusCode;
+
+/// Why a request could not be served. Handlers bubble these up with `?` and
+/// the router turns them into a bodiless response with [`Self::status`].
+#[derive(Debug)]
+pub enum HandlerError {
+    /// A query parameter or path segment is malformed.
+    BadRequest(&'static str),
+    /// A protected endpoint was called without the expected token.
+    Unauthorized,
+    /// The request body could not be read.
+    Body(hyper::Error),
+    /// The client did not finish sending the body in time.
+    BodyTimeout,
+    /// The handler outlasted its route's timeout.
+    Timeout,
+    Pool(deadpool_postgres::PoolError),
+    /// The database pool's breaker is open after repeated failures.
+    DatabaseUnavailable,
+    Database(tokio_postgres::Error),
+    Redis(redis::RedisError),
+    Worker(WorkerSummaryError),
+    Serialization(serde_json::Error),
+}
+
+impl HandlerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HandlerError::BadRequest(_) | HandlerError::Body(_) => StatusCode::BAD_REQUEST,
+            HandlerError::Unauthorized => StatusCode::UNAUTHORIZED,
+            HandlerError::BodyTimeout => StatusCode::REQUEST_TIMEOUT,
+            HandlerError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            HandlerError::Pool(_) | HandlerError::DatabaseUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            HandlerError::Database(_)
+            | HandlerError::Redis(_)
+            | HandlerError::Worker(_)
+            | HandlerError::Serialization(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl std::fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandlerError::BadRequest(reason) => write!(f, "Bad request: {}", reason),
+            HandlerError::Unauthorized => write!(f, "Unauthorized"),
+            HandlerError::Body(e) => write!(f, "Failed to read request body: {}", e),
+            HandlerError::BodyTimeout => write!(f, "Timed out reading request body"),
+            HandlerError::Timeout => write!(f, "Timed out handling request"),
+            HandlerError::Pool(e) => write!(f, "No database connection available: {}", e),
+            HandlerError::DatabaseUnavailable => write!(f, "Database unavailable, breaker open"),
+            HandlerError::Database(e) => write!(f, "Database error: {}", e),
+            HandlerError::Redis(e) => write!(f, "Redis error: {}", e),
+            HandlerError::Worker(e) => write!(f, "Worker summary error: {}", e),
+            HandlerError::Serialization(e) => write!(f, "Serialization error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+impl From<hyper::Error> for HandlerError {
+    fn from(e: hyper::Error) -> Self {
+        HandlerError::Body(e)
+    }
+}
+
+impl From<deadpool_postgres::PoolError> for HandlerError {
+    fn from(e: deadpool_postgres::PoolError) -> Self {
+        HandlerError::Pool(e)
+    }
+}
+
+impl From<tokio_postgres::Error> for HandlerError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        HandlerError::Database(e)
+    }
+}
+
+impl From<redis::RedisError> for HandlerError {
+    fn from(e: redis::RedisError) -> Self {
+        HandlerError::Redis(e)
+    }
+}
+
+impl From<WorkerSummaryError> for HandlerError {
+    fn from(e: WorkerSummaryError) -> Self {
+        HandlerError::Worker(e)
+    }
+}
+
+impl From<serde_json::Error> for HandlerError {
+    fn from(e: serde_json::Error) -> Self {
+        HandlerError::Serialization(e)
+    }
+}
@@ -1,60 +1,433 @@
-﻿use crate::publisher::Publisher;
+﻿use crate::db_pool::{BreakerConfig, DbPool};
+use crate::error::HandlerError;
+use crate::listener::ListenAddr;
+use crate::publisher::{BatchConfig, Dispatch, FanOutPublisher, Publisher, PublisherError};
+use crate::rate_limiter::{RateLimit, RateLimiter};
+use crate::redis_summary::RedisSummary;
+use crate::startup;
+use crate::stats::Stats;
+use crate::summary_snapshot::SummarySnapshot;
+use crate::worker_summary::WorkerSummary;
 use std::env;
-use deadpool_postgres::{Manager, ManagerConfig, RecyclingMethod};
-use tokio_postgres::NoTls;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[derive(Clone)]
 pub struct GatewayConfig {
-    pub publish_path: String,
-    pub listen_path: String,
+    /// Worker sockets (`GATEWAY_PUBLISH_SOCKET`, comma separated).
+    pub publish_paths: Vec<String>,
+    /// How payments are spread over `publish_paths`
+    /// (`GATEWAY_PUBLISH_DISPATCH`, `round-robin` or `hash`).
+    pub publish_dispatch: Dispatch,
+    /// Coalesces concurrent publishes when set (`GATEWAY_PUBLISH_BATCH_WINDOW_US`).
+    pub publish_batch: Option<BatchConfig>,
+    /// Waits for worker credits before publishing and answers 429 without
+    /// them (`GATEWAY_PUBLISH_CREDITS`); the workers must run with
+    /// `RECEIVER_CREDITS`.
+    pub publish_credits: bool,
+    /// Sends LZ4-compressed frames, for payloads carrying more than a
+    /// payment (`GATEWAY_PUBLISH_COMPRESSION`, `none` or `lz4`).
+    pub publish_compression: bool,
+    /// Public listeners, each served by its own accept loop, so a proxy can
+    /// spread connections over several sockets and their accept queues.
+    pub listen: Vec<ListenAddr>,
+    /// Unix socket serving `/internal/stats`, `/internal/readyz` and
+    /// `/internal/log-level` (`GATEWAY_ADMIN_SOCKET`).
+    pub admin_listen: Option<ListenAddr>,
     pub postgres_url: String,
+    /// Connections in the summary/lookup pool (`GATEWAY_DB_POOL_SIZE`).
+    pub db_pool_size: usize,
+    /// How long a request waits for a pooled connection before it is
+    /// answered with 503; unbounded when unset
+    /// (`GATEWAY_DB_POOL_WAIT_TIMEOUT_MS`, `0` to disable).
+    pub db_pool_wait_timeout: Option<Duration>,
+    /// Refuses database requests for a while and rebuilds the pool after
+    /// repeated connection failures; see [`crate::db_pool`].
+    pub db_breaker: Option<BreakerConfig>,
+    pub http1: Http1Config,
+    /// Time a client gets to send a whole request body, answered with 408
+    /// past it (`GATEWAY_BODY_READ_TIMEOUT_MS`, 5 s by default, `0` waits
+    /// forever).
+    pub body_read_timeout: Option<Duration>,
+    /// Time from receiving a payment to the worker processing it
+    /// (`GATEWAY_REQUEST_BUDGET_MS`, `0` for no limit). A publish still
+    /// pending at the deadline is answered 504. Anything answered 202 is
+    /// processed; the deadline travels in the message only so the worker
+    /// routes payments that miss it as late.
+    pub request_budget: Option<Duration>,
+    /// Longest a summary or payment lookup may take before it is answered
+    /// 504 (`GATEWAY_QUERY_TIMEOUT_MS`, `0` for no limit).
+    pub query_timeout: Option<Duration>,
+    pub rate_limit: Option<RateLimit>,
+    pub peer_rate_limit: Option<RateLimit>,
+    /// Serve `/payments-summary` from the worker-maintained Redis counters
+    /// at this URL instead of Postgres (`SUMMARY_BACKEND=redis`).
+    pub summary_redis_url: Option<String>,
+    /// Serve `/payments-summary` from the totals workers hold in memory,
+    /// read from these admin sockets (`SUMMARY_BACKEND=worker`,
+    /// `SUMMARY_WORKER_SOCKETS`). Postgres is then neither queried nor
+    /// purged; `/purge-payments` needs the workers to run with
+    /// `ADMIN_ALLOW_PURGE`.
+    pub summary_worker_sockets: Vec<String>,
+    /// When set, `/purge-payments` requires a matching `X-Purge-Token`.
+    pub purge_token: Option<String>,
+    /// Refresh interval of the precomputed all-time summary
+    /// (`GATEWAY_SUMMARY_REFRESH_MS`, `0` to query Postgres per request).
+    /// Only used with the Postgres summary backend. Purges and imports drop
+    /// it on every replica, through a Postgres `NOTIFY`.
+    pub summary_refresh: Option<Duration>,
+    /// Longest startup waits for Postgres, then for the worker sockets,
+    /// before serving anyway (`GATEWAY_STARTUP_TIMEOUT_MS`). `/readyz` stays
+    /// `warming` until both have answered.
+    pub startup_timeout: Duration,
+    /// Exit rather than serve when no worker socket took a connection within
+    /// `startup_timeout` (`GATEWAY_REQUIRE_WORKERS`), so payments are never
+    /// accepted only to fail on the first publish.
+    pub require_workers: bool,
+    /// Longest the gateway waits after SIGTERM for payments still being
+    /// published, batched ones included (`GATEWAY_SHUTDOWN_TIMEOUT_MS`).
+    pub shutdown_timeout: Duration,
+    /// Tags every published payment (`RUN_ID`). Generated at startup when
+    /// unset; replicas that should count as one run need it set explicitly.
+    pub run_id: String,
+    #[cfg(feature = "shm-transport")]
+    pub shm_ring_path: Option<String>,
+}
+
+/// Knobs for the hyper HTTP/1 connection builder.
+#[derive(Clone)]
+pub struct Http1Config {
+    pub keep_alive: bool,
+    pub writev: bool,
+    /// Hyper rejects anything below 8 KiB, so smaller values are raised.
+    pub max_buf_size: usize,
+    /// Time a client gets to send a request's headers, counted from when the
+    /// connection starts waiting for them, idle keep-alive included. A
+    /// client that takes longer is disconnected
+    /// (`GATEWAY_HTTP1_HEADER_READ_TIMEOUT_MS`, `0` to disable).
+    ///
+    /// On by default with 10 s, where it used to be off: a keep-alive
+    /// connection left idle for longer is now closed, which clients pooling
+    /// connections to the gateway, such as the load balancer, must expect.
+    /// Setting it to `0` restores the old behaviour.
+    pub header_read_timeout: Option<Duration>,
+    /// Closes a connection that neither reads nor writes for this long,
+    /// once its request in flight is answered
+    /// (`GATEWAY_HTTP1_IDLE_TIMEOUT_MS`, `0` to disable).
+    pub idle_timeout: Option<Duration>,
+    /// Connections a single TCP peer may hold open; further ones are
+    /// closed as soon as they are accepted
+    /// (`GATEWAY_MAX_CONNECTIONS_PER_PEER`, `0` for no cap).
+    pub max_connections_per_peer: Option<usize>,
+}
+
+impl Http1Config {
+    pub fn from_env() -> Self {
+        let header_read_timeout_ms: u64 = env_or("GATEWAY_HTTP1_HEADER_READ_TIMEOUT_MS", 10_000);
+
+        Self {
+            keep_alive: env_or("GATEWAY_HTTP1_KEEP_ALIVE", true),
+            writev: env_or("GATEWAY_HTTP1_WRITEV", true),
+            max_buf_size: env_or("GATEWAY_HTTP1_MAX_BUF_SIZE", 16 * 1024usize).max(8 * 1024),
+            header_read_timeout: (header_read_timeout_ms > 0)
+                .then(|| Duration::from_millis(header_read_timeout_ms)),
+            idle_timeout: Some(env_or("GATEWAY_HTTP1_IDLE_TIMEOUT_MS", 0u64))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            max_connections_per_peer: Some(env_or("GATEWAY_MAX_CONNECTIONS_PER_PEER", 0usize))
+                .filter(|max| *max > 0),
+        }
+    }
+}
+
+/// Reads `<prefix>_RPS` and `<prefix>_BURST`; a rate of zero disables the limit.
+fn rate_limit_from_env(prefix: &str) -> Option<RateLimit> {
+    let per_second: f64 = env_or(&format!("{}_RPS", prefix), 0.0);
+    if per_second <= 0.0 {
+        return None;
+    }
+
+    Some(RateLimit {
+        per_second,
+        burst: env_or(&format!("{}_BURST", prefix), per_second).max(1.0),
+    })
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
 }
 
 impl GatewayConfig {
     pub fn from_env() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let listen_path = env::var("GATEWAY_LISTEN_SOCKET").unwrap();
+        // `GATEWAY_LISTEN` takes `tcp://` or `unix://` addresses; the older
+        // `GATEWAY_LISTEN_SOCKET` takes bare socket paths. Both are comma
+        // separated.
+        let listen = env::var("GATEWAY_LISTEN")
+            .or_else(|_| env::var("GATEWAY_LISTEN_SOCKET"))
+            .unwrap()
+            .split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<ListenAddr>, _>>()?;
+        if listen.is_empty() {
+            return Err("GATEWAY_LISTEN lists no address".into());
+        }
+
+        let admin_listen = match env::var("GATEWAY_ADMIN_SOCKET") {
+            Ok(addr) => match addr.parse()? {
+                ListenAddr::Tcp(_) => return Err("GATEWAY_ADMIN_SOCKET must be a unix socket".into()),
+                unix => Some(unix),
+            },
+            Err(_) => None,
+        };
+
+        let publish_paths: Vec<String> = env::var("GATEWAY_PUBLISH_SOCKET")
+            .unwrap()
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .map(String::from)
+            .collect();
+        if publish_paths.is_empty() {
+            return Err("GATEWAY_PUBLISH_SOCKET lists no socket".into());
+        }
+
+        let publish_dispatch = match env::var("GATEWAY_PUBLISH_DISPATCH") {
+            Ok(dispatch) => dispatch.parse()?,
+            Err(_) => Dispatch::RoundRobin,
+        };
 
-        let publish_path = env::var("GATEWAY_PUBLISH_SOCKET").unwrap();
+        let batch_window_us: u64 = env_or("GATEWAY_PUBLISH_BATCH_WINDOW_US", 0);
+        let publish_batch = (batch_window_us > 0).then(|| BatchConfig {
+            window: Duration::from_micros(batch_window_us),
+            max_messages: env_or("GATEWAY_PUBLISH_BATCH_MAX", 64usize).max(1),
+        });
 
-        let postgres_url = env::var("POSTGRES_URL").unwrap();
+        let publish_compression = match env::var("GATEWAY_PUBLISH_COMPRESSION").as_deref() {
+            Err(_) | Ok("none") => false,
+            Ok("lz4") => true,
+            Ok(other) => return Err(format!("unknown publish compression: {}", other).into()),
+        };
+
+        let (summary_redis_url, summary_worker_sockets) = match env::var("SUMMARY_BACKEND").as_deref() {
+            Err(_) | Ok("postgres") => (None, Vec::new()),
+            Ok("redis") => (
+                Some(env::var("REDIS_URL").map_err(|_| "SUMMARY_BACKEND=redis requires REDIS_URL")?),
+                Vec::new(),
+            ),
+            Ok("worker") => {
+                let sockets: Vec<String> = env::var("SUMMARY_WORKER_SOCKETS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|path| !path.is_empty())
+                    .map(String::from)
+                    .collect();
+                if sockets.is_empty() {
+                    return Err("SUMMARY_BACKEND=worker requires SUMMARY_WORKER_SOCKETS".into());
+                }
+                (None, sockets)
+            }
+            Ok(other) => return Err(format!("unknown SUMMARY_BACKEND: {}", other).into()),
+        };
+
+        // Only payment lookups still need Postgres with the worker backend.
+        let postgres_url = match env::var("POSTGRES_URL") {
+            Ok(url) => url,
+            Err(_) if !summary_worker_sockets.is_empty() => String::new(),
+            Err(e) => return Err(format!("POSTGRES_URL: {}", e).into()),
+        };
+
+        let run_id = match env::var("RUN_ID") {
+            Ok(run_id) if is_valid_run_id(&run_id) => run_id,
+            Ok(run_id) => return Err(format!("invalid RUN_ID {:?}: use 1-64 of [A-Za-z0-9_.-]", run_id).into()),
+            Err(_) => generate_run_id(),
+        };
 
         Ok(Self {
-            listen_path,
-            publish_path,
-            postgres_url
+            listen,
+            admin_listen,
+            publish_paths,
+            publish_dispatch,
+            publish_batch,
+            publish_credits: env_or("GATEWAY_PUBLISH_CREDITS", false),
+            publish_compression,
+            postgres_url,
+            db_pool_size: env_or("GATEWAY_DB_POOL_SIZE", 3usize).max(1),
+            db_pool_wait_timeout: Some(env_or("GATEWAY_DB_POOL_WAIT_TIMEOUT_MS", 10u64))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            db_breaker: Some(env_or("GATEWAY_DB_BREAKER_FAILURES", 5u32))
+                .filter(|failures| *failures > 0)
+                .map(|failures| BreakerConfig {
+                    failures,
+                    cooldown: Duration::from_millis(env_or("GATEWAY_DB_BREAKER_COOLDOWN_MS", 2_000)),
+                }),
+            http1: Http1Config::from_env(),
+            request_budget: Some(env_or("GATEWAY_REQUEST_BUDGET_MS", 0u64))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            query_timeout: Some(env_or("GATEWAY_QUERY_TIMEOUT_MS", 0u64))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            body_read_timeout: Some(env_or("GATEWAY_BODY_READ_TIMEOUT_MS", 5_000u64))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            rate_limit: rate_limit_from_env("GATEWAY_RATE_LIMIT"),
+            peer_rate_limit: rate_limit_from_env("GATEWAY_PEER_RATE_LIMIT"),
+            summary_redis_url,
+            summary_worker_sockets,
+            run_id,
+            startup_timeout: Duration::from_millis(env_or("GATEWAY_STARTUP_TIMEOUT_MS", 30_000)),
+            require_workers: env_or("GATEWAY_REQUIRE_WORKERS", false),
+            shutdown_timeout: Duration::from_millis(env_or("GATEWAY_SHUTDOWN_TIMEOUT_MS", 5_000)),
+            purge_token: env::var("GATEWAY_PURGE_TOKEN").ok().filter(|token| !token.is_empty()),
+            summary_refresh: Some(env_or("GATEWAY_SUMMARY_REFRESH_MS", 0u64))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            #[cfg(feature = "shm-transport")]
+            shm_ring_path: env::var("GATEWAY_SHM_RING_PATH").ok(),
         })
     }
 }
 
+/// Run ids are embedded in JSON and SQL parameters as-is, so only plain
+/// characters are allowed.
+pub fn is_valid_run_id(run_id: &str) -> bool {
+    (1..=64).contains(&run_id.len())
+        && run_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b'-'))
+}
+
+/// Request ids (`X-Request-Id`) are copied into worker messages unescaped,
+/// so they must pass the same check as run ids; others are ignored.
+pub fn is_valid_request_id(request_id: &str) -> bool {
+    is_valid_run_id(request_id)
+}
+
+/// Startup time in hex microseconds; unique enough to tell runs apart.
+fn generate_run_id() -> String {
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros())
+        .unwrap_or_default();
+    format!("{:x}", micros)
+}
+
 pub struct Gateway {
-    pub publisher: Publisher,
-    pub pool: deadpool_postgres::Pool,
+    pub publisher: FanOutPublisher,
+    #[cfg(feature = "shm-transport")]
+    pub shm_publisher: Option<crate::shm_transport::ShmPublisher>,
+    pub pool: DbPool,
+    pub redis_summary: Option<RedisSummary>,
+    pub worker_summary: Option<WorkerSummary>,
+    /// Serves unfiltered summaries when configured; see
+    /// [`GatewayConfig::summary_refresh`].
+    pub summary_snapshot: Option<SummarySnapshot>,
+    pub rate_limiter: RateLimiter,
+    pub stats: Stats,
+    pub purge_token: Option<String>,
+    pub run_id: String,
+    pub body_read_timeout: Option<Duration>,
+    pub request_budget: Option<Duration>,
 }
 
 impl Gateway {
     pub async fn new(
         config: GatewayConfig,
     ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let publisher = Publisher::new(config.publish_path, 1024).await?;
+        let mut publishers = Vec::with_capacity(config.publish_paths.len());
+        for path in config.publish_paths {
+            let mut publisher = Publisher::new(path, 1024).await?;
+            // Before batching, which hands the batch task its own copy.
+            if config.publish_credits {
+                publisher = publisher.with_credit_flow();
+            }
+            if config.publish_compression {
+                publisher = publisher.with_compression();
+            }
+            if let Some(batch) = config.publish_batch {
+                publisher = publisher.with_batching(batch);
+            }
+            publishers.push(publisher);
+        }
+        let publisher = FanOutPublisher::new(publishers, config.publish_dispatch);
 
         let pg_config = config.postgres_url
             .parse::<tokio_postgres::Config>()
             .expect("Invalid DATABASE_URL");
 
-        let mgr = Manager::from_config(
-            pg_config,
-            NoTls,
-            ManagerConfig {
-                recycling_method: RecyclingMethod::Fast
-            }
-        );
+        let pool = DbPool::new(pg_config, config.db_pool_size, config.db_pool_wait_timeout, config.db_breaker);
+
+        let redis_summary = match &config.summary_redis_url {
+            Some(url) => Some(RedisSummary::connect(url).await?),
+            None => None,
+        };
+
+        #[cfg(feature = "shm-transport")]
+        let shm_publisher = match &config.shm_ring_path {
+            Some(path) => Some(crate::shm_transport::ShmPublisher::open(path)?),
+            None => None,
+        };
+
+        Ok(Self {
+            publisher,
+            #[cfg(feature = "shm-transport")]
+            shm_publisher,
+            pool,
+            summary_snapshot: config
+                .summary_refresh
+                .filter(|_| redis_summary.is_none() && config.summary_worker_sockets.is_empty())
+                .map(SummarySnapshot::new),
+            redis_summary,
+            worker_summary: Some(config.summary_worker_sockets)
+                .filter(|sockets| !sockets.is_empty())
+                .map(WorkerSummary::new),
+            rate_limiter: RateLimiter::new(config.rate_limit, config.peer_rate_limit),
+            stats: Stats::default(),
+            purge_token: config.purge_token,
+            run_id: config.run_id,
+            body_read_timeout: config.body_read_timeout,
+            request_budget: config.request_budget,
+        })
+    }
+
+    /// Hands a payment to the worker over the shared memory ring when one is
+    /// configured, falling back to the unix socket publisher otherwise.
+    pub async fn publish(&self, msg: &[u8]) -> Result<(), PublisherError> {
+        let result = self.publish_inner(msg).await;
+        self.stats.record_publish(&result);
+        result
+    }
+
+    /// Polls Postgres until it answers or `timeout` passes, see
+    /// [`startup::wait_for_database`]. Always true with the worker summary
+    /// backend, which needs no database.
+    pub async fn wait_for_database(&self, timeout: Duration) -> bool {
+        self.worker_summary.is_some() || startup::wait_for_database(&self.pool.pool(), timeout).await
+    }
+
+    /// A pooled connection, recording how long it took to get one. Refused
+    /// at once, and not recorded, while the pool's breaker is open.
+    pub async fn db_client(&self) -> Result<deadpool_postgres::Object, HandlerError> {
+        let started = Instant::now();
+        let client = self.pool.get().await;
+        if !matches!(client, Err(HandlerError::DatabaseUnavailable)) {
+            self.stats.record_pool_wait(started.elapsed(), client.is_ok());
+        }
+        client
+    }
 
-        let pool = deadpool_postgres::Pool::builder(mgr)
-            .max_size(3)
-            .runtime(deadpool_postgres::Runtime::Tokio1)
-            .build()
-            .unwrap();
+    async fn publish_inner(&self, msg: &[u8]) -> Result<(), PublisherError> {
+        #[cfg(feature = "shm-transport")]
+        if let Some(shm_publisher) = &self.shm_publisher {
+            return shm_publisher.publish(msg);
+        }
 
-        Ok(Self { publisher, pool })
+        self.publisher.publish(msg).await
     }
 }
@@ -1,28 +1,52 @@
 extern crate core;
 
+mod api;
+mod compression;
+mod conn_limits;
+mod db_pool;
+mod error;
 mod gateway;
+mod payment_import;
 mod publisher;
+mod rate_limiter;
+mod redis_summary;
+mod router;
+mod static_response;
+mod stats;
+mod summary_queries;
+mod summary_snapshot;
+mod worker_summary;
+#[cfg(feature = "shm-transport")]
+mod shm_transport;
 
-use crate::gateway::{Gateway, GatewayConfig};
-use deadpool_postgres::Pool;
+use common::{accept_loop, build_info, listener, logging, startup};
+use crate::api::{Amount, PaymentBody, ProcessorSummary, Summary};
+use crate::compression::Encoding;
+use crate::error::HandlerError;
+use crate::gateway::{Gateway, GatewayConfig, Http1Config};
+use crate::listener::Listener;
+use crate::publisher::{stamp_message, stamped_key, PublisherError};
+use crate::redis_summary::RedisSummary;
+use crate::router::{HandlerResult, Metrics, Params, Query, Router, Timeout};
+use crate::worker_summary::WorkerSummary;
+use crate::conn_limits::{Activity, PeerLimit, Tracked};
+use crate::stats::ConnectionErrorKind;
 use http_body_util::{combinators::BoxBody, BodyExt};
 use http_body_util::{Empty, Full};
 use hyper::body::{Bytes, Incoming};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response};
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioIo, TokioTimer};
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::Serialize;
 use std::error::Error;
 use std::fmt;
-use std::os::unix::fs::PermissionsExt;
 use std::sync::Arc;
 use time::format_description::well_known::Rfc3339;
 use time::PrimitiveDateTime;
-use tokio::net::UnixListener;
-use tokio_postgres::types::{FromSql, Type};
+use tokio_postgres::types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+use bytes::BytesMut;
 
 fn empty() -> BoxBody<Bytes, hyper::Error> {
     Empty::<Bytes>::new()
@@ -35,7 +59,7 @@ fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
         .boxed()
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ServiceType {
     Default,
     Fallback,
@@ -50,225 +74,887 @@ impl fmt::Display for ServiceType {
     }
 }
 
-impl<'a> FromSql<'a> for ServiceType {
-    fn from_sql(_ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
-        let s = std::str::from_utf8(raw)?;
+impl std::str::FromStr for ServiceType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "default" => Ok(ServiceType::Default),
             "fallback" => Ok(ServiceType::Fallback),
-            _ => Err(format!("unknown service_type variant: {}", s).into()),
+            _ => Err(format!("unknown service_type variant: {}", s)),
         }
     }
+}
+
+impl ToSql for ServiceType {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        self.to_string().as_str().to_sql(ty, out)
+    }
 
     fn accepts(ty: &Type) -> bool {
         ty.name() == "service_type"
     }
+
+    to_sql_checked!();
 }
 
+impl<'a> FromSql<'a> for ServiceType {
+    fn from_sql(_ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(std::str::from_utf8(raw)?.parse()?)
+    }
 
-#[derive(Deserialize, Serialize)]
-struct ProcessorSummary {
-    #[serde(rename = "totalRequests")]
-    total_requests: i64,
-    #[serde(rename = "totalAmount")]
-    total_amount: Decimal,
+    fn accepts(ty: &Type) -> bool {
+        ty.name() == "service_type"
+    }
 }
 
-#[derive(Deserialize, Serialize)]
-struct Summary {
-    default: ProcessorSummary,
-    fallback: ProcessorSummary,
+async fn payments_summary_handler(
+    gateway: &Gateway,
+    from: Option<PrimitiveDateTime>,
+    to: Option<PrimitiveDateTime>,
+    processor: Option<ServiceType>,
+    run_id: Option<&str>,
+    with_meta: bool,
+    encoding: Option<Encoding>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, HandlerError> {
+    let snapshot = gateway
+        .summary_snapshot
+        .as_ref()
+        .filter(|_| from.is_none() && to.is_none() && run_id.is_none() && !with_meta)
+        .and_then(|snapshot| snapshot.get());
+
+    let ((default_summary, fallback_summary), last_requested_at) =
+        match (snapshot, &gateway.redis_summary, &gateway.worker_summary) {
+            (Some(totals), _, _) => (totals, None),
+            (None, Some(_), _) if run_id.is_some() => {
+                return Err(HandlerError::BadRequest("runId needs the postgres or worker summary backend"));
+            }
+            (None, Some(redis_summary), _) => (redis_totals(redis_summary, from, to).await?, None),
+            (None, None, Some(worker_summary)) => (worker_totals(worker_summary, from, to, run_id).await?, None),
+            (None, None, None) => postgres_summary(gateway, from, to, &processor, run_id, with_meta).await?,
+        };
+
+    let summary = Summary {
+        default: (processor != Some(ServiceType::Fallback)).then_some(default_summary),
+        fallback: (processor != Some(ServiceType::Default)).then_some(fallback_summary),
+        last_requested_at: last_requested_at.and_then(|at| at.format(&Rfc3339).ok()),
+    };
+
+    let json_summary = serde_json::to_vec(&summary)?;
+    let mut ok = encoded_response(json_summary, encoding).await;
+    ok.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    Ok(ok)
 }
 
-async fn payments_summary_handler(
-    pool: &Pool,
+/// Totals per processor and, with `with_meta`, the newest `requested_at`.
+/// Both statements are sent before either result is awaited, so tokio-postgres
+/// pipelines them on the connection and the metadata costs no extra round
+/// trip.
+async fn postgres_summary(
+    gateway: &Gateway,
     from: Option<PrimitiveDateTime>,
     to: Option<PrimitiveDateTime>,
-) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-    match pool.get().await {
-        Ok(client) => {
-            let stmt = client
-                .prepare(
-                    "
-                SELECT COUNT(*) AS total_requests,
-                      SUM(amount) AS total_amount,
-                      service_used
-                FROM payments
-                WHERE ($1::timestamp IS NULL OR requested_at >= $1::timestamp)
-                 AND ($2::timestamp IS NULL OR requested_at <= $2::timestamp)
-                GROUP BY service_used;
-            ",
-                )
-                .await
-                .unwrap();
+    processor: &Option<ServiceType>,
+    run_id: Option<&str>,
+    with_meta: bool,
+) -> Result<((ProcessorSummary, ProcessorSummary), Option<time::OffsetDateTime>), HandlerError> {
+    let client = gateway.db_client().await?;
+    let params: [&(dyn ToSql + Sync); 4] = [&from, &to, processor, &run_id];
 
-            let rows = client.query(&stmt, &[&from, &to]).await.unwrap();
+    if !with_meta {
+        let totals = client.prepare_cached(summary_queries::TOTALS).await?;
+        let rows = client.query(&totals, &params).await?;
+        return Ok((totals_from_rows(rows)?, None));
+    }
 
-            let mut default_summary = ProcessorSummary {
-                total_requests: 0,
-                total_amount: Decimal::ZERO
-            };
-            let mut fallback_summary = ProcessorSummary {
-                total_requests: 0,
-                total_amount: Decimal::ZERO,
-            };
+    let (totals, last_requested_at) = tokio::try_join!(
+        client.prepare_cached(summary_queries::TOTALS),
+        client.prepare_cached(summary_queries::LAST_REQUESTED_AT),
+    )?;
+    let (rows, last_row) = tokio::try_join!(
+        client.query(&totals, &params),
+        client.query_one(&last_requested_at, &params),
+    )?;
 
-            for row in rows {
-                let total_requests: i64 = row.get("total_requests");
-                let total_amount: Decimal = row.get("total_amount");
-                let processor: ServiceType = row.get("service_used");
+    Ok((totals_from_rows(rows)?, last_row.try_get("last_requested_at")?))
+}
 
-                if processor == ServiceType::Default {
-                    default_summary.total_requests = total_requests;
-                    default_summary.total_amount = total_amount;
-                } else {
-                    fallback_summary.total_requests = total_requests;
-                    fallback_summary.total_amount = total_amount;
-                }
-            }
+fn totals_from_rows(
+    rows: Vec<tokio_postgres::Row>,
+) -> Result<(ProcessorSummary, ProcessorSummary), HandlerError> {
+    let mut default_summary = ProcessorSummary {
+        total_requests: 0,
+        total_amount: Decimal::ZERO,
+    };
+    let mut fallback_summary = ProcessorSummary {
+        total_requests: 0,
+        total_amount: Decimal::ZERO,
+    };
 
-            let summary = Summary {
-                default: default_summary,
-                fallback: fallback_summary,
-            };
+    for row in rows {
+        let total_requests: i64 = row.try_get("total_requests")?;
+        let total_amount: Decimal = row.try_get("total_amount")?;
+        // Processors past the default/fallback pair are not part of the
+        // summary.
+        let Ok(processor) = row.try_get::<_, ServiceType>("service_used") else {
+            continue;
+        };
+
+        if processor == ServiceType::Default {
+            default_summary.total_requests = total_requests;
+            default_summary.total_amount = total_amount;
+        } else {
+            fallback_summary.total_requests = total_requests;
+            fallback_summary.total_amount = total_amount;
+        }
+    }
+
+    Ok((default_summary, fallback_summary))
+}
+
+async fn worker_totals(
+    worker_summary: &WorkerSummary,
+    from: Option<PrimitiveDateTime>,
+    to: Option<PrimitiveDateTime>,
+    run_id: Option<&str>,
+) -> Result<(ProcessorSummary, ProcessorSummary), HandlerError> {
+    let unix_millis = |at: PrimitiveDateTime| (at.assume_utc().unix_timestamp_nanos() / 1_000_000) as i64;
+
+    let [default, fallback] = worker_summary
+        .totals(from.map(unix_millis), to.map(unix_millis), run_id)
+        .await?;
+
+    let to_summary = |(total_requests, cents): (i64, i64)| ProcessorSummary {
+        total_requests,
+        total_amount: Decimal::new(cents, 2),
+    };
+    Ok((to_summary(default), to_summary(fallback)))
+}
+
+async fn redis_totals(
+    redis_summary: &RedisSummary,
+    from: Option<PrimitiveDateTime>,
+    to: Option<PrimitiveDateTime>,
+) -> Result<(ProcessorSummary, ProcessorSummary), HandlerError> {
+    let unix_millis = |at: PrimitiveDateTime| (at.assume_utc().unix_timestamp_nanos() / 1_000_000) as i64;
+
+    let [default, fallback] = redis_summary
+        .totals(from.map(unix_millis), to.map(unix_millis))
+        .await?;
 
-            let json_summary = serde_json::to_string(&summary).unwrap();
-            let mut ok = Response::new(full(json_summary));
-            *ok.status_mut() = hyper::StatusCode::OK;
-            ok.headers_mut().insert(
-                hyper::header::CONTENT_TYPE,
-                "application/json".parse().unwrap(),
+    let to_summary = |(total_requests, cents): (i64, i64)| ProcessorSummary {
+        total_requests,
+        total_amount: Decimal::new(cents, 2),
+    };
+
+    Ok((to_summary(default), to_summary(fallback)))
+}
+
+/// Builds a response with `body` compressed using `encoding`, sending it
+/// uncompressed if no coding was negotiated or compression fails.
+async fn encoded_response(
+    body: Vec<u8>,
+    encoding: Option<Encoding>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let Some(encoding) = encoding else {
+        return Response::new(full(body));
+    };
+
+    let mut response = match encoding.compress(&body).await {
+        Ok(compressed) => {
+            let mut response = Response::new(full(compressed));
+            response.headers_mut().insert(
+                hyper::header::CONTENT_ENCODING,
+                hyper::header::HeaderValue::from_static(encoding.as_str()),
             );
-            Ok(ok)
+            response
         }
-        Err(_) => {
-            let mut ok = Response::new(empty());
-            *ok.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
-            Ok(ok)
+        Err(_) => Response::new(full(body)),
+    };
+    response.headers_mut().insert(
+        hyper::header::VARY,
+        hyper::header::HeaderValue::from_static("accept-encoding"),
+    );
+    response
+}
+
+/// 202 for a published payment, echoing its correlationId and pointing
+/// `Location` at its lookup. Bodies without a readable id get a bare 202;
+/// they are rejected downstream.
+fn accepted(correlation_id: Option<&str>) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let Some(correlation_id) = correlation_id else {
+        let mut ok = Response::new(empty());
+        *ok.status_mut() = hyper::StatusCode::ACCEPTED;
+        return ok;
+    };
+
+    let mut ok = Response::new(full(format!(r#"{{"correlationId":"{}"}}"#, correlation_id)));
+    *ok.status_mut() = hyper::StatusCode::ACCEPTED;
+    let headers = ok.headers_mut();
+    headers.insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    if let Ok(location) = format!("/payments/{}", correlation_id).parse() {
+        headers.insert(hyper::header::LOCATION, location);
+    }
+    ok
+}
+
+#[derive(Serialize)]
+struct PaymentRecord {
+    #[serde(rename = "correlationId")]
+    correlation_id: String,
+    amount: Decimal,
+    #[serde(rename = "requestedAt")]
+    requested_at: String,
+    processor: String,
+}
+
+/// `GET /payments/{correlationId}`: the stored payment, or 404 while it is
+/// still queued, being retried or was never accepted. A malformed id is a
+/// 400 rather than a database error.
+async fn payment_lookup_handler(_: Request<Incoming>, params: Params, gateway: Arc<Gateway>) -> HandlerResult {
+    let correlation_id = params.parse::<uuid::Uuid>("id")?.hyphenated().to_string();
+
+    let client = gateway.db_client().await?;
+
+    // Casting the text parameter keeps the unique index on correlation_id
+    // usable.
+    let row = client
+        .query_opt(
+            "SELECT amount, requested_at, service_used::text FROM payments WHERE correlation_id = $1::text::uuid",
+            &[&correlation_id],
+        )
+        .await?;
+
+    let Some(row) = row else {
+        return Ok(static_response::not_found());
+    };
+
+    let requested_at: time::OffsetDateTime = row.try_get("requested_at")?;
+    let processor: String = row.try_get("service_used")?;
+    let record = PaymentRecord {
+        correlation_id,
+        amount: row.try_get("amount")?,
+        requested_at: requested_at.format(&Rfc3339).unwrap_or_default(),
+        processor,
+    };
+
+    let mut ok = Response::new(full(serde_json::to_string(&record)?));
+    ok.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    Ok(ok)
+}
+
+/// What `/purge-payments` cleared.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PurgeReport {
+    tables: Vec<String>,
+    redis_summary: bool,
+    worker_summary: bool,
+    rate_limiter: bool,
+}
+
+/// Tables written alongside `payments` that only exist in some deployments.
+const OPTIONAL_PURGE_TABLES: &str =
+    "SELECT name FROM unnest(ARRAY['payments_summary', 'scheduled_retries', 'quarantined_payments']) AS name
+     WHERE to_regclass(name) IS NOT NULL";
+
+/// Empties `payments` and everything derived from it, so a purge between
+/// test runs leaves no stale totals or pending retries behind. Purging twice
+/// is harmless.
+async fn purge_handler(req: Request<Incoming>, _: Params, gateway: Arc<Gateway>) -> HandlerResult {
+    if let Some(token) = &gateway.purge_token {
+        let given = req.headers().get("x-purge-token").map(|v| v.as_bytes());
+        if !given.is_some_and(|given| tokens_match(given, token.as_bytes())) {
+            return Err(HandlerError::Unauthorized);
         }
     }
+
+    let mut tables = Vec::new();
+    if let Some(worker_summary) = &gateway.worker_summary {
+        worker_summary.purge().await?;
+    } else {
+        let client = gateway.db_client().await?;
+        tables.push("payments".to_string());
+        for row in client.query(OPTIONAL_PURGE_TABLES, &[]).await? {
+            tables.push(row.try_get("name")?);
+        }
+        // Delivered on commit, to every replica's summary snapshot.
+        client
+            .batch_execute(&format!(
+                "TRUNCATE TABLE {}; NOTIFY {}",
+                tables.join(", "),
+                summary_snapshot::INVALIDATE_CHANNEL
+            ))
+            .await?;
+    }
+
+    if let Some(redis_summary) = &gateway.redis_summary {
+        redis_summary.purge().await?;
+    }
+    if let Some(snapshot) = &gateway.summary_snapshot {
+        snapshot.invalidate();
+    }
+    let rate_limiter = gateway.rate_limiter.reset();
+
+    let report = PurgeReport {
+        tables,
+        redis_summary: gateway.redis_summary.is_some(),
+        worker_summary: gateway.worker_summary.is_some(),
+        rate_limiter,
+    };
+
+    let mut ok = Response::new(full(serde_json::to_vec(&report)?));
+    ok.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    Ok(ok)
+}
+
+/// Compares every byte whatever the first mismatch, so the time taken does
+/// not reveal how much of the purge token a guess got right.
+fn tokens_match(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len() && given.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The startup phase, with 503 until it is `ready`, and `no-workers` with
+/// 503 once started whenever no worker socket holds a live connection.
+async fn readyz(gateway: &Gateway) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let phase = startup::current();
+    let state = match phase {
+        startup::Phase::Ready if gateway.publisher.live_workers().await == 0 => "no-workers",
+        phase => phase.as_str(),
+    };
+    let mut response = Response::new(full(format!("{}\n", state)));
+    if state != startup::Phase::Ready.as_str() {
+        *response.status_mut() = hyper::StatusCode::SERVICE_UNAVAILABLE;
+    }
+    response
+}
+
+fn status_response(status: hyper::StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = Response::new(empty());
+    *response.status_mut() = status;
+    // Whatever is left of a body that timed out is not worth waiting for.
+    if status == hyper::StatusCode::REQUEST_TIMEOUT {
+        response.headers_mut().insert(
+            hyper::header::CONNECTION,
+            hyper::header::HeaderValue::from_static("close"),
+        );
+    }
+    response
+}
+
+/// The whole request body, giving up after the body read timeout so a client
+/// trickling it cannot hold the handler and its connection.
+async fn read_body(gateway: &Gateway, body: Incoming) -> Result<Bytes, HandlerError> {
+    let result = collect_body(body, gateway.body_read_timeout).await;
+    if let Err(HandlerError::BodyTimeout) = result {
+        gateway.stats.record_body_timeout();
+    }
+    result
+}
+
+async fn collect_body<B>(body: B, limit: Option<std::time::Duration>) -> Result<Bytes, HandlerError>
+where
+    B: hyper::body::Body<Error = hyper::Error>,
+{
+    let collected = match limit {
+        Some(limit) => tokio::time::timeout(limit, body.collect())
+            .await
+            .map_err(|_| HandlerError::BodyTimeout)??,
+        None => body.collect().await?,
+    };
+    Ok(collected.to_bytes())
+}
+
+/// First (client) address of `X-Forwarded-For`, if present.
+fn forwarded_for(req: &Request<Incoming>) -> Option<&str> {
+    req.headers()
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(str::trim)
 }
 
-fn parse_query_params(req: &Request<Incoming>) -> HashMap<String, String> {
-    let query = req.uri().query().unwrap_or("");
-    form_urlencoded::parse(query.as_bytes())
-        .into_owned()
-        .collect()
+/// When a payment received now must have been processed by, given
+/// [`GatewayConfig::request_budget`].
+struct RequestDeadline {
+    at: tokio::time::Instant,
+    /// The same instant in microseconds since the unix epoch, for the worker.
+    unix_micros: u64,
+}
+
+impl RequestDeadline {
+    fn after(budget: std::time::Duration) -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            at: tokio::time::Instant::now() + budget,
+            unix_micros: (now + budget).as_micros() as u64,
+        }
+    }
+}
+
+/// `X-Request-Id`, set by the load balancer, when it is safe to forward.
+fn request_id(req: &Request<Incoming>) -> Option<String> {
+    req.headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| gateway::is_valid_request_id(id))
+        .map(str::to_owned)
 }
 
 async fn echo(
     req: Request<Incoming>,
     gateway: Arc<Gateway>,
+    router: Arc<Router<Gateway>>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-    match (req.method(), req.uri().path()) {
-        (&Method::GET, "/health") => Ok(Response::new(full("OK"))),
-        (&Method::POST, "/payments") => {
-            let body = req.into_body();
-            let body_bytes = body.collect().await?.to_bytes();
-
-            match gateway
-                .publisher
-                .publish(body_bytes.iter().as_slice())
-                .await
+    match route(req, gateway, &router).await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            // A saturated pool and slow queries are counted in the stats
+            // rather than logged once per rejected request.
+            if e.status().is_server_error()
+                && !matches!(
+                    e,
+                    HandlerError::Pool(deadpool_postgres::PoolError::Timeout(_))
+                        | HandlerError::DatabaseUnavailable
+                        | HandlerError::Timeout
+                )
             {
-                Ok(_) => {
-                    let mut ok = Response::new(empty());
-                    *ok.status_mut() = hyper::StatusCode::ACCEPTED;
-                    Ok(ok)
-                }
-                Err(_) => {
-                    let mut ok = Response::new(empty());
-                    *ok.status_mut() = hyper::StatusCode::TOO_MANY_REQUESTS;
-                    Ok(ok)
-                }
+                tracing::error!(error = %e, "Request failed");
             }
-            // let mut ok = Response::new(empty());
-            // *ok.status_mut() = hyper::StatusCode::ACCEPTED;
-            // Ok(ok)
+            Ok(status_response(e.status()))
         }
-        (&Method::GET, "/payments-summary") => {
-            let params = parse_query_params(&req);
+    }
+}
 
-            let from = params
-                .get("from")
-                .map(|s| PrimitiveDateTime::parse(s.as_str(), &Rfc3339).expect("Invalid date"));
-            let to = params
-                .get("to")
-                .map(|s| PrimitiveDateTime::parse(s.as_str(), &Rfc3339).expect("Invalid date"));
+async fn route(req: Request<Incoming>, gateway: Arc<Gateway>, router: &Router<Gateway>) -> HandlerResult {
+    if !matches!(req.uri().path(), "/health" | "/readyz") && !gateway.rate_limiter.check(forwarded_for(&req)) {
+        gateway.stats.record_rate_limited();
+        return Ok(status_response(hyper::StatusCode::TOO_MANY_REQUESTS));
+    }
+    router.dispatch(req, gateway).await
+}
 
-            payments_summary_handler(&gateway.pool, from, to).await
-        }
-        (&Method::POST, "/purge-payments") => {
-            match gateway.pool.get().await {
-                Ok(client) => {
-                    let stm = client.prepare("TRUNCATE TABLE payments").await.unwrap();
-
-                    if let Err(_) = client.execute(&stm, &[]).await {
-                        let mut ok = Response::new(empty());
-                        *ok.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
-                        return Ok(ok);
-                    }
+/// The public routes. Summaries and lookups are bounded by
+/// [`GatewayConfig::query_timeout`], and every route is counted in the
+/// stats.
+fn api_routes(gateway: &Gateway, query_timeout: Option<std::time::Duration>) -> Router<Gateway> {
+    Router::new()
+        .route(Method::GET, "/health", |_, _, _| async { Ok(static_response::health()) })
+        .route(Method::HEAD, "/health", |_, _, _| async { Ok(static_response::health_head()) })
+        .route(Method::GET, "/readyz", |_, _, gateway: Arc<Gateway>| async move { Ok(readyz(&gateway).await) })
+        .route(Method::POST, "/payments", publish_handler)
+        .route(Method::GET, "/payments-summary", payments_summary_route)
+        .with(Timeout(query_timeout))
+        .route(Method::GET, "/payments/{id}", payment_lookup_handler)
+        .with(Timeout(query_timeout))
+        .route(Method::POST, "/purge-payments", purge_handler)
+        .with_each(Metrics(&gateway.stats))
+}
 
-                    let mut ok = Response::new(empty());
-                    *ok.status_mut() = hyper::StatusCode::OK;
-                    Ok(ok)
+/// `POST /payments`: publishes the payment to a worker, within the request
+/// budget when there is one.
+async fn publish_handler(req: Request<Incoming>, _: Params, gateway: Arc<Gateway>) -> HandlerResult {
+    let deadline = gateway.request_budget.map(RequestDeadline::after);
+    let request_id = request_id(&req);
+    let body_bytes = read_body(&gateway, req.into_body()).await?;
+    // Bodies that do not parse are published as they came, for the worker
+    // to reject.
+    let payment = serde_json::from_slice::<PaymentBody>(&body_bytes).ok();
+    if let Some(PaymentBody { amount: Some(Amount::Invalid), .. }) = payment {
+        return Err(HandlerError::BadRequest("invalid amount"));
+    }
+    let canonical = payment.as_ref().and_then(PaymentBody::canonical);
+    // The canonical body only has the fields it was built from; one passed
+    // through must not already carry a key the gateway stamps.
+    if canonical.is_none() && stamped_key(&body_bytes).is_some() {
+        return Err(HandlerError::BadRequest("reserved field"));
+    }
+    let msg = stamp_message(
+        canonical.as_deref().unwrap_or(&body_bytes),
+        &gateway.run_id,
+        request_id.as_deref(),
+        deadline.as_ref().map(|deadline| deadline.unix_micros),
+    );
+
+    let published = match &deadline {
+        Some(deadline) => {
+            // A payment not handed over by the deadline is answered 504
+            // and never published, so a 504 always means not processed.
+            let publish = async {
+                if tokio::time::Instant::now() >= deadline.at {
+                    return None;
                 }
-                Err(_) => {
-                    let mut ok = Response::new(empty());
-                    *ok.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
-                    Ok(ok)
+                tokio::time::timeout_at(deadline.at, gateway.publish(&msg)).await.ok()
+            };
+            match publish.await {
+                Some(published) => published,
+                None => {
+                    gateway.stats.record_budget_exceeded();
+                    tracing::debug!(request_id = request_id.as_deref(), "Payment missed its budget");
+                    return Ok(status_response(hyper::StatusCode::GATEWAY_TIMEOUT));
                 }
             }
         }
-        _ => {
-            let mut not_found = Response::new(empty());
-            *not_found.status_mut() = hyper::StatusCode::NOT_FOUND;
-            Ok(not_found)
+        None => gateway.publish(&msg).await,
+    };
+    match published {
+        Ok(_) => {
+            tracing::debug!(request_id = request_id.as_deref(), "Payment published");
+            Ok(accepted(payment.as_ref().map(|payment| payment.correlation_id)))
+        }
+        Err(PublisherError::Closed) => Ok(status_response(hyper::StatusCode::SERVICE_UNAVAILABLE)),
+        Err(e) => {
+            tracing::debug!(request_id = request_id.as_deref(), error = %e, "Failed to publish payment");
+            Ok(status_response(hyper::StatusCode::TOO_MANY_REQUESTS))
         }
     }
 }
 
+/// `GET /payments-summary`, with its query parameters checked.
+async fn payments_summary_route(req: Request<Incoming>, _: Params, gateway: Arc<Gateway>) -> HandlerResult {
+    let query = Query::from_uri(req.uri());
+
+    let from = query.optional("from")?;
+    let to = query.optional("to")?;
+    let processor = query.optional("processor")?;
+
+    // `runId=current` stands for this gateway's own run.
+    let run_id = match query.get("runId") {
+        None => None,
+        Some("current") => Some(gateway.run_id.as_str()),
+        Some(run_id) if gateway::is_valid_run_id(run_id) => Some(run_id),
+        Some(_) => return Err(HandlerError::BadRequest("invalid runId")),
+    };
+
+    let with_meta = query.get("meta") == Some("true");
+
+    let encoding = Encoding::negotiate(req.headers());
+    payments_summary_handler(&gateway, from, to, processor, run_id, with_meta, encoding).await
+}
+
+fn json_response(body: impl Into<Bytes>) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut ok = Response::new(full(body));
+    ok.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    ok
+}
+
+/// Requests on the admin socket; nothing here is reachable from the public
+/// listener.
+fn admin_routes() -> Router<Gateway> {
+    Router::new()
+        .route(Method::GET, "/internal/stats", |_, _, gateway: Arc<Gateway>| async move {
+            let report = gateway
+                .stats
+                .report(gateway.publisher.idle_connections(), &gateway.pool);
+            Ok(json_response(serde_json::to_vec(&report)?))
+        })
+        .route(Method::GET, "/internal/readyz", |_, _, gateway: Arc<Gateway>| async move {
+            Ok(readyz(&gateway).await)
+        })
+        .route(Method::POST, "/internal/payments/import", import_handler)
+        .route(Method::GET, "/internal/version", |_, _, _| async {
+            Ok(json_response(build_info!().json("gateway")))
+        })
+        .route(Method::POST, "/internal/log-level", |req: Request<Incoming>, _, _| async move {
+            let body = req.into_body().collect().await?.to_bytes();
+            let directives = String::from_utf8(body.to_vec()).map_err(|_| "log-level body must be UTF-8".to_string());
+            match directives.and_then(|directives| logging::set_filter(&directives)) {
+                Ok(active) => Ok(Response::new(full(active + "\n"))),
+                Err(e) => {
+                    let mut bad = Response::new(full(e + "\n"));
+                    *bad.status_mut() = hyper::StatusCode::BAD_REQUEST;
+                    Ok(bad)
+                }
+            }
+        })
+}
+
+async fn import_handler(req: Request<Incoming>, _: Params, gateway: Arc<Gateway>) -> HandlerResult {
+    let body = req.into_body().collect().await?.to_bytes();
+    let report = payment_import::import(&gateway, &body)
+        .await
+        .inspect_err(|e| tracing::warn!(error = %e, "Payment import failed"))?;
+    Ok(json_response(serde_json::to_vec(&report)?))
+}
+
+async fn admin(
+    req: Request<Incoming>,
+    gateway: Arc<Gateway>,
+    router: Arc<Router<Gateway>>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    Ok(router
+        .dispatch(req, gateway)
+        .await
+        .unwrap_or_else(|e| status_response(e.status())))
+}
+
+async fn serve_admin(listener: Listener, gateway: Arc<Gateway>) {
+    let router = Arc::new(admin_routes());
+    accept_loop::serve(listener, "admin", |stream| {
+        let gateway = Arc::clone(&gateway);
+        let router = Arc::clone(&router);
+        async move {
+            let service = service_fn(move |req| admin(req, Arc::clone(&gateway), Arc::clone(&router)));
+            if let Err(err) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                tracing::warn!(error = ?err, "Error serving admin connection");
+            }
+        }
+    })
+    .await;
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    build_info::mark_started();
+    logging::init("warn");
+    tokio::spawn(logging::watch_sigusr2());
+
+    startup::enter(startup::Phase::ConnectingDb);
     let config = GatewayConfig::from_env()?;
     let server = Arc::new(Gateway::new(config.clone()).await?);
 
-    let socket_path = &config.listen_path;
-    if std::fs::metadata(socket_path).is_ok() {
-        std::fs::remove_file(socket_path)?;
+    // Up first, so `/internal/readyz` can tell how far startup got.
+    if let Some(admin_listen) = &config.admin_listen {
+        tokio::spawn(serve_admin(Listener::bind_admin(admin_listen)?, Arc::clone(&server)));
     }
 
-    let listener = UnixListener::bind(socket_path)?;
+    let database_ready = server.wait_for_database(config.startup_timeout).await;
+    startup::enter(startup::Phase::Warming);
+    let reached = server.publisher.wait_for_workers(config.startup_timeout).await;
+    if reached == 0 && config.require_workers {
+        return Err(format!("no worker listening on {}", config.publish_paths.join(", ")).into());
+    }
+
+    let listeners = config
+        .listen
+        .iter()
+        .map(Listener::bind)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if server.summary_snapshot.is_some() {
+        let gateway = Arc::clone(&server);
+        tokio::spawn(async move {
+            let Some(snapshot) = &gateway.summary_snapshot else { return };
+            snapshot.follow_invalidations(gateway.pool.pg_config().clone()).await;
+        });
+        let gateway = Arc::clone(&server);
+        tokio::spawn(async move {
+            let Some(snapshot) = &gateway.summary_snapshot else { return };
+            snapshot
+                .run(|| async {
+                    postgres_summary(&gateway, None, None, &None, None, false)
+                        .await
+                        .map(|(totals, _)| totals)
+                })
+                .await;
+        });
+    }
 
-    let permissions = std::fs::Permissions::from_mode(0o666);
-    std::fs::set_permissions(socket_path, permissions)?;
+    if database_ready && reached > 0 {
+        startup::enter(startup::Phase::Ready);
+    } else {
+        // Serve anyway, but leave `/readyz` red until both have answered.
+        tracing::error!(database_ready, workers = reached, "Startup timed out, staying in warming");
+        let gateway = Arc::clone(&server);
+        let timeout = config.startup_timeout;
+        tokio::spawn(async move {
+            while !gateway.wait_for_database(timeout).await {}
+            while gateway.publisher.wait_for_workers(timeout).await == 0 {}
+            startup::enter(startup::Phase::Ready);
+        });
+    }
 
-    // We start a loop to continuously accept incoming connections
-    loop {
-        let (stream, _) = listener.accept().await?;
+    let router = Arc::new(api_routes(&server, config.query_timeout));
+    // Shared by every listener, so a peer cannot get around it by spreading
+    // its connections.
+    let peer_limit = config.http1.max_connections_per_peer.map(|max| Arc::new(PeerLimit::new(max)));
+    let mut api_servers = tokio::task::JoinSet::new();
+    for listener in listeners {
+        api_servers.spawn(serve_api(
+            listener,
+            Arc::clone(&server),
+            Arc::clone(&router),
+            config.http1.clone(),
+            peer_limit.clone(),
+        ));
+    }
+    let serving = async {
+        while let Some(served) = api_servers.join_next().await {
+            served?;
+        }
+        Ok::<(), tokio::task::JoinError>(())
+    };
+    tokio::select! {
+        served = serving => served?,
+        _ = shutdown_signal() => tracing::warn!("Shutdown requested, no longer accepting connections"),
+    }
 
+    // Connections already open are still served, but what they publish from
+    // now on is refused, so the payments in flight can all reach a worker
+    // before the process exits.
+    api_servers.abort_all();
+    let unpublished = server.publisher.shutdown(config.shutdown_timeout).await;
+    if unpublished > 0 {
+        tracing::error!(payments = unpublished, "Shutdown timed out with payments still being published");
+    }
+
+    Ok(())
+}
+
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+async fn serve_api(
+    listener: Listener,
+    server: Arc<Gateway>,
+    router: Arc<Router<Gateway>>,
+    http1_config: Http1Config,
+    peer_limit: Option<Arc<PeerLimit>>,
+) {
+    accept_loop::serve(listener, "api", |stream| {
+        // Taken at accept, so a burst of connections cannot all get in
+        // before the first task runs.
+        let slot = match (&peer_limit, stream.peer_ip()) {
+            (Some(limit), Some(peer)) => Some((peer, limit.acquire(peer))),
+            _ => None,
+        };
+        let activity = Arc::new(Activity::new());
         // Use an adapter to access something implementing `tokio::io` traits as if they implement
         // `hyper::rt` IO traits.
-        let io = TokioIo::new(stream);
+        let io = TokioIo::new(Tracked::new(stream, Arc::clone(&activity)));
         let server_clone = Arc::clone(&server);
+        let gateway = Arc::clone(&server);
+        let http1_config = http1_config.clone();
+        let router = Arc::clone(&router);
 
-        // Spawn a tokio task to serve multiple connections concurrently
-        tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .keep_alive(true)
+        async move {
+            let _slot = match slot {
+                Some((peer, None)) => {
+                    gateway.stats.record_peer_limited();
+                    tracing::debug!(%peer, "Peer at its connection cap, closing connection");
+                    return;
+                }
+                slot => slot,
+            };
+            let conn = http1::Builder::new()
+                .timer(TokioTimer::new())
+                .keep_alive(http1_config.keep_alive)
                 .half_close(false)
-                .writev(true)
-                .max_buf_size(16 * 1024)
+                .writev(http1_config.writev)
+                .max_buf_size(http1_config.max_buf_size)
+                .header_read_timeout(http1_config.header_read_timeout)
                 .preserve_header_case(false)
                 .title_case_headers(false)
                 .serve_connection(
                     io,
-                    service_fn(move |req| echo(req, Arc::clone(&server_clone))),
-                )
-                .await
-            {
-                eprintln!("Error serving connection: {:?}", err);
+                    service_fn(move |req| echo(req, Arc::clone(&server_clone), Arc::clone(&router))),
+                );
+            let mut conn = std::pin::pin!(conn);
+            let mut closing = false;
+            let result = loop {
+                tokio::select! {
+                    result = conn.as_mut() => break result,
+                    _ = activity.idle(http1_config.idle_timeout.unwrap_or_default()),
+                        if http1_config.idle_timeout.is_some() && !closing =>
+                    {
+                        // Lets a request in flight finish before closing.
+                        gateway.stats.record_idle_closed();
+                        conn.as_mut().graceful_shutdown();
+                        closing = true;
+                    }
+                }
+            };
+            if let Err(err) = result {
+                let kind = ConnectionErrorKind::classify(&err);
+                gateway.stats.record_connection_error(kind);
+                if kind.is_benign() {
+                    tracing::debug!(error = %err, ?kind, "Connection closed by client");
+                } else {
+                    tracing::warn!(error = ?err, ?kind, "Error serving connection");
+                }
             }
-        });
+        }
+    })
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collect_body, status_response, tokens_match};
+    use crate::error::HandlerError;
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::{Body, Frame};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    /// A body whose client sent its first bytes and then stopped.
+    struct Stalled(Option<Bytes>);
+
+    impl Body for Stalled {
+        type Data = Bytes;
+        type Error = hyper::Error;
+
+        fn poll_frame(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+            match self.0.take() {
+                Some(sent) => Poll::Ready(Some(Ok(Frame::data(sent)))),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn bodies_trickled_past_the_timeout_are_refused_with_408() {
+        let result = collect_body(Stalled(Some(Bytes::from_static(b"{\"amount\":"))), Some(Duration::from_millis(20))).await;
+        let err = result.unwrap_err();
+        assert!(matches!(err, HandlerError::BodyTimeout));
+        assert_eq!(err.status(), hyper::StatusCode::REQUEST_TIMEOUT);
+
+        // The rest of the body is never read, so the connection is closed.
+        let response = status_response(err.status());
+        assert_eq!(response.status(), hyper::StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(response.headers()[hyper::header::CONNECTION], "close");
+    }
+
+    #[tokio::test]
+    async fn bodies_within_the_timeout_are_read_whole() {
+        let body = Full::new(Bytes::from_static(b"{\"amount\":19.90}")).map_err(|never| match never {});
+        let body = collect_body(body, Some(Duration::from_millis(20))).await.unwrap();
+        assert_eq!(&body[..], b"{\"amount\":19.90}");
+        assert!(status_response(hyper::StatusCode::BAD_REQUEST).headers().get(hyper::header::CONNECTION).is_none());
+    }
+
+    #[test]
+    fn tokens_match_only_the_exact_token() {
+        assert!(tokens_match(b"s3cret", b"s3cret"));
+        assert!(!tokens_match(b"s3creT", b"s3cret"));
+        assert!(!tokens_match(b"s3cre", b"s3cret"));
+        assert!(!tokens_match(b"", b"s3cret"));
     }
 }
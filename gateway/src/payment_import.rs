@@ -0,0 +1,268 @@
+//! `POST /internal/payments/import`: writes payments that were already
+//! processed straight into Postgres, for reconciliation and replay tooling.
+//! Nothing is published to the workers, so no processor sees them again.
+
+use crate::error::HandlerError;
+use crate::gateway::{self, Gateway};
+use crate::ServiceType;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// One payment as the tooling sends it. `processor` and `requestedAt` are
+/// taken as given rather than decided here.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImportRequest {
+    correlation_id: String,
+    amount: Decimal,
+    processor: String,
+    requested_at: String,
+    #[serde(default)]
+    run_id: Option<String>,
+}
+
+struct ImportedPayment {
+    correlation_id: String,
+    amount: Decimal,
+    processor: ServiceType,
+    requested_at: OffsetDateTime,
+    run_id: Option<String>,
+}
+
+impl ImportedPayment {
+    fn parse(request: ImportRequest) -> Result<Self, HandlerError> {
+        let correlation_id = uuid::Uuid::parse_str(&request.correlation_id)
+            .map_err(|_| HandlerError::BadRequest("invalid correlationId"))?;
+        if request.amount <= Decimal::ZERO || request.amount.scale() > 2 {
+            return Err(HandlerError::BadRequest("invalid amount"));
+        }
+        if request.run_id.as_deref().is_some_and(|run_id| !gateway::is_valid_run_id(run_id)) {
+            return Err(HandlerError::BadRequest("invalid runId"));
+        }
+
+        Ok(Self {
+            correlation_id: correlation_id.hyphenated().to_string(),
+            amount: request.amount,
+            processor: request
+                .processor
+                .parse()
+                .map_err(|_| HandlerError::BadRequest("invalid processor"))?,
+            requested_at: OffsetDateTime::parse(&request.requested_at, &Rfc3339)
+                .map_err(|_| HandlerError::BadRequest("invalid requestedAt"))?,
+            run_id: request.run_id,
+        })
+    }
+}
+
+/// What an import wrote.
+#[derive(Serialize)]
+pub struct ImportReport {
+    received: usize,
+    imported: usize,
+    /// Already stored under the same correlationId, or repeated in the
+    /// request; the stored row is kept.
+    duplicates: usize,
+}
+
+/// Rows with a correlationId already present are skipped, so importing the
+/// same file twice is harmless.
+const INSERT_PAYMENTS: &str = "
+    INSERT INTO payments (amount, requested_at, service_used, correlation_id, run_id)
+    SELECT amount, requested_at, service_used, correlation_id::uuid, run_id
+    FROM UNNEST($1::numeric[], $2::timestamptz[], $3::service_type[], $4::text[], $5::text[])
+         AS imported(amount, requested_at, service_used, correlation_id, run_id)
+    ON CONFLICT (correlation_id) DO NOTHING
+    RETURNING correlation_id::text";
+
+/// The stored rows of the given correlationIds, whoever wrote them.
+const SELECT_STORED: &str = "
+    SELECT correlation_id::text, amount, requested_at, service_used
+    FROM payments
+    WHERE correlation_id = ANY($1::text[]::uuid[])";
+
+/// Same counters the worker keeps when the table exists, grouped and sorted
+/// as it does so concurrent upserts lock keys in the same order.
+const UPSERT_SUMMARY: &str = "
+    INSERT INTO payments_summary (service_used, requested_at, total_requests, total_amount)
+    SELECT service_used, requested_at, COUNT(*), SUM(amount)
+    FROM UNNEST($1::numeric[], $2::timestamptz[], $3::service_type[]) AS imported(amount, requested_at, service_used)
+    GROUP BY service_used, requested_at
+    ORDER BY service_used::text, requested_at
+    ON CONFLICT (service_used, requested_at) DO UPDATE
+    SET total_requests = payments_summary.total_requests + EXCLUDED.total_requests,
+        total_amount = payments_summary.total_amount + EXCLUDED.total_amount";
+
+/// Stores the JSON array of payments in `body` in one transaction, keeping
+/// `payments_summary`, the Redis counters and the summary snapshot in step.
+/// Refused when the workers hold the payments in memory, since there is no
+/// table to write to.
+///
+/// Redis cannot join the transaction, so its counters are updated after the
+/// commit from the stored rows of every payment in the request. They count
+/// each correlationId once, so when that update fails (answered as a 500)
+/// importing the same payments again catches them up.
+pub async fn import(gateway: &Gateway, body: &[u8]) -> Result<ImportReport, HandlerError> {
+    if gateway.worker_summary.is_some() {
+        return Err(HandlerError::BadRequest("payments are held by the workers"));
+    }
+
+    let requests: Vec<ImportRequest> =
+        serde_json::from_slice(body).map_err(|_| HandlerError::BadRequest("invalid payments"))?;
+    let payments = requests
+        .into_iter()
+        .map(ImportedPayment::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+    let received = payments.len();
+    if payments.is_empty() {
+        return Ok(ImportReport { received, imported: 0, duplicates: 0 });
+    }
+
+    let mut client = gateway.db_client().await?;
+    let transaction = client.transaction().await?;
+
+    let amounts: Vec<Decimal> = payments.iter().map(|p| p.amount).collect();
+    let requested_at: Vec<OffsetDateTime> = payments.iter().map(|p| p.requested_at).collect();
+    let processors: Vec<&ServiceType> = payments.iter().map(|p| &p.processor).collect();
+    let ids: Vec<&str> = payments.iter().map(|p| p.correlation_id.as_str()).collect();
+    let run_ids: Vec<Option<&str>> = payments.iter().map(|p| p.run_id.as_deref()).collect();
+
+    let rows = transaction
+        .query(INSERT_PAYMENTS, &[&amounts, &requested_at, &processors, &ids, &run_ids])
+        .await?;
+    let mut inserted: HashSet<String> = HashSet::with_capacity(rows.len());
+    for row in rows {
+        inserted.insert(row.try_get(0)?);
+    }
+    let imported = newly_imported(&payments, &inserted);
+
+    let summary_table: bool = transaction
+        .query_one("SELECT to_regclass('payments_summary') IS NOT NULL", &[])
+        .await?
+        .try_get(0)?;
+    if summary_table && !imported.is_empty() {
+        let amounts: Vec<Decimal> = imported.iter().map(|p| p.amount).collect();
+        let requested_at: Vec<OffsetDateTime> = imported.iter().map(|p| p.requested_at).collect();
+        let processors: Vec<&ServiceType> = imported.iter().map(|p| &p.processor).collect();
+        transaction
+            .execute(UPSERT_SUMMARY, &[&amounts, &requested_at, &processors])
+            .await?;
+    }
+    if !imported.is_empty() {
+        transaction
+            .batch_execute(&format!("NOTIFY {}", crate::summary_snapshot::INVALIDATE_CHANNEL))
+            .await?;
+    }
+    transaction.commit().await?;
+
+    if let Some(redis_summary) = &gateway.redis_summary {
+        let mut stored = Vec::with_capacity(ids.len());
+        for row in client.query(SELECT_STORED, &[&ids]).await? {
+            let amount: Decimal = row.try_get(1)?;
+            let requested_at: OffsetDateTime = row.try_get(2)?;
+            let millis = (requested_at.unix_timestamp_nanos() / 1_000_000) as i64;
+            let cents = (amount * Decimal::ONE_HUNDRED).round().to_i64().unwrap_or_default();
+            stored.push((row.try_get::<_, String>(0)?, row.try_get::<_, ServiceType>(3)?, millis, cents));
+        }
+        redis_summary
+            .record(stored.iter().map(|(id, processor, millis, cents)| (id.as_str(), processor, *millis, *cents)))
+            .await?;
+    }
+    if let Some(snapshot) = &gateway.summary_snapshot {
+        snapshot.invalidate();
+    }
+
+    tracing::warn!(received, imported = imported.len(), "Imported payments");
+    Ok(ImportReport {
+        received,
+        imported: imported.len(),
+        duplicates: received - imported.len(),
+    })
+}
+
+/// The payments of `payments` that `inserted` says were written, each
+/// correlationId once: one repeated within the request is inserted once.
+fn newly_imported<'a>(payments: &'a [ImportedPayment], inserted: &HashSet<String>) -> Vec<&'a ImportedPayment> {
+    let mut seen = HashSet::with_capacity(inserted.len());
+    payments
+        .iter()
+        .filter(|p| inserted.contains(&p.correlation_id) && seen.insert(p.correlation_id.as_str()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3";
+
+    fn parse(json: &str) -> Result<ImportedPayment, &'static str> {
+        let request: ImportRequest = serde_json::from_str(json).unwrap();
+        ImportedPayment::parse(request).map_err(|e| match e {
+            HandlerError::BadRequest(reason) => reason,
+            other => panic!("unexpected error {:?}", other),
+        })
+    }
+
+    fn request(amount: &str, processor: &str, requested_at: &str) -> String {
+        format!(
+            r#"{{"correlationId":"{}","amount":{},"processor":"{}","requestedAt":"{}"}}"#,
+            ID, amount, processor, requested_at
+        )
+    }
+
+    #[test]
+    fn parse_takes_processor_and_requested_at_as_given() {
+        let payment = parse(&request("19.90", "fallback", "2025-07-15T12:34:56.789Z")).unwrap();
+        assert_eq!(payment.correlation_id, ID);
+        assert_eq!(payment.amount, Decimal::new(1990, 2));
+        assert_eq!(payment.processor, ServiceType::Fallback);
+        assert_eq!(payment.requested_at.unix_timestamp_nanos(), 1_752_582_896_789_000_000);
+        assert_eq!(payment.run_id, None);
+    }
+
+    #[test]
+    fn parse_refuses_amounts_that_are_not_positive_cents() {
+        assert_eq!(parse(&request("0", "default", "2025-07-15T12:34:56Z")).err(), Some("invalid amount"));
+        assert_eq!(parse(&request("0.00", "default", "2025-07-15T12:34:56Z")).err(), Some("invalid amount"));
+        assert_eq!(parse(&request("-1.00", "default", "2025-07-15T12:34:56Z")).err(), Some("invalid amount"));
+        assert_eq!(parse(&request("1.001", "default", "2025-07-15T12:34:56Z")).err(), Some("invalid amount"));
+        assert!(parse(&request("0.01", "default", "2025-07-15T12:34:56Z")).is_ok());
+    }
+
+    #[test]
+    fn parse_refuses_malformed_fields() {
+        assert_eq!(parse(&request("1", "backup", "2025-07-15T12:34:56Z")).err(), Some("invalid processor"));
+        assert_eq!(parse(&request("1", "default", "2025-07-15 12:34:56")).err(), Some("invalid requestedAt"));
+        let bad_id = request("1", "default", "2025-07-15T12:34:56Z").replace(ID, "not-a-uuid");
+        assert_eq!(parse(&bad_id).err(), Some("invalid correlationId"));
+        let bad_run = request("1", "default", "2025-07-15T12:34:56Z").replace('}', r#","runId":"no spaces allowed"}"#);
+        assert_eq!(parse(&bad_run).err(), Some("invalid runId"));
+    }
+
+    #[test]
+    fn parse_normalizes_the_correlation_id() {
+        let upper = request("1", "default", "2025-07-15T12:34:56Z").replace(ID, &ID.to_uppercase());
+        assert_eq!(parse(&upper).unwrap().correlation_id, ID);
+    }
+
+    #[test]
+    fn repeated_and_already_stored_payments_are_not_imported() {
+        let at = "2025-07-15T12:34:56Z";
+        let other = "0b0a3c4e-5a8e-4c1e-9d0f-2b5f6a7c8d9e";
+        let payments = vec![
+            parse(&request("1", "default", at)).unwrap(),
+            parse(&request("2", "default", at)).unwrap(),
+            parse(&request("3", "fallback", at).replace(ID, other)).unwrap(),
+        ];
+        let inserted = HashSet::from([ID.to_string()]);
+
+        let imported = newly_imported(&payments, &inserted);
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[0].amount, Decimal::ONE);
+        assert_eq!(payments.len() - imported.len(), 2);
+    }
+}
@@ -1,15 +1,36 @@
-﻿use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
+﻿use crossbeam_queue::ArrayQueue;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::io::{AsyncWriteExt, BufWriter};
 use tokio::net::UnixStream;
-use tokio::sync::{Mutex, mpsc};
+use tokio::sync::{mpsc, oneshot};
+
+/// Pause between connection attempts while waiting for workers at startup.
+const WORKER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// The pause between startup checks doubles up to this.
+const WORKER_POLL_MAX_INTERVAL: Duration = Duration::from_secs(2);
+/// Opens a connection that sends LZ4 frames; see the worker's
+/// `receiver::LZ4_HELLO` for the framing, which must be kept in sync.
+const LZ4_HELLO: u8 = 0x04;
+/// The worker's answer to [`LZ4_HELLO`], arriving after any opening credits.
+const LZ4_ACK: u8 = b'z';
 
 #[derive(Debug)]
 pub enum PublisherError {
     ConnectionFailed(std::io::Error),
     WriteError(std::io::Error),
     Timeout,
+    /// The worker has not granted enough credits for the message.
+    NoCredits,
+    /// The batching task is gone, so nothing can be published.
+    BatcherStopped,
+    /// The gateway is shutting down and publishes no more.
+    Closed,
+    #[cfg(feature = "shm-transport")]
+    RingFull,
+    #[cfg(feature = "shm-transport")]
+    MessageTooLarge,
 }
 
 impl std::fmt::Display for PublisherError {
@@ -17,116 +38,435 @@ impl std::fmt::Display for PublisherError {
         match self {
             PublisherError::ConnectionFailed(e) => write!(f, "Connection failed: {}", e),
             PublisherError::WriteError(e) => write!(f, "Write error: {}", e),
-            PublisherError::Timeout => write!(f, "Operation timed out")
+            PublisherError::Timeout => write!(f, "Operation timed out"),
+            PublisherError::NoCredits => write!(f, "Worker granted no credits"),
+            PublisherError::BatcherStopped => write!(f, "Publish batcher stopped"),
+            PublisherError::Closed => write!(f, "Publisher closed for shutdown"),
+            #[cfg(feature = "shm-transport")]
+            PublisherError::RingFull => write!(f, "Shared memory ring is full"),
+            #[cfg(feature = "shm-transport")]
+            PublisherError::MessageTooLarge => write!(f, "Message does not fit in a ring slot"),
         }
     }
 }
 
 impl std::error::Error for PublisherError {}
 
+impl PublisherError {
+    /// Copy of the error handed to every payment of a failed batch.
+    fn duplicate(&self) -> Self {
+        match self {
+            PublisherError::ConnectionFailed(e) => {
+                PublisherError::ConnectionFailed(std::io::Error::new(e.kind(), e.to_string()))
+            }
+            PublisherError::WriteError(e) => {
+                PublisherError::WriteError(std::io::Error::new(e.kind(), e.to_string()))
+            }
+            PublisherError::Timeout => PublisherError::Timeout,
+            PublisherError::NoCredits => PublisherError::NoCredits,
+            other => PublisherError::WriteError(std::io::Error::other(other.to_string())),
+        }
+    }
+}
+
+/// Coalescing of concurrent publishes into a single write.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    /// How long the first payment of a batch waits for others. Tokio timers
+    /// have millisecond resolution, so sub-millisecond windows last until
+    /// the next timer tick.
+    pub window: Duration,
+    pub max_messages: usize,
+}
+
+struct Pending {
+    frame: Vec<u8>,
+    reply: oneshot::Sender<Result<(), PublisherError>>,
+}
+
+/// Appends `"ingestTs"` (unix epoch, microseconds), `"runId"` and, when
+/// given, `"requestId"` and `"deadlineTs"` (unix epoch, microseconds) to a
+/// JSON object payload, so the worker can measure end-to-end pipeline
+/// latency, tag the stored payment with the run it belongs to, log the
+/// request it came in and drop it once it is too late. Anything that does
+/// not look like an object is forwarded unchanged and rejected downstream.
+///
+/// `run_id` and `request_id` are written unescaped; only ids passing
+/// [`crate::gateway::is_valid_run_id`] may be given.
+pub fn stamp_message(msg: &[u8], run_id: &str, request_id: Option<&str>, deadline_ts: Option<u64>) -> Vec<u8> {
+    let Some(close) = msg.iter().rposition(|b| *b == b'}') else {
+        return msg.to_vec();
+    };
+
+    let ingest_ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default();
+
+    let body = &msg[..close];
+    let is_empty_object = body.iter().rev().find(|b| !b.is_ascii_whitespace()) == Some(&b'{');
+
+    let mut stamped = Vec::with_capacity(msg.len() + 96 + run_id.len() + request_id.map_or(0, str::len));
+    stamped.extend_from_slice(body);
+    if !is_empty_object {
+        stamped.push(b',');
+    }
+    stamped.extend_from_slice(b"\"ingestTs\":");
+    stamped.extend_from_slice(ingest_ts.to_string().as_bytes());
+    stamped.extend_from_slice(b",\"runId\":\"");
+    stamped.extend_from_slice(run_id.as_bytes());
+    if let Some(request_id) = request_id {
+        stamped.extend_from_slice(b"\",\"requestId\":\"");
+        stamped.extend_from_slice(request_id.as_bytes());
+    }
+    stamped.push(b'"');
+    if let Some(deadline_ts) = deadline_ts {
+        stamped.extend_from_slice(b",\"deadlineTs\":");
+        stamped.extend_from_slice(deadline_ts.to_string().as_bytes());
+    }
+    stamped.push(b'}');
+    stamped
+}
+
+/// Keys [`stamp_message`] appends. A body that already has one is refused,
+/// since the worker would read the duplicate key as a malformed payment.
+const STAMPED_KEYS: &[&str] = &["ingestTs", "requestId", "deadlineTs", "runId"];
+
+/// The first of the keys [`stamp_message`] appends that the JSON object
+/// `msg` already has. Anything else is left for the worker to judge.
+pub fn stamped_key(msg: &[u8]) -> Option<&'static str> {
+    let object = serde_json::from_slice::<std::collections::HashMap<String, serde::de::IgnoredAny>>(msg).ok()?;
+    STAMPED_KEYS.iter().copied().find(|key| object.contains_key(*key))
+}
+
+/// A pooled connection to the worker.
+struct Conn {
+    stream: UnixStream,
+    /// Messages the worker still accepts on this connection, when it runs
+    /// with `RECEIVER_CREDITS`.
+    credits: usize,
+    /// Whether the worker's opening grant has arrived.
+    granted: bool,
+    /// Whether the worker accepted LZ4 frames on this connection.
+    compressed: bool,
+}
+
+impl Conn {
+    fn new(stream: UnixStream) -> Self {
+        Self { stream, credits: 0, granted: false, compressed: false }
+    }
+
+    /// Asks the worker for compression and waits for its [`LZ4_ACK`],
+    /// counting the credits sent ahead of it. A worker that does not know
+    /// the handshake never answers.
+    async fn negotiate_compression(&mut self) -> std::io::Result<()> {
+        self.stream.write_all(&[LZ4_HELLO]).await?;
+        let mut buf = [0u8; 512];
+        while !self.compressed {
+            self.stream.readable().await?;
+            match self.stream.try_read(&mut buf) {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    self.compressed = buf[..n].contains(&LZ4_ACK);
+                    let credits = n - usize::from(self.compressed);
+                    self.credits += credits;
+                    self.granted |= credits > 0;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds up the credits the worker sent since the last call, one byte
+    /// each, without waiting for more.
+    fn collect_credits(&mut self) -> std::io::Result<()> {
+        let mut buf = [0u8; 512];
+        loop {
+            match self.stream.try_read(&mut buf) {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    self.credits += n;
+                    self.granted = true;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
 pub struct Publisher {
     socket_path: String,
-    max_conns: usize,
-    conn_pool: mpsc::Sender<UnixStream>,
-    conn_receiver: Arc<Mutex<mpsc::Receiver<UnixStream>>>,
+    idle_conns: Arc<ArrayQueue<Conn>>,
     connect_timeout: Duration,
-    pool_size: Arc<AtomicUsize>,
+    batcher: Option<mpsc::Sender<Pending>>,
+    credit_flow: bool,
+    compression: bool,
+    /// Publishes started and not answered yet, batched ones included.
+    in_flight: Arc<AtomicUsize>,
+    closed: Arc<AtomicBool>,
+}
+
+/// Counts a publish in [`Publisher::in_flight`] until it is answered.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn enter(in_flight: &'a AtomicUsize) -> Self {
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        Self(in_flight)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl Publisher {
     pub async fn new(socket_path: String, max_conns: usize) -> Result<Self, PublisherError> {
-        let (sender, receiver) = mpsc::channel(max_conns);
+        let idle_conns = ArrayQueue::new(max_conns);
 
         // Pre-populate the pool with connections
-        let mut initial_connections = 0;
         for _ in 0..std::cmp::min(max_conns, 5) {
-            if let Ok(conn) = tokio::time::timeout(
+            if let Ok(Ok(conn)) = tokio::time::timeout(
                 Duration::from_millis(100),
                 UnixStream::connect(&socket_path),
-            ).await {
-                if let Ok(conn) = conn {
-                    if sender.send(conn).await.is_ok() {
-                        initial_connections += 1;
-                    }
-                }
+            ).await
+            {
+                let _ = idle_conns.push(Conn::new(conn));
             }
         }
 
         Ok(Publisher {
             socket_path,
-            max_conns,
-            conn_pool: sender,
-            conn_receiver: Arc::new(Mutex::new(receiver)),
+            idle_conns: Arc::new(idle_conns),
             connect_timeout: Duration::from_millis(50), // Reduced timeout
-            pool_size: Arc::new(AtomicUsize::new(initial_connections)),
+            batcher: None,
+            credit_flow: false,
+            compression: false,
+            in_flight: Arc::default(),
+            closed: Arc::default(),
         })
 
     }
 
+    /// Routes publishes through a task that gathers the payments arriving
+    /// within `config.window` and writes them with one syscall.
+    pub fn with_batching(mut self, config: BatchConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(config.max_messages * 4);
+        tokio::spawn(self.clone().batch_loop(receiver, config));
+        self.batcher = Some(sender);
+        self
+    }
+
+    /// Spends a worker credit per message and fails with
+    /// [`PublisherError::NoCredits`] when a connection has too few, for
+    /// workers running with `RECEIVER_CREDITS`. A batch is written whole or
+    /// not at all.
+    pub fn with_credit_flow(mut self) -> Self {
+        self.credit_flow = true;
+        self
+    }
+
+    /// Sends LZ4 frames, one per write, asking the worker for them on
+    /// every connection before its first message.
+    pub fn with_compression(mut self) -> Self {
+        self.compression = true;
+        self
+    }
+
     pub async fn publish(&self, msg: &[u8]) -> Result<(), PublisherError> {
+        // Counted before the check, so a shutdown that sees no publish in
+        // flight knows none can start.
+        let _in_flight = InFlight::enter(&self.in_flight);
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(PublisherError::Closed);
+        }
+        if let Some(batcher) = &self.batcher {
+            let (reply, result) = oneshot::channel();
+            let pending = Pending { frame: msg.to_vec(), reply };
+            batcher.send(pending).await.map_err(|_| PublisherError::BatcherStopped)?;
+            return result.await.unwrap_or(Err(PublisherError::BatcherStopped));
+        }
+
+        self.write(&[msg, b"\n"], 1).await
+    }
+
+    async fn batch_loop(self, mut receiver: mpsc::Receiver<Pending>, config: BatchConfig) {
+        let mut batch = Vec::with_capacity(config.max_messages);
+        let mut buf = Vec::with_capacity(config.max_messages * 256);
+
+        while let Some(first) = receiver.recv().await {
+            batch.push(first);
+
+            let deadline = tokio::time::Instant::now() + config.window;
+            while batch.len() < config.max_messages {
+                match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                    Ok(Some(pending)) => batch.push(pending),
+                    Ok(None) | Err(_) => break,
+                }
+            }
+
+            buf.clear();
+            for pending in &batch {
+                buf.extend_from_slice(&pending.frame);
+                buf.push(b'\n');
+            }
+
+            let result = self.write(&[&buf], batch.len()).await;
+            for pending in batch.drain(..) {
+                let reply = match &result {
+                    Ok(()) => Ok(()),
+                    Err(e) => Err(e.duplicate()),
+                };
+                let _ = pending.reply.send(reply);
+            }
+        }
+    }
+
+    async fn write(&self, parts: &[&[u8]], messages: usize) -> Result<(), PublisherError> {
         let mut conn = self.acquire().await?;
 
-        let mut writer = BufWriter::with_capacity(1024, &mut conn);
+        if self.credit_flow {
+            match self.spend_credits(&mut conn, messages).await {
+                Ok(()) => {}
+                Err(PublisherError::NoCredits) => {
+                    self.release(conn);
+                    return Err(PublisherError::NoCredits);
+                }
+                Err(e) => {
+                    self.discard(conn).await;
+                    return Err(e);
+                }
+            }
+        }
+
+        let frame;
+        let parts = if conn.compressed {
+            frame = compressed_frame(parts);
+            &[frame.as_slice()]
+        } else {
+            parts
+        };
+
+        let mut writer = BufWriter::with_capacity(1024, &mut conn.stream);
 
         let write_result = async {
-            writer.write_all(msg).await?;
-            writer.write_all(b"\n").await?;
+            for part in parts {
+                writer.write_all(part).await?;
+            }
             writer.flush().await?;
             Ok::<(), std::io::Error>(())
         }.await;
 
         match write_result {
             Ok(_) => {
-                self.release(conn).await;
+                self.release(conn);
                 Ok(())
             },
             Err(e ) => {
-                let _ = conn.shutdown().await;
-                self.pool_size.fetch_sub(1, Ordering::Relaxed);
-                tokio::task::spawn({
-                    let publisher = self.clone();
-                    async move {
-                        publisher.replace().await;
-                    }
-                });
+                self.discard(conn).await;
                 Err(PublisherError::WriteError(e))
             }
         }
     }
 
-    async fn acquire(&self) -> Result<UnixStream, PublisherError> {
-        if let Ok(mut receiver) = self.conn_receiver.try_lock() {
-            if let Ok(conn) = receiver.try_recv() {
-                self.pool_size.fetch_sub(1, Ordering::Relaxed);
-                return Ok(conn);
+    /// Takes `messages` credits from the connection. A fresh connection
+    /// waits up to the connect timeout for the worker's opening grant.
+    async fn spend_credits(&self, conn: &mut Conn, messages: usize) -> Result<(), PublisherError> {
+        conn.collect_credits().map_err(PublisherError::WriteError)?;
+        if !conn.granted {
+            let _ = tokio::time::timeout(self.connect_timeout, conn.stream.readable()).await;
+            conn.collect_credits().map_err(PublisherError::WriteError)?;
+        }
+
+        if conn.credits < messages {
+            return Err(PublisherError::NoCredits);
+        }
+        conn.credits -= messages;
+        Ok(())
+    }
+
+    /// Closes a broken connection and opens a replacement in the background.
+    async fn discard(&self, mut conn: Conn) {
+        let _ = conn.stream.shutdown().await;
+        tokio::task::spawn({
+            let publisher = self.clone();
+            async move {
+                publisher.replace().await;
+            }
+        });
+    }
+
+    /// Connections parked for reuse right now.
+    pub fn idle_connections(&self) -> usize {
+        self.idle_conns.len()
+    }
+
+    /// Parks a connection when none is, returning whether one is parked now,
+    /// i.e. whether the worker is listening.
+    async fn warm(&self) -> bool {
+        if self.idle_conns.is_empty() {
+            self.replace().await;
+        }
+        !self.idle_conns.is_empty()
+    }
+
+    /// Whether the worker holds a connection open: parked connections it
+    /// closed are dropped first, and one is opened when none is left.
+    async fn is_live(&self) -> bool {
+        for _ in 0..self.idle_conns.len() {
+            let Some(mut conn) = self.idle_conns.pop() else { break };
+            if conn.collect_credits().is_ok() {
+                self.release(conn);
+            }
+        }
+        self.warm().await
+    }
+
+    async fn acquire(&self) -> Result<Conn, PublisherError> {
+        if let Some(mut conn) = self.idle_conns.pop() {
+            // Opened by `new`, before compression was asked for.
+            if self.compression && !conn.compressed {
+                self.negotiate_compression(&mut conn).await?;
             }
+            return Ok(conn);
         }
 
         // Create new connection if pool is empty
-        tokio::time::timeout(self.connect_timeout, UnixStream::connect(&self.socket_path))
+        self.connect().await
+    }
+
+    async fn connect(&self) -> Result<Conn, PublisherError> {
+        let mut conn = tokio::time::timeout(self.connect_timeout, UnixStream::connect(&self.socket_path))
+            .await
+            .map_err(|_| PublisherError::Timeout)?
+            .map(Conn::new)
+            .map_err(PublisherError::ConnectionFailed)?;
+        if self.compression {
+            self.negotiate_compression(&mut conn).await?;
+        }
+        Ok(conn)
+    }
+
+    async fn negotiate_compression(&self, conn: &mut Conn) -> Result<(), PublisherError> {
+        tokio::time::timeout(self.connect_timeout, conn.negotiate_compression())
             .await
             .map_err(|_| PublisherError::Timeout)?
             .map_err(PublisherError::ConnectionFailed)
     }
 
-    async fn release(&self, conn: UnixStream) {
-        if self.pool_size.load(Ordering::Relaxed) < self.max_conns {
-            if self.conn_pool.try_send(conn).is_ok() {
-                self.pool_size.fetch_add(1, Ordering::Relaxed);
-            }
-        }
+    /// Parks the connection for reuse; it is dropped when the pool is full.
+    fn release(&self, conn: Conn) {
+        let _ = self.idle_conns.push(conn);
     }
 
     async fn replace(&self) {
-        if let Ok(conn) = tokio::time::timeout(
-            self.connect_timeout,
-            UnixStream::connect(&self.socket_path)
-        ).await {
-            if let Ok(conn) = conn {
-                if self.conn_pool.try_send(conn).is_ok() {
-                    self.pool_size.fetch_add(1, Ordering::Relaxed);
-                }
-            }
+        if let Ok(conn) = self.connect().await {
+            self.release(conn);
         }
     }
 }
@@ -135,15 +475,369 @@ impl Clone for Publisher {
     fn clone(&self) -> Self {
         Self {
             socket_path: self.socket_path.clone(),
-            max_conns: self.max_conns,
-            conn_pool: self.conn_pool.clone(),
-            conn_receiver: self.conn_receiver.clone(),
+            idle_conns: self.idle_conns.clone(),
             connect_timeout: self.connect_timeout,
-            pool_size: self.pool_size.clone(),
+            batcher: self.batcher.clone(),
+            credit_flow: self.credit_flow,
+            compression: self.compression,
+            in_flight: self.in_flight.clone(),
+            closed: self.closed.clone(),
+        }
+    }
+}
+
+/// How [`FanOutPublisher`] picks a worker socket for a payment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatch {
+    RoundRobin,
+    /// By correlationId, so every copy of a payment reaches the same worker:
+    /// socket `id % sockets`, matching the workers' `WORKER_SHARD_INDEX`.
+    /// Payloads without a readable id are spread round-robin.
+    Hash,
+}
+
+impl std::str::FromStr for Dispatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Dispatch::RoundRobin),
+            "hash" => Ok(Dispatch::Hash),
+            other => Err(format!("unknown publish dispatch: {}", other)),
+        }
+    }
+}
+
+/// One [`Publisher`] per worker socket, for several worker processes
+/// behind a single gateway.
+pub struct FanOutPublisher {
+    publishers: Vec<Publisher>,
+    dispatch: Dispatch,
+    next: AtomicUsize,
+}
+
+impl FanOutPublisher {
+    pub fn new(publishers: Vec<Publisher>, dispatch: Dispatch) -> Self {
+        assert!(!publishers.is_empty(), "at least one worker socket is required");
+        Self {
+            publishers,
+            dispatch,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Publishes to the socket picked by [`Dispatch`], moving on to the
+    /// following sockets when one cannot be connected to or has no credits
+    /// left, since it has received nothing; a failed write is not repeated.
+    /// A hashed payment redirected this way is processed by a worker that
+    /// does not own its shard rather than lost.
+    pub async fn publish(&self, msg: &[u8]) -> Result<(), PublisherError> {
+        let owner = match self.dispatch {
+            Dispatch::Hash => correlation_key(msg)
+                .and_then(|key| uuid::Uuid::try_parse_ascii(key).ok())
+                .map(|id| (id.as_u128() % self.publishers.len() as u128) as usize),
+            Dispatch::RoundRobin => None,
+        };
+
+        let start = owner.unwrap_or_else(|| self.next.fetch_add(1, Ordering::Relaxed));
+        let mut result = Ok(());
+        for offset in 0..self.publishers.len() {
+            let publisher = &self.publishers[(start + offset) % self.publishers.len()];
+            result = publisher.publish(msg).await;
+            match &result {
+                Err(PublisherError::ConnectionFailed(_) | PublisherError::Timeout | PublisherError::NoCredits) => continue,
+                _ => break,
+            }
+        }
+        result
+    }
+
+    /// Refuses further publishes with [`PublisherError::Closed`] and waits up
+    /// to `timeout` for those in flight, payments still gathered in a batch
+    /// included, to reach the workers. Returns how many had not.
+    pub async fn shutdown(&self, timeout: Duration) -> usize {
+        for publisher in &self.publishers {
+            publisher.closed.store(true, Ordering::SeqCst);
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let in_flight = self.publishers.iter().map(|p| p.in_flight.load(Ordering::SeqCst)).sum();
+            if in_flight == 0 || tokio::time::Instant::now() >= deadline {
+                return in_flight;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    /// Connections parked for reuse across all sockets.
+    pub fn idle_connections(&self) -> usize {
+        self.publishers.iter().map(Publisher::idle_connections).sum()
+    }
+
+    /// How many worker sockets hold a live connection right now.
+    pub async fn live_workers(&self) -> usize {
+        let mut live = 0;
+        for publisher in &self.publishers {
+            live += usize::from(publisher.is_live().await);
+        }
+        live
+    }
+
+    /// Waits up to `timeout` for every worker socket to take a connection,
+    /// since workers bind theirs only once they can process payments.
+    /// Checks back off from 100ms to 2s. Returns how many sockets did.
+    pub async fn wait_for_workers(&self, timeout: Duration) -> usize {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut interval = WORKER_POLL_INTERVAL;
+        loop {
+            let mut missing = Vec::new();
+            for publisher in &self.publishers {
+                if !publisher.warm().await {
+                    missing.push(publisher.socket_path.as_str());
+                }
+            }
+            let reached = self.publishers.len() - missing.len();
+            if missing.is_empty() {
+                return reached;
+            }
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                tracing::error!(?missing, reached, "Workers did not come up in time");
+                return reached;
+            }
+            tracing::info!(?missing, "Waiting for workers");
+            tokio::time::sleep(interval.min(deadline - now)).await;
+            interval = (interval * 2).min(WORKER_POLL_MAX_INTERVAL);
         }
     }
 }
 
-unsafe impl Send for Publisher {}
-unsafe impl Sync for Publisher {}
+/// `parts` as one LZ4 frame behind its little-endian `u32` length.
+fn compressed_frame(parts: &[&[u8]]) -> Vec<u8> {
+    let compressed = common::lz4::compress(&parts.concat());
+    let mut frame = Vec::with_capacity(4 + compressed.len());
+    frame.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&compressed);
+    frame
+}
+
+/// The `correlationId` string of a payment payload, found without parsing
+/// the whole body.
+fn correlation_key(msg: &[u8]) -> Option<&[u8]> {
+    const KEY: &[u8] = b"\"correlationId\"";
+    let after_key = msg.windows(KEY.len()).position(|w| w == KEY)? + KEY.len();
+    let rest = &msg[after_key..];
+    let open = rest.iter().position(|b| *b == b'"')? + 1;
+    let len = rest[open..].iter().position(|b| *b == b'"')?;
+    Some(&rest[open..open + len])
+}
+
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixListener;
+
+    #[test]
+    fn stamped_key_finds_keys_the_client_already_sent() {
+        assert_eq!(stamped_key(br#"{"correlationId":"x","ingestTs":1}"#), Some("ingestTs"));
+        assert_eq!(stamped_key(br#"{"correlationId":"x","requestId":"y"}"#), Some("requestId"));
+        assert_eq!(stamped_key(br#"{"correlationId":"x","deadlineTs":"y"}"#), Some("deadlineTs"));
+        assert_eq!(stamped_key(br#"{"correlationId":"x","runId":"y"}"#), Some("runId"));
+        assert_eq!(stamped_key(br#"{"correlationId":"x","amount":1}"#), None);
+        assert_eq!(stamped_key(b"not json"), None);
+    }
+
+    #[test]
+    fn correlation_key_reads_the_id_wherever_it_is() {
+        assert_eq!(correlation_key(br#"{"correlationId":"abc","amount":1}"#), Some(&b"abc"[..]));
+        assert_eq!(correlation_key(br#"{"amount":1, "correlationId" : "abc"}"#), Some(&b"abc"[..]));
+        assert_eq!(correlation_key(br#"{"amount":1}"#), None);
+        assert_eq!(correlation_key(br#"{"correlationId":"abc"#), None);
+        assert_eq!("hash".parse::<Dispatch>().unwrap(), Dispatch::Hash);
+        assert_eq!("round-robin".parse::<Dispatch>().unwrap(), Dispatch::RoundRobin);
+        assert!("random".parse::<Dispatch>().is_err());
+    }
+
+    /// The lines each of two worker sockets received from `messages`.
+    async fn fan_out_to_two(name: &str, dispatch: Dispatch, messages: &[&[u8]]) -> [Vec<String>; 2] {
+        use tokio::io::AsyncBufReadExt;
+
+        let mut publishers = Vec::new();
+        let mut listeners = Vec::new();
+        for socket in 0..2 {
+            let path = std::env::temp_dir().join(format!("gateway-{}-{}-{}.sock", name, socket, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            listeners.push((UnixListener::bind(&path).unwrap(), path.clone()));
+            publishers.push(Publisher::new(path.to_str().unwrap().to_string(), 1).await.unwrap());
+        }
+        let fan_out = FanOutPublisher::new(publishers, dispatch);
+        for msg in messages {
+            fan_out.publish(msg).await.unwrap();
+        }
+
+        let mut received = [Vec::new(), Vec::new()];
+        for (socket, (listener, path)) in listeners.into_iter().enumerate() {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut lines = tokio::io::BufReader::new(stream).lines();
+            while let Ok(Ok(Some(line))) = tokio::time::timeout(Duration::from_millis(50), lines.next_line()).await {
+                received[socket].push(line);
+            }
+            let _ = std::fs::remove_file(&path);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn round_robin_alternates_sockets() {
+        let received = fan_out_to_two("round-robin", Dispatch::RoundRobin, &[b"1", b"2", b"3", b"4"]).await;
+        assert_eq!(received[0].len(), 2);
+        assert_eq!(received[1].len(), 2);
+    }
+
+    #[tokio::test]
+    async fn hashed_payments_reach_the_socket_owning_their_id() {
+        let even = br#"{"correlationId":"00000000-0000-0000-0000-000000000002"}"#;
+        let odd = br#"{"correlationId":"00000000-0000-0000-0000-000000000003"}"#;
+        let received = fan_out_to_two("hash", Dispatch::Hash, &[even, odd, even, odd, odd]).await;
+        assert_eq!(received[0], [std::str::from_utf8(even).unwrap(); 2]);
+        assert_eq!(received[1], [std::str::from_utf8(odd).unwrap(); 3]);
+    }
+
+    #[tokio::test]
+    async fn hashed_payments_move_on_when_their_owner_is_down() {
+        use tokio::io::AsyncReadExt;
+
+        let dir = std::env::temp_dir();
+        let down = dir.join(format!("gateway-hash-down-{}.sock", std::process::id()));
+        let up = dir.join(format!("gateway-hash-up-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&up);
+        let listener = UnixListener::bind(&up).unwrap();
+        let publishers = vec![
+            Publisher::new(down.to_str().unwrap().to_string(), 1).await.unwrap(),
+            Publisher::new(up.to_str().unwrap().to_string(), 1).await.unwrap(),
+        ];
+        let fan_out = FanOutPublisher::new(publishers, Dispatch::Hash);
+
+        // Owned by the first socket, which has no worker behind it.
+        let msg = br#"{"correlationId":"00000000-0000-0000-0000-000000000002","amount":1}"#;
+        fan_out.publish(msg).await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = [0; 1];
+        assert_eq!(stream.read(&mut received).await.unwrap(), 1);
+        let _ = std::fs::remove_file(&up);
+    }
+
+    fn spawn_publishes(fan_out: &Arc<FanOutPublisher>, count: usize) -> Vec<tokio::task::JoinHandle<Result<(), PublisherError>>> {
+        (0..count)
+            .map(|i| {
+                let fan_out = Arc::clone(fan_out);
+                tokio::spawn(async move { fan_out.publish(format!("{{\"n\":{}}}", i).as_bytes()).await })
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn full_batches_are_written_without_waiting_for_the_window() {
+        use tokio::io::AsyncReadExt;
+
+        let path = std::env::temp_dir().join(format!("gateway-batch-full-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let publisher = Publisher::new(path.to_str().unwrap().to_string(), 1)
+            .await
+            .unwrap()
+            .with_batching(BatchConfig { window: Duration::from_secs(30), max_messages: 2 });
+        let fan_out = Arc::new(FanOutPublisher::new(vec![publisher], Dispatch::RoundRobin));
+
+        let started = tokio::time::Instant::now();
+        for publish in spawn_publishes(&fan_out, 2) {
+            publish.await.unwrap().unwrap();
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = vec![0; 16];
+        stream.read_exact(&mut received).await.unwrap();
+        let mut lines: Vec<_> = received.split(|b| *b == b'\n').filter(|l| !l.is_empty()).collect();
+        lines.sort();
+        assert_eq!(lines, [&b"{\"n\":0}"[..], b"{\"n\":1}"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn a_failed_batch_fails_each_of_its_payments() {
+        let path = std::env::temp_dir().join(format!("gateway-batch-down-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let publisher = Publisher::new(path.to_str().unwrap().to_string(), 1)
+            .await
+            .unwrap()
+            .with_batching(BatchConfig { window: Duration::from_millis(20), max_messages: 16 });
+        let fan_out = Arc::new(FanOutPublisher::new(vec![publisher], Dispatch::RoundRobin));
+
+        let started = tokio::time::Instant::now();
+        for publish in spawn_publishes(&fan_out, 3) {
+            assert!(matches!(publish.await.unwrap(), Err(PublisherError::ConnectionFailed(_))));
+        }
+        // Gathered for the whole window, since the batch never filled.
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_batched_payments() {
+        use tokio::io::AsyncReadExt;
+
+        let path = std::env::temp_dir().join(format!("gateway-shutdown-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let publisher = Publisher::new(path.to_str().unwrap().to_string(), 1)
+            .await
+            .unwrap()
+            .with_batching(BatchConfig { window: Duration::from_millis(50), max_messages: 16 });
+        let fan_out = Arc::new(FanOutPublisher::new(vec![publisher], Dispatch::RoundRobin));
+
+        let publishes: Vec<_> = (0..3)
+            .map(|_| {
+                let fan_out = Arc::clone(&fan_out);
+                tokio::spawn(async move { fan_out.publish(b"{}").await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // The batch is still gathering; shutdown waits for its write.
+        assert_eq!(fan_out.shutdown(Duration::from_secs(1)).await, 0);
+        for publish in publishes {
+            publish.await.unwrap().unwrap();
+        }
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = [0; 9];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"{}\n{}\n{}\n");
+
+        assert!(matches!(fan_out.publish(b"{}").await, Err(PublisherError::Closed)));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn live_workers_follows_the_worker_socket() {
+        let path = std::env::temp_dir().join(format!("gateway-live-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let socket_path = path.to_str().unwrap().to_string();
+        let publisher = Publisher::new(socket_path.clone(), 4).await.unwrap();
+        let fan_out = FanOutPublisher::new(vec![publisher], Dispatch::RoundRobin);
+        assert_eq!(fan_out.live_workers().await, 1);
+
+        // The worker going away closes the parked connections.
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(fan_out.live_workers().await, 0);
+        assert_eq!(fan_out.idle_connections(), 0);
+
+        let _listener = UnixListener::bind(&path).unwrap();
+        assert_eq!(fan_out.live_workers().await, 1);
+        let _ = std::fs::remove_file(&path);
+    }
+}
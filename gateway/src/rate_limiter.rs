@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Upper bound on tracked peers; idle buckets are evicted past this.
+const MAX_TRACKED_PEERS: usize = 4096;
+
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst,
+            last_refill: now,
+        }
+    }
+
+    fn try_take(&mut self, limit: RateLimit, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&self, limit: RateLimit, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens + elapsed * limit.per_second >= limit.burst
+    }
+}
+
+/// Token-bucket limiter applied globally and, optionally, per client address
+/// taken from `X-Forwarded-For`.
+pub struct RateLimiter {
+    global: Option<(RateLimit, Mutex<TokenBucket>)>,
+    per_peer: Option<(RateLimit, Mutex<HashMap<String, TokenBucket>>)>,
+}
+
+impl RateLimiter {
+    pub fn new(global: Option<RateLimit>, per_peer: Option<RateLimit>) -> Self {
+        let now = Instant::now();
+        Self {
+            global: global.map(|limit| (limit, Mutex::new(TokenBucket::new(limit, now)))),
+            per_peer: per_peer.map(|limit| (limit, Mutex::new(HashMap::new()))),
+        }
+    }
+
+    /// Refills every bucket and forgets all peers. Returns whether any limit
+    /// is configured.
+    pub fn reset(&self) -> bool {
+        let now = Instant::now();
+        if let Some((limit, bucket)) = &self.global {
+            *bucket.lock().unwrap() = TokenBucket::new(*limit, now);
+        }
+        if let Some((_, buckets)) = &self.per_peer {
+            buckets.lock().unwrap().clear();
+        }
+        self.global.is_some() || self.per_peer.is_some()
+    }
+
+    /// Returns `false` when the request must be rejected.
+    pub fn check(&self, peer: Option<&str>) -> bool {
+        let now = Instant::now();
+
+        if let (Some((limit, buckets)), Some(peer)) = (&self.per_peer, peer) {
+            let mut buckets = buckets.lock().unwrap();
+            if buckets.len() >= MAX_TRACKED_PEERS && !buckets.contains_key(peer) {
+                buckets.retain(|_, bucket| !bucket.is_full(*limit, now));
+            }
+
+            let allowed = buckets
+                .entry(peer.to_string())
+                .or_insert_with(|| TokenBucket::new(*limit, now))
+                .try_take(*limit, now);
+            if !allowed {
+                return false;
+            }
+        }
+
+        match &self.global {
+            Some((limit, bucket)) => bucket.lock().unwrap().try_take(*limit, now),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const LIMIT: RateLimit = RateLimit { per_second: 10.0, burst: 2.0 };
+
+    #[test]
+    fn buckets_refill_at_the_rate_up_to_the_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT, start);
+        assert!(bucket.try_take(LIMIT, start));
+        assert!(bucket.try_take(LIMIT, start));
+        assert!(!bucket.try_take(LIMIT, start));
+
+        // A token every 100ms.
+        assert!(!bucket.try_take(LIMIT, start + Duration::from_millis(50)));
+        assert!(bucket.try_take(LIMIT, start + Duration::from_millis(110)));
+        assert!(!bucket.is_full(LIMIT, start + Duration::from_millis(110)));
+
+        // A long pause gives back the burst, no more.
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.is_full(LIMIT, later));
+        assert!(bucket.try_take(LIMIT, later));
+        assert!(bucket.try_take(LIMIT, later));
+        assert!(!bucket.try_take(LIMIT, later));
+    }
+
+    #[test]
+    fn peers_are_limited_separately() {
+        let limiter = RateLimiter::new(None, Some(LIMIT));
+        assert!(limiter.check(Some("10.0.0.1")));
+        assert!(limiter.check(Some("10.0.0.1")));
+        assert!(!limiter.check(Some("10.0.0.1")));
+        assert!(limiter.check(Some("10.0.0.2")));
+        // Without a client address only the global limit applies.
+        assert!(limiter.check(None));
+    }
+
+    #[test]
+    fn the_global_limit_covers_every_peer() {
+        let limiter = RateLimiter::new(Some(LIMIT), Some(RateLimit { per_second: 10.0, burst: 100.0 }));
+        assert!(limiter.check(Some("10.0.0.1")));
+        assert!(limiter.check(Some("10.0.0.2")));
+        assert!(!limiter.check(Some("10.0.0.3")));
+        assert!(!limiter.check(None));
+
+        assert!(limiter.reset());
+        assert!(limiter.check(Some("10.0.0.3")));
+        assert!(!RateLimiter::new(None, None).reset());
+    }
+
+    #[test]
+    fn idle_peers_are_forgotten_past_the_cap() {
+        let limiter = RateLimiter::new(None, Some(LIMIT));
+        for peer in 0..MAX_TRACKED_PEERS {
+            assert!(limiter.check(Some(&peer.to_string())));
+        }
+        // Every bucket spent a token, so none is full yet and none is evicted.
+        assert!(limiter.check(Some("new")));
+        assert_eq!(limiter.per_peer.as_ref().unwrap().1.lock().unwrap().len(), MAX_TRACKED_PEERS + 1);
+
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(limiter.check(Some("newer")));
+        assert_eq!(limiter.per_peer.as_ref().unwrap().1.lock().unwrap().len(), 1);
+    }
+}
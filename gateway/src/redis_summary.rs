@@ -0,0 +1,104 @@
+use crate::ServiceType;
+use common::redis_summary::{self, RECORDED_KEY, RECORD_SCRIPT};
+use redis::aio::ConnectionManager;
+use redis::Script;
+
+/// Sums the `<ms>:n` (requests) and `<ms>:a` (cents) fields of every key
+/// whose millisecond bucket falls within `[ARGV[1], ARGV[2]]`, an empty
+/// bound being open. Returns `requests, cents` per key.
+const TOTALS_SCRIPT: &str = r#"
+local from = tonumber(ARGV[1])
+local to = tonumber(ARGV[2])
+local totals = {}
+for _, key in ipairs(KEYS) do
+  local requests, cents = 0, 0
+  local fields = redis.call('HGETALL', key)
+  for i = 1, #fields, 2 do
+    local sep = string.find(fields[i], ':', 1, true)
+    local millis = tonumber(string.sub(fields[i], 1, sep - 1))
+    if (not from or millis >= from) and (not to or millis <= to) then
+      if string.sub(fields[i], sep + 1) == 'n' then
+        requests = requests + tonumber(fields[i + 1])
+      else
+        cents = cents + tonumber(fields[i + 1])
+      end
+    end
+  end
+  table.insert(totals, requests)
+  table.insert(totals, cents)
+end
+return totals
+"#;
+
+/// Reads the per-processor counters the worker keeps in Redis, so the
+/// summary can be served without Postgres.
+pub struct RedisSummary {
+    conn: ConnectionManager,
+    script: Script,
+    record_script: Script,
+}
+
+impl RedisSummary {
+    pub async fn connect(url: &str) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let conn = ConnectionManager::new(client).await?;
+        Ok(Self {
+            conn,
+            script: Script::new(TOTALS_SCRIPT),
+            record_script: Script::new(RECORD_SCRIPT),
+        })
+    }
+
+    /// `(requests, cents)` for the default and fallback processors between
+    /// `from` and `to` (unix milliseconds, inclusive).
+    pub async fn totals(
+        &self,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> redis::RedisResult<[(i64, i64); 2]> {
+        let mut invocation = self.script.prepare_invoke();
+        invocation
+            .key(redis_summary::key("default"))
+            .key(redis_summary::key("fallback"))
+            .arg(from.map(|v| v.to_string()).unwrap_or_default())
+            .arg(to.map(|v| v.to_string()).unwrap_or_default());
+
+        let mut conn = self.conn.clone();
+        let (default_requests, default_cents, fallback_requests, fallback_cents) =
+            invocation.invoke_async(&mut conn).await?;
+
+        Ok([
+            (default_requests, default_cents),
+            (fallback_requests, fallback_cents),
+        ])
+    }
+
+    /// Adds `(correlationId, processor, unix millis, cents)` payments to the
+    /// counters in one script, as the worker does for the payments it
+    /// stores. Payments already counted are skipped, so recording the same
+    /// payments again is harmless. Returns how many were counted.
+    pub async fn record<'a>(
+        &self,
+        payments: impl IntoIterator<Item = (&'a str, &'a ServiceType, i64, i64)>,
+    ) -> redis::RedisResult<i64> {
+        let (keys, args) = redis_summary::record_args(payments);
+        if args.is_empty() {
+            return Ok(0);
+        }
+        let mut invocation = self.record_script.prepare_invoke();
+        invocation.key(keys).arg(args);
+
+        let mut conn = self.conn.clone();
+        invocation.invoke_async(&mut conn).await
+    }
+
+    pub async fn purge(&self) -> redis::RedisResult<()> {
+        let mut conn = self.conn.clone();
+        redis::cmd("DEL")
+            .arg(redis_summary::key("default"))
+            .arg(redis_summary::key("fallback"))
+            .arg(RECORDED_KEY)
+            .query_async(&mut conn)
+            .await
+    }
+}
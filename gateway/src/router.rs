@@ -0,0 +1,271 @@
+//! Routing for the public and admin listeners. Routes are registered once at
+//! startup as a method plus a path pattern whose `{name}` segments capture
+//! path parameters; layers wrap a route's handler to bound or measure it.
+//! Unknown paths and methods get the static 404.
+
+use crate::error::HandlerError;
+use crate::stats::Stats;
+use crate::{static_response, ServiceType};
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Bytes, Incoming};
+use hyper::{Method, Request, Response, Uri};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use time::format_description::well_known::Rfc3339;
+use time::PrimitiveDateTime;
+
+pub type HandlerResult = Result<Response<BoxBody<Bytes, hyper::Error>>, HandlerError>;
+pub type HandlerFuture = Pin<Box<dyn Future<Output = HandlerResult> + Send>>;
+pub type Handler<S> = Arc<dyn Fn(Request<Incoming>, Params, Arc<S>) -> HandlerFuture + Send + Sync>;
+
+/// A value taken from a path segment or query parameter.
+pub trait FromParam: Sized {
+    /// Why a request carrying an unparseable value is rejected.
+    const INVALID: &'static str;
+
+    fn from_param(value: &str) -> Option<Self>;
+}
+
+/// RFC 3339, as the summary's `from` and `to` are given.
+impl FromParam for PrimitiveDateTime {
+    const INVALID: &'static str = "invalid date";
+
+    fn from_param(value: &str) -> Option<Self> {
+        PrimitiveDateTime::parse(value, &Rfc3339).ok()
+    }
+}
+
+impl FromParam for ServiceType {
+    const INVALID: &'static str = "invalid processor";
+
+    fn from_param(value: &str) -> Option<Self> {
+        value.parse().ok()
+    }
+}
+
+impl FromParam for uuid::Uuid {
+    const INVALID: &'static str = "invalid correlationId";
+
+    fn from_param(value: &str) -> Option<Self> {
+        uuid::Uuid::parse_str(value).ok()
+    }
+}
+
+/// Path parameters captured by the matched route.
+#[derive(Debug, Default)]
+pub struct Params(Vec<(&'static str, String)>);
+
+impl Params {
+    /// The parameter `name`, rejecting the request if it does not parse.
+    pub fn parse<T: FromParam>(&self, name: &str) -> Result<T, HandlerError> {
+        self.0
+            .iter()
+            .find(|(param, _)| *param == name)
+            .and_then(|(_, value)| T::from_param(value))
+            .ok_or(HandlerError::BadRequest(T::INVALID))
+    }
+}
+
+/// The decoded query string.
+pub struct Query(HashMap<String, String>);
+
+impl Query {
+    pub fn from_uri(uri: &Uri) -> Self {
+        let query = uri.query().unwrap_or("");
+        Self(form_urlencoded::parse(query.as_bytes()).into_owned().collect())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    /// Optional parameter, rejecting the request if present but
+    /// unparseable.
+    pub fn optional<T: FromParam>(&self, name: &str) -> Result<Option<T>, HandlerError> {
+        self.get(name)
+            .map(|value| T::from_param(value).ok_or(HandlerError::BadRequest(T::INVALID)))
+            .transpose()
+    }
+}
+
+/// Wraps a route's handler; `route` is its `METHOD /pattern`.
+pub trait Layer<S> {
+    fn wrap(&self, route: &str, handler: Handler<S>) -> Handler<S>;
+}
+
+/// Answers 504 when the handler takes longer than the limit; a no-op
+/// without one.
+pub struct Timeout(pub Option<Duration>);
+
+impl<S: 'static> Layer<S> for Timeout {
+    fn wrap(&self, _route: &str, handler: Handler<S>) -> Handler<S> {
+        let Some(limit) = self.0 else { return handler };
+        Arc::new(move |req, params, state| {
+            let handling = handler(req, params, state);
+            Box::pin(async move {
+                tokio::time::timeout(limit, handling)
+                    .await
+                    .unwrap_or(Err(HandlerError::Timeout))
+            })
+        })
+    }
+}
+
+/// Counts the route's requests, error statuses and latency in
+/// [`Stats`], under `routes` in `/internal/stats`.
+pub struct Metrics<'a>(pub &'a Stats);
+
+impl<S: 'static> Layer<S> for Metrics<'_> {
+    fn wrap(&self, route: &str, handler: Handler<S>) -> Handler<S> {
+        let counters = self.0.route(route);
+        Arc::new(move |req, params, state| {
+            let started = Instant::now();
+            let handling = handler(req, params, state);
+            let counters = Arc::clone(&counters);
+            Box::pin(async move {
+                let result = handling.await;
+                let status = match &result {
+                    Ok(response) => response.status(),
+                    Err(e) => e.status(),
+                };
+                counters.record(status, started.elapsed());
+                result
+            })
+        })
+    }
+}
+
+enum Segment {
+    Literal(&'static str),
+    Param(&'static str),
+}
+
+struct Route<S> {
+    method: Method,
+    name: String,
+    segments: Vec<Segment>,
+    handler: Handler<S>,
+}
+
+pub struct Router<S> {
+    routes: Vec<Route<S>>,
+}
+
+impl<S: Send + Sync + 'static> Router<S> {
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Sends `method` requests for paths matching `pattern` to `handler`.
+    /// Earlier routes win when several match.
+    pub fn route<F, Fut>(mut self, method: Method, pattern: &'static str, handler: F) -> Self
+    where
+        F: Fn(Request<Incoming>, Params, Arc<S>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HandlerResult> + Send + 'static,
+    {
+        let segments = pattern
+            .split('/')
+            .map(|segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(param) => Segment::Param(param),
+                None => Segment::Literal(segment),
+            })
+            .collect();
+        self.routes.push(Route {
+            name: format!("{} {}", method, pattern),
+            method,
+            segments,
+            handler: Arc::new(move |req, params, state| Box::pin(handler(req, params, state))),
+        });
+        self
+    }
+
+    /// Wraps the route registered last in `layer`. Layers added later run
+    /// outside those added before.
+    pub fn with(mut self, layer: impl Layer<S>) -> Self {
+        if let Some(route) = self.routes.last_mut() {
+            route.handler = layer.wrap(&route.name, Arc::clone(&route.handler));
+        }
+        self
+    }
+
+    /// Wraps every route registered so far in `layer`.
+    pub fn with_each(mut self, layer: impl Layer<S>) -> Self {
+        for route in &mut self.routes {
+            route.handler = layer.wrap(&route.name, Arc::clone(&route.handler));
+        }
+        self
+    }
+
+    fn find(&self, method: &Method, path: &str) -> Option<(&Route<S>, Params)> {
+        'routes: for route in self.routes.iter().filter(|route| route.method == method) {
+            let mut params = Params::default();
+            let mut segments = path.split('/');
+            for expected in &route.segments {
+                match (expected, segments.next()) {
+                    (Segment::Literal(literal), Some(segment)) if *literal == segment => {}
+                    (Segment::Param(name), Some(segment)) => params.0.push((name, segment.to_string())),
+                    _ => continue 'routes,
+                }
+            }
+            if segments.next().is_none() {
+                return Some((route, params));
+            }
+        }
+        None
+    }
+
+    pub async fn dispatch(&self, req: Request<Incoming>, state: Arc<S>) -> HandlerResult {
+        match self.find(req.method(), req.uri().path()) {
+            Some((route, params)) => (route.handler)(req, params, state).await,
+            None => Ok(static_response::not_found()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router<()> {
+        Router::new()
+            .route(Method::GET, "/payments-summary", |_, _, _| async { Ok(static_response::health()) })
+            .route(Method::GET, "/payments/{id}", |_, _, _| async { Ok(static_response::health()) })
+    }
+
+    #[test]
+    fn matches_literal_and_parameter_segments() {
+        let router = router();
+
+        let (route, _) = router.find(&Method::GET, "/payments-summary").unwrap();
+        assert_eq!(route.name, "GET /payments-summary");
+
+        let id = "4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3";
+        let (route, params) = router.find(&Method::GET, &format!("/payments/{}", id)).unwrap();
+        assert_eq!(route.name, "GET /payments/{id}");
+        assert_eq!(params.parse::<uuid::Uuid>("id").unwrap().to_string(), id);
+
+        assert!(router.find(&Method::POST, "/payments-summary").is_none());
+        assert!(router.find(&Method::GET, &format!("/payments/{}/extra", id)).is_none());
+        assert!(router.find(&Method::GET, "/payments").is_none());
+    }
+
+    #[test]
+    fn rejects_unparseable_parameters() {
+        let (_, params) = router().find(&Method::GET, "/payments/not-a-uuid").unwrap();
+        assert!(matches!(
+            params.parse::<uuid::Uuid>("id"),
+            Err(HandlerError::BadRequest("invalid correlationId"))
+        ));
+
+        let query = Query::from_uri(&"/payments-summary?from=2020-07-10T12:34:56.000Z&processor=nope".parse().unwrap());
+        assert!(query.optional::<PrimitiveDateTime>("from").unwrap().is_some());
+        assert!(query.optional::<PrimitiveDateTime>("to").unwrap().is_none());
+        assert!(matches!(
+            query.optional::<ServiceType>("processor"),
+            Err(HandlerError::BadRequest("invalid processor"))
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+//! Experimental gateway -> worker transport over a memory-mapped ring buffer.
+//!
+//! The ring file and eventfd are owned by the worker; see its
+//! `shm_transport` module for the layout, which must be kept in sync.
+
+use crate::publisher::PublisherError;
+use memmap2::MmapMut;
+use std::fs::OpenOptions;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+
+const MAGIC: u64 = 0x5249_4e47_4841_3235;
+const HEADER_SIZE: usize = 256;
+const SLOT_HEADER_SIZE: usize = 16;
+
+const OFF_MAGIC: usize = 0;
+const OFF_CAPACITY: usize = 8;
+const OFF_SLOT_SIZE: usize = 16;
+const OFF_WAITING: usize = 64;
+const OFF_ENQUEUE: usize = 128;
+
+pub struct ShmPublisher {
+    map: MmapMut,
+    capacity: u64,
+    slot_size: usize,
+    stride: usize,
+    event_fd: OwnedFd,
+}
+
+impl ShmPublisher {
+    /// Maps the ring created by the worker and fetches its eventfd from the
+    /// `<ring path>.sock` control socket.
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let map = unsafe { MmapMut::map_mut(&file)? };
+
+        if map.len() < HEADER_SIZE {
+            return Err(invalid_ring("ring file is too small"));
+        }
+
+        let header = |offset: usize| unsafe { &*(map.as_ptr().add(offset) as *const AtomicU64) };
+        if header(OFF_MAGIC).load(Ordering::Acquire) != MAGIC {
+            return Err(invalid_ring("ring file is not initialized"));
+        }
+
+        let capacity = header(OFF_CAPACITY).load(Ordering::Relaxed);
+        let slot_size = header(OFF_SLOT_SIZE).load(Ordering::Relaxed) as usize;
+        let stride = (SLOT_HEADER_SIZE + slot_size).next_multiple_of(64);
+
+        if !capacity.is_power_of_two() || map.len() < HEADER_SIZE + stride * capacity as usize {
+            return Err(invalid_ring("ring header does not match file size"));
+        }
+
+        let control = UnixStream::connect(format!("{}.sock", path))?;
+        let event_fd = recv_fd(control.as_raw_fd())?;
+
+        Ok(Self {
+            map,
+            capacity,
+            slot_size,
+            stride,
+            event_fd,
+        })
+    }
+
+    fn header(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.map.as_ptr().add(offset) as *const AtomicU64) }
+    }
+
+    fn slot_ptr(&self, pos: u64) -> *mut u8 {
+        let index = (pos & (self.capacity - 1)) as usize;
+        unsafe { (self.map.as_ptr() as *mut u8).add(HEADER_SIZE + index * self.stride) }
+    }
+
+    fn slot_seq(&self, pos: u64) -> &AtomicU64 {
+        unsafe { &*(self.slot_ptr(pos) as *const AtomicU64) }
+    }
+
+    /// Claims a slot, copies `msg` into it and wakes the worker if it is parked.
+    pub fn publish(&self, msg: &[u8]) -> Result<(), PublisherError> {
+        if msg.len() > self.slot_size {
+            return Err(PublisherError::MessageTooLarge);
+        }
+
+        let enqueue = self.header(OFF_ENQUEUE);
+        let mut pos = enqueue.load(Ordering::Relaxed);
+
+        loop {
+            let seq = self.slot_seq(pos).load(Ordering::Acquire);
+            let diff = seq as i64 - pos as i64;
+
+            if diff == 0 {
+                match enqueue.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => break,
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                return Err(PublisherError::RingFull);
+            } else {
+                pos = enqueue.load(Ordering::Relaxed);
+            }
+        }
+
+        let slot = self.slot_ptr(pos);
+        unsafe {
+            (slot.add(8) as *mut u32).write(msg.len() as u32);
+            std::ptr::copy_nonoverlapping(msg.as_ptr(), slot.add(SLOT_HEADER_SIZE), msg.len());
+        }
+        self.slot_seq(pos).store(pos + 1, Ordering::Release);
+
+        fence(Ordering::SeqCst);
+        let waiting = unsafe { &*(self.map.as_ptr().add(OFF_WAITING) as *const AtomicU32) };
+        if waiting.load(Ordering::Relaxed) == 1 {
+            let one = 1u64;
+            unsafe {
+                libc::write(
+                    self.event_fd.as_raw_fd(),
+                    &one as *const u64 as *const libc::c_void,
+                    std::mem::size_of::<u64>(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid_ring(reason: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason.to_string())
+}
+
+fn recv_fd(socket: RawFd) -> std::io::Result<OwnedFd> {
+    let mut payload = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    unsafe {
+        if libc::recvmsg(socket, &mut msg, 0) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(invalid_ring("control socket did not send an eventfd"));
+        }
+
+        let fd = (libc::CMSG_DATA(cmsg) as *const RawFd).read_unaligned();
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
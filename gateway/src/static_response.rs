@@ -0,0 +1,43 @@
+//! Fixed responses for health checks and unknown paths. Bodies and header
+//! values are built from `'static` data rather than formatted per request.
+
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Response, StatusCode};
+
+const HEALTH_BODY: &[u8] = b"OK";
+const HEALTH_LENGTH: &str = "2";
+const TEXT_PLAIN: &str = "text/plain";
+
+type StaticResponse = Response<BoxBody<Bytes, hyper::Error>>;
+
+/// `GET /health`.
+pub fn health() -> StaticResponse {
+    let body = Full::new(Bytes::from_static(HEALTH_BODY)).map_err(|never| match never {});
+    let mut response = Response::new(BoxBody::new(body));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(TEXT_PLAIN));
+    response
+}
+
+/// `HEAD /health`, with the headers a `GET` would carry.
+pub fn health_head() -> StaticResponse {
+    let mut response = Response::new(empty());
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(TEXT_PLAIN));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from_static(HEALTH_LENGTH));
+    response
+}
+
+pub fn not_found() -> StaticResponse {
+    let mut response = Response::new(empty());
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
+
+fn empty() -> BoxBody<Bytes, hyper::Error> {
+    BoxBody::new(Empty::new().map_err(|never| match never {}))
+}
@@ -0,0 +1,422 @@
+use crate::db_pool::DbPool;
+use crate::publisher::PublisherError;
+use serde::Serialize;
+use std::error::Error as _;
+use hyper::StatusCode;
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds, in microseconds, of the pool wait buckets.
+const POOL_WAIT_BOUNDS_US: [u64; 5] = [100, 1_000, 5_000, 20_000, 100_000];
+
+/// Counters behind `GET /internal/stats`, telling apart 429s caused by the
+/// rate limiter, a worker that cannot keep up and a publisher that cannot
+/// reach the worker at all.
+#[derive(Default)]
+pub struct Stats {
+    published: AtomicU64,
+    connect_failures: AtomicU64,
+    timeouts: AtomicU64,
+    write_failures: AtomicU64,
+    no_credits: AtomicU64,
+    ring_full: AtomicU64,
+    other_failures: AtomicU64,
+    budget_exceeded: AtomicU64,
+    rate_limited: AtomicU64,
+    /// Waits for a pooled connection, per bucket of [`POOL_WAIT_BOUNDS_US`]
+    /// plus one for anything slower.
+    pool_waits: [AtomicU64; POOL_WAIT_BOUNDS_US.len() + 1],
+    pool_wait_us_total: AtomicU64,
+    pool_timeouts: AtomicU64,
+    connection_resets: AtomicU64,
+    connection_timeouts: AtomicU64,
+    connection_protocol_errors: AtomicU64,
+    connection_other_errors: AtomicU64,
+    body_timeouts: AtomicU64,
+    idle_closed: AtomicU64,
+    peer_limited: AtomicU64,
+    /// Per route of the public router, keyed `METHOD /pattern`.
+    routes: Mutex<BTreeMap<String, Arc<RouteCounters>>>,
+}
+
+/// What one route answered, fed by the router's metrics layer.
+#[derive(Default)]
+pub struct RouteCounters {
+    requests: AtomicU64,
+    client_errors: AtomicU64,
+    server_errors: AtomicU64,
+    timeouts: AtomicU64,
+    latency_us_total: AtomicU64,
+}
+
+impl RouteCounters {
+    pub fn record(&self, status: StatusCode, elapsed: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.latency_us_total.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        if status.is_client_error() {
+            self.client_errors.fetch_add(1, Ordering::Relaxed);
+        } else if status.is_server_error() {
+            self.server_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        if status == StatusCode::GATEWAY_TIMEOUT {
+            self.timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Why a client connection ended in an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionErrorKind {
+    /// The client went away mid-request or before the response was written.
+    /// Routine when load generators tear down keep-alive connections.
+    Reset,
+    /// No complete request head within the header read timeout.
+    Timeout,
+    /// The client sent something that is not HTTP/1.
+    Protocol,
+    Other,
+}
+
+impl ConnectionErrorKind {
+    pub fn classify(err: &hyper::Error) -> Self {
+        if err.is_incomplete_message() || err.is_canceled() || err.is_closed() {
+            return Self::Reset;
+        }
+        if err.is_timeout() {
+            return Self::Timeout;
+        }
+        if err.is_parse() || err.is_parse_too_large() || err.is_parse_status() {
+            return Self::Protocol;
+        }
+
+        let mut source = err.source();
+        while let Some(cause) = source {
+            if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+                return match io.kind() {
+                    ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof => Self::Reset,
+                    ErrorKind::TimedOut => Self::Timeout,
+                    _ => Self::Other,
+                };
+            }
+            source = cause.source();
+        }
+        Self::Other
+    }
+
+    /// Resets and timeouts are the client's doing and not worth a log line.
+    pub fn is_benign(self) -> bool {
+        matches!(self, Self::Reset | Self::Timeout)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublishStats {
+    pub published: u64,
+    pub connect_failures: u64,
+    pub timeouts: u64,
+    pub write_failures: u64,
+    /// Payments the worker had no credits for.
+    pub no_credits: u64,
+    pub ring_full: u64,
+    pub other_failures: u64,
+    /// Payments answered 504 because publishing outlasted the request
+    /// budget. Not counted as failed publishes: they may still go out.
+    pub budget_exceeded: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DbPoolStats {
+    pub max_size: usize,
+    pub size: usize,
+    pub available: usize,
+    pub waiting: usize,
+    /// Requests that gave up waiting and were answered with 503.
+    pub timeouts: u64,
+    pub wait_us_total: u64,
+    pub waits: Vec<WaitBucket>,
+    /// `closed`, `open` or `probing`; see [`crate::db_pool`].
+    pub breaker: &'static str,
+    /// Times the pool was rebuilt after its breaker opened.
+    pub rebuilds: u64,
+}
+
+/// Waits that took at most `le_us` microseconds; `None` is the overflow
+/// bucket.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WaitBucket {
+    pub le_us: Option<u64>,
+    pub count: u64,
+}
+
+/// Client connections that ended in an error, by [`ConnectionErrorKind`].
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionErrorStats {
+    pub resets: u64,
+    pub timeouts: u64,
+    pub protocol_errors: u64,
+    pub other: u64,
+    /// Requests answered 408 because the body did not arrive in time.
+    pub body_timeouts: u64,
+    /// Closed after `GATEWAY_HTTP1_IDLE_TIMEOUT_MS` without I/O.
+    pub idle_closed: u64,
+    /// Closed on accept, their peer being at
+    /// `GATEWAY_MAX_CONNECTIONS_PER_PEER`.
+    pub peer_limited: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteStats {
+    pub requests: u64,
+    /// Answered 4xx, including 429s.
+    pub client_errors: u64,
+    pub server_errors: u64,
+    /// Answered 504, by a route timeout or the request budget; also counted
+    /// in `server_errors`.
+    pub timeouts: u64,
+    pub latency_us_total: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsReport {
+    pub publish: PublishStats,
+    /// Every 429 sent: rate limited requests plus failed publishes.
+    pub too_many_requests: u64,
+    pub rate_limited: u64,
+    pub publisher_idle_connections: usize,
+    pub db_pool: DbPoolStats,
+    pub connection_errors: ConnectionErrorStats,
+    pub routes: BTreeMap<String, RouteStats>,
+}
+
+impl Stats {
+    pub fn record_publish(&self, result: &Result<(), PublisherError>) {
+        let counter = match result {
+            Ok(()) => &self.published,
+            Err(PublisherError::ConnectionFailed(_)) => &self.connect_failures,
+            Err(PublisherError::Timeout) => &self.timeouts,
+            Err(PublisherError::WriteError(_)) => &self.write_failures,
+            Err(PublisherError::NoCredits) => &self.no_credits,
+            #[cfg(feature = "shm-transport")]
+            Err(PublisherError::RingFull) => &self.ring_full,
+            Err(_) => &self.other_failures,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_budget_exceeded(&self) {
+        self.budget_exceeded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connection_error(&self, kind: ConnectionErrorKind) {
+        let counter = match kind {
+            ConnectionErrorKind::Reset => &self.connection_resets,
+            ConnectionErrorKind::Timeout => &self.connection_timeouts,
+            ConnectionErrorKind::Protocol => &self.connection_protocol_errors,
+            ConnectionErrorKind::Other => &self.connection_other_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_body_timeout(&self) {
+        self.body_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_idle_closed(&self) {
+        self.idle_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_peer_limited(&self) {
+        self.peer_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counters of `route`, created on first use.
+    pub fn route(&self, route: &str) -> Arc<RouteCounters> {
+        Arc::clone(self.routes.lock().unwrap().entry(route.to_string()).or_default())
+    }
+
+    pub fn record_pool_wait(&self, waited: Duration, acquired: bool) {
+        let us = waited.as_micros() as u64;
+        let bucket = POOL_WAIT_BOUNDS_US
+            .iter()
+            .position(|bound| us <= *bound)
+            .unwrap_or(POOL_WAIT_BOUNDS_US.len());
+        self.pool_waits[bucket].fetch_add(1, Ordering::Relaxed);
+        self.pool_wait_us_total.fetch_add(us, Ordering::Relaxed);
+        if !acquired {
+            self.pool_timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn report(&self, publisher_idle_connections: usize, db: &DbPool) -> StatsReport {
+        let db_pool = db.status();
+        let publish = PublishStats {
+            published: self.published.load(Ordering::Relaxed),
+            connect_failures: self.connect_failures.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            write_failures: self.write_failures.load(Ordering::Relaxed),
+            no_credits: self.no_credits.load(Ordering::Relaxed),
+            ring_full: self.ring_full.load(Ordering::Relaxed),
+            other_failures: self.other_failures.load(Ordering::Relaxed),
+            budget_exceeded: self.budget_exceeded.load(Ordering::Relaxed),
+        };
+        let rate_limited = self.rate_limited.load(Ordering::Relaxed);
+        let failed_publishes = publish.connect_failures
+            + publish.timeouts
+            + publish.write_failures
+            + publish.no_credits
+            + publish.ring_full
+            + publish.other_failures;
+
+        StatsReport {
+            publish,
+            too_many_requests: rate_limited + failed_publishes,
+            rate_limited,
+            publisher_idle_connections,
+            db_pool: DbPoolStats {
+                max_size: db_pool.max_size,
+                size: db_pool.size,
+                available: db_pool.available,
+                waiting: db_pool.waiting,
+                timeouts: self.pool_timeouts.load(Ordering::Relaxed),
+                wait_us_total: self.pool_wait_us_total.load(Ordering::Relaxed),
+                waits: self
+                    .pool_waits
+                    .iter()
+                    .enumerate()
+                    .map(|(i, count)| WaitBucket {
+                        le_us: POOL_WAIT_BOUNDS_US.get(i).copied(),
+                        count: count.load(Ordering::Relaxed),
+                    })
+                    .collect(),
+                breaker: db.breaker_state(),
+                rebuilds: db.rebuilds(),
+            },
+            connection_errors: ConnectionErrorStats {
+                resets: self.connection_resets.load(Ordering::Relaxed),
+                timeouts: self.connection_timeouts.load(Ordering::Relaxed),
+                protocol_errors: self.connection_protocol_errors.load(Ordering::Relaxed),
+                other: self.connection_other_errors.load(Ordering::Relaxed),
+                body_timeouts: self.body_timeouts.load(Ordering::Relaxed),
+                idle_closed: self.idle_closed.load(Ordering::Relaxed),
+                peer_limited: self.peer_limited.load(Ordering::Relaxed),
+            },
+            routes: self
+                .routes
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(route, counters)| {
+                    let stats = RouteStats {
+                        requests: counters.requests.load(Ordering::Relaxed),
+                        client_errors: counters.client_errors.load(Ordering::Relaxed),
+                        server_errors: counters.server_errors.load(Ordering::Relaxed),
+                        timeouts: counters.timeouts.load(Ordering::Relaxed),
+                        latency_us_total: counters.latency_us_total.load(Ordering::Relaxed),
+                    };
+                    (route.clone(), stats)
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Empty;
+    use hyper::body::{Bytes, Incoming};
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response};
+    use hyper_util::rt::{TokioIo, TokioTimer};
+    use tokio::io::AsyncWriteExt;
+
+    /// Serves one connection whose client writes `sent` and then hangs up,
+    /// or stays silent when `hang_up` is false, returning how it ended.
+    async fn serve(sent: &[u8], hang_up: bool, answer: Result<(), ErrorKind>) -> hyper::Error {
+        let (client, server) = tokio::io::duplex(4096);
+        let service = service_fn(move |_req: Request<Incoming>| async move {
+            answer.map(|()| Response::new(Empty::<Bytes>::new())).map_err(std::io::Error::from)
+        });
+        let conn = http1::Builder::new()
+            .timer(TokioTimer::new())
+            .header_read_timeout(Duration::from_millis(20))
+            .serve_connection(TokioIo::new(server), service);
+
+        let mut client = client;
+        client.write_all(sent).await.unwrap();
+        if hang_up {
+            drop(client);
+            conn.await.unwrap_err()
+        } else {
+            let err = conn.await.unwrap_err();
+            drop(client);
+            err
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_hanging_up_mid_request_are_resets() {
+        let err = serve(b"POST /payments HTTP/1.1\r\nHost: gat", true, Ok(())).await;
+        let kind = ConnectionErrorKind::classify(&err);
+        assert_eq!(kind, ConnectionErrorKind::Reset);
+        assert!(kind.is_benign());
+    }
+
+    #[tokio::test]
+    async fn slow_request_heads_are_timeouts() {
+        let err = serve(b"POST /payments HTTP/1.1\r\n", false, Ok(())).await;
+        let kind = ConnectionErrorKind::classify(&err);
+        assert_eq!(kind, ConnectionErrorKind::Timeout);
+        assert!(kind.is_benign());
+    }
+
+    #[tokio::test]
+    async fn garbage_is_a_protocol_error() {
+        let err = serve(b"GET /payments-summary HTTP/9.9\r\n\r\n", false, Ok(())).await;
+        let kind = ConnectionErrorKind::classify(&err);
+        assert_eq!(kind, ConnectionErrorKind::Protocol);
+        assert!(!kind.is_benign());
+    }
+
+    #[tokio::test]
+    async fn io_errors_are_classified_by_their_kind() {
+        let request = b"GET /payments-summary HTTP/1.1\r\nHost: gateway\r\n\r\n";
+        let err = serve(request, false, Err(ErrorKind::BrokenPipe)).await;
+        assert_eq!(ConnectionErrorKind::classify(&err), ConnectionErrorKind::Reset);
+
+        let err = serve(request, false, Err(ErrorKind::PermissionDenied)).await;
+        let kind = ConnectionErrorKind::classify(&err);
+        assert_eq!(kind, ConnectionErrorKind::Other);
+        assert!(!kind.is_benign());
+    }
+
+    #[test]
+    fn connection_errors_are_counted_by_kind() {
+        let stats = Stats::default();
+        stats.record_connection_error(ConnectionErrorKind::Reset);
+        stats.record_connection_error(ConnectionErrorKind::Reset);
+        stats.record_connection_error(ConnectionErrorKind::Protocol);
+
+        assert_eq!(stats.connection_resets.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.connection_protocol_errors.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.connection_timeouts.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.connection_other_errors.load(Ordering::Relaxed), 0);
+    }
+}
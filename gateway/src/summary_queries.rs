@@ -0,0 +1,25 @@
+//! SQL behind `/payments-summary`. Kept apart from the handlers so the
+//! pipelining benchmark runs exactly the statements the gateway does.
+
+/// `$1`/`$2` bound the `requested_at` window, `$3` filters on the processor
+/// and `$4` on the run; each is ignored when NULL.
+pub const TOTALS: &str = "
+    SELECT COUNT(*) AS total_requests,
+           COALESCE(SUM(amount), 0) AS total_amount,
+           service_used
+    FROM payments
+    WHERE ($1::timestamp IS NULL OR requested_at >= $1::timestamp)
+      AND ($2::timestamp IS NULL OR requested_at <= $2::timestamp)
+      AND ($3::service_type IS NULL OR service_used = $3::service_type)
+      AND ($4::text IS NULL OR run_id = $4::text)
+    GROUP BY service_used";
+
+/// Newest `requested_at` under the same filters as [`TOTALS`], NULL when
+/// nothing matches.
+pub const LAST_REQUESTED_AT: &str = "
+    SELECT MAX(requested_at) AS last_requested_at
+    FROM payments
+    WHERE ($1::timestamp IS NULL OR requested_at >= $1::timestamp)
+      AND ($2::timestamp IS NULL OR requested_at <= $2::timestamp)
+      AND ($3::service_type IS NULL OR service_used = $3::service_type)
+      AND ($4::text IS NULL OR run_id = $4::text)";
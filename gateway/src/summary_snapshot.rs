@@ -0,0 +1,131 @@
+use crate::api::ProcessorSummary;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use tokio_postgres::{AsyncMessage, NoTls};
+
+/// Refreshes that may fail in a row before the snapshot is no longer served
+/// and summaries fall back to querying Postgres.
+const MAX_MISSED_REFRESHES: u32 = 5;
+/// Postgres channel notified when payments change outside the workers'
+/// writes, by a purge or an import, so every replica drops its snapshot.
+pub const INVALIDATE_CHANNEL: &str = "payments_summary_stale";
+/// Pause before listening again after the connection was lost.
+const LISTEN_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// All-time totals per processor, refreshed in the background so an
+/// unfiltered `/payments-summary` costs a read lock instead of a scan that
+/// grows with the table.
+pub struct SummarySnapshot {
+    interval: Duration,
+    latest: RwLock<Option<Totals>>,
+    /// Bumped by [`SummarySnapshot::invalidate`], so a refresh that was
+    /// already running does not bring back pre-purge totals.
+    generation: AtomicU64,
+}
+
+struct Totals {
+    default: ProcessorSummary,
+    fallback: ProcessorSummary,
+    refreshed_at: Instant,
+}
+
+impl SummarySnapshot {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            latest: RwLock::new(None),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Default and fallback totals, `None` before the first refresh or when
+    /// refreshes have been failing.
+    pub fn get(&self) -> Option<(ProcessorSummary, ProcessorSummary)> {
+        let latest = self.latest.read().unwrap();
+        let totals = latest.as_ref()?;
+        if totals.refreshed_at.elapsed() > self.interval * MAX_MISSED_REFRESHES {
+            return None;
+        }
+        Some((totals.default.clone(), totals.fallback.clone()))
+    }
+
+    /// Drops the snapshot, e.g. after the payments were purged, so nothing
+    /// older is served until the next refresh.
+    pub fn invalidate(&self) {
+        let mut latest = self.latest.write().unwrap();
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        *latest = None;
+    }
+
+    /// Invalidates on every [`INVALIDATE_CHANNEL`] notification, whichever
+    /// replica sent it. A lost connection invalidates too, since
+    /// notifications may have been missed meanwhile. Runs forever.
+    pub async fn follow_invalidations(&self, pg_config: tokio_postgres::Config) {
+        loop {
+            if let Err(e) = self.listen(&pg_config).await {
+                tracing::warn!(error = %e, "Summary invalidation listener failed");
+            }
+            self.invalidate();
+            tokio::time::sleep(LISTEN_RETRY_INTERVAL).await;
+        }
+    }
+
+    async fn listen(&self, pg_config: &tokio_postgres::Config) -> Result<(), tokio_postgres::Error> {
+        let (client, mut connection) = pg_config.connect(NoTls).await?;
+        // The connection has to be polled for the LISTEN to complete.
+        let statement = format!("LISTEN {}", INVALIDATE_CHANNEL);
+        let listen = client.batch_execute(&statement);
+        tokio::pin!(listen);
+        let mut listening = false;
+        loop {
+            tokio::select! {
+                result = &mut listen, if !listening => {
+                    result?;
+                    listening = true;
+                }
+                message = std::future::poll_fn(|cx| connection.poll_message(cx)) => match message {
+                    Some(Ok(AsyncMessage::Notification(_))) => {
+                        tracing::debug!("Summary snapshot invalidated");
+                        self.invalidate();
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e),
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+
+    /// Calls `refresh` every interval and keeps its result. Runs forever.
+    pub async fn run<F, Fut, E>(&self, mut refresh: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(ProcessorSummary, ProcessorSummary), E>>,
+        E: std::fmt::Display,
+    {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            let started = Instant::now();
+            let generation = self.generation.load(Ordering::Relaxed);
+            match refresh().await {
+                Ok((default, fallback)) => {
+                    let mut latest = self.latest.write().unwrap();
+                    if self.generation.load(Ordering::Relaxed) != generation {
+                        continue;
+                    }
+                    *latest = Some(Totals {
+                        default,
+                        fallback,
+                        refreshed_at: started,
+                    });
+                }
+                Err(e) => tracing::warn!(error = %e, "Failed to refresh the summary snapshot"),
+            }
+        }
+    }
+}
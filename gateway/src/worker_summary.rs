@@ -0,0 +1,130 @@
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::client::conn::http1;
+use hyper::{Method, Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use tokio::net::UnixStream;
+
+/// Longest a single worker may take to answer before the summary fails.
+const WORKER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Why the workers' totals could not be read.
+#[derive(Debug)]
+pub enum WorkerSummaryError {
+    Io(std::io::Error),
+    Http(hyper::Error),
+    Timeout,
+    /// The worker answered, but not with totals (e.g. it does not run the
+    /// memory backend).
+    Status(StatusCode),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for WorkerSummaryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkerSummaryError::Io(e) => write!(f, "Failed to reach worker: {}", e),
+            WorkerSummaryError::Http(e) => write!(f, "Worker request failed: {}", e),
+            WorkerSummaryError::Timeout => write!(f, "Worker did not answer in time"),
+            WorkerSummaryError::Status(status) => write!(f, "Worker answered {}", status),
+            WorkerSummaryError::Json(e) => write!(f, "Invalid worker summary: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WorkerSummaryError {}
+
+#[derive(Deserialize)]
+struct Totals {
+    requests: i64,
+    cents: i64,
+}
+
+/// Reads the totals workers hold in memory (`STORE_BACKEND=memory`) from
+/// their admin sockets and adds them up, so the summary needs no database.
+/// Workers are asked one after the other; there are only a handful.
+pub struct WorkerSummary {
+    sockets: Vec<String>,
+}
+
+impl WorkerSummary {
+    pub fn new(sockets: Vec<String>) -> Self {
+        Self { sockets }
+    }
+
+    /// `(requests, cents)` for the default and fallback processors between
+    /// `from` and `to` (unix milliseconds, inclusive), over every worker.
+    pub async fn totals(
+        &self,
+        from: Option<i64>,
+        to: Option<i64>,
+        run_id: Option<&str>,
+    ) -> Result<[(i64, i64); 2], WorkerSummaryError> {
+        let mut query = Vec::new();
+        if let Some(from) = from {
+            query.push(format!("from={}", from));
+        }
+        if let Some(to) = to {
+            query.push(format!("to={}", to));
+        }
+        if let Some(run_id) = run_id {
+            query.push(format!("runId={}", run_id));
+        }
+        let uri = format!("/summary?{}", query.join("&"));
+
+        let mut totals = [(0, 0); 2];
+        for socket in &self.sockets {
+            let reply = request(socket, Method::GET, &uri).await?;
+            let by_processor: HashMap<String, Totals> =
+                serde_json::from_slice(&reply).map_err(WorkerSummaryError::Json)?;
+            for (slot, name) in totals.iter_mut().zip(["default", "fallback"]) {
+                if let Some(processor) = by_processor.get(name) {
+                    slot.0 += processor.requests;
+                    slot.1 += processor.cents;
+                }
+            }
+        }
+        Ok(totals)
+    }
+
+    /// Drops the payments every worker holds.
+    pub async fn purge(&self) -> Result<(), WorkerSummaryError> {
+        for socket in &self.sockets {
+            request(socket, Method::POST, "/purge").await?;
+        }
+        Ok(())
+    }
+}
+
+/// One request on a fresh connection to the admin socket at `path`,
+/// returning the body of a 200 answer.
+async fn request(path: &str, method: Method, uri: &str) -> Result<Bytes, WorkerSummaryError> {
+    let exchange = async {
+        let stream = UnixStream::connect(path).await.map_err(WorkerSummaryError::Io)?;
+        let (mut sender, conn) = http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(WorkerSummaryError::Http)?;
+        tokio::spawn(conn);
+
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(hyper::header::HOST, "worker")
+            .body(Empty::<Bytes>::new())
+            .expect("valid admin request");
+        let response = sender.send_request(request).await.map_err(WorkerSummaryError::Http)?;
+        if response.status() != StatusCode::OK {
+            return Err(WorkerSummaryError::Status(response.status()));
+        }
+        let body = response.into_body().collect().await.map_err(WorkerSummaryError::Http)?;
+        Ok(body.to_bytes())
+    };
+
+    tokio::time::timeout(WORKER_TIMEOUT, exchange)
+        .await
+        .map_err(|_| WorkerSummaryError::Timeout)?
+}
@@ -0,0 +1,272 @@
+use crate::load_balancer::env_or;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+/// First pause after the process ran out of descriptors, doubled on every
+/// further failure up to [`ACCEPT_BACKOFF_MAX`].
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Linux errnos meaning the process or system is out of descriptors or
+/// socket memory: `EMFILE`, `ENFILE`, `ENOBUFS`, `ENOMEM`.
+const RESOURCE_ERRNOS: [i32; 4] = [24, 23, 105, 12];
+
+/// Which address families the LB accepts clients on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenFamily {
+    V4,
+    /// IPv6 only, for IPv6-only container networks.
+    V6,
+    /// Separate IPv4 and IPv6 sockets on the same port.
+    Dual,
+}
+
+impl FromStr for ListenFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "v4" | "ipv4" => Ok(ListenFamily::V4),
+            "v6" | "ipv6" => Ok(ListenFamily::V6),
+            "dual" => Ok(ListenFamily::Dual),
+            other => Err(format!("Unknown listen family: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ListenConfig {
+    pub family: ListenFamily,
+    pub port: u16,
+}
+
+impl ListenConfig {
+    /// `LB_LISTEN_FAMILY` (`v4`, `v6` or `dual`, default `v4`) and
+    /// `LB_LISTEN_PORT` (default 9999).
+    pub fn from_env() -> Result<Self, String> {
+        let family = match std::env::var("LB_LISTEN_FAMILY") {
+            Ok(family) => family.parse()?,
+            Err(_) => ListenFamily::V4,
+        };
+
+        Ok(Self {
+            family,
+            port: env_or("LB_LISTEN_PORT", 9999),
+        })
+    }
+
+    fn addrs(&self) -> Vec<SocketAddr> {
+        let v4 = SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.port));
+        let v6 = SocketAddr::from((Ipv6Addr::UNSPECIFIED, self.port));
+
+        match self.family {
+            ListenFamily::V4 => vec![v4],
+            ListenFamily::V6 => vec![v6],
+            ListenFamily::Dual => vec![v4, v6],
+        }
+    }
+}
+
+/// One or more bound sockets served as a single stream of connections.
+pub struct Listeners {
+    listeners: Vec<TcpListener>,
+}
+
+impl Listeners {
+    pub fn bind(config: &ListenConfig) -> std::io::Result<Self> {
+        let listeners = config
+            .addrs()
+            .into_iter()
+            .map(bind_one)
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(Self { listeners })
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|l| l.local_addr().ok())
+            .collect()
+    }
+
+    /// Next connection from whichever socket has one ready. Cancel safe.
+    pub async fn accept(&self) -> std::io::Result<(TcpStream, SocketAddr)> {
+        let accepts = self.listeners.iter().map(|l| Box::pin(l.accept()));
+        futures_util::future::select_all(accepts).await.0
+    }
+}
+
+/// Keeps accept failures from taking the LB down and caps open client
+/// connections (`LB_MAX_CONNECTIONS`, `0` for no cap).
+///
+/// Running out of descriptors makes `accept` fail until connections close,
+/// so retrying at once would spin; instead the loop backs off. Connections
+/// over the cap are accepted and closed straight away, which keeps the
+/// listen queue from holding clients that would only time out.
+pub struct AcceptGuard {
+    max_connections: usize,
+    active: Arc<AtomicUsize>,
+    /// Current pause in microseconds, reset by the next accepted connection.
+    backoff_us: AtomicU64,
+    exhausted: AtomicU64,
+    errors: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// An open client connection, counted until dropped.
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AcceptGuard {
+    pub fn from_env() -> Self {
+        Self::new(env_or("LB_MAX_CONNECTIONS", 0))
+    }
+
+    pub fn new(max_connections: usize) -> Self {
+        Self {
+            max_connections,
+            active: Arc::new(AtomicUsize::new(0)),
+            backoff_us: AtomicU64::new(0),
+            exhausted: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts an accepted connection, or `None` when the cap is reached and
+    /// it should be closed.
+    pub fn admit(&self) -> Option<ConnectionSlot> {
+        if self.backoff_us.load(Ordering::Relaxed) != 0 {
+            self.backoff_us.store(0, Ordering::Relaxed);
+        }
+
+        let previous = self.active.fetch_add(1, Ordering::Relaxed);
+        let slot = ConnectionSlot(self.active.clone());
+        if self.max_connections > 0 && previous >= self.max_connections {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(slot)
+    }
+
+    /// How long to wait before accepting again after `err`.
+    pub fn on_error(&self, err: &std::io::Error) -> Duration {
+        if !err.raw_os_error().is_some_and(|errno| RESOURCE_ERRNOS.contains(&errno)) {
+            // Typically a client that reset before being accepted.
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            tracing::debug!(error = %err, "Accept failed");
+            return Duration::ZERO;
+        }
+
+        self.exhausted.fetch_add(1, Ordering::Relaxed);
+        let previous = Duration::from_micros(self.backoff_us.load(Ordering::Relaxed));
+        let backoff = (previous * 2).clamp(ACCEPT_BACKOFF_MIN, ACCEPT_BACKOFF_MAX);
+        self.backoff_us.store(backoff.as_micros() as u64, Ordering::Relaxed);
+        tracing::warn!(error = %err, ?backoff, active = self.active.load(Ordering::Relaxed), "Out of descriptors, pausing accepts");
+        backoff
+    }
+
+    /// Counters for `GET /_lb/stats`.
+    pub fn stats_json(&self) -> String {
+        format!(
+            r#"{{"activeConnections":{},"maxConnections":{},"rejectedConnections":{},"acceptExhausted":{},"acceptErrors":{}}}"#,
+            self.active.load(Ordering::Relaxed),
+            self.max_connections,
+            self.rejected.load(Ordering::Relaxed),
+            self.exhausted.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+        )
+    }
+}
+
+fn bind_one(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // The v6 socket never takes v4-mapped traffic, so it can share the port
+    // with a v4 socket in dual mode and behaves the same on every kernel.
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_recv_buffer_size(16 * 1024)?;
+    socket.set_send_buffer_size(16 * 1024)?;
+    socket.set_nonblocking(true)?;
+
+    socket.bind(&addr.into())?;
+    socket.listen(16 * 1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn running_out_of_descriptors_backs_off_until_a_connection_is_accepted() {
+        let guard = AcceptGuard::new(0);
+        let emfile = Error::from_raw_os_error(24);
+
+        assert_eq!(guard.on_error(&emfile), ACCEPT_BACKOFF_MIN);
+        assert_eq!(guard.on_error(&emfile), ACCEPT_BACKOFF_MIN * 2);
+        assert_eq!(guard.on_error(&Error::from_raw_os_error(23)), ACCEPT_BACKOFF_MIN * 4);
+        for _ in 0..20 {
+            guard.on_error(&emfile);
+        }
+        assert_eq!(guard.on_error(&emfile), ACCEPT_BACKOFF_MAX);
+
+        drop(guard.admit());
+        assert_eq!(guard.on_error(&emfile), ACCEPT_BACKOFF_MIN);
+    }
+
+    #[test]
+    fn other_accept_errors_retry_at_once() {
+        let guard = AcceptGuard::new(0);
+        assert_eq!(guard.on_error(&Error::from(ErrorKind::ConnectionAborted)), Duration::ZERO);
+        assert_eq!(guard.on_error(&Error::from_raw_os_error(103)), Duration::ZERO);
+        guard.on_error(&Error::from_raw_os_error(24));
+
+        assert_eq!(
+            guard.stats_json(),
+            r#"{"activeConnections":0,"maxConnections":0,"rejectedConnections":0,"acceptExhausted":1,"acceptErrors":2}"#
+        );
+    }
+
+    #[test]
+    fn connections_over_the_cap_are_rejected_until_one_closes() {
+        let guard = AcceptGuard::new(2);
+        let first = guard.admit().unwrap();
+        let _second = guard.admit().unwrap();
+        assert!(guard.admit().is_none());
+        assert!(guard.admit().is_none());
+
+        drop(first);
+        let _third = guard.admit().unwrap();
+        assert_eq!(
+            guard.stats_json(),
+            r#"{"activeConnections":2,"maxConnections":2,"rejectedConnections":2,"acceptExhausted":0,"acceptErrors":0}"#
+        );
+    }
+
+    #[test]
+    fn no_cap_admits_everything() {
+        let guard = AcceptGuard::new(0);
+        let slots: Vec<_> = (0..1000).map(|_| guard.admit().unwrap()).collect();
+        assert_eq!(guard.active.load(Ordering::Relaxed), 1000);
+        drop(slots);
+        assert_eq!(guard.active.load(Ordering::Relaxed), 0);
+    }
+}
@@ -1,22 +1,233 @@
-﻿use http_body_util::BodyExt;
-use hyper::body::Incoming;
-use hyper::{Method, Request, Response};
+﻿use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::{Body, Bytes, Frame, Incoming, SizeHint};
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode, Version};
+use crate::request_id;
+use crate::upstream_pool::{CountingConnector, HostSample, PoolStats};
 use hyper_util::client::legacy::Client;
-use hyperlocal::{UnixConnector, Uri};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use hyperlocal::Uri;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub enum LoadBalancerError {
-    ConnectionFailed,
-    WriteError,
-    ReadError,
-    Timeout,
-    PoolExhausted,
+    /// The backend socket is missing or refused the connection.
+    ConnectFailed {
+        backend: String,
+        source: hyper_util::client::legacy::Error,
+    },
+    /// The backend did not answer within the upstream timeout.
+    Timeout { backend: String },
+    /// The connection broke mid-request or the backend sent something that
+    /// is not HTTP/1.
+    Protocol {
+        backend: String,
+        source: hyper_util::client::legacy::Error,
+    },
+    /// The backend's response body broke off while it was being buffered.
+    ResponseBody(hyper::Error),
     NoHealthyBackends,
+    /// Every candidate backend is at its in-flight cap.
+    AllBackendsBusy,
+    /// An `X-Debug-Backend` header names no backend.
+    UnknownBackend(String),
+}
+
+impl LoadBalancerError {
+    fn from_client(backend: &str, source: hyper_util::client::legacy::Error) -> Self {
+        let backend = backend.to_string();
+        if source.is_connect() {
+            LoadBalancerError::ConnectFailed { backend, source }
+        } else {
+            LoadBalancerError::Protocol { backend, source }
+        }
+    }
+
+    /// The backend that failed, when one was picked.
+    pub fn backend(&self) -> Option<&str> {
+        match self {
+            LoadBalancerError::ConnectFailed { backend, .. }
+            | LoadBalancerError::Timeout { backend }
+            | LoadBalancerError::Protocol { backend, .. } => Some(backend),
+            LoadBalancerError::ResponseBody(_)
+            | LoadBalancerError::NoHealthyBackends
+            | LoadBalancerError::AllBackendsBusy
+            | LoadBalancerError::UnknownBackend(_) => None,
+        }
+    }
+
+    /// Nothing reached a backend for 503s, so the client may safely retry
+    /// them; 502 and 504 mean the request may have been seen.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            LoadBalancerError::ConnectFailed { .. }
+            | LoadBalancerError::NoHealthyBackends
+            | LoadBalancerError::AllBackendsBusy => StatusCode::SERVICE_UNAVAILABLE,
+            LoadBalancerError::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            LoadBalancerError::Protocol { .. } | LoadBalancerError::ResponseBody(_) => StatusCode::BAD_GATEWAY,
+            LoadBalancerError::UnknownBackend(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl std::fmt::Display for LoadBalancerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadBalancerError::ConnectFailed { backend, source } => {
+                write!(f, "Failed to connect to {}: {}", backend, source)
+            }
+            LoadBalancerError::Timeout { backend } => write!(f, "Timed out waiting for {}", backend),
+            LoadBalancerError::Protocol { backend, source } => {
+                write!(f, "Request to {} failed: {}", backend, source)
+            }
+            LoadBalancerError::ResponseBody(e) => write!(f, "Response body failed: {}", e),
+            LoadBalancerError::NoHealthyBackends => write!(f, "No backends available"),
+            LoadBalancerError::AllBackendsBusy => write!(f, "All backends are busy"),
+            LoadBalancerError::UnknownBackend(index) => write!(f, "No backend at index {:?}", index),
+        }
+    }
+}
+
+impl std::error::Error for LoadBalancerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadBalancerError::ConnectFailed { source, .. } | LoadBalancerError::Protocol { source, .. } => {
+                Some(source)
+            }
+            LoadBalancerError::ResponseBody(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Pins a request to the backend at this index of `BACKENDS` (or of the
+/// discovered sockets), bypassing routes and round-robin, when
+/// `LB_DEBUG_BACKEND_HEADER` is set.
+pub const DEBUG_BACKEND_HEADER: HeaderName = HeaderName::from_static("x-debug-backend");
+
+/// Pins requests matching `method` (any method when `None`) and an exact
+/// `path` to a dedicated set of backends.
+pub struct RouteRule {
+    pub method: Option<Method>,
+    pub path: String,
+    pub backends: Vec<String>,
+}
+
+impl RouteRule {
+    /// Parses a single `[METHOD ]PATH=backend[,backend...]` rule.
+    fn parse(rule: &str) -> Option<Self> {
+        let (matcher, backends) = rule.split_once('=')?;
+        let (method, path) = match matcher.trim().split_once(' ') {
+            Some(("*", path)) => (None, path.trim()),
+            Some((method, path)) => (Some(method.parse().ok()?), path.trim()),
+            None => (None, matcher.trim()),
+        };
+
+        let backends: Vec<String> = backends
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        if backends.is_empty() || !path.starts_with('/') {
+            return None;
+        }
+
+        Some(RouteRule {
+            method,
+            path: path.to_string(),
+            backends,
+        })
+    }
+
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        self.path == path && self.method.as_ref().is_none_or(|m| m == method)
+    }
+}
+
+/// Knobs for the hyper HTTP/1 connection builder serving clients.
+#[derive(Clone)]
+pub struct Http1Config {
+    pub keep_alive: bool,
+    pub writev: bool,
+    /// Hyper rejects anything below 8 KiB, so smaller values are raised.
+    pub max_buf_size: usize,
+    pub header_read_timeout: Option<Duration>,
+}
+
+impl Http1Config {
+    pub fn from_env() -> Self {
+        let header_read_timeout_ms: u64 = env_or("LB_HTTP1_HEADER_READ_TIMEOUT_MS", 0);
+
+        Self {
+            keep_alive: env_or("LB_HTTP1_KEEP_ALIVE", true),
+            writev: env_or("LB_HTTP1_WRITEV", true),
+            max_buf_size: env_or("LB_HTTP1_MAX_BUF_SIZE", 16 * 1024usize).max(8 * 1024),
+            header_read_timeout: (header_read_timeout_ms > 0)
+                .then(|| Duration::from_millis(header_read_timeout_ms)),
+        }
+    }
+}
+
+/// How long in-flight requests may take to finish after SIGTERM
+/// (`LB_SHUTDOWN_TIMEOUT_MS`).
+pub fn shutdown_timeout_from_env() -> Duration {
+    Duration::from_millis(env_or("LB_SHUTDOWN_TIMEOUT_MS", 5_000))
+}
+
+pub(crate) fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
 }
 
 pub struct UnixLoadBalancerConfig {
     pub backends: Vec<String>,
+    pub routes: Vec<RouteRule>,
+    /// Idle connections opened per backend at startup. They are subject to
+    /// the pool idle timeout like any other parked connection.
+    pub prewarm_connections: usize,
+    /// Cap on concurrent requests proxied to a single backend, `0` meaning
+    /// unlimited. A backend at its cap is skipped in favour of the next one.
+    pub max_in_flight_per_backend: usize,
+    /// Directory scanned for `*.sock` files which replace `backends` when
+    /// set, so gateway replicas can come and go (`BACKEND_DIR`).
+    pub backend_dir: Option<PathBuf>,
+    pub backend_scan_interval: Duration,
+    /// Time allowed for a backend to send response headers, unlimited when
+    /// unset (`LB_UPSTREAM_TIMEOUT_MS`). The body streams without a limit.
+    pub upstream_timeout: Option<Duration>,
+    /// How long a backend's last non-5xx answer vouches for it, letting
+    /// `/health` be answered without a request (`LB_HEALTH_CACHE_MS`, `0`
+    /// forwards every health check).
+    pub health_cache: Option<Duration>,
+    /// Idle connections the client pool keeps per backend
+    /// (`LB_POOL_MAX_IDLE_PER_HOST`).
+    pub pool_max_idle_per_host: usize,
+    /// How long a parked connection is kept (`LB_POOL_IDLE_TIMEOUT_MS`).
+    pub pool_idle_timeout: Duration,
+    /// Read buffer of upstream connections (`LB_UPSTREAM_HTTP1_MAX_BUF_SIZE`),
+    /// raised to hyper's 8 KiB minimum.
+    pub upstream_max_buf_size: usize,
+    /// Give requests without an `X-Request-Id` a generated one before
+    /// forwarding them (`LB_REQUEST_ID`).
+    pub request_ids: bool,
+    /// Window over which a backend discovered after startup, or answering
+    /// again after failed requests, grows from a tenth to its full share of
+    /// traffic (`LB_SLOW_START_MS`, `0` gives it its share at once).
+    pub slow_start: Option<Duration>,
+    /// Honour [`DEBUG_BACKEND_HEADER`], to reproduce an issue against a
+    /// single gateway through the normal entry point
+    /// (`LB_DEBUG_BACKEND_HEADER`). Off by default, since it lets clients
+    /// defeat the balancing.
+    pub debug_backend_header: bool,
 }
 
 impl UnixLoadBalancerConfig {
@@ -27,75 +238,955 @@ impl UnixLoadBalancerConfig {
                 .split(',')
                 .map(|s| s.to_string())
                 .collect(),
+            routes: Self::parse_routes(&std::env::var("ROUTES").unwrap_or_default()),
+            prewarm_connections: env_or("LB_PREWARM_CONNECTIONS", 0),
+            max_in_flight_per_backend: env_or("LB_BACKEND_MAX_IN_FLIGHT", 0),
+            backend_dir: std::env::var("BACKEND_DIR").ok().map(PathBuf::from),
+            backend_scan_interval: Duration::from_millis(env_or("LB_BACKEND_SCAN_INTERVAL_MS", 1_000)),
+            upstream_timeout: Some(env_or("LB_UPSTREAM_TIMEOUT_MS", 0u64))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            health_cache: Some(env_or("LB_HEALTH_CACHE_MS", 0u64))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            pool_max_idle_per_host: env_or("LB_POOL_MAX_IDLE_PER_HOST", 2048),
+            pool_idle_timeout: Duration::from_millis(env_or("LB_POOL_IDLE_TIMEOUT_MS", 2_000)),
+            upstream_max_buf_size: env_or("LB_UPSTREAM_HTTP1_MAX_BUF_SIZE", 16 * 1024usize).max(8 * 1024),
+            request_ids: env_or("LB_REQUEST_ID", true),
+            slow_start: Some(env_or("LB_SLOW_START_MS", 0u64))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            debug_backend_header: env_or("LB_DEBUG_BACKEND_HEADER", false),
         }
     }
+
+    /// Parses `ROUTES`, a `;`-separated list of rules such as
+    /// `GET /payments-summary=/tmp/gateway1.sock;POST /payments=/tmp/gateway2.sock`.
+    /// Requests that match no rule are balanced across `BACKENDS`.
+    fn parse_routes(routes: &str) -> Vec<RouteRule> {
+        routes
+            .split(';')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .filter_map(|rule| {
+                let parsed = RouteRule::parse(rule);
+                if parsed.is_none() {
+                    tracing::warn!(rule, "Ignoring invalid route rule");
+                }
+                parsed
+            })
+            .collect()
+    }
+}
+
+struct Backend {
+    address: String,
+    in_flight: AtomicUsize,
+    /// When the backend last answered with something other than a 5xx, in
+    /// milliseconds since the balancer started plus one; `0` if never.
+    answered_at_ms: AtomicU64,
+    /// When the backend's slow start began, on the same clock as
+    /// `answered_at_ms`; `0` once it takes its full share.
+    warming_since_ms: AtomicU64,
+    /// Round-robin turns offered to the backend during its slow start.
+    turns: AtomicU64,
+    /// Whether its last request failed without an answer.
+    failing: AtomicBool,
+}
+
+impl Backend {
+    fn new(address: String) -> Self {
+        Self {
+            address,
+            in_flight: AtomicUsize::new(0),
+            answered_at_ms: AtomicU64::new(0),
+            warming_since_ms: AtomicU64::new(0),
+            turns: AtomicU64::new(0),
+            failing: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the backend takes the turn it is offered, which it does for
+    /// `weight` of them, spread evenly.
+    fn takes_turn(&self, weight: f64) -> bool {
+        let turn = self.turns.fetch_add(1, Ordering::Relaxed) as f64;
+        ((turn + 1.0) * weight).floor() > (turn * weight).floor()
+    }
+}
+
+/// Share of traffic a backend starts its slow start with.
+const SLOW_START_MIN_WEIGHT: f64 = 0.1;
+
+/// Holds one of a backend's in-flight slots until dropped.
+struct InFlightGuard(Arc<Backend>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Response body that keeps the backend slot claimed until it is fully
+/// streamed to the client.
+struct GuardedBody {
+    inner: Incoming,
+    _guard: InFlightGuard,
+}
+
+impl Body for GuardedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+struct Route {
+    rule: RouteRule,
+    backends: Vec<Arc<Backend>>,
+    current_index: AtomicUsize,
 }
 
 pub struct UnixLoadBalancer {
     current_index: AtomicUsize,
-    backends: Vec<String>,
-    client: Client<UnixConnector, Incoming>,
-    backend_count: usize,
+    /// Swapped as a whole when discovery finds a different set of sockets;
+    /// requests clone the current list under a short read lock.
+    backends: RwLock<Arc<Vec<Arc<Backend>>>>,
+    routes: Vec<Route>,
+    client: Client<CountingConnector, BoxBody<Bytes, hyper::Error>>,
+    pool_stats: Arc<PoolStats>,
+    pool_max_idle_per_host: usize,
+    pool_idle_timeout: Duration,
+    prewarm_connections: usize,
+    max_in_flight_per_backend: usize,
+    backend_dir: Option<PathBuf>,
+    backend_scan_interval: Duration,
+    upstream_timeout: Option<Duration>,
+    health_cache: Option<Duration>,
+    request_ids: bool,
+    slow_start: Option<Duration>,
+    debug_backend_header: bool,
+    started: Instant,
 }
 
 impl UnixLoadBalancer {
     pub fn new(config: UnixLoadBalancerConfig) -> Self {
-        let connector = UnixConnector;
+        let pool_stats = Arc::new(PoolStats::new());
+        let connector = CountingConnector::new(pool_stats.clone());
         let client = Client::builder(hyper_util::rt::TokioExecutor::new())
-            .pool_max_idle_per_host(2048)
-            .pool_idle_timeout(std::time::Duration::from_secs(2))
-            .http1_max_buf_size(16 * 1024)
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .pool_idle_timeout(config.pool_idle_timeout)
+            .http1_max_buf_size(config.upstream_max_buf_size)
             .http1_writev(true)
             .http1_preserve_header_case(false)
             .http1_title_case_headers(false)
             .pool_timer(hyper_util::rt::TokioTimer::new())
             .build(connector);
 
+        // Routes share counters with `BACKENDS` so a backend's cap holds
+        // across every path that reaches it.
+        let mut known: HashMap<String, Arc<Backend>> = HashMap::new();
+        let mut backend = |address: &String| {
+            known
+                .entry(address.clone())
+                .or_insert_with(|| Arc::new(Backend::new(address.clone())))
+                .clone()
+        };
+
+        if config.debug_backend_header {
+            tracing::warn!("Requests may pick their backend with X-Debug-Backend");
+        }
+
+        let backends = match &config.backend_dir {
+            Some(dir) => scan_backend_dir(dir)
+                .unwrap_or_else(|e| {
+                    tracing::warn!(dir = %dir.display(), error = %e, "Failed to scan backend directory");
+                    Vec::new()
+                })
+                .iter()
+                .map(&mut backend)
+                .collect(),
+            None => config.backends.iter().map(&mut backend).collect(),
+        };
+
         UnixLoadBalancer {
             current_index: AtomicUsize::new(0),
             client,
-            backend_count: config.backends.len(),
-            backends: config.backends,
+            pool_stats,
+            pool_max_idle_per_host: config.pool_max_idle_per_host,
+            pool_idle_timeout: config.pool_idle_timeout,
+            prewarm_connections: config.prewarm_connections,
+            max_in_flight_per_backend: config.max_in_flight_per_backend,
+            backend_dir: config.backend_dir,
+            backend_scan_interval: config.backend_scan_interval,
+            upstream_timeout: config.upstream_timeout,
+            health_cache: config.health_cache,
+            request_ids: config.request_ids,
+            slow_start: config.slow_start,
+            debug_backend_header: config.debug_backend_header,
+            started: Instant::now(),
+            backends: RwLock::new(Arc::new(backends)),
+            routes: config
+                .routes
+                .into_iter()
+                .map(|rule| Route {
+                    backends: rule.backends.iter().map(&mut backend).collect(),
+                    rule,
+                    current_index: AtomicUsize::new(0),
+                })
+                .collect(),
         }
     }
 
+    /// Proxies `req` to a backend, streaming the body both ways as frames
+    /// arrive. A client's `Expect: 100-continue` is answered by the server
+    /// connection once the backend starts reading the body, so it is not
+    /// passed on. Requests without an `X-Request-Id` get one.
     pub async fn forward_request(
         &self,
-        method: Method,
-        original_uri: hyper::Uri,
-        body: Incoming,
-    ) -> Result<Response<Incoming>, LoadBalancerError> {
-        let backend = self.select_backend()?;
+        req: Request<Incoming>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, LoadBalancerError> {
+        let (mut parts, body) = req.into_parts();
+        let slot = match self.debug_backend(&parts.headers) {
+            Some(index) => {
+                let slot = self.select_pinned(index)?;
+                parts.headers.remove(DEBUG_BACKEND_HEADER);
+                slot
+            }
+            None => self.select_backend(&parts.method, parts.uri.path())?,
+        };
+        let backend = slot.0.address.as_str();
 
-        let path_and_query = original_uri
+        let path_and_query = parts
+            .uri
             .path_and_query()
             .map(|pq| pq.as_str())
             .unwrap_or("/");
 
-        let uri = Uri::new(backend, path_and_query);
+        parts.uri = Uri::new(backend, path_and_query).into();
+        parts.version = Version::HTTP_11;
+        parts.extensions.clear();
+        strip_hop_by_hop(&mut parts.headers);
+        if self.request_ids && !parts.headers.contains_key(request_id::HEADER) {
+            parts.headers.insert(request_id::HEADER, request_id::generate());
+        }
+
+        let request = Request::from_parts(parts, body.boxed());
+
+        let pending = self.client.request(request);
+        let response = match self.upstream_timeout {
+            Some(limit) => tokio::time::timeout(limit, pending)
+                .await
+                .map_err(|_| LoadBalancerError::Timeout { backend: backend.to_string() }),
+            None => Ok(pending.await),
+        }
+        .and_then(|answered| answered.map_err(|e| LoadBalancerError::from_client(backend, e)))
+        .inspect_err(|_| slot.0.failing.store(true, Ordering::Relaxed))?;
+
+        if !response.status().is_server_error() {
+            slot.0.answered_at_ms.store(self.elapsed_ms() + 1, Ordering::Relaxed);
+            if slot.0.failing.swap(false, Ordering::Relaxed) {
+                self.start_warming(&slot.0);
+            }
+        }
+
+        Ok(response.map(|inner| BoxBody::new(GuardedBody { inner, _guard: slot })))
+    }
+
+    /// Opens `prewarm_connections` concurrent `HEAD /health` requests per
+    /// backend so the client pool already holds idle connections when the
+    /// first burst of traffic arrives.
+    pub async fn prewarm(&self) {
+        if self.prewarm_connections == 0 {
+            return;
+        }
 
+        let current = self.current_backends();
+        let mut backends: Vec<&str> = current.iter().map(|b| b.address.as_str()).collect();
+        for route in &self.routes {
+            backends.extend(route.backends.iter().map(|b| b.address.as_str()));
+        }
+        backends.sort_unstable();
+        backends.dedup();
+
+        let requests = backends.iter().flat_map(|backend| {
+            (0..self.prewarm_connections).map(move |_| self.warm_connection(backend))
+        });
+        let results = futures_util::future::join_all(requests).await;
+
+        let warmed = results.iter().filter(|ok| **ok).count();
+        tracing::info!(warmed, requested = results.len(), "Pre-warmed backend connections");
+    }
+
+    async fn warm_connection(&self, backend: &str) -> bool {
         let request = Request::builder()
-            .method(method)
-            .uri(uri)
-            .body(body)
-            .map_err(|_| LoadBalancerError::WriteError)?;
-
-        let response = self
-            .client
-            .request(request)
-            .await
-            .map_err(|_| LoadBalancerError::ConnectionFailed)?;
+            .method(Method::HEAD)
+            .uri(Uri::new(backend, "/health"))
+            .body(Empty::new().map_err(|never| match never {}).boxed());
+
+        let Ok(request) = request else {
+            return false;
+        };
+
+        match self.client.request(request).await {
+            Ok(response) => response.into_body().collect().await.is_ok(),
+            Err(e) => {
+                tracing::warn!(backend, error = %e, "Failed to pre-warm backend connection");
+                false
+            }
+        }
+    }
+
+    /// Whether `/health` may be answered locally: the health cache is on
+    /// and some backend answered within it. Until then health checks are
+    /// forwarded, and their answers refresh the cache.
+    pub fn has_recently_healthy_backend(&self) -> bool {
+        let Some(max_age) = self.health_cache else {
+            return false;
+        };
+        let now = self.elapsed_ms() + 1;
+        let max_age = max_age.as_millis() as u64;
+        let answered_within = |backend: &Arc<Backend>| {
+            // Another request may have stored an answer newer than `now`.
+            let answered_at = backend.answered_at_ms.load(Ordering::Relaxed);
+            answered_at != 0 && now.saturating_sub(answered_at) <= max_age
+        };
+
+        self.current_backends().iter().any(answered_within)
+            || self.routes.iter().flat_map(|route| &route.backends).any(answered_within)
+    }
+
+    /// Whether `req` picks its own backend, in which case nothing should be
+    /// answered on a backend's behalf.
+    pub fn is_pinned<B>(&self, req: &Request<B>) -> bool {
+        self.debug_backend(req.headers()).is_some()
+    }
+
+    fn debug_backend<'a>(&self, headers: &'a HeaderMap) -> Option<&'a HeaderValue> {
+        headers.get(DEBUG_BACKEND_HEADER).filter(|_| self.debug_backend_header)
+    }
+
+    /// The backend an `X-Debug-Backend` header names, even while it warms
+    /// up; only its in-flight cap is honoured.
+    fn select_pinned(&self, index: &HeaderValue) -> Result<InFlightGuard, LoadBalancerError> {
+        let backends = self.current_backends();
+        let backend = index
+            .to_str()
+            .ok()
+            .and_then(|index| index.trim().parse::<usize>().ok())
+            .and_then(|index| backends.get(index))
+            .ok_or_else(|| LoadBalancerError::UnknownBackend(String::from_utf8_lossy(index.as_bytes()).into_owned()))?;
+        tracing::debug!(backend = %backend.address, "Request pinned by X-Debug-Backend");
+        self.acquire_slot(backend).ok_or(LoadBalancerError::AllBackendsBusy)
+    }
+
+    /// Backends the balancer spreads unrouted requests over.
+    pub fn backend_count(&self) -> usize {
+        self.current_backends().len()
+    }
 
-        Ok(response)
+    /// Upstream connections per backend next to the pool settings, for
+    /// tuning reuse against the load.
+    pub fn pool_stats_json(&self) -> String {
+        let current = self.current_backends();
+        let mut backends: Vec<&Arc<Backend>> = current.iter().collect();
+        for route in &self.routes {
+            backends.extend(&route.backends);
+        }
+        backends.sort_unstable_by(|a, b| a.address.cmp(&b.address));
+        backends.dedup_by(|a, b| a.address == b.address);
+
+        let samples: Vec<HostSample<'_>> = backends
+            .iter()
+            .map(|backend| HostSample {
+                address: &backend.address,
+                in_flight: backend.in_flight.load(Ordering::Relaxed),
+                weight: self.weight(backend),
+            })
+            .collect();
+        format!(
+            r#"{{"maxIdlePerHost":{},"idleTimeoutMs":{},"backends":{}}}"#,
+            self.pool_max_idle_per_host,
+            self.pool_idle_timeout.as_millis(),
+            self.pool_stats.json(&samples),
+        )
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
     }
 
+    fn current_backends(&self) -> Arc<Vec<Arc<Backend>>> {
+        self.backends.read().unwrap().clone()
+    }
+
+    /// Rescans `BACKEND_DIR` every `backend_scan_interval`, adding sockets
+    /// that appeared and dropping those that disappeared. Backends present
+    /// in both scans keep their in-flight counters. Returns immediately when
+    /// discovery is not configured.
+    pub async fn watch_backend_dir(self: Arc<Self>) {
+        let Some(dir) = self.backend_dir.clone() else {
+            return;
+        };
+
+        let mut interval = tokio::time::interval(self.backend_scan_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            match scan_backend_dir(&dir) {
+                Ok(addresses) => self.replace_backends(addresses),
+                Err(e) => tracing::warn!(dir = %dir.display(), error = %e, "Failed to scan backend directory"),
+            }
+        }
+    }
+
+    fn replace_backends(&self, addresses: Vec<String>) {
+        let current = self.current_backends();
+        if current.iter().map(|b| &b.address).eq(addresses.iter()) {
+            return;
+        }
+
+        let backends: Vec<Arc<Backend>> = addresses
+            .into_iter()
+            .map(|address| {
+                current
+                    .iter()
+                    .find(|b| b.address == address)
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        tracing::warn!(backend = %address, "Discovered backend");
+                        let backend = Arc::new(Backend::new(address));
+                        self.start_warming(&backend);
+                        backend
+                    })
+            })
+            .collect();
+
+        for removed in current.iter().filter(|b| !backends.iter().any(|n| n.address == b.address)) {
+            tracing::warn!(backend = %removed.address, "Backend socket removed");
+        }
+
+        *self.backends.write().unwrap() = Arc::new(backends);
+    }
+
+    /// Puts `backend` in slow start, when it is configured.
+    fn start_warming(&self, backend: &Backend) {
+        let Some(window) = self.slow_start else {
+            return;
+        };
+        backend.turns.store(0, Ordering::Relaxed);
+        backend.warming_since_ms.store(self.elapsed_ms() + 1, Ordering::Relaxed);
+        tracing::warn!(backend = %backend.address, window_ms = window.as_millis() as u64, "Backend slow start began");
+    }
+
+    /// Share of its round-robin turns `backend` takes, growing linearly
+    /// over the slow start window; `1.0` outside of one.
+    fn weight(&self, backend: &Backend) -> f64 {
+        let since = backend.warming_since_ms.load(Ordering::Relaxed);
+        let Some(window) = self.slow_start.filter(|_| since != 0) else {
+            return 1.0;
+        };
+        let warmed = (self.elapsed_ms() + 1).saturating_sub(since) as f64 / window.as_millis() as f64;
+        if warmed >= 1.0 {
+            // A slow start begun meanwhile is left running.
+            if backend
+                .warming_since_ms
+                .compare_exchange(since, 0, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+            {
+                tracing::warn!(backend = %backend.address, "Backend slow start finished");
+            }
+            return 1.0;
+        }
+        warmed.max(SLOW_START_MIN_WEIGHT)
+    }
+
+    /// Picks the next backend in round-robin order for the request, moving
+    /// on to the following ones while the candidate is at its in-flight cap
+    /// or passes its turn during slow start.
     #[inline(always)]
-    fn select_backend(&self) -> Result<&str, LoadBalancerError> {
-        if self.backends.is_empty() {
+    fn select_backend(&self, method: &Method, path: &str) -> Result<InFlightGuard, LoadBalancerError> {
+        let discovered;
+        let (backends, current_index) = match self.routes.iter().find(|r| r.rule.matches(method, path)) {
+            Some(route) => (&route.backends, &route.current_index),
+            None => {
+                discovered = self.current_backends();
+                (&*discovered, &self.current_index)
+            }
+        };
+
+        if backends.is_empty() {
             return Err(LoadBalancerError::NoHealthyBackends);
         }
 
-        let index = self.current_index.fetch_add(1, Ordering::Relaxed) % self.backend_count;
-        Ok(&self.backends[index].as_str())
+        let start = current_index.fetch_add(1, Ordering::Relaxed);
+        let mut passed = None;
+        for offset in 0..backends.len() {
+            let backend = &backends[(start + offset) % backends.len()];
+            let weight = self.weight(backend);
+            if weight < 1.0 && !backend.takes_turn(weight) {
+                passed.get_or_insert(backend);
+                continue;
+            }
+            if let Some(slot) = self.acquire_slot(backend) {
+                return Ok(slot);
+            }
+        }
+
+        // A warming backend still serves when no other one can.
+        passed
+            .and_then(|backend| self.acquire_slot(backend))
+            .ok_or(LoadBalancerError::AllBackendsBusy)
+    }
+
+    fn acquire_slot(&self, backend: &Arc<Backend>) -> Option<InFlightGuard> {
+        let previous = backend.in_flight.fetch_add(1, Ordering::Relaxed);
+        let slot = InFlightGuard(backend.clone());
+
+        if self.max_in_flight_per_backend > 0 && previous >= self.max_in_flight_per_backend {
+            return None;
+        }
+        Some(slot)
+    }
+}
+
+/// Headers that describe the client connection rather than the request;
+/// the backend connection negotiates its own.
+const HOP_BY_HOP: [HeaderName; 7] = [
+    header::CONNECTION,
+    header::EXPECT,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    // `Connection` may name further per-connection headers.
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect();
+    for name in listed.iter().chain(&HOP_BY_HOP) {
+        headers.remove(name);
+    }
+    headers.remove("keep-alive");
+}
+
+/// Sorted paths of the unix sockets named `*.sock` in `dir`.
+fn scan_backend_dir(dir: &Path) -> std::io::Result<Vec<String>> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let mut sockets = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "sock") && entry.file_type()?.is_socket() {
+            sockets.push(path.to_string_lossy().into_owned());
+        }
+    }
+
+    sockets.sort_unstable();
+    Ok(sockets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Full;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream, UnixListener};
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    const WAIT: Duration = Duration::from_secs(2);
+
+    /// Backend that reports every body chunk as it arrives and answers with
+    /// the whole body plus the `Expect` and `Content-Type` headers it saw.
+    async fn backend(name: &str) -> (String, mpsc::UnboundedReceiver<Bytes>) {
+        let path = std::env::temp_dir().join(format!("lb-test-{}-{}.sock", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (chunks, received) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let chunks = chunks.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let chunks = chunks.clone();
+                    async move {
+                        let header = |name| {
+                            req.headers()
+                                .get(name)
+                                .map(|v| v.to_str().unwrap().to_string())
+                                .unwrap_or_else(|| "none".to_string())
+                        };
+                        let (expect, content_type) = (header(header::EXPECT), header(header::CONTENT_TYPE));
+                        let request_id = header(request_id::HEADER);
+
+                        let mut body = req.into_body();
+                        let mut whole = Vec::new();
+                        while let Some(frame) = body.frame().await {
+                            if let Ok(data) = frame?.into_data() {
+                                whole.extend_from_slice(&data);
+                                let _ = chunks.send(data);
+                            }
+                        }
+
+                        Ok::<_, hyper::Error>(
+                            Response::builder()
+                                .header("x-expect", expect)
+                                .header("x-content-type", content_type)
+                                .header("x-seen-request-id", request_id)
+                                .body(Full::new(Bytes::from(whole)))
+                                .unwrap(),
+                        )
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        (path.to_string_lossy().into_owned(), received)
+    }
+
+    /// Unix socket whose connections are handed to `serve` instead of an
+    /// HTTP server, for backends that misbehave.
+    async fn raw_backend<F, Fut>(name: &str, serve: F) -> String
+    where
+        F: Fn(tokio::net::UnixStream) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let path = std::env::temp_dir().join(format!("lb-test-{}-{}.sock", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream));
+            }
+        });
+
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Serves the balancer on an ephemeral TCP port, as `main` does,
+    /// answering failed requests with [`LoadBalancerError::status`].
+    async fn proxy(backend: String) -> std::net::SocketAddr {
+        proxy_with_timeout(backend, None).await
+    }
+
+    async fn proxy_with_timeout(backend: String, upstream_timeout: Option<Duration>) -> std::net::SocketAddr {
+        serve(Arc::new(UnixLoadBalancer::new(UnixLoadBalancerConfig {
+            upstream_timeout,
+            ..config(backend)
+        })))
+        .await
+    }
+
+    fn config(backend: String) -> UnixLoadBalancerConfig {
+        UnixLoadBalancerConfig {
+            backends: vec![backend],
+            routes: Vec::new(),
+            prewarm_connections: 0,
+            max_in_flight_per_backend: 0,
+            backend_dir: None,
+            backend_scan_interval: Duration::from_secs(1),
+            upstream_timeout: None,
+            health_cache: None,
+            pool_max_idle_per_host: 2048,
+            pool_idle_timeout: Duration::from_secs(2),
+            upstream_max_buf_size: 16 * 1024,
+            request_ids: true,
+            slow_start: None,
+            debug_backend_header: false,
+        }
+    }
+
+    async fn serve(lb: Arc<UnixLoadBalancer>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let lb = lb.clone();
+                let service = service_fn(move |req| {
+                    let lb = lb.clone();
+                    async move {
+                        Ok::<_, hyper::Error>(lb.forward_request(req).await.unwrap_or_else(|e| {
+                            Response::builder()
+                                .status(e.status())
+                                .body(Empty::new().map_err(|never| match never {}).boxed())
+                                .unwrap()
+                        }))
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        addr
+    }
+
+    /// Reads from `client` until the accumulated response contains `needle`.
+    async fn read_until(client: &mut TcpStream, response: &mut String, needle: &str) {
+        let mut buf = [0u8; 1024];
+        timeout(WAIT, async {
+            while !response.contains(needle) {
+                let n = client.read(&mut buf).await.unwrap();
+                assert!(n > 0, "connection closed before {:?}, got {:?}", needle, response);
+                response.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("no {:?} in {:?}", needle, response));
+    }
+
+    #[tokio::test]
+    async fn streams_chunked_bodies_without_buffering() {
+        let (socket, mut chunks) = backend("chunked").await;
+        let mut client = TcpStream::connect(proxy(socket).await).await.unwrap();
+
+        client
+            .write_all(b"POST /payments HTTP/1.1\r\nHost: lb\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n")
+            .await
+            .unwrap();
+
+        // The first chunk reaches the backend while the client still holds
+        // the rest of the body.
+        let first = timeout(WAIT, chunks.recv()).await.expect("first chunk was held back");
+        assert_eq!(first.unwrap(), Bytes::from_static(b"hello"));
+
+        client.write_all(b"6\r\n world\r\n0\r\n\r\n").await.unwrap();
+
+        let mut response = String::new();
+        read_until(&mut client, &mut response, "hello world").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn answers_expect_continue_and_forwards_the_body() {
+        let (socket, _chunks) = backend("expect").await;
+        let mut client = TcpStream::connect(proxy(socket).await).await.unwrap();
+
+        client
+            .write_all(
+                b"POST /payments HTTP/1.1\r\nHost: lb\r\nContent-Type: application/json\r\n\
+                  Content-Length: 11\r\nExpect: 100-continue\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        read_until(&mut client, &mut response, "HTTP/1.1 100 Continue\r\n\r\n").await;
+
+        client.write_all(b"hello world").await.unwrap();
+        read_until(&mut client, &mut response, "hello world").await;
+
+        assert!(response.contains("x-expect: none"), "{}", response);
+        assert!(response.contains("x-content-type: application/json"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn forwards_request_ids_generating_missing_ones() {
+        let (socket, _chunks) = backend("request-id").await;
+        let mut client = TcpStream::connect(proxy(socket).await).await.unwrap();
+
+        client.write_all(b"GET /payments-summary HTTP/1.1\r\nHost: lb\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        read_until(&mut client, &mut response, "\r\n\r\n").await;
+        let generated = response
+            .lines()
+            .find_map(|line| line.strip_prefix("x-seen-request-id: "))
+            .unwrap_or_else(|| panic!("no request id in {:?}", response));
+        assert_eq!(generated.len(), 26);
+
+        client
+            .write_all(b"GET /payments-summary HTTP/1.1\r\nHost: lb\r\nX-Request-Id: client-1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        read_until(&mut client, &mut response, "x-seen-request-id: client-1\r\n").await;
+    }
+
+    async fn status_of(addr: std::net::SocketAddr) -> String {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET /payments-summary HTTP/1.1\r\nHost: lb\r\n\r\n").await.unwrap();
+
+        let mut response = String::new();
+        read_until(&mut client, &mut response, "\r\n").await;
+        response.lines().next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn missing_backend_is_unavailable() {
+        let socket = std::env::temp_dir().join(format!("lb-test-{}-missing.sock", std::process::id()));
+        let addr = proxy(socket.to_string_lossy().into_owned()).await;
+        assert_eq!(status_of(addr).await, "HTTP/1.1 503 Service Unavailable");
+    }
+
+    #[tokio::test]
+    async fn silent_backend_times_out() {
+        let socket = raw_backend("silent", |stream| async move {
+            tokio::time::sleep(WAIT).await;
+            drop(stream);
+        })
+        .await;
+        let addr = proxy_with_timeout(socket, Some(Duration::from_millis(50))).await;
+        assert_eq!(status_of(addr).await, "HTTP/1.1 504 Gateway Timeout");
+    }
+
+    #[tokio::test]
+    async fn garbled_backend_is_a_bad_gateway() {
+        let socket = raw_backend("garbled", |mut stream| async move {
+            let _ = stream.write_all(b"not http\r\n\r\n").await;
+        })
+        .await;
+        assert_eq!(status_of(proxy(socket).await).await, "HTTP/1.1 502 Bad Gateway");
+    }
+
+    #[tokio::test]
+    async fn answered_requests_vouch_for_backend_health() {
+        let (socket, _chunks) = backend("health").await;
+        let lb = Arc::new(UnixLoadBalancer::new(UnixLoadBalancerConfig {
+            health_cache: Some(Duration::from_millis(100)),
+            ..config(socket)
+        }));
+        assert!(!lb.has_recently_healthy_backend());
+
+        assert_eq!(status_of(serve(lb.clone()).await).await, "HTTP/1.1 200 OK");
+        assert!(lb.has_recently_healthy_backend());
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(!lb.has_recently_healthy_backend());
+    }
+
+    #[tokio::test]
+    async fn pool_stats_count_reused_connections() {
+        let (socket, _chunks) = backend("pool").await;
+        let lb = Arc::new(UnixLoadBalancer::new(config(socket.clone())));
+        let addr = serve(lb.clone()).await;
+
+        assert_eq!(status_of(addr).await, "HTTP/1.1 200 OK");
+        assert_eq!(status_of(addr).await, "HTTP/1.1 200 OK");
+
+        // Both requests went over one upstream connection, now parked.
+        let stats = lb.pool_stats_json();
+        let expected = format!(r#""{}":{{"open":1,"idle":1,"created":1,"#, socket);
+        assert!(stats.contains(&expected), "{}", stats);
+    }
+
+    #[tokio::test]
+    async fn warming_backends_take_a_growing_share() {
+        let lb = UnixLoadBalancer::new(UnixLoadBalancerConfig {
+            backends: vec!["/tmp/lb-test-warm.sock".to_string(), "/tmp/lb-test-cold.sock".to_string()],
+            slow_start: Some(Duration::from_millis(200)),
+            ..config(String::new())
+        });
+        let picks = |lb: &UnixLoadBalancer| {
+            (0..100)
+                .filter(|_| lb.select_backend(&Method::GET, "/").unwrap().0.address.contains("warm"))
+                .count()
+        };
+        assert_eq!(picks(&lb), 50);
+
+        let warm = lb.current_backends()[0].clone();
+        lb.start_warming(&warm);
+        assert_eq!(picks(&lb), 5);
+
+        tokio::time::sleep(Duration::from_millis(210)).await;
+        assert_eq!(picks(&lb), 50);
+        assert_eq!(warm.warming_since_ms.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn debug_header_pins_a_backend_only_when_enabled() {
+        let backends = vec!["/tmp/lb-test-first.sock".to_string(), "/tmp/lb-test-second.sock".to_string()];
+        let pinned = |index| {
+            let mut request = Request::new(());
+            request.headers_mut().insert(DEBUG_BACKEND_HEADER, HeaderValue::from_static(index));
+            request
+        };
+
+        let lb = UnixLoadBalancer::new(UnixLoadBalancerConfig {
+            backends: backends.clone(),
+            debug_backend_header: true,
+            ..config(String::new())
+        });
+        assert!(lb.is_pinned(&pinned("1")));
+        for _ in 0..4 {
+            assert_eq!(lb.select_pinned(&HeaderValue::from_static("1")).unwrap().0.address, backends[1]);
+        }
+        for unknown in ["2", "first", ""] {
+            let error = lb.select_pinned(&HeaderValue::from_static(unknown)).err().unwrap();
+            assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let lb = UnixLoadBalancer::new(UnixLoadBalancerConfig { backends, ..config(String::new()) });
+        assert!(!lb.is_pinned(&pinned("1")));
+    }
+
+    #[test]
+    fn backend_dir_scan_lists_only_sockets_named_sock() {
+        let dir = std::env::temp_dir().join(format!("lb-test-scan-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let _b = std::os::unix::net::UnixListener::bind(dir.join("b.sock")).unwrap();
+        let _a = std::os::unix::net::UnixListener::bind(dir.join("a.sock")).unwrap();
+        let _other = std::os::unix::net::UnixListener::bind(dir.join("admin")).unwrap();
+        std::fs::write(dir.join("stale.sock"), b"").unwrap();
+
+        let found = scan_backend_dir(&dir).unwrap();
+        let names: Vec<_> = found.iter().map(|path| Path::new(path).file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["a.sock", "b.sock"]);
+        assert!(scan_backend_dir(&dir.join("missing")).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn rescans_keep_the_backends_that_stay() {
+        let address = |name: &str| format!("/tmp/lb-test-{}.sock", name);
+        let lb = UnixLoadBalancer::new(UnixLoadBalancerConfig {
+            backends: vec![address("a"), address("b")],
+            ..config(String::new())
+        });
+        let b = lb.current_backends()[1].clone();
+        b.in_flight.fetch_add(3, Ordering::Relaxed);
+
+        lb.replace_backends(vec![address("b"), address("c")]);
+        let backends = lb.current_backends();
+        let addresses: Vec<_> = backends.iter().map(|backend| backend.address.clone()).collect();
+        assert_eq!(addresses, [address("b"), address("c")]);
+        assert!(Arc::ptr_eq(&backends[0], &b));
+        assert_eq!(backends[0].in_flight.load(Ordering::Relaxed), 3);
+        assert_eq!(backends[1].in_flight.load(Ordering::Relaxed), 0);
+
+        // An unchanged scan leaves the list as it is.
+        lb.replace_backends(vec![address("b"), address("c")]);
+        assert!(Arc::ptr_eq(&lb.current_backends(), &backends));
     }
 }
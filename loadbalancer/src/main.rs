@@ -1,107 +1,243 @@
-﻿mod load_balancer;
+﻿mod listener;
+mod load_balancer;
+mod request_id;
+mod summary_cache;
+mod upstream_pool;
 
-use std::net::SocketAddr;
 use std::sync::Arc;
 
-use crate::load_balancer::{UnixLoadBalancer, UnixLoadBalancerConfig};
+use common::{build_info, logging, startup};
+use crate::listener::{AcceptGuard, ListenConfig, Listeners};
+use crate::load_balancer::{Http1Config, UnixLoadBalancer, UnixLoadBalancerConfig};
+use crate::summary_cache::SummaryCache;
 use http_body_util::combinators::BoxBody;
-use http_body_util::BodyExt;
+use http_body_util::{BodyExt, Full};
 use hyper::body::Incoming;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Request, Response};
-use hyper_util::rt::TokioIo;
-use tokio::net::TcpSocket;
-
-use tracing::Level;
-use tracing_subscriber::FmtSubscriber;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use hyper_util::server::graceful::GracefulShutdown;
 
 use hyper::body::{Bytes};
 
-enum ProxyResponse {
-    Success(Response<Incoming>),
-    Error,
+/// Hit and miss counters of the summary cache, answered by the LB itself.
+const CACHE_STATS_PATH: &str = "/_lb/cache-stats";
+/// The LB's own build info; `/internal/version` goes to the gateways.
+const VERSION_PATH: &str = "/_lb/version";
+/// Connection and accept counters.
+const STATS_PATH: &str = "/_lb/stats";
+/// Upstream connections per backend and the client pool settings.
+const POOL_STATS_PATH: &str = "/_lb/pool-stats";
+/// The LB's own liveness, for container healthchecks; never forwarded.
+const LB_HEALTH_PATH: &str = "/lb-health";
+/// The gateways' health check, answered from cached backend health when
+/// `LB_HEALTH_CACHE_MS` is set.
+const HEALTH_PATH: &str = "/health";
+const HEALTH_BODY: &[u8] = b"OK";
+/// The LB's startup phase, 503 until it is `ready`; never forwarded.
+const READYZ_PATH: &str = "/readyz";
+
+fn json_response(body: String) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = Response::new(Full::new(Bytes::from(body)).map_err(|never| match never {}).boxed());
+    response
+        .headers_mut()
+        .insert(hyper::header::CONTENT_TYPE, hyper::header::HeaderValue::from_static("application/json"));
+    response
 }
 
-impl From<ProxyResponse> for Response<BoxBody<Bytes, hyper::Error>> {
-    fn from(resp: ProxyResponse) -> Self {
-        match resp {
-            ProxyResponse::Success(r) => r.map(|body| BoxBody::new(body)),
-            ProxyResponse::Error => Response::builder()
-                .status(502)
-                .body(BoxBody::new(
-                    http_body_util::Empty::new().map_err(|never| match never {}),
-                ))
-                .unwrap(),
-        }
+/// `OK` for a `GET`, headers only for a `HEAD`; `source` tells whether the
+/// LB answered for itself or for its backends.
+fn health_response(status: StatusCode, head: bool, source: &'static str) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let body = if head || !status.is_success() { Bytes::new() } else { Bytes::from_static(HEALTH_BODY) };
+    let mut response = Response::new(Full::new(body).map_err(|never| match never {}).boxed());
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(hyper::header::CONTENT_TYPE, hyper::header::HeaderValue::from_static("text/plain"));
+    if head && status.is_success() {
+        headers.insert(hyper::header::CONTENT_LENGTH, hyper::header::HeaderValue::from(HEALTH_BODY.len()));
     }
+    headers.insert("x-lb-health", hyper::header::HeaderValue::from_static(source));
+    response
+}
+
+fn empty_response(status: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(status)
+        .body(BoxBody::new(
+            http_body_util::Empty::new().map_err(|never| match never {}),
+        ))
+        .unwrap()
 }
 
 async fn proxy_service(
     balancer: Arc<UnixLoadBalancer>,
+    cache: Option<Arc<SummaryCache>>,
+    accept_guard: Arc<AcceptGuard>,
     req: Request<Incoming>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-    let method = req.method().clone();
-    let uri = req.uri().clone();
+    if req.method() == Method::GET {
+        match req.uri().path() {
+            VERSION_PATH => return Ok(json_response(build_info!().json("loadbalancer"))),
+            STATS_PATH => return Ok(json_response(accept_guard.stats_json())),
+            POOL_STATS_PATH => return Ok(json_response(balancer.pool_stats_json())),
+            _ => {}
+        }
+    }
+
+    if matches!(*req.method(), Method::GET | Method::HEAD) {
+        let head = req.method() == Method::HEAD;
+        match req.uri().path() {
+            LB_HEALTH_PATH => {
+                let status = if balancer.backend_count() > 0 { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+                return Ok(health_response(status, head, "lb"));
+            }
+            READYZ_PATH => {
+                let phase = startup::current();
+                let status = if phase == startup::Phase::Ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+                let body = if head { Bytes::new() } else { Bytes::from(format!("{}\n", phase.as_str())) };
+                let mut response = Response::new(Full::new(body).map_err(|never| match never {}).boxed());
+                *response.status_mut() = status;
+                return Ok(response);
+            }
+            HEALTH_PATH if balancer.has_recently_healthy_backend() && !balancer.is_pinned(&req) => {
+                return Ok(health_response(StatusCode::OK, head, "cached"));
+            }
+            _ => {}
+        }
+    }
 
-    let response = match balancer.forward_request(method, uri, req.into_body()).await {
-        Ok(resp) => ProxyResponse::Success(resp),
-        Err(_) => ProxyResponse::Error,
+    // `OPTIONS *` asks about the server itself, which is the LB.
+    if req.method() == Method::OPTIONS && req.uri() == "*" {
+        let mut response = empty_response(StatusCode::NO_CONTENT);
+        response
+            .headers_mut()
+            .insert(hyper::header::ALLOW, hyper::header::HeaderValue::from_static("GET, HEAD, POST, OPTIONS"));
+        return Ok(response);
+    }
+
+    let result = match &cache {
+        Some(cache) if req.method() == Method::GET && req.uri().path() == CACHE_STATS_PATH => {
+            return Ok(json_response(cache.stats_json()));
+        }
+        Some(cache) if SummaryCache::is_cacheable(&req) && !balancer.is_pinned(&req) => {
+            cache.get_or_fetch(req, |req| balancer.forward_request(req)).await
+        }
+        _ => balancer.forward_request(req).await,
     };
 
-    Ok(response.into())
+    match result {
+        Ok(resp) => Ok(resp),
+        Err(e) => {
+            // Saturation is expected under load and would flood the log.
+            if e.backend().is_some() {
+                tracing::warn!(backend = e.backend(), status = e.status().as_u16(), error = %e, "Proxy request failed");
+            }
+            Ok(empty_response(e.status()))
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::WARN)
-        .with_ansi(false)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).unwrap();
+    build_info::mark_started();
+    logging::init("warn");
+    tokio::spawn(logging::watch_sigusr2());
 
     let balancer_config = UnixLoadBalancerConfig::from_env();
     let lb = Arc::new(UnixLoadBalancer::new(balancer_config));
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], 9999));
-
-    let socket = TcpSocket::new_v4().unwrap();
-    socket.set_reuseaddr(true).unwrap();
-    socket.set_reuseport(true).unwrap();
-    socket.set_recv_buffer_size(16 * 1024).unwrap();
-    socket.set_send_buffer_size(16 * 1024).unwrap();
-
-    socket.bind(addr).unwrap();
-    let listener = socket.listen(16 * 1024).unwrap();
+    let summary_cache = SummaryCache::from_env().map(Arc::new);
+    // Nothing to connect to before the backends.
+    startup::enter(startup::Phase::Warming);
+    lb.prewarm().await;
+    tokio::spawn(lb.clone().watch_backend_dir());
+    let http1_config = Http1Config::from_env();
+    let shutdown_timeout = load_balancer::shutdown_timeout_from_env();
+
+    let listen_config = ListenConfig::from_env().unwrap();
+    let listeners = Listeners::bind(&listen_config).unwrap();
+    let accept_guard = Arc::new(AcceptGuard::from_env());
+    tracing::info!(addrs = ?listeners.local_addrs(), "Listening");
+    startup::enter(startup::Phase::Ready);
+
+    // Connections are watched so that on shutdown idle keep-alive sockets are
+    // closed and in-flight responses go out with `Connection: close`.
+    let graceful = GracefulShutdown::new();
+    let mut shutdown = std::pin::pin!(shutdown_signal());
 
     loop {
-        let (tcp_stream, _) = listener.accept().await.unwrap();
+        let accepted = tokio::select! {
+            conn = listeners.accept() => conn,
+            _ = &mut shutdown => break,
+        };
+        let tcp_stream = match accepted {
+            Ok((tcp_stream, _)) => tcp_stream,
+            Err(e) => {
+                let backoff = accept_guard.on_error(&e);
+                if !backoff.is_zero() {
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {}
+                        _ = &mut shutdown => break,
+                    }
+                }
+                continue;
+            }
+        };
+        let Some(slot) = accept_guard.admit() else {
+            continue;
+        };
 
-        tcp_stream.set_nodelay(true).unwrap();
-        tcp_stream.set_ttl(64).unwrap();
+        // The client may already be gone; serving will notice.
+        let _ = tcp_stream.set_nodelay(true);
+        let _ = tcp_stream.set_ttl(64);
 
         let lb_clone = lb.clone();
+        let accept_guard = accept_guard.clone();
+        let summary_cache = summary_cache.clone();
+        let http1_config = http1_config.clone();
+        let watcher = graceful.watcher();
 
         tokio::spawn(async move {
+            let _slot = slot;
             let io = TokioIo::new(tcp_stream);
 
             let service = service_fn(move |req| {
                 let balancer = lb_clone.clone();
-                proxy_service(balancer, req)
+                proxy_service(balancer, summary_cache.clone(), accept_guard.clone(), req)
             });
 
             let conn = http1::Builder::new()
-                .keep_alive(true)
+                .timer(TokioTimer::new())
+                .keep_alive(http1_config.keep_alive)
                 .half_close(false)
-                .writev(true)
-                .max_buf_size(16 * 1024)
+                .writev(http1_config.writev)
+                .max_buf_size(http1_config.max_buf_size)
+                .header_read_timeout(http1_config.header_read_timeout)
                 .preserve_header_case(false)
                 .title_case_headers(false)
                 .serve_connection(io, service);
 
-            if let Err(err) = conn.await {
-                eprintln!("Error serving connection: {:?}", err);
+            if let Err(err) = watcher.watch(conn).await {
+                tracing::warn!(error = ?err, "Error serving connection");
             }
         });
     }
+
+    drop(listeners);
+    tracing::warn!(connections = graceful.count(), "Shutting down, draining connections");
+
+    if tokio::time::timeout(shutdown_timeout, graceful.shutdown()).await.is_err() {
+        tracing::warn!("Drain timeout elapsed, dropping remaining connections");
+    }
+}
+
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
 }
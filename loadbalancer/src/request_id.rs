@@ -0,0 +1,68 @@
+//! `X-Request-Id` for requests that arrive without one, so a payment can be
+//! followed through the gateway and worker logs. Ids are ULIDs: 48 bits of
+//! unix milliseconds then 80 random bits, in Crockford base32, so they sort
+//! by creation time.
+
+use hyper::header::{HeaderName, HeaderValue};
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const RANDOM_BITS: u32 = 80;
+
+thread_local! {
+    static SEED: RandomState = RandomState::new();
+    static COUNTER: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A new id for the current time.
+pub fn generate() -> HeaderValue {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    let ulid = encode(millis, random());
+    HeaderValue::from_bytes(&ulid).expect("base32 is a valid header value")
+}
+
+/// Not cryptographic: ids only have to be unique, not unguessable.
+fn random() -> u128 {
+    let n = COUNTER.with(|counter| {
+        let n = counter.get().wrapping_add(1);
+        counter.set(n);
+        n
+    });
+    SEED.with(|seed| (u128::from(seed.hash_one(n)) << 64) | u128::from(seed.hash_one(!n)))
+}
+
+fn encode(millis: u64, random: u128) -> [u8; 26] {
+    let value = (u128::from(millis) << RANDOM_BITS) | (random & ((1 << RANDOM_BITS) - 1));
+    let mut ulid = [0u8; 26];
+    for (i, c) in ulid.iter_mut().enumerate() {
+        let shift = 125 - 5 * i as u32;
+        *c = ALPHABET[((value >> shift) & 0x1f) as usize];
+    }
+    ulid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_time_first_in_crockford_base32() {
+        assert_eq!(&encode(1_469_918_176_385, 0), b"01ARYZ6S410000000000000000");
+        assert_eq!(&encode(0, u128::MAX)[10..], b"ZZZZZZZZZZZZZZZZ");
+    }
+
+    #[test]
+    fn generated_ids_differ() {
+        let (a, b) = (generate(), generate());
+        assert_eq!(a.len(), 26);
+        assert_ne!(a, b);
+    }
+}
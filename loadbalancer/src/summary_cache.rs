@@ -0,0 +1,266 @@
+use crate::load_balancer::{env_or, LoadBalancerError};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+pub const SUMMARY_PATH: &str = "/payments-summary";
+
+/// Entries kept before expired ones are swept out, so distinct `from`/`to`
+/// windows cannot grow the map without bound.
+const SWEEP_THRESHOLD: usize = 1024;
+
+/// Response header telling whether a summary came from the cache.
+const CACHE_STATUS: &str = "x-lb-cache";
+
+/// Micro-cache for `GET /payments-summary`, keyed on the full URI.
+///
+/// A burst of identical polls costs one backend request: the first one
+/// fetches while the others wait for its result, which is then reused for
+/// the TTL. Only 200s are cached. `Cache-Control: no-cache` on the request
+/// skips the cache.
+pub struct SummaryCache {
+    ttl: Duration,
+    slots: Mutex<HashMap<String, Slot>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bypassed: AtomicU64,
+}
+
+struct Slot {
+    created: Instant,
+    response: Arc<OnceCell<CachedResponse>>,
+}
+
+/// Why a fetch left the cache empty.
+enum Uncached {
+    NotOk(Response<BoxBody<Bytes, hyper::Error>>),
+    Failed(LoadBalancerError),
+}
+
+struct CachedResponse {
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl SummaryCache {
+    /// `None` unless `LB_SUMMARY_CACHE_TTL_MS` is set; 100-250ms keeps
+    /// summaries close to live while absorbing bursts.
+    pub fn from_env() -> Option<Self> {
+        let ttl_ms: u64 = env_or("LB_SUMMARY_CACHE_TTL_MS", 0);
+        (ttl_ms > 0).then(|| Self::new(Duration::from_millis(ttl_ms)))
+    }
+
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            slots: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            bypassed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_cacheable<B>(req: &Request<B>) -> bool {
+        req.method() == Method::GET && req.uri().path() == SUMMARY_PATH
+    }
+
+    /// Answers `req` from the cache, calling `fetch` when there is no fresh
+    /// entry. Failed and non-200 responses are handed back as they are.
+    pub async fn get_or_fetch<B, F, Fut>(
+        &self,
+        req: Request<B>,
+        fetch: F,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, LoadBalancerError>
+    where
+        F: FnOnce(Request<B>) -> Fut,
+        Fut: Future<Output = Result<Response<BoxBody<Bytes, hyper::Error>>, LoadBalancerError>>,
+    {
+        let bypass = req
+            .headers()
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .any(|value| value.to_str().is_ok_and(|value| value.contains("no-cache")));
+        if bypass {
+            self.bypassed.fetch_add(1, Ordering::Relaxed);
+            return fetch(req).await;
+        }
+
+        let key = req.uri().path_and_query().map_or(SUMMARY_PATH, |pq| pq.as_str()).to_string();
+        let cell = self.slot(key);
+
+        // Set when this request ran the fetch rather than reusing another's.
+        let mut fetched = false;
+        let fetched_ref = &mut fetched;
+        let cached = cell
+            .get_or_try_init(|| async move {
+                *fetched_ref = true;
+                let response = fetch(req).await.map_err(Uncached::Failed)?;
+                if response.status() != StatusCode::OK {
+                    return Err(Uncached::NotOk(response));
+                }
+
+                let (mut parts, body) = response.into_parts();
+                let body = match body.collect().await {
+                    Ok(body) => body.to_bytes(),
+                    Err(e) => return Err(Uncached::Failed(LoadBalancerError::ResponseBody(e))),
+                };
+                parts.headers.remove(header::TRANSFER_ENCODING);
+                parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+                Ok(CachedResponse { headers: parts.headers, body })
+            })
+            .await;
+
+        let counter = if fetched { &self.misses } else { &self.hits };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        match cached {
+            Ok(cached) => Ok(cached.to_response(if fetched { "miss" } else { "hit" })),
+            Err(Uncached::NotOk(response)) => Ok(response),
+            Err(Uncached::Failed(e)) => Err(e),
+        }
+    }
+
+    /// The cell for `key`, replaced by an empty one once older than the TTL.
+    fn slot(&self, key: String) -> Arc<OnceCell<CachedResponse>> {
+        let mut slots = self.slots.lock().unwrap();
+        if slots.len() >= SWEEP_THRESHOLD {
+            slots.retain(|_, slot| slot.created.elapsed() < self.ttl);
+        }
+
+        let slot = slots.entry(key).or_insert_with(|| Slot {
+            created: Instant::now(),
+            response: Arc::new(OnceCell::new()),
+        });
+        if slot.created.elapsed() >= self.ttl {
+            *slot = Slot {
+                created: Instant::now(),
+                response: Arc::new(OnceCell::new()),
+            };
+        }
+        slot.response.clone()
+    }
+
+    /// `{"hits":…,"misses":…,"bypassed":…}` for `GET /_lb/cache-stats`.
+    pub fn stats_json(&self) -> String {
+        format!(
+            r#"{{"hits":{},"misses":{},"bypassed":{}}}"#,
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+            self.bypassed.load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl CachedResponse {
+    fn to_response(&self, cache_status: &'static str) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut response = Response::new(Full::new(self.body.clone()).map_err(|never| match never {}).boxed());
+        *response.headers_mut() = self.headers.clone();
+        response
+            .headers_mut()
+            .insert(CACHE_STATUS, HeaderValue::from_static(cache_status));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn get(uri: &str) -> Request<()> {
+        Request::get(uri).body(()).unwrap()
+    }
+
+    /// Answers `status` with a body numbering the calls, counting them.
+    async fn backend(calls: &AtomicUsize, status: StatusCode) -> Result<Response<BoxBody<Bytes, hyper::Error>>, LoadBalancerError> {
+        let call = calls.fetch_add(1, Ordering::Relaxed) + 1;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let mut response = Response::new(Full::new(Bytes::from(call.to_string())).map_err(|never| match never {}).boxed());
+        *response.status_mut() = status;
+        Ok(response)
+    }
+
+    async fn body(response: Response<BoxBody<Bytes, hyper::Error>>) -> String {
+        String::from_utf8(response.into_body().collect().await.unwrap().to_bytes().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_burst_of_polls_costs_one_fetch() {
+        let cache = SummaryCache::new(Duration::from_secs(60));
+        let calls = AtomicUsize::new(0);
+        let poll = || cache.get_or_fetch(get("/payments-summary?from=a"), |_| backend(&calls, StatusCode::OK));
+
+        let (first, second, third) = tokio::join!(poll(), poll(), poll());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        let statuses: Vec<_> = [&first, &second, &third]
+            .iter()
+            .map(|r| r.as_ref().unwrap().headers()[CACHE_STATUS].to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(statuses.iter().filter(|s| *s == "miss").count(), 1);
+        assert_eq!(first.as_ref().unwrap().headers()[header::CONTENT_LENGTH], "1");
+        for response in [first, second, third] {
+            assert_eq!(body(response.unwrap()).await, "1");
+        }
+
+        // Another window is another entry.
+        let other = cache.get_or_fetch(get("/payments-summary?from=b"), |_| backend(&calls, StatusCode::OK)).await;
+        assert_eq!(body(other.unwrap()).await, "2");
+        assert_eq!(cache.stats_json(), r#"{"hits":2,"misses":2,"bypassed":0}"#);
+    }
+
+    #[tokio::test]
+    async fn entries_expire_after_the_ttl() {
+        let cache = SummaryCache::new(Duration::from_millis(30));
+        let calls = AtomicUsize::new(0);
+        let poll = || cache.get_or_fetch(get("/payments-summary"), |_| backend(&calls, StatusCode::OK));
+
+        assert_eq!(body(poll().await.unwrap()).await, "1");
+        assert_eq!(body(poll().await.unwrap()).await, "1");
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(body(poll().await.unwrap()).await, "2");
+    }
+
+    #[tokio::test]
+    async fn only_successful_summaries_are_cached() {
+        let cache = SummaryCache::new(Duration::from_secs(60));
+        let calls = AtomicUsize::new(0);
+
+        let failed = cache
+            .get_or_fetch(get("/payments-summary"), |_| backend(&calls, StatusCode::SERVICE_UNAVAILABLE))
+            .await
+            .unwrap();
+        assert_eq!(failed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(failed.headers().get(CACHE_STATUS).is_none());
+
+        let ok = cache.get_or_fetch(get("/payments-summary"), |_| backend(&calls, StatusCode::OK)).await;
+        assert_eq!(body(ok.unwrap()).await, "2");
+    }
+
+    #[tokio::test]
+    async fn no_cache_requests_skip_the_cache() {
+        let cache = SummaryCache::new(Duration::from_secs(60));
+        let calls = AtomicUsize::new(0);
+        let fresh = || {
+            let mut req = get("/payments-summary");
+            req.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+            req
+        };
+
+        cache.get_or_fetch(get("/payments-summary"), |_| backend(&calls, StatusCode::OK)).await.unwrap();
+        let bypassed = cache.get_or_fetch(fresh(), |_| backend(&calls, StatusCode::OK)).await.unwrap();
+        assert_eq!(body(bypassed).await, "2");
+        assert_eq!(cache.stats_json(), r#"{"hits":0,"misses":1,"bypassed":1}"#);
+
+        assert!(SummaryCache::is_cacheable(&get("/payments-summary?from=a")));
+        assert!(!SummaryCache::is_cacheable(&get("/payments")));
+        assert!(!SummaryCache::is_cacheable(&Request::post("/payments-summary").body(()).unwrap()));
+    }
+}
@@ -0,0 +1,175 @@
+//! Counts the upstream connections the client pool opens, so connection
+//! reuse can be checked against the concurrency of a run. Hyper's pool does
+//! not report its idle connections, so they are estimated as the open ones
+//! not carrying a request.
+
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper::Uri;
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use hyperlocal::{UnixConnector, UnixStream};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tower_service::Service;
+
+/// Connection counters of one backend.
+#[derive(Default)]
+pub struct HostCounters {
+    created: AtomicU64,
+    open: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Counters per backend, keyed by the URI host the client connects to.
+pub struct PoolStats {
+    hosts: Mutex<HashMap<String, Arc<HostCounters>>>,
+    /// When stats were last read and how many connections each host had
+    /// created by then, for the creation rate.
+    last_read: Mutex<(Instant, HashMap<String, u64>)>,
+}
+
+/// One backend's line in [`PoolStats::json`].
+pub struct HostSample<'a> {
+    pub address: &'a str,
+    pub in_flight: usize,
+    /// Share of its turns the backend takes, below 1 during slow start.
+    pub weight: f64,
+}
+
+impl PoolStats {
+    pub fn new() -> Self {
+        Self {
+            hosts: Mutex::new(HashMap::new()),
+            last_read: Mutex::new((Instant::now(), HashMap::new())),
+        }
+    }
+
+    fn host(&self, host: &str) -> Arc<HostCounters> {
+        self.hosts.lock().unwrap().entry(host.to_string()).or_default().clone()
+    }
+
+    /// `{"<backend>":{"open":…,"idle":…,"created":…,"createdPerSec":…,"connectFailed":…,"weight":…},…}`.
+    /// The rate covers the time since the previous call.
+    pub fn json(&self, backends: &[HostSample<'_>]) -> String {
+        let now = Instant::now();
+        let mut last_read = self.last_read.lock().unwrap();
+        let elapsed = now.duration_since(last_read.0).as_secs_f64().max(f64::EPSILON);
+        let hosts = self.hosts.lock().unwrap();
+
+        let mut created_by_host = HashMap::new();
+        let entries: Vec<String> = backends
+            .iter()
+            .map(|backend| {
+                let host = host_of(backend.address);
+                let counters = hosts.get(&host).cloned().unwrap_or_default();
+                let open = counters.open.load(Ordering::Relaxed);
+                let created = counters.created.load(Ordering::Relaxed);
+                let before = last_read.1.get(&host).copied().unwrap_or(0);
+                created_by_host.insert(host, created);
+                format!(
+                    r#""{}":{{"open":{},"idle":{},"created":{},"createdPerSec":{:.1},"connectFailed":{},"weight":{:.2}}}"#,
+                    backend.address.escape_default(),
+                    open,
+                    open.saturating_sub(backend.in_flight as u64),
+                    created,
+                    created.saturating_sub(before) as f64 / elapsed,
+                    counters.failed.load(Ordering::Relaxed),
+                    backend.weight,
+                )
+            })
+            .collect();
+
+        *last_read = (now, created_by_host);
+        format!("{{{}}}", entries.join(","))
+    }
+}
+
+/// The host `hyperlocal` puts in the URI for `address`, which is what the
+/// connector sees.
+fn host_of(address: &str) -> String {
+    let uri: Uri = hyperlocal::Uri::new(address, "/").into();
+    uri.host().unwrap_or_default().to_string()
+}
+
+/// [`UnixConnector`] that counts the connections it opens.
+#[derive(Clone)]
+pub struct CountingConnector {
+    stats: Arc<PoolStats>,
+}
+
+impl CountingConnector {
+    pub fn new(stats: Arc<PoolStats>) -> Self {
+        Self { stats }
+    }
+}
+
+impl Service<Uri> for CountingConnector {
+    type Response = CountedStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<CountedStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let counters = self.stats.host(uri.host().unwrap_or_default());
+        let connecting = UnixConnector.call(uri);
+        Box::pin(async move {
+            match connecting.await {
+                Ok(inner) => {
+                    counters.created.fetch_add(1, Ordering::Relaxed);
+                    counters.open.fetch_add(1, Ordering::Relaxed);
+                    Ok(CountedStream { inner, counters })
+                }
+                Err(e) => {
+                    counters.failed.fetch_add(1, Ordering::Relaxed);
+                    Err(e)
+                }
+            }
+        })
+    }
+}
+
+/// Upstream connection that counts itself closed when the pool drops it.
+pub struct CountedStream {
+    inner: UnixStream,
+    counters: Arc<HostCounters>,
+}
+
+impl Drop for CountedStream {
+    fn drop(&mut self) {
+        self.counters.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Read for CountedStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: ReadBufCursor<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl Write for CountedStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl Connection for CountedStream {
+    fn connected(&self) -> Connected {
+        self.inner.connected()
+    }
+}
@@ -0,0 +1,169 @@
+mod report;
+
+use crate::report::{Outcome, Report, Summary};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Method, Request, StatusCode};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use rust_decimal::Decimal;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// Fires `POST /payments` at a target and checks `/payments-summary` once
+/// the run is over, e.g. `LOADGEN_TARGET=http://localhost:9999 LOADGEN_RATE=500 loadgen`.
+pub struct LoadgenConfig {
+    pub target: String,
+    /// Requests started per second, `0` meaning as fast as concurrency allows.
+    pub rate: u64,
+    pub concurrency: usize,
+    pub duration: Duration,
+    pub amount: Decimal,
+    /// Time given to the backend to drain its queues before validating.
+    pub settle: Duration,
+    /// Calls `/purge-payments` before starting.
+    pub purge: bool,
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+impl LoadgenConfig {
+    pub fn from_env() -> Self {
+        Self {
+            target: env_or("LOADGEN_TARGET", "http://localhost:9999".to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            rate: env_or("LOADGEN_RATE", 0),
+            concurrency: env_or("LOADGEN_CONCURRENCY", 64usize).max(1),
+            duration: Duration::from_secs(env_or("LOADGEN_DURATION_SECS", 30)),
+            amount: env_or("LOADGEN_AMOUNT", Decimal::new(1990, 2)),
+            settle: Duration::from_millis(env_or("LOADGEN_SETTLE_MS", 5_000)),
+            purge: env_or("LOADGEN_PURGE", false),
+        }
+    }
+}
+
+type HttpClient = Client<HttpConnector, Full<Bytes>>;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = LoadgenConfig::from_env();
+
+    let mut connector = HttpConnector::new();
+    connector.set_nodelay(true);
+    let client: HttpClient = Client::builder(hyper_util::rt::TokioExecutor::new())
+        .pool_max_idle_per_host(config.concurrency)
+        .build(connector);
+
+    if config.purge {
+        let status = send(&client, Method::POST, format!("{}/purge-payments", config.target), Bytes::new())
+            .await?
+            .0;
+        println!("purge-payments: {}", status);
+    }
+
+    let from = OffsetDateTime::now_utc();
+    let started = Instant::now();
+    let mut report = run(&client, &config).await;
+    let elapsed = started.elapsed();
+
+    tokio::time::sleep(config.settle).await;
+    let to = OffsetDateTime::now_utc();
+
+    let summary_uri = format!(
+        "{}/payments-summary?from={}&to={}",
+        config.target,
+        from.format(&Rfc3339)?,
+        to.format(&Rfc3339)?,
+    );
+    let (status, body) = send(&client, Method::GET, summary_uri, Bytes::new()).await?;
+    if status != StatusCode::OK {
+        return Err(format!("payments-summary returned {}", status).into());
+    }
+    let summary: Summary = serde_json::from_slice(&body)?;
+
+    report.print(elapsed);
+    if !report.validate(&summary, config.amount) {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Issues payments for `config.duration`, pacing starts to `config.rate`
+/// and never exceeding `config.concurrency` requests in flight.
+async fn run(client: &HttpClient, config: &LoadgenConfig) -> Report {
+    let permits = Arc::new(Semaphore::new(config.concurrency));
+    let mut tasks = JoinSet::new();
+    let mut report = Report::default();
+
+    let mut pacer = (config.rate > 0).then(|| {
+        let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / config.rate as f64));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval
+    });
+
+    let deadline = tokio::time::Instant::now() + config.duration;
+    while tokio::time::Instant::now() < deadline {
+        if let Some(pacer) = pacer.as_mut() {
+            pacer.tick().await;
+        }
+
+        let permit = permits.clone().acquire_owned().await.expect("semaphore is never closed");
+        let client = client.clone();
+        let uri = format!("{}/payments", config.target);
+        let body = Bytes::from(format!(
+            r#"{{"correlationId":"{}","amount":{}}}"#,
+            uuid::Uuid::new_v4(),
+            config.amount
+        ));
+
+        tasks.spawn(async move {
+            let _permit = permit;
+            let started = Instant::now();
+            match send(&client, Method::POST, uri, body).await {
+                Ok((status, _)) if status.is_success() => Outcome::Accepted(started.elapsed()),
+                Ok(_) => Outcome::Rejected(started.elapsed()),
+                Err(_) => Outcome::Failed,
+            }
+        });
+
+        while let Some(Ok(outcome)) = tasks.try_join_next() {
+            report.record(outcome);
+        }
+    }
+
+    while let Some(Ok(outcome)) = tasks.join_next().await {
+        report.record(outcome);
+    }
+
+    report
+}
+
+async fn send(
+    client: &HttpClient,
+    method: Method,
+    uri: String,
+    body: Bytes,
+) -> Result<(StatusCode, Bytes), Box<dyn Error + Send + Sync>> {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .body(Full::new(body))?;
+
+    let response = client.request(request).await?;
+    let status = response.status();
+    let body = response.into_body().collect().await?.to_bytes();
+    Ok((status, body))
+}
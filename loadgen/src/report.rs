@@ -0,0 +1,104 @@
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::time::Duration;
+
+pub enum Outcome {
+    /// 2xx, so the payment must show up in the summary.
+    Accepted(Duration),
+    Rejected(Duration),
+    /// Connection or protocol error, no response received.
+    Failed,
+}
+
+#[derive(Deserialize)]
+pub struct ProcessorSummary {
+    #[serde(rename = "totalRequests")]
+    pub total_requests: i64,
+    #[serde(rename = "totalAmount")]
+    pub total_amount: Decimal,
+}
+
+#[derive(Deserialize)]
+pub struct Summary {
+    pub default: ProcessorSummary,
+    pub fallback: ProcessorSummary,
+}
+
+#[derive(Default)]
+pub struct Report {
+    accepted: u64,
+    rejected: u64,
+    failed: u64,
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    pub fn record(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Accepted(latency) => {
+                self.accepted += 1;
+                self.latencies.push(latency);
+            }
+            Outcome::Rejected(latency) => {
+                self.rejected += 1;
+                self.latencies.push(latency);
+            }
+            Outcome::Failed => self.failed += 1,
+        }
+    }
+
+    pub fn print(&mut self, elapsed: Duration) {
+        let total = self.accepted + self.rejected + self.failed;
+        println!(
+            "requests: {} in {:.1}s ({:.0} req/s)",
+            total,
+            elapsed.as_secs_f64(),
+            total as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+        );
+        println!(
+            "accepted: {}, rejected: {}, failed: {}",
+            self.accepted, self.rejected, self.failed
+        );
+
+        self.latencies.sort_unstable();
+        for (label, q) in [("p50", 0.50), ("p90", 0.90), ("p99", 0.99), ("max", 1.0)] {
+            println!("{}: {:.2}ms", label, self.percentile(q).as_secs_f64() * 1_000.0);
+        }
+    }
+
+    /// Compares accepted payments with what the backend reports, expecting
+    /// every accepted payment to be counted exactly once.
+    pub fn validate(&self, summary: &Summary, amount: Decimal) -> bool {
+        let requests = summary.default.total_requests + summary.fallback.total_requests;
+        let total_amount = summary.default.total_amount + summary.fallback.total_amount;
+        let expected_amount = amount * Decimal::from(self.accepted);
+
+        println!(
+            "summary: default {} / {}, fallback {} / {}",
+            summary.default.total_requests,
+            summary.default.total_amount,
+            summary.fallback.total_requests,
+            summary.fallback.total_amount
+        );
+
+        let ok = requests == self.accepted as i64 && total_amount == expected_amount;
+        if ok {
+            println!("summary matches accepted payments");
+        } else {
+            println!(
+                "MISMATCH: expected {} requests / {}, summary has {} / {}",
+                self.accepted, expected_amount, requests, total_amount
+            );
+        }
+        ok
+    }
+
+    /// Expects `latencies` to be sorted.
+    fn percentile(&self, q: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let index = ((self.latencies.len() - 1) as f64 * q).round() as usize;
+        self.latencies[index]
+    }
+}
@@ -0,0 +1,65 @@
+//! Criterion benchmarks for the body of every processor request, written by
+//! serde and by the `fast-json` template.
+//!
+//! `cargo bench --features fast-json --bench request_body`
+
+#[allow(dead_code)]
+#[path = "../src/request_body.rs"]
+mod request_body;
+
+/// Stands in for the worker's module, which `request_body`'s tests compare
+/// against.
+mod payment_processor {
+    use rust_decimal::Decimal;
+    use serde::Serialize;
+    use time::OffsetDateTime;
+
+    #[derive(Serialize)]
+    pub struct PaymentRequest {
+        pub amount: Decimal,
+        #[serde(rename = "correlationId")]
+        pub correlation_id: uuid::Uuid,
+        #[serde(rename = "requestedAt", with = "time::serde::rfc3339")]
+        pub requested_at: OffsetDateTime,
+    }
+}
+
+use bytes::{BufMut, BytesMut};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use payment_processor::PaymentRequest;
+use rust_decimal::Decimal;
+use std::hint::black_box;
+use time::OffsetDateTime;
+
+fn request_body(c: &mut Criterion) {
+    let request = PaymentRequest {
+        amount: Decimal::new(1990, 2),
+        correlation_id: uuid::Uuid::parse_str("4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3").unwrap(),
+        requested_at: OffsetDateTime::from_unix_timestamp_nanos(1_752_000_000_123_456_000).unwrap(),
+    };
+    let mut group = c.benchmark_group("request_body");
+    group.throughput(Throughput::Elements(1));
+    let mut out = BytesMut::with_capacity(16 * 1024);
+
+    group.bench_function("serde_json", |b| {
+        b.iter(|| {
+            out.reserve(256);
+            serde_json::to_writer((&mut out).writer(), black_box(&request)).unwrap();
+            black_box(out.split())
+        })
+    });
+
+    group.bench_function("fast_json", |b| {
+        b.iter(|| {
+            out.reserve(256);
+            let request = black_box(&request);
+            request_body::write_payment_request(&mut out, &request.amount, &request.correlation_id, request.requested_at);
+            black_box(out.split())
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, request_body);
+criterion_main!(benches);
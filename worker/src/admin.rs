@@ -0,0 +1,231 @@
+use crate::accept_loop;
+use crate::build_info;
+use crate::listener::{ListenAddr, Listener};
+use crate::logging;
+use crate::store::SummaryFilter;
+use crate::metrics::METRICS;
+use crate::settings::SettingsReloader;
+use crate::startup;
+use crate::worker_pool::WorkerPool;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+/// Small HTTP server on a unix socket (or `tcp://host:port`) used to inspect and steer a running
+/// worker, e.g. `curl --unix-socket /tmp/worker-admin.sock http://w/metrics`.
+///
+/// `POST /pause` stops workers from pulling payments while the receiver keeps
+/// queueing them, and `POST /resume` lets them drain the backlog. `GET /ledger`
+/// counts payments processors accepted that are not stored yet, and
+/// `POST /log-level` replaces the log filter with the `RUST_LOG`-style
+/// directives in the body (an empty body restores the startup filter).
+///
+/// `GET /summary?from=<ms>&to=<ms>&runId=<id>` returns the stored totals per
+/// processor in cents and `POST /purge` drops every payment, answering 403
+/// unless `ADMIN_ALLOW_PURGE` is set; the gateway's `SUMMARY_BACKEND=worker`
+/// relies on both.
+/// `GET /readyz` names the startup phase, answering 503 until `ready`.
+/// `GET /routing` shows the processor payments go to right now and why.
+/// `GET /dual-write` compares the answers of mirrored payments when
+/// `DUAL_WRITE_PERCENT` is set.
+pub struct AdminServer {
+    listen: ListenAddr,
+    reloader: Arc<SettingsReloader>,
+    worker_pool: Arc<WorkerPool>,
+    allow_purge: bool,
+}
+
+impl AdminServer {
+    pub fn new(listen: ListenAddr, reloader: Arc<SettingsReloader>, worker_pool: Arc<WorkerPool>) -> Self {
+        Self {
+            listen,
+            reloader,
+            worker_pool,
+            allow_purge: false,
+        }
+    }
+
+    /// Lets `POST /purge` drop every stored payment.
+    pub fn with_purge(mut self, allow_purge: bool) -> Self {
+        self.allow_purge = allow_purge;
+        self
+    }
+
+    pub async fn start(self) -> std::io::Result<()> {
+        let listener = Listener::bind_admin(&self.listen)?;
+        tracing::info!(listen = ?self.listen, "Admin server listening");
+
+        accept_loop::serve(listener, "admin", |stream| {
+            let reloader = self.reloader.clone();
+            let worker_pool = self.worker_pool.clone();
+            async move {
+                let service =
+                    service_fn(move |req| Self::handle(req, reloader.clone(), worker_pool.clone(), self.allow_purge));
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    tracing::debug!(error = %e, "Admin connection closed with error");
+                }
+            }
+        })
+        .await;
+
+        Ok(())
+    }
+
+    async fn handle(
+        req: Request<Incoming>,
+        reloader: Arc<SettingsReloader>,
+        worker_pool: Arc<WorkerPool>,
+        allow_purge: bool,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let response = match (req.method(), req.uri().path()) {
+            (&Method::POST, "/reload") => match reloader.reload() {
+                Ok(settings) => Response::builder().body(Full::new(Bytes::from(format!("{:#?}\n", settings)))),
+                Err(e) => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Full::new(Bytes::from(e.to_string()))),
+            },
+            (&Method::POST, "/pause") => {
+                if worker_pool.pause() {
+                    tracing::warn!("Workers paused");
+                }
+                Response::builder().body(Full::new(Bytes::from("paused\n")))
+            }
+            (&Method::POST, "/resume") => {
+                if worker_pool.resume() {
+                    tracing::warn!("Workers resumed");
+                }
+                Response::builder().body(Full::new(Bytes::from("running\n")))
+            }
+            (&Method::POST, "/log-level") => {
+                let directives = match req.into_body().collect().await {
+                    Ok(body) => String::from_utf8(body.to_bytes().to_vec())
+                        .map_err(|_| "log-level body must be UTF-8".to_string()),
+                    Err(e) => Err(format!("failed to read log-level body: {e}")),
+                };
+                match directives.and_then(|directives| logging::set_filter(&directives)) {
+                    Ok(active) => Response::builder().body(Full::new(Bytes::from(active + "\n"))),
+                    Err(e) => Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Full::new(Bytes::from(e + "\n"))),
+                }
+            }
+            (&Method::GET, "/status") => {
+                let state = if worker_pool.is_paused() { "paused\n" } else { "running\n" };
+                Response::builder().body(Full::new(Bytes::from(state)))
+            }
+            (&Method::GET, "/ledger") => match worker_pool.store().ledger().map(|ledger| serde_json::to_vec(&ledger.report())) {
+                None => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Full::new(Bytes::from("the store keeps no ledger\n"))),
+                Some(Ok(body)) => Response::builder()
+                    .header("content-type", "application/json")
+                    .body(Full::new(Bytes::from(body))),
+                Some(Err(e)) => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Full::new(Bytes::from(e.to_string()))),
+            },
+            (&Method::GET, "/workers") => match serde_json::to_vec(&worker_pool.stats()) {
+                Ok(body) => Response::builder()
+                    .header("content-type", "application/json")
+                    .body(Full::new(Bytes::from(body))),
+                Err(e) => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Full::new(Bytes::from(e.to_string()))),
+            },
+            (&Method::GET, "/routing") => match serde_json::to_vec(&worker_pool.routing_report()) {
+                Ok(body) => Response::builder()
+                    .header("content-type", "application/json")
+                    .body(Full::new(Bytes::from(body))),
+                Err(e) => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Full::new(Bytes::from(e.to_string()))),
+            },
+            (&Method::GET, "/dual-write") => match worker_pool.dual_write_report().map(|report| serde_json::to_vec(&report)) {
+                None => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Full::new(Bytes::from("dual writes are off\n"))),
+                Some(Ok(body)) => Response::builder()
+                    .header("content-type", "application/json")
+                    .body(Full::new(Bytes::from(body))),
+                Some(Err(e)) => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Full::new(Bytes::from(e.to_string()))),
+            },
+            (&Method::GET, "/summary") => match summary_filter(req.uri().query()) {
+                Err(e) => Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Full::new(Bytes::from(e + "\n"))),
+                Ok(filter) => match worker_pool.store().summary(filter).await.map(|summary| serde_json::to_vec(&summary)) {
+                    Ok(Ok(body)) => Response::builder()
+                        .header("content-type", "application/json")
+                        .body(Full::new(Bytes::from(body))),
+                    Ok(Err(e)) => Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Full::new(Bytes::from(e.to_string()))),
+                    Err(e) => Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .body(Full::new(Bytes::from(e.to_string() + "\n"))),
+                },
+            },
+            (&Method::POST, "/purge") if !allow_purge => Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Full::new(Bytes::from("purge is disabled, set ADMIN_ALLOW_PURGE=true\n"))),
+            (&Method::POST, "/purge") => match worker_pool.store().purge().await {
+                Ok(()) => {
+                    tracing::warn!("Purged stored payments");
+                    Response::builder().body(Full::new(Bytes::from("purged\n")))
+                }
+                Err(e) => Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Full::new(Bytes::from(e.to_string() + "\n"))),
+            },
+            (&Method::GET, "/readyz") => {
+                let phase = startup::current();
+                let status = if phase == startup::Phase::Ready {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                Response::builder()
+                    .status(status)
+                    .body(Full::new(Bytes::from(format!("{}\n", phase.as_str()))))
+            }
+            (&Method::GET, "/version") => Response::builder()
+                .header("content-type", "application/json")
+                .body(Full::new(Bytes::from(build_info!().json("worker")))),
+            (&Method::GET, "/metrics") => Response::builder()
+                .header("content-type", "text/plain; version=0.0.4")
+                .body(Full::new(Bytes::from(METRICS.render()))),
+            _ => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Full::new(Bytes::new())),
+        };
+
+        Ok(response.unwrap())
+    }
+}
+
+/// `from` and `to` are unix milliseconds; unknown parameters are ignored.
+fn summary_filter(query: Option<&str>) -> Result<SummaryFilter, String> {
+    let mut filter = SummaryFilter::default();
+    for pair in query.unwrap_or("").split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let millis = || value.parse().map_err(|_| format!("invalid {}: {}", name, value));
+        match name {
+            "from" => filter.from = Some(millis()?),
+            "to" => filter.to = Some(millis()?),
+            "runId" => filter.run_id = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    Ok(filter)
+}
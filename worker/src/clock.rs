@@ -0,0 +1,57 @@
+use std::future::Future;
+use std::pin::Pin;
+use tokio::time::Instant;
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Source of time for the retry and health subsystems, so tests can drive
+/// time explicitly instead of sleeping.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+/// Clock that only moves when [`ManualClock::advance`] is called.
+#[cfg(test)]
+pub struct ManualClock {
+    now: tokio::sync::watch::Sender<Instant>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            now: tokio::sync::watch::channel(Instant::now()).0,
+        }
+    }
+
+    pub fn advance(&self, by: std::time::Duration) {
+        self.now.send_modify(|now| *now += by);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        let mut now = self.now.subscribe();
+        Box::pin(async move {
+            let _ = now.wait_for(|now| *now >= deadline).await;
+        })
+    }
+}
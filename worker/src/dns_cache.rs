@@ -0,0 +1,179 @@
+//! Name resolution for the processor clients. Without it every new
+//! connection resolves the processor's hostname again, and a DNS hiccup in
+//! docker-compose fails payments that had a perfectly good address a moment
+//! ago. Answers are kept for a while, the last good one is used when a
+//! lookup fails, and hosts can be pinned to addresses so DNS is not asked
+//! at all.
+
+use hyper_util::client::legacy::connect::dns::Name;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower_service::Service;
+
+#[derive(Debug, Clone)]
+pub struct DnsConfig {
+    /// How long an answer is used before the name is looked up again
+    /// (`PROCESSOR_DNS_TTL_MS`). The last good answer outlives it when the
+    /// lookup fails.
+    pub ttl: Duration,
+    /// Hosts never looked up (`PROCESSOR_ADDRS`).
+    pub pinned: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self { ttl: Duration::from_secs(30), pinned: HashMap::new() }
+    }
+}
+
+/// Parses `PROCESSOR_ADDRS`, comma separated `host=ip` entries such as
+/// `payment-processor-default=172.18.0.3,payment-processor-fallback=172.18.0.4`.
+/// A host listed twice gets both addresses.
+pub fn parse_pinned(spec: &str) -> Result<HashMap<String, Vec<IpAddr>>, String> {
+    let mut pinned: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (host, ip) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid PROCESSOR_ADDRS entry: {}", entry))?;
+        let ip = ip
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid address for {}: {}", host.trim(), ip.trim()))?;
+        pinned.entry(host.trim().to_ascii_lowercase()).or_default().push(ip);
+    }
+    Ok(pinned)
+}
+
+struct Cached {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Instant,
+}
+
+struct Inner {
+    config: DnsConfig,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+/// Resolver shared by every processor client; cloning shares the cache.
+#[derive(Clone)]
+pub struct CachingResolver {
+    inner: Arc<Inner>,
+}
+
+static RESOLVER: OnceLock<CachingResolver> = OnceLock::new();
+
+/// Sets up the shared resolver. Must run before the first client is built;
+/// later calls are ignored.
+pub fn install(config: DnsConfig) {
+    if RESOLVER.set(CachingResolver::new(config)).is_err() {
+        tracing::warn!("DNS cache already installed, keeping its settings");
+    }
+}
+
+/// The shared resolver, with default settings unless [`install`] ran first.
+pub fn resolver() -> CachingResolver {
+    RESOLVER.get_or_init(|| CachingResolver::new(DnsConfig::default())).clone()
+}
+
+impl CachingResolver {
+    pub fn new(config: DnsConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner { config, cache: Mutex::new(HashMap::new()) }),
+        }
+    }
+
+    /// Resolves `hosts` ahead of the first payment, so a failing lookup
+    /// shows up at startup and the cache is warm once traffic arrives.
+    pub async fn prime<'a>(&self, hosts: impl Iterator<Item = &'a str>) {
+        for host in hosts {
+            if let Err(e) = self.lookup(host).await {
+                tracing::warn!(host, error = %e, "Failed to resolve processor host");
+            }
+        }
+    }
+
+    /// Addresses of `host` with port `0`, which the connector replaces with
+    /// the url's.
+    pub async fn lookup(&self, host: &str) -> io::Result<Vec<SocketAddr>> {
+        let host = host.to_ascii_lowercase();
+        if let Some(ips) = self.inner.config.pinned.get(&host) {
+            return Ok(ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect());
+        }
+
+        if let Some(cached) = self.inner.cache.lock().unwrap().get(&host)
+            && cached.resolved_at.elapsed() < self.inner.config.ttl
+        {
+            return Ok(cached.addrs.clone());
+        }
+
+        let looked_up = tokio::net::lookup_host((host.as_str(), 0)).await.map(Iterator::collect::<Vec<_>>);
+        match looked_up {
+            Ok(addrs) => {
+                self.inner
+                    .cache
+                    .lock()
+                    .unwrap()
+                    .insert(host, Cached { addrs: addrs.clone(), resolved_at: Instant::now() });
+                Ok(addrs)
+            }
+            Err(e) => match self.inner.cache.lock().unwrap().get(&host) {
+                Some(cached) => {
+                    tracing::warn!(host, error = %e, "Lookup failed, using the last known addresses");
+                    Ok(cached.addrs.clone())
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+impl Service<Name> for CachingResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolver = self.clone();
+        Box::pin(async move { resolver.lookup(name.as_str()).await.map(Vec::into_iter) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pinned_hosts_skip_dns() {
+        let pinned = parse_pinned("Processor.Example=10.0.0.7, processor.example=10.0.0.8").unwrap();
+        let resolver = CachingResolver::new(DnsConfig { pinned, ..DnsConfig::default() });
+
+        let addrs = resolver.lookup("processor.example").await.unwrap();
+        assert_eq!(addrs, ["10.0.0.7:0".parse().unwrap(), "10.0.0.8:0".parse().unwrap()]);
+        assert!(parse_pinned("processor.example").is_err());
+        assert!(parse_pinned("processor.example=not-an-ip").is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_lookups_fall_back_to_the_last_answer() {
+        let resolver = CachingResolver::new(DnsConfig { ttl: Duration::ZERO, ..DnsConfig::default() });
+        let known: SocketAddr = "10.0.0.9:0".parse().unwrap();
+        // `.invalid` never resolves (RFC 6761).
+        resolver.inner.cache.lock().unwrap().insert(
+            "processor.invalid".to_string(),
+            Cached { addrs: vec![known], resolved_at: Instant::now() },
+        );
+
+        assert_eq!(resolver.lookup("processor.invalid").await.unwrap(), [known]);
+        assert!(resolver.lookup("other.invalid").await.is_err());
+    }
+}
@@ -0,0 +1,130 @@
+//! Dual writes, for conformance testing only. A sampled share of the
+//! payments a processor answered for is sent again to another processor of
+//! the chain under a shadow correlationId, and the two answers are compared,
+//! to check the fallback treats payments like the default before relying on
+//! it in the middle of a test. Shadow payments are never stored, but the
+//! processor that takes them counts them in its own summary, so the mode is
+//! not meant for scored runs.
+
+use crate::error::WorkerError;
+use crate::payment::Payment;
+use crate::payment_processor::Processor;
+use crate::processor_type::ProcessorType;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What an answer says about a payment, the level at which the answers of
+/// the two processors are compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Taken, or already known to the processor.
+    Accepted,
+    Unprocessable,
+    Failed,
+}
+
+impl Verdict {
+    pub fn of(result: &Result<(), WorkerError>) -> Self {
+        match result {
+            Ok(()) | Err(WorkerError::AlreadyProcessed) => Self::Accepted,
+            Err(WorkerError::Unprocessable(_)) => Self::Unprocessable,
+            Err(_) => Self::Failed,
+        }
+    }
+}
+
+/// Comparisons between one primary processor and its shadow.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct PairReport {
+    pub mirrored: u64,
+    pub agreed: u64,
+    pub disagreed: u64,
+    /// Shadow latency minus primary latency, summed over mirrored payments.
+    #[serde(rename = "latencyDeltaUsTotal")]
+    pub latency_delta_us_total: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DualWriteReport {
+    #[serde(rename = "samplePercent")]
+    pub sample_percent: u8,
+    /// Keyed `primary->shadow`.
+    pub pairs: BTreeMap<String, PairReport>,
+}
+
+pub struct DualWrite {
+    sample_percent: u8,
+    pairs: Mutex<BTreeMap<String, PairReport>>,
+}
+
+impl DualWrite {
+    /// Mirrors `sample_percent` of the payments, capped at 100.
+    pub fn new(sample_percent: u8) -> Self {
+        Self {
+            sample_percent: sample_percent.min(100),
+            pairs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Whether the payment is in the sample. Correlation ids are random, so
+    /// their value picks a stable share and a retried payment stays in or out.
+    pub fn samples(&self, correlation_id: &uuid::Uuid) -> bool {
+        correlation_id.as_u128() % 100 < u128::from(self.sample_percent)
+    }
+
+    /// Sends `payment` to `shadow` under its shadow id and compares the
+    /// answer with the `verdict` the primary gave after `primary_latency`.
+    pub async fn mirror<P: Processor>(
+        self: Arc<Self>,
+        primary: ProcessorType,
+        verdict: Verdict,
+        primary_latency: Duration,
+        shadow: Arc<P>,
+        payment: Payment,
+    ) {
+        let correlation_id = payment.correlation_id;
+        let payment = Payment {
+            correlation_id: shadow_id(&correlation_id),
+            processor: shadow.processor_type(),
+            run_id: None,
+            ..payment
+        };
+
+        let started = Instant::now();
+        let shadow_verdict = Verdict::of(&shadow.process(payment).await);
+        let delta = started.elapsed().as_micros() as i64 - primary_latency.as_micros() as i64;
+
+        let mut pairs = self.pairs.lock().unwrap();
+        let pair = pairs.entry(format!("{}->{}", primary, shadow.processor_type())).or_default();
+        pair.mirrored += 1;
+        pair.latency_delta_us_total += delta;
+        if shadow_verdict == verdict {
+            pair.agreed += 1;
+        } else {
+            pair.disagreed += 1;
+            tracing::warn!(
+                %correlation_id,
+                %primary,
+                shadow = %shadow.processor_type(),
+                primary_verdict = ?verdict,
+                shadow_verdict = ?shadow_verdict,
+                "Processors disagree on a mirrored payment"
+            );
+        }
+    }
+
+    pub fn report(&self) -> DualWriteReport {
+        DualWriteReport {
+            sample_percent: self.sample_percent,
+            pairs: self.pairs.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// The id a payment is mirrored under: its first 32 bits inverted, so a
+/// shadow is told apart from its original and traced back to it.
+pub fn shadow_id(correlation_id: &uuid::Uuid) -> uuid::Uuid {
+    uuid::Uuid::from_u128(correlation_id.as_u128() ^ (u128::from(u32::MAX) << 96))
+}
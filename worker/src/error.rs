@@ -0,0 +1,87 @@
+use crate::processor_type::ProcessorType;
+
+/// Errors raised while moving a payment through the worker.
+#[derive(Debug)]
+pub enum WorkerError {
+    /// A producer sent something that is not a payment message.
+    InvalidMessage(serde_json::Error),
+    /// A message decoded fine but carries values no payment can have.
+    RejectedMessage(&'static str),
+    /// The receiver's unix socket could not be set up.
+    Socket(std::io::Error),
+    /// A worker queue has no room left.
+    QueueFull,
+    /// The worker pool is not running.
+    QueueClosed,
+    /// The payment could not be turned into a processor request.
+    InvalidPayment,
+    /// The processor answered 422 with this body: it cannot accept what we
+    /// send, and never will, so the payment is quarantined.
+    Unprocessable(bytes::Bytes),
+    /// The processor could not be reached, timed out or is at its
+    /// concurrency cap.
+    ProcessorUnavailable,
+    /// The processor did not answer within its request deadline. It may
+    /// still have taken the payment, so the retry goes to the same processor,
+    /// which answers a duplicate with 409.
+    ProcessorTimeout(ProcessorType),
+    /// The processor already holds a payment with this correlation id, e.g.
+    /// an earlier attempt succeeded but its response was lost.
+    AlreadyProcessed,
+    /// Health checks report every usable processor as failing.
+    AllProcessorsFailing,
+    /// The store's buffer is full or it was never started.
+    StoreUnavailable,
+}
+
+impl WorkerError {
+    /// Whether repeating the failed operation later may succeed. Only
+    /// retryable payment failures are put back on the retry queue.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            WorkerError::QueueFull
+            | WorkerError::ProcessorUnavailable
+            | WorkerError::ProcessorTimeout(_)
+            | WorkerError::AllProcessorsFailing
+            | WorkerError::StoreUnavailable => true,
+            WorkerError::InvalidMessage(_)
+            | WorkerError::RejectedMessage(_)
+            | WorkerError::Socket(_)
+            | WorkerError::QueueClosed
+            | WorkerError::InvalidPayment
+            | WorkerError::Unprocessable(_)
+            | WorkerError::AlreadyProcessed => false,
+        }
+    }
+}
+
+impl std::fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorkerError::InvalidMessage(e) => write!(f, "JSON parse error: {}", e),
+            WorkerError::RejectedMessage(reason) => write!(f, "Rejected message: {}", reason),
+            WorkerError::Socket(e) => write!(f, "Socket error: {}", e),
+            WorkerError::QueueFull => write!(f, "Queue full"),
+            WorkerError::QueueClosed => write!(f, "Queue closed"),
+            WorkerError::InvalidPayment => write!(f, "invalid payment"),
+            WorkerError::Unprocessable(body) => {
+                write!(f, "processor rejected the payment: {}", String::from_utf8_lossy(body))
+            }
+            WorkerError::ProcessorUnavailable => write!(f, "processor is unavailable"),
+            WorkerError::ProcessorTimeout(processor) => write!(f, "processor {} did not answer in time", processor),
+            WorkerError::AlreadyProcessed => write!(f, "payment was already processed"),
+            WorkerError::AllProcessorsFailing => write!(f, "Both processors are failing"),
+            WorkerError::StoreUnavailable => write!(f, "push payment into the store failed"),
+        }
+    }
+}
+
+impl std::error::Error for WorkerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WorkerError::InvalidMessage(e) => Some(e),
+            WorkerError::Socket(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -1,165 +1,550 @@
-﻿use crate::processor_type::ProcessorType;
+﻿use crate::clock::Clock;
+use crate::error::WorkerError;
+use crate::http_client::HttpClient;
+use crate::processor_chain::ProcessorConfig;
+use crate::processor_type::ProcessorType;
+use crate::routing_strategy::{Reason, RoutingStrategy};
 use bytes::Bytes;
 use http_body_util::{BodyExt, Empty};
-use hyper;
-use hyper::client::conn::http1;
-use hyper::client::conn::http1::SendRequest;
 use hyper::{Method, Request};
-use hyper_util::rt::TokioIo;
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use hyper_util::client::legacy::Client;
-use hyper_util::client::legacy::connect::HttpConnector;
-use tokio::net::TcpStream;
-use tokio::sync::RwLock;
-use tokio::time::interval;
+use tokio::sync::watch;
+use tokio::time::Instant;
 
-#[derive(Debug, Deserialize)]
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+/// How often observed request latencies are folded into the snapshot.
+const LATENCY_REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Where in the probe interval this replica probes, so replicas started
+/// together do not hit the health endpoint's rate limit at the same moment.
+/// Replicas that know their `(index, count)` are spaced evenly; the others
+/// are placed by a hash of their hostname.
+pub fn probe_phase(replica: Option<(u32, u32)>, hostname: Option<&str>) -> Duration {
+    let interval = PROBE_INTERVAL.as_millis() as u64;
+    let millis = match (replica, hostname) {
+        (Some((index, count)), _) if count > 1 => interval * index as u64 / count as u64,
+        (_, Some(hostname)) => {
+            let mut hasher = std::hash::DefaultHasher::new();
+            hostname.hash(&mut hasher);
+            hasher.finish() % interval
+        }
+        _ => 0,
+    };
+    Duration::from_millis(millis)
+}
+
+/// Anywhere from zero to `max`, different on every start.
+pub fn random_delay(max: Duration) -> Duration {
+    let max = max.as_millis() as u64;
+    if max == 0 {
+        return Duration::ZERO;
+    }
+    let random = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    Duration::from_millis(random % (max + 1))
+}
+
+/// Why a probe brought back no health.
+#[derive(Debug)]
+enum ProbeError {
+    /// The health endpoint answered 429, asking to wait `retry_after`
+    /// (`Retry-After`) when it said how long.
+    RateLimited { retry_after: Option<Duration> },
+    Failed(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::RateLimited { retry_after: Some(wait) } => {
+                write!(f, "Rate limited, retry after {}s", wait.as_secs())
+            }
+            ProbeError::RateLimited { retry_after: None } => write!(f, "Rate limited"),
+            ProbeError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: Into<Box<dyn std::error::Error + Send + Sync>>> From<E> for ProbeError {
+    fn from(e: E) -> Self {
+        ProbeError::Failed(e.into())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
 pub struct ProcessorHealth {
     pub failing: bool,
     #[serde(rename = "minResponseTime")]
     pub min_response_time: u16,
+    /// When the last successful probe was recorded.
+    #[serde(skip)]
+    pub updated_at: Option<Instant>,
+    /// Moving average of real payment round trips, in ms.
+    #[serde(skip)]
+    pub observed_latency: Option<u16>,
 }
 
-pub struct HealthMonitor {
-    urls: HashMap<ProcessorType, String>,
-    healths: Arc<RwLock<HashMap<ProcessorType, ProcessorHealth>>>,
+impl ProcessorHealth {
+    /// Latency used for routing: the worse of the probed minimum and what
+    /// payments actually experience.
+    pub fn latency(&self) -> u16 {
+        self.min_response_time.max(self.observed_latency.unwrap_or(0))
+    }
 }
 
-const MAX_ACCEPTABLE_RESPONSE_TIME: u16 = 50;
+/// Exponentially weighted moving average of a processor's round trips,
+/// updated lock-free on every attempt. Each sample moves the average by
+/// 1/8 of the difference.
+#[derive(Default)]
+pub struct LatencyEwma {
+    /// Microseconds, `0` until the first sample.
+    micros: AtomicU32,
+}
 
-#[derive(Debug)]
-pub enum HealthMonitorError {
-    BothProcessorsFailing,
+impl LatencyEwma {
+    pub fn observe(&self, elapsed: Duration) {
+        let sample = u32::try_from(elapsed.as_micros()).unwrap_or(u32::MAX).max(1);
+        let _ = self.micros.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+            Some(if current == 0 {
+                sample
+            } else {
+                (current as i64 + (sample as i64 - current as i64) / 8) as u32
+            })
+        });
+    }
+
+    /// Forgets the average. A processor routed around stops receiving
+    /// samples, so without this a stale high average would keep it out.
+    fn reset(&self) {
+        self.micros.store(0, Ordering::Relaxed);
+    }
+
+    fn millis(&self) -> Option<u16> {
+        match self.micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(u16::try_from(micros.div_ceil(1_000)).unwrap_or(u16::MAX)),
+        }
+    }
 }
 
-impl std::fmt::Display for HealthMonitorError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            HealthMonitorError::BothProcessorsFailing => write!(f, "Both processors are failing"),
+/// Per-attempt deadline for a processor's payment requests: twice its probed
+/// `minResponseTime`, never below the floor. It tightens when the processor
+/// is fast and relaxes when it is slow but alive; until the first probe the
+/// floor applies alone.
+pub struct RequestDeadline {
+    /// Milliseconds, `0` disabling the deadline.
+    floor: u32,
+    millis: AtomicU32,
+}
+
+impl RequestDeadline {
+    pub fn new(floor: Option<Duration>) -> Self {
+        let floor = floor.map_or(0, |floor| u32::try_from(floor.as_millis()).unwrap_or(u32::MAX));
+        Self {
+            floor,
+            millis: AtomicU32::new(floor),
+        }
+    }
+
+    fn update(&self, min_response_time: u16) {
+        if self.floor > 0 {
+            let millis = (u32::from(min_response_time) * 2).max(self.floor);
+            self.millis.store(millis, Ordering::Relaxed);
+        }
+    }
+
+    /// `None` when deadlines are disabled.
+    pub fn current(&self) -> Option<Duration> {
+        match self.millis.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(Duration::from_millis(millis.into())),
+        }
+    }
+}
+
+/// Latest known health of every processor in the chain and the route it
+/// implies under the current strategy.
+#[derive(Debug, Clone)]
+pub struct HealthSnapshot {
+    /// Chain order, matching `healths`.
+    pub processors: Vec<ProcessorType>,
+    pub healths: Vec<ProcessorHealth>,
+    /// `None` when every usable processor is failing.
+    pub route: Option<ProcessorType>,
+    /// Where payments that keep failing on `route` are sent instead.
+    pub escalation: Escalation,
+    /// Where payments past their budget are sent, `None` with `route`.
+    pub late_route: Option<ProcessorType>,
+}
+
+/// Healthy processors after the routed one, taken one step per
+/// `after_retries` failed attempts of a payment.
+#[derive(Debug, Clone, Default)]
+pub struct Escalation {
+    pub after_retries: u32,
+    pub processors: Vec<ProcessorType>,
+}
+
+impl Escalation {
+    fn processor_for(&self, retry_count: u32) -> Option<ProcessorType> {
+        if self.after_retries == 0 || self.processors.is_empty() {
+            return None;
+        }
+
+        let steps = (retry_count / self.after_retries) as usize;
+        let step = steps.checked_sub(1)?;
+        Some(self.processors[step.min(self.processors.len() - 1)])
+    }
+}
+
+impl HealthSnapshot {
+    fn reroute(&mut self, strategy: &RoutingStrategy) {
+        let routed = strategy.decide(&self.healths).ok();
+        self.route = routed.map(|index| self.processors[index]);
+        self.late_route = routed.map(|index| self.processors[strategy.late_route(&self.healths, index)]);
+        self.escalation = Escalation {
+            after_retries: strategy.escalate_after_retries,
+            processors: routed
+                .map(|index| strategy.escalation(&self.healths, index))
+                .unwrap_or_default()
+                .into_iter()
+                .map(|index| self.processors[index])
+                .collect(),
+        };
+    }
+}
+
+/// One processor's line in [`RoutingReport`].
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessorRouting {
+    pub processor: String,
+    pub failing: bool,
+    pub min_response_time_ms: u16,
+    pub observed_latency_ms: Option<u16>,
+    /// What routing compares, the worse of the two above.
+    pub latency_ms: u16,
+    /// Since the last successful probe, `None` before the first.
+    pub probe_age_ms: Option<u64>,
+    pub deadline_ms: Option<u64>,
+    /// Payments per second while slow start paces the processor.
+    pub pacing_rps: Option<f64>,
+    pub reason: Reason,
+}
+
+/// The route payments take right now and what it rests on.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoutingReport {
+    /// `None` when every usable processor is failing.
+    pub route: Option<String>,
+    pub escalation: Vec<String>,
+    /// Where payments past their budget go.
+    pub late_route: Option<String>,
+    pub strategy: RoutingStrategy,
+    /// Chain order.
+    pub processors: Vec<ProcessorRouting>,
+}
+
+type Healths = Arc<watch::Sender<HealthSnapshot>>;
+type Strategy = Arc<std::sync::RwLock<RoutingStrategy>>;
+
+/// Probes the processors of the chain and publishes every change to subscribers, so the
+/// routing decision is only recomputed when health or strategy change.
+pub struct HealthMonitor {
+    chain: Vec<ProcessorConfig>,
+    /// Chain order, matching the snapshot.
+    latencies: Arc<[Arc<LatencyEwma>]>,
+    /// Chain order, matching the snapshot.
+    deadlines: Arc<[Arc<RequestDeadline>]>,
+    healths: Healths,
+    strategy: Strategy,
+    clock: Arc<dyn Clock>,
+    /// Delay before the first probe; see [`probe_phase`].
+    probe_offset: Duration,
+}
+
+/// A worker's view of processor health. It keeps the last published route
+/// and only touches the channel when the monitor has published a change, so
+/// the per-payment cost of [`HealthSubscription::next_processor`] is a
+/// single atomic version check.
+pub struct HealthSubscription {
+    receiver: watch::Receiver<HealthSnapshot>,
+    route: Option<ProcessorType>,
+    escalation: Escalation,
+    late_route: Option<ProcessorType>,
+}
+
+impl HealthSubscription {
+    /// Processor for a payment that already failed `retry_count` attempts.
+    pub fn next_processor(&mut self, retry_count: u32) -> Result<ProcessorType, WorkerError> {
+        self.refresh();
+        let route = self.route.ok_or(WorkerError::AllProcessorsFailing)?;
+        Ok(self.escalation.processor_for(retry_count).unwrap_or(route))
+    }
+
+    /// Processor for a payment past its budget.
+    pub fn late_processor(&mut self) -> Result<ProcessorType, WorkerError> {
+        self.refresh();
+        self.late_route.ok_or(WorkerError::AllProcessorsFailing)
+    }
+
+    fn refresh(&mut self) {
+        if self.receiver.has_changed().unwrap_or(false) {
+            let snapshot = self.receiver.borrow_and_update();
+            self.route = snapshot.route;
+            self.escalation = snapshot.escalation.clone();
+            self.late_route = snapshot.late_route;
         }
     }
 }
 
-impl std::error::Error for HealthMonitorError {}
 impl HealthMonitor {
-    pub fn new(default_processor_url: &str, fallback_processor_url: &str) -> Self {
-        let mut healths = HashMap::with_capacity(2);
-        healths.insert(
-            ProcessorType::Default,
-            ProcessorHealth {
-                min_response_time: 0,
-                failing: false,
-            },
-        );
-        healths.insert(
-            ProcessorType::Fallback,
-            ProcessorHealth {
-                min_response_time: 0,
-                failing: false,
-            },
-        );
-
-        let mut urls = HashMap::with_capacity(2);
-        urls.insert(ProcessorType::Default, default_processor_url.to_string());
-        urls.insert(ProcessorType::Fallback, fallback_processor_url.to_string());
+    pub fn new(chain: &[ProcessorConfig], strategy: RoutingStrategy, clock: Arc<dyn Clock>) -> Self {
+        let unknown = ProcessorHealth {
+            min_response_time: 0,
+            failing: false,
+            updated_at: None,
+            observed_latency: None,
+        };
+        let mut snapshot = HealthSnapshot {
+            processors: chain.iter().map(|config| config.processor_type).collect(),
+            healths: vec![unknown; chain.len()],
+            route: None,
+            escalation: Escalation::default(),
+            late_route: None,
+        };
+        snapshot.reroute(&strategy);
 
         Self {
-            urls,
-            healths: Arc::new(RwLock::new(healths)),
+            chain: chain.to_vec(),
+            latencies: chain.iter().map(|_| Arc::new(LatencyEwma::default())).collect(),
+            deadlines: chain.iter().map(|_| Arc::new(RequestDeadline::new(None))).collect(),
+            healths: Arc::new(watch::channel(snapshot).0),
+            strategy: Arc::new(std::sync::RwLock::new(strategy)),
+            clock,
+            probe_offset: Duration::ZERO,
         }
     }
 
+    /// Holds the first probe back by `offset`; the rest follow every
+    /// probe interval from there.
+    pub fn with_probe_offset(mut self, offset: Duration) -> Self {
+        self.probe_offset = offset;
+        self
+    }
+
+    /// Enables request deadlines of at least `floor`.
+    pub fn with_deadline_floor(mut self, floor: Option<Duration>) -> Self {
+        self.deadlines = self.chain.iter().map(|_| Arc::new(RequestDeadline::new(floor))).collect();
+        self
+    }
+
+    /// Deadline the processor of `processor_type` puts on each request.
+    pub fn request_deadline(&self, processor_type: ProcessorType) -> Option<Arc<RequestDeadline>> {
+        let index = self.chain.iter().position(|config| config.processor_type == processor_type)?;
+        Some(self.deadlines[index].clone())
+    }
+
+    /// Tracker the processor of `processor_type` feeds its round trips into.
+    pub fn latency_tracker(&self, processor_type: ProcessorType) -> Option<Arc<LatencyEwma>> {
+        let index = self.chain.iter().position(|config| config.processor_type == processor_type)?;
+        Some(self.latencies[index].clone())
+    }
+
     pub async fn start(&self) {
-        let client =
-            Client::builder(hyper_util::rt::TokioExecutor::new()).build(HttpConnector::new());
-        let default_url = self.urls.get(&ProcessorType::Default).unwrap().clone();
-        let fallback_url = self.urls.get(&ProcessorType::Fallback).unwrap().clone();
+        let probed: Vec<(usize, String, HttpClient<Empty<Bytes>>)> = self
+            .chain
+            .iter()
+            .enumerate()
+            .filter(|(_, config)| config.probe_health)
+            .filter_map(|(index, config)| {
+                let url = config.url.clone()?;
+                let client = HttpClient::for_url(&url, config.http2);
+                Some((index, url, client))
+            })
+            .collect();
+        let latencies = self.latencies.clone();
+        let deadlines = self.deadlines.clone();
+        let healths = self.healths.clone();
+        let strategy = self.strategy.clone();
+        let clock = self.clock.clone();
+        let probe_offset = self.probe_offset;
+
+        tokio::spawn(async move {
+            let mut next_probe = clock.now() + probe_offset;
+            clock.sleep_until(next_probe).await;
+            // Per processor, when a 429 allows probing it again.
+            let mut not_before: Vec<Option<Instant>> = vec![None; probed.len()];
+
+            loop {
+                for ((index, url, client), not_before) in probed.iter().zip(&mut not_before) {
+                    if not_before.is_some_and(|at| clock.now() < at) {
+                        continue;
+                    }
+                    // A fresh probe gives the processor another chance at
+                    // real traffic; the average rebuilds from the next round
+                    // trips.
+                    let deadline = &deadlines[*index];
+                    match Self::try_update_health(*index, client.clone(), url, &healths, &strategy, deadline, clock.as_ref()).await {
+                        Ok(()) => latencies[*index].reset(),
+                        Err(ProbeError::RateLimited { retry_after }) => {
+                            *not_before = Some(clock.now() + retry_after.unwrap_or(PROBE_INTERVAL));
+                        }
+                        Err(ProbeError::Failed(_)) => {}
+                    }
+                }
+
+                next_probe += PROBE_INTERVAL;
+                clock.sleep_until(next_probe).await;
+            }
+        });
+
+        let latencies = self.latencies.clone();
         let healths = self.healths.clone();
+        let strategy = self.strategy.clone();
+        let clock = self.clock.clone();
 
         tokio::spawn(async move {
-            let mut ticker = interval(Duration::from_secs(5));
+            let mut next_refresh = clock.now();
 
             loop {
-                Self::try_update_health(&ProcessorType::Default, client.clone(), &default_url, healths.clone())
-                    .await;
-                Self::try_update_health(&ProcessorType::Fallback, client.clone(), &fallback_url, healths.clone())
-                    .await;
-                ticker.tick().await;
+                Self::refresh_latencies(&latencies, &healths, &strategy);
+
+                next_refresh += LATENCY_REFRESH_INTERVAL;
+                clock.sleep_until(next_refresh).await;
+            }
+        });
+    }
+
+    /// Copies the observed latencies into the snapshot, publishing only when
+    /// one of them changed.
+    fn refresh_latencies(latencies: &[Arc<LatencyEwma>], healths: &Healths, strategy: &Strategy) {
+        healths.send_if_modified(|snapshot| {
+            let mut modified = false;
+            for (health, latency) in snapshot.healths.iter_mut().zip(latencies) {
+                let observed = latency.millis();
+                if health.observed_latency != observed {
+                    health.observed_latency = observed;
+                    modified = true;
+                }
             }
+
+            if modified {
+                snapshot.reroute(&strategy.read().unwrap());
+            }
+            modified
         });
     }
 
-    async fn try_update_health(processor_type: &ProcessorType, client: Client<HttpConnector, Empty<Bytes>>, url: &String, healths: Arc<RwLock<HashMap<ProcessorType, ProcessorHealth>>>) {
+    async fn try_update_health(index: usize, client: HttpClient<Empty<Bytes>>, url: &str, healths: &Healths, strategy: &Strategy, deadline: &RequestDeadline, clock: &dyn Clock) -> Result<(), ProbeError> {
         match Self::probe_health(client, url).await {
             Ok(probed_health) => {
-                let mut healths = healths.write().await;
-                if let Some(health) = healths.get_mut(processor_type) {
-                    health.failing = probed_health.failing;
-                    health.min_response_time = probed_health.min_response_time;
-                    tracing::info!(
-                        processor = ?processor_type,
-                        health = ?health,
-                        "Updated health for processor"
-                    );
-                }
+                deadline.update(probed_health.min_response_time);
+                Self::record(healths, strategy, index, probed_health, clock.now());
+                Ok(())
             }
             Err(err) => {
-                tracing::warn!(error = ?err, "Failed to update health for processor");
+                tracing::warn!(error = %err, url, "Failed to update health for processor");
+                Err(err)
             }
         }
     }
 
-    // pub async fn next_processor(&self) -> Result<ProcessorType, HealthMonitorError> {
-    //     let healths = self.healths.read().await;
-    //     let default_health = healths.get(&ProcessorType::Default).unwrap();
-    //     let fallback_health = healths.get(&ProcessorType::Fallback).unwrap();
-    //
-    //     let default_failing = default_health.failing
-    //         || default_health.min_response_time > MAX_ACCEPTABLE_RESPONSE_TIME;
-    //     let fallback_failing = fallback_health.failing
-    //         || fallback_health.min_response_time > MAX_ACCEPTABLE_RESPONSE_TIME;
-    //
-    //     if default_failing && fallback_failing {
-    //         return Err(HealthMonitorError::BothProcessorsFailing);
-    //     }
-    //
-    //     if default_failing {
-    //         return Ok(ProcessorType::Fallback);
-    //     }
-    //
-    //     if fallback_failing {
-    //         return Ok(ProcessorType::Default);
-    //     }
-    //
-    //     if default_health.min_response_time < (3 * fallback_health.min_response_time) {
-    //         return Ok(ProcessorType::Default);
-    //     }
-    //
-    //     Ok(ProcessorType::Fallback)
-    // }
+    /// Stores a probe result as if it had just been received.
+    #[cfg(test)]
+    pub fn record_probe(&self, processor_type: &ProcessorType, probed_health: ProcessorHealth) {
+        let index = self
+            .chain
+            .iter()
+            .position(|config| config.processor_type == *processor_type)
+            .unwrap();
+        self.deadlines[index].update(probed_health.min_response_time);
+        Self::record(&self.healths, &self.strategy, index, probed_health, self.clock.now());
+    }
+
+    fn record(healths: &Healths, strategy: &Strategy, index: usize, probed_health: ProcessorHealth, now: Instant) {
+        healths.send_modify(|snapshot| {
+            let processor_type = snapshot.processors[index];
+            let health = &mut snapshot.healths[index];
+            health.failing = probed_health.failing;
+            health.min_response_time = probed_health.min_response_time;
+            health.updated_at = Some(now);
+            tracing::info!(
+                processor = %processor_type,
+                health = ?health,
+                "Updated health for processor"
+            );
 
-    pub async fn next_processor(&self) -> Result<ProcessorType, HealthMonitorError> {
-        let healths = self.healths.read().await;
-        let default_health = healths.get(&ProcessorType::Default).unwrap();
+            snapshot.reroute(&strategy.read().unwrap());
+        });
+    }
 
-        let default_failing = default_health.failing
-            || default_health.min_response_time > MAX_ACCEPTABLE_RESPONSE_TIME;
+    pub fn subscribe(&self) -> HealthSubscription {
+        let mut receiver = self.healths.subscribe();
+        let snapshot = receiver.borrow_and_update();
+        let (route, escalation, late_route) = (snapshot.route, snapshot.escalation.clone(), snapshot.late_route);
+        drop(snapshot);
+        HealthSubscription { receiver, route, escalation, late_route }
+    }
+
+    /// Every published snapshot, for those following the route itself
+    /// rather than asking it per payment.
+    pub fn watch(&self) -> watch::Receiver<HealthSnapshot> {
+        self.healths.subscribe()
+    }
 
+    /// The published route next to the health, deadline and routing reason
+    /// of every processor.
+    pub fn routing_report(&self) -> RoutingReport {
+        let strategy = self.strategy.read().unwrap().clone();
+        let snapshot = self.healths.borrow();
+        let now = self.clock.now();
+        let reasons = strategy.explain(&snapshot.healths);
+        let processors = snapshot
+            .processors
+            .iter()
+            .zip(&snapshot.healths)
+            .zip(reasons)
+            .zip(self.deadlines.iter())
+            .map(|(((processor, health), reason), deadline)| ProcessorRouting {
+                processor: processor.to_string(),
+                failing: health.failing,
+                min_response_time_ms: health.min_response_time,
+                observed_latency_ms: health.observed_latency,
+                latency_ms: health.latency(),
+                probe_age_ms: health
+                    .updated_at
+                    .map(|at| now.saturating_duration_since(at).as_millis() as u64),
+                deadline_ms: deadline.current().map(|deadline| deadline.as_millis() as u64),
+                pacing_rps: None,
+                reason,
+            })
+            .collect();
 
-        if default_failing  {
-            return Err(HealthMonitorError::BothProcessorsFailing);
+        RoutingReport {
+            route: snapshot.route.map(|route| route.to_string()),
+            escalation: snapshot.escalation.processors.iter().map(ToString::to_string).collect(),
+            late_route: snapshot.late_route.map(|route| route.to_string()),
+            strategy,
+            processors,
         }
+    }
 
-        Ok(ProcessorType::Default)
+    pub fn set_strategy(&self, strategy: RoutingStrategy) {
+        let mut current = self.strategy.write().unwrap();
+        *current = strategy;
+        self.healths.send_modify(|snapshot| snapshot.reroute(&current));
     }
 
     async fn probe_health(
-        client: Client<HttpConnector, Empty<Bytes>>,
+        client: HttpClient<Empty<Bytes>>,
         url: &str,
-    ) -> Result<ProcessorHealth, Box<dyn std::error::Error + Send + Sync>> {
+    ) -> Result<ProcessorHealth, ProbeError> {
         let uri = format!("{}/payments/service-health", url).parse::<hyper::Uri>()?;
 
         let req = Request::builder()
@@ -169,6 +554,15 @@ impl HealthMonitor {
 
         let res = client.request(req).await?;
 
+        if res.status() == hyper::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = res
+                .headers()
+                .get(hyper::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs);
+            return Err(ProbeError::RateLimited { retry_after });
+        }
         if res.status() != hyper::StatusCode::OK {
             return Err(format!("Invalid status code: {}", res.status()).into());
         }
@@ -181,3 +575,165 @@ impl HealthMonitor {
         Ok(health)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::processor_chain::default_and_fallback;
+
+    fn health(failing: bool) -> ProcessorHealth {
+        ProcessorHealth {
+            failing,
+            min_response_time: 10,
+            updated_at: None,
+            observed_latency: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn routing_follows_flapping_health() {
+        let clock = Arc::new(ManualClock::new());
+        let strategy = RoutingStrategy {
+            fallback_enabled: true,
+            ..RoutingStrategy::default()
+        };
+        let monitor = HealthMonitor::new(&default_and_fallback("http://default", "http://fallback"), strategy, clock.clone());
+        let mut subscription = monitor.subscribe();
+        monitor.record_probe(&ProcessorType::FALLBACK, health(false));
+
+        for round in 0..4 {
+            let failing = round % 2 == 0;
+            monitor.record_probe(&ProcessorType::DEFAULT, health(failing));
+
+            let expected = if failing { ProcessorType::FALLBACK } else { ProcessorType::DEFAULT };
+            assert_eq!(subscription.next_processor(0).unwrap(), expected);
+
+            let updated_at = monitor.healths.borrow().healths[0].updated_at;
+            assert_eq!(updated_at, Some(clock.now()));
+
+            clock.advance(PROBE_INTERVAL);
+        }
+    }
+
+    #[test]
+    fn strategy_change_is_published() {
+        let clock = Arc::new(ManualClock::new());
+        let monitor = HealthMonitor::new(&default_and_fallback("http://default", "http://fallback"), RoutingStrategy::default(), clock);
+        let mut subscription = monitor.subscribe();
+        monitor.record_probe(&ProcessorType::DEFAULT, health(true));
+        assert!(subscription.next_processor(0).is_err());
+
+        monitor.set_strategy(RoutingStrategy {
+            fallback_enabled: true,
+            ..RoutingStrategy::default()
+        });
+        assert_eq!(subscription.next_processor(0).unwrap(), ProcessorType::FALLBACK);
+    }
+
+    #[test]
+    fn deadline_follows_probed_response_time() {
+        let clock = Arc::new(ManualClock::new());
+        let monitor = HealthMonitor::new(&default_and_fallback("http://default", "http://fallback"), RoutingStrategy::default(), clock)
+            .with_deadline_floor(Some(Duration::from_millis(50)));
+        let deadline = monitor.request_deadline(ProcessorType::DEFAULT).unwrap();
+        assert_eq!(deadline.current(), Some(Duration::from_millis(50)));
+
+        for (min_response_time, expected) in [(10, 50), (400, 800), (20, 50)] {
+            monitor.record_probe(&ProcessorType::DEFAULT, ProcessorHealth { min_response_time, ..health(false) });
+            assert_eq!(deadline.current(), Some(Duration::from_millis(expected)));
+        }
+
+        let disabled = HealthMonitor::new(&default_and_fallback("http://default", "http://fallback"), RoutingStrategy::default(), Arc::new(ManualClock::new()));
+        disabled.record_probe(&ProcessorType::DEFAULT, health(false));
+        assert_eq!(disabled.request_deadline(ProcessorType::DEFAULT).unwrap().current(), None);
+    }
+
+    #[test]
+    fn repeatedly_failing_payment_escalates() {
+        let clock = Arc::new(ManualClock::new());
+        let strategy = RoutingStrategy {
+            fallback_enabled: true,
+            escalate_after_retries: 3,
+            ..RoutingStrategy::default()
+        };
+        let monitor = HealthMonitor::new(&default_and_fallback("http://default", "http://fallback"), strategy, clock);
+        let mut subscription = monitor.subscribe();
+
+        assert_eq!(subscription.next_processor(2).unwrap(), ProcessorType::DEFAULT);
+        assert_eq!(subscription.next_processor(3).unwrap(), ProcessorType::FALLBACK);
+        assert_eq!(subscription.next_processor(9).unwrap(), ProcessorType::FALLBACK);
+
+        monitor.record_probe(&ProcessorType::FALLBACK, health(true));
+        assert_eq!(subscription.next_processor(3).unwrap(), ProcessorType::DEFAULT);
+    }
+
+    #[test]
+    fn latency_average_moves_an_eighth_towards_each_sample() {
+        let ewma = LatencyEwma::default();
+        assert_eq!(ewma.millis(), None);
+
+        ewma.observe(Duration::from_millis(10));
+        assert_eq!(ewma.millis(), Some(10));
+        ewma.observe(Duration::from_millis(90));
+        assert_eq!(ewma.millis(), Some(20));
+        ewma.observe(Duration::from_millis(20));
+        assert_eq!(ewma.millis(), Some(20));
+        // Partial milliseconds round up, and a sample is never zero.
+        ewma.observe(Duration::from_micros(12));
+        assert_eq!(ewma.millis(), Some(18));
+        ewma.reset();
+        ewma.observe(Duration::ZERO);
+        assert_eq!(ewma.millis(), Some(1));
+
+        ewma.reset();
+        assert_eq!(ewma.millis(), None);
+    }
+
+    #[test]
+    fn observed_latency_reroutes_until_reset() {
+        let clock = Arc::new(ManualClock::new());
+        let strategy = RoutingStrategy {
+            fallback_enabled: true,
+            ..RoutingStrategy::default()
+        };
+        let monitor = HealthMonitor::new(&default_and_fallback("http://default", "http://fallback"), strategy, clock);
+        let mut subscription = monitor.subscribe();
+        monitor.record_probe(&ProcessorType::DEFAULT, health(false));
+        monitor.record_probe(&ProcessorType::FALLBACK, health(false));
+        assert_eq!(subscription.next_processor(0).unwrap(), ProcessorType::DEFAULT);
+
+        // Probes say 10ms, payments take far longer.
+        let latency = monitor.latency_tracker(ProcessorType::DEFAULT).unwrap();
+        latency.observe(Duration::from_millis(400));
+        HealthMonitor::refresh_latencies(&monitor.latencies, &monitor.healths, &monitor.strategy);
+        assert_eq!(monitor.healths.borrow().healths[0].observed_latency, Some(400));
+        assert_eq!(monitor.healths.borrow().healths[0].latency(), 400);
+        assert_eq!(subscription.next_processor(0).unwrap(), ProcessorType::FALLBACK);
+
+        // Unchanged averages publish nothing.
+        let version = monitor.healths.subscribe();
+        HealthMonitor::refresh_latencies(&monitor.latencies, &monitor.healths, &monitor.strategy);
+        assert!(!version.has_changed().unwrap());
+
+        latency.reset();
+        HealthMonitor::refresh_latencies(&monitor.latencies, &monitor.healths, &monitor.strategy);
+        assert_eq!(subscription.next_processor(0).unwrap(), ProcessorType::DEFAULT);
+        assert!(monitor.latency_tracker(ProcessorType::new("absent")).is_none());
+    }
+
+    #[test]
+    fn replicas_probe_at_different_phases() {
+        let phases: Vec<_> = (0..4).map(|index| probe_phase(Some((index, 4)), Some("worker"))).collect();
+        assert_eq!(phases, [0, 1250, 2500, 3750].map(Duration::from_millis));
+
+        // Without an index the hostname decides, the same way every time.
+        let by_host = probe_phase(None, Some("worker-1"));
+        assert!(by_host < PROBE_INTERVAL);
+        assert_eq!(by_host, probe_phase(Some((0, 1)), Some("worker-1")));
+        assert_eq!(probe_phase(None, None), Duration::ZERO);
+
+        assert!(random_delay(Duration::from_millis(500)) <= Duration::from_millis(500));
+        assert_eq!(random_delay(Duration::ZERO), Duration::ZERO);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::dns_cache::{self, CachingResolver};
+use hyper::body::{Body, Incoming};
+use hyper::{Request, Response};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::{Client, Error};
+use hyper_util::rt::TokioExecutor;
+
+type Connector = HttpConnector<CachingResolver>;
+
+/// Client for one processor, speaking TLS when its url is `https://`.
+/// Hostnames go through the shared [`dns_cache`].
+#[derive(Clone)]
+pub enum HttpClient<B> {
+    Plain(Client<Connector, B>),
+    #[cfg(feature = "tls")]
+    Tls(Client<hyper_rustls::HttpsConnector<Connector>, B>),
+}
+
+impl<B> HttpClient<B>
+where
+    B: Body + Send + Unpin + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    /// Picks the connector from the scheme of `url`. `https://` urls are
+    /// rejected by [`check_scheme`] unless the `tls` feature is enabled.
+    ///
+    /// With `http2`, plain `http://` urls speak h2c with prior knowledge:
+    /// every request multiplexes over one connection instead of each
+    /// in-flight request holding its own. `https://` urls stay on HTTP/1.1.
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    pub fn for_url(url: &str, http2: bool) -> Self {
+        let mut builder = Client::builder(TokioExecutor::new());
+        let http = HttpConnector::new_with_resolver(dns_cache::resolver());
+
+        #[cfg(feature = "tls")]
+        if is_https(url) {
+            let mut http = http;
+            http.enforce_http(false);
+            let connector = hyper_rustls::HttpsConnectorBuilder::new()
+                .with_webpki_roots()
+                .https_only()
+                .enable_http1()
+                .wrap_connector(http);
+            return HttpClient::Tls(builder.build(connector));
+        }
+
+        builder.http2_only(http2);
+        HttpClient::Plain(builder.build(http))
+    }
+
+    pub async fn request(&self, req: Request<B>) -> Result<Response<Incoming>, Error> {
+        match self {
+            HttpClient::Plain(client) => client.request(req).await,
+            #[cfg(feature = "tls")]
+            HttpClient::Tls(client) => client.request(req).await,
+        }
+    }
+}
+
+fn is_https(url: &str) -> bool {
+    url.get(..8).is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://"))
+}
+
+/// Fails for urls this build cannot reach: anything but `http://`, plus
+/// `https://` when built without the `tls` feature.
+pub fn check_scheme(url: &str) -> Result<(), String> {
+    if is_https(url) {
+        return if cfg!(feature = "tls") {
+            Ok(())
+        } else {
+            Err(format!("{} needs the worker built with the tls feature", url))
+        };
+    }
+
+    if url.get(..7).is_some_and(|scheme| scheme.eq_ignore_ascii_case("http://")) {
+        Ok(())
+    } else {
+        Err(format!("Unsupported processor url: {}", url))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::body::Bytes;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// Serves h2c or HTTP/1.1 on a local port, answering every request with
+    /// the version it came in on. Returns the url and a count of the
+    /// connections accepted.
+    async fn processor(http2: bool) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::Relaxed);
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    // Keeps the requests in flight together, so HTTP/1.1
+                    // needs a connection for each.
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    let version = format!("{:?}", req.version());
+                    Ok::<_, std::convert::Infallible>(Response::new(Full::new(Bytes::from(version))))
+                });
+                let io = TokioIo::new(stream);
+                tokio::spawn(async move {
+                    if http2 {
+                        let _ = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                            .serve_connection(io, service)
+                            .await;
+                    } else {
+                        let _ = hyper::server::conn::http1::Builder::new().serve_connection(io, service).await;
+                    }
+                });
+            }
+        });
+        (url, connections)
+    }
+
+    /// Sends `count` requests at once, returning the versions they were
+    /// served with.
+    async fn post_all(client: &HttpClient<Empty<Bytes>>, url: &str, count: usize) -> Vec<String> {
+        let requests = (0..count).map(|_| async {
+            let req = Request::post(format!("{}/payments", url)).body(Empty::new()).unwrap();
+            let body = client.request(req).await.unwrap().into_body().collect().await.unwrap();
+            String::from_utf8(body.to_bytes().to_vec()).unwrap()
+        });
+        futures_util::future::join_all(requests).await
+    }
+
+    #[tokio::test]
+    async fn http2_multiplexes_requests_over_one_connection() {
+        let (url, connections) = processor(true).await;
+        let client = HttpClient::for_url(&url, true);
+
+        let versions = post_all(&client, &url, 16).await;
+        assert!(versions.iter().all(|v| v == "HTTP/2.0"), "{:?}", versions);
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn http1_holds_a_connection_per_request_in_flight() {
+        let (url, connections) = processor(false).await;
+        let client = HttpClient::for_url(&url, false);
+
+        let versions = post_all(&client, &url, 4).await;
+        assert!(versions.iter().all(|v| v == "HTTP/1.1"), "{:?}", versions);
+        assert_eq!(connections.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn only_reachable_schemes_pass() {
+        assert!(check_scheme("http://pp-default:8080").is_ok());
+        assert!(check_scheme("HTTP://pp-default:8080").is_ok());
+        assert_eq!(check_scheme("https://pp-default").is_ok(), cfg!(feature = "tls"));
+        assert!(check_scheme("ftp://pp-default").is_err());
+        assert!(check_scheme("pp-default:8080").is_err());
+    }
+}
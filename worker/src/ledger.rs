@@ -0,0 +1,228 @@
+use crate::payment::Payment;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// Payments are spread over this many locks by correlationId, so the
+/// insert loops and every payment accepted do not queue on one lock.
+const SHARDS: usize = 16;
+
+/// Most rejected payments kept whole, split evenly over the shards.
+const REJECTED_KEPT: usize = 10_000;
+
+/// Payments a processor has accepted but Postgres does not hold yet.
+///
+/// Every payment handed to the [`crate::store::PostgresStore`] is pending until its
+/// batch commits. A failed write moves the batch to `failed`, where it stays
+/// until reconciliation either finds it already stored or writes it again, so
+/// a summary that under-counts can be told apart from one that is complete.
+/// Payments Postgres refuses on their own are set aside as `rejected` and
+/// not written again. Past [`REJECTED_KEPT`] of them only their count and
+/// amount are kept.
+pub struct Ledger {
+    shards: Box<[Mutex<LedgerShard>]>,
+}
+
+#[derive(Default)]
+struct LedgerShard {
+    pending: HashSet<uuid::Uuid>,
+    failed: HashMap<uuid::Uuid, Payment>,
+    rejected: HashMap<uuid::Uuid, Payment>,
+    /// Rejected payments not kept in `rejected`, as it was full.
+    rejected_beyond: usize,
+    rejected_beyond_amount: Decimal,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LedgerReport {
+    pub pending: usize,
+    pub failed: usize,
+    pub failed_amount: Decimal,
+    pub rejected: usize,
+    pub rejected_amount: Decimal,
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+}
+
+impl Ledger {
+    fn shard(&self, correlation_id: &uuid::Uuid) -> &Mutex<LedgerShard> {
+        &self.shards[(correlation_id.as_u128() % SHARDS as u128) as usize]
+    }
+
+    pub fn accepted(&self, payment: &Payment) {
+        self.shard(&payment.correlation_id).lock().unwrap().pending.insert(payment.correlation_id);
+    }
+
+    pub fn persisted(&self, payments: &[Payment]) {
+        for payment in payments {
+            let mut shard = self.shard(&payment.correlation_id).lock().unwrap();
+            shard.pending.remove(&payment.correlation_id);
+            shard.failed.remove(&payment.correlation_id);
+        }
+    }
+
+    pub fn write_failed(&self, payments: &[Payment]) {
+        for payment in payments {
+            let mut shard = self.shard(&payment.correlation_id).lock().unwrap();
+            shard.pending.remove(&payment.correlation_id);
+            shard.failed.insert(payment.correlation_id, payment.clone());
+        }
+    }
+
+    pub fn rejected(&self, payments: &[Payment]) {
+        for payment in payments {
+            let mut shard = self.shard(&payment.correlation_id).lock().unwrap();
+            shard.pending.remove(&payment.correlation_id);
+            shard.failed.remove(&payment.correlation_id);
+            if shard.rejected.len() < REJECTED_KEPT / SHARDS || shard.rejected.contains_key(&payment.correlation_id) {
+                shard.rejected.insert(payment.correlation_id, payment.clone());
+            } else {
+                shard.rejected_beyond += 1;
+                shard.rejected_beyond_amount += payment.amount;
+            }
+        }
+    }
+
+    /// Forgets failed and rejected payments, after a purge emptied the
+    /// tables they were meant for. Pending ones are still being written.
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            shard.failed.clear();
+            shard.rejected.clear();
+            shard.rejected_beyond = 0;
+            shard.rejected_beyond_amount = Decimal::ZERO;
+        }
+    }
+
+    /// Up to `limit` failed payments to reconcile. They stay in the ledger
+    /// until reported [`Self::persisted`].
+    pub fn failed(&self, limit: usize) -> Vec<Payment> {
+        let mut failed = Vec::new();
+        for shard in self.shards.iter() {
+            let room = limit - failed.len();
+            if room == 0 {
+                break;
+            }
+            failed.extend(shard.lock().unwrap().failed.values().take(room).cloned());
+        }
+        failed
+    }
+
+    pub fn report(&self) -> LedgerReport {
+        let mut report = LedgerReport {
+            pending: 0,
+            failed: 0,
+            failed_amount: Decimal::ZERO,
+            rejected: 0,
+            rejected_amount: Decimal::ZERO,
+        };
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            report.pending += shard.pending.len();
+            report.failed += shard.failed.len();
+            report.failed_amount += shard.failed.values().map(|payment| payment.amount).sum::<Decimal>();
+            report.rejected += shard.rejected.len() + shard.rejected_beyond;
+            report.rejected_amount +=
+                shard.rejected.values().map(|payment| payment.amount).sum::<Decimal>() + shard.rejected_beyond_amount;
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor_type::ProcessorType;
+    use time::OffsetDateTime;
+
+    fn payment(cents: i64) -> Payment {
+        Payment::new(
+            Decimal::new(cents, 2),
+            uuid::Uuid::new_v4(),
+            ProcessorType::DEFAULT,
+            OffsetDateTime::now_utc(),
+        )
+    }
+
+    #[test]
+    fn failed_writes_stay_until_persisted() {
+        let ledger = Ledger::default();
+        let payments = [payment(1000), payment(250)];
+        for payment in &payments {
+            ledger.accepted(payment);
+        }
+        assert_eq!(ledger.report().pending, 2);
+
+        ledger.write_failed(&payments);
+        let report = ledger.report();
+        assert_eq!((report.pending, report.failed), (0, 2));
+        assert_eq!(report.failed_amount, Decimal::new(1250, 2));
+        assert_eq!(ledger.failed(1).len(), 1);
+        assert_eq!(ledger.report().failed, 2);
+
+        ledger.persisted(&payments[..1]);
+        let report = ledger.report();
+        assert_eq!((report.pending, report.failed), (0, 1));
+        assert_eq!(report.failed_amount, Decimal::new(250, 2));
+    }
+
+    #[test]
+    fn rejected_payments_are_not_reconciled() {
+        let ledger = Ledger::default();
+        let payments = [payment(1000), payment(250)];
+        ledger.write_failed(&payments);
+
+        ledger.rejected(&payments[1..]);
+        let report = ledger.report();
+        assert_eq!((report.failed, report.rejected), (1, 1));
+        assert_eq!(report.rejected_amount, Decimal::new(250, 2));
+        assert_eq!(ledger.failed(usize::MAX)[0].correlation_id, payments[0].correlation_id);
+    }
+
+    #[test]
+    fn rejected_payments_past_the_cap_are_only_counted() {
+        let ledger = Ledger::default();
+        let payments: Vec<_> = (0..2 * REJECTED_KEPT).map(|_| payment(100)).collect();
+        ledger.rejected(&payments);
+
+        let report = ledger.report();
+        assert_eq!(report.rejected, 2 * REJECTED_KEPT);
+        assert_eq!(report.rejected_amount, Decimal::from(2 * REJECTED_KEPT));
+        let kept: usize = ledger.shards.iter().map(|shard| shard.lock().unwrap().rejected.len()).sum();
+        assert_eq!(kept, REJECTED_KEPT);
+    }
+
+    #[test]
+    fn failed_is_limited_across_shards() {
+        let ledger = Ledger::default();
+        let payments: Vec<_> = (0..64).map(|_| payment(100)).collect();
+        ledger.write_failed(&payments);
+
+        assert_eq!(ledger.failed(10).len(), 10);
+        assert_eq!(ledger.failed(usize::MAX).len(), 64);
+        assert_eq!(ledger.report().failed_amount, Decimal::from(64));
+    }
+
+    #[test]
+    fn clear_keeps_only_pending_payments() {
+        let ledger = Ledger::default();
+        let payments = [payment(1000), payment(250), payment(75)];
+        ledger.accepted(&payments[0]);
+        ledger.write_failed(&payments[1..2]);
+        ledger.rejected(&payments[2..]);
+
+        ledger.clear();
+        let report = ledger.report();
+        assert_eq!((report.pending, report.failed, report.rejected), (1, 0, 0));
+        assert!(ledger.failed(usize::MAX).is_empty());
+    }
+}
@@ -3,56 +3,283 @@ mod receiver;
 mod worker_pool;
 mod health_monitor;
 mod processor_type;
+mod processor_chain;
 mod payment_processor;
 mod payment;
 mod store;
+mod routing_strategy;
+mod retry_policy;
+mod settings;
+mod clock;
+mod error;
+mod metrics;
+mod admin;
+mod redis_summary;
+mod http_client;
+mod dns_cache;
+mod ledger;
+mod memory_store;
+mod worker_stats;
+mod slow_start;
+mod dual_write;
+mod verify;
+mod report;
+#[cfg(feature = "fast-json")]
+mod request_body;
+#[cfg(feature = "shm-transport")]
+mod shm_transport;
 
 use crate::receiver::Receiver;
+use common::{accept_loop, build_info, listener, logging, startup};
 use std::sync::Arc;
+use std::time::Duration;
 use deadpool_postgres::{Manager, ManagerConfig, RecyclingMethod};
 use tokio_postgres::NoTls;
+use crate::clock::{Clock, SystemClock};
 use crate::health_monitor::HealthMonitor;
+use crate::processor_chain::ProcessorConfig;
+use crate::settings::{RuntimeSettings, SettingsReloader};
+use crate::store::PaymentStore;
 
 pub struct WorkerConfig {
     pub listen_path: String,
+    /// Longest line accepted from a producer before it is disconnected.
+    pub max_message_size: usize,
+    /// Lines each producer connection may have in flight, at most its share
+    /// of the queue headroom (`RECEIVER_CREDITS`, `0` disables flow control).
+    /// Producers must be configured to wait for credits too.
+    pub receiver_credits: Option<usize>,
     pub num_workers: usize,
+    /// Unused, and may be unset, with the memory backend.
     pub postgres_url: String,
-    pub default_processor_url: String,
-    pub fallback_processor_url: String,
+    /// Keeps payments in memory instead of Postgres (`STORE_BACKEND=memory`).
+    pub memory_backend: Option<MemoryBackendConfig>,
+    /// Failover chain, most preferred first.
+    pub processors: Vec<ProcessorConfig>,
+    /// How processor hostnames are resolved and cached.
+    pub dns: dns_cache::DnsConfig,
+    pub settings_file: Option<String>,
+    pub settings: RuntimeSettings,
+    pub shutdown_timeout: Duration,
+    /// Longest startup waits for Postgres to answer, and then for the schema
+    /// to be created, before failing (`STARTUP_DB_TIMEOUT_MS`).
+    pub startup_db_timeout: Duration,
+    /// Admin server address (`ADMIN_SOCKET`), a socket path or `tcp://host:port`.
+    pub admin_socket: Option<listener::ListenAddr>,
+    /// Whether the admin server's `POST /purge` may drop every stored
+    /// payment (`ADMIN_ALLOW_PURGE`).
+    pub admin_allow_purge: bool,
+    /// Time a payment may spend in the pipeline before it is shed to the
+    /// fallback processor, when fallback is enabled and it is healthy.
+    pub message_budget: Option<Duration>,
+    /// Most queued payments a worker sends together to a processor with
+    /// `batch=on` (`PROCESSOR_BATCH_SIZE`); `1` sends them one by one.
+    pub batch_size: usize,
+    /// Pacing toward the most preferred processor after it recovers
+    /// (`SLOW_START_RPS`, `0` disables it).
+    pub slow_start: Option<slow_start::SlowStartConfig>,
+    /// Share of payments also sent to a second processor under a shadow
+    /// id, for conformance tests only (`DUAL_WRITE_PERCENT`, `0` disables it).
+    pub dual_write_percent: u8,
+    /// Part of the correlationId space this replica owns
+    /// (`WORKER_SHARD_INDEX` of `WORKER_SHARD_COUNT`).
+    pub shard: Option<worker_pool::Shard>,
+    /// Lower bound of the per-attempt processor deadline, which otherwise is
+    /// twice the probed `minResponseTime`. `None`, the default, disables
+    /// deadlines; a payment whose attempt times out is only retried on the
+    /// same processor.
+    pub processor_timeout_floor: Option<Duration>,
+    /// Delay before the first health probe: this replica's phase in the
+    /// probe interval (by `WORKER_SHARD_INDEX`, else `HOSTNAME`) plus up to
+    /// `PROBE_JITTER_MS` at random (`PROBE_PHASE_OFFSET_MS` overrides both).
+    pub probe_offset: Duration,
+    /// Busiest worker's share over the mean that is logged as imbalanced
+    /// (`WORKER_IMBALANCE_THRESHOLD`, `0` disables the check).
+    pub imbalance_threshold: Option<f64>,
+    /// Retries kept in memory before the rest are spilled to Postgres.
+    pub retry_capacity: Option<usize>,
+    /// Insert loops writing payments in parallel.
+    pub flush_pipelines: usize,
+    /// Durability traded for write throughput (`STORE_UNLOGGED`,
+    /// `STORE_SYNCHRONOUS_COMMIT`); see [`store::Durability`].
+    pub durability: store::Durability,
+    /// Update `payments_summary` in the same transaction as the payments.
+    pub transactional_summary: bool,
+    /// Create `payments_summary` at startup when it is missing
+    /// (`STORE_SUMMARY_TABLE`).
+    pub summary_table: bool,
+    /// Mirrors per-processor totals into Redis for the gateway's summary.
+    pub redis_url: Option<String>,
+    #[cfg(feature = "shm-transport")]
+    pub shm_ring: Option<ShmRingConfig>,
+}
+
+pub struct MemoryBackendConfig {
+    /// Restart recovery file (`MEMORY_SNAPSHOT_PATH`); without it payments
+    /// are lost when the worker stops.
+    pub snapshot_path: Option<std::path::PathBuf>,
+    pub snapshot_interval: Duration,
+}
+
+#[cfg(feature = "shm-transport")]
+pub struct ShmRingConfig {
+    pub path: String,
+    pub capacity: u64,
+    pub slot_size: usize,
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// The failover chain from `PROCESSORS`, or from `DEFAULT_PROCESSOR_URL` and
+/// `FALLBACK_PROCESSOR_URL` without it.
+fn processors_from_env() -> Vec<ProcessorConfig> {
+    let processors = match std::env::var("PROCESSORS") {
+        Ok(spec) => processor_chain::parse_chain(&spec).unwrap(),
+        Err(_) => {
+            let mut chain = processor_chain::default_and_fallback(
+                &std::env::var("DEFAULT_PROCESSOR_URL").unwrap(),
+                &std::env::var("FALLBACK_PROCESSOR_URL").unwrap(),
+            );
+            // `PROCESSORS` entries take `;http2=on` instead.
+            let http2 = env_or("PROCESSOR_HTTP2", false);
+            chain.iter_mut().for_each(|config| config.http2 = http2);
+            chain
+        }
+    };
+    for url in processors.iter().filter_map(|config| config.url.as_deref()) {
+        http_client::check_scheme(url).unwrap();
+    }
+    processors
 }
 
 impl WorkerConfig {
     pub fn from_env() -> WorkerConfig {
         let listen_path = std::env::var("LISTEN_PATH").unwrap();
         let num_workers = std::env::var("NUM_WORKERS").unwrap();
-        let postgres_url = std::env::var("POSTGRES_URL").unwrap();
-        let default_processor_url = std::env::var("DEFAULT_PROCESSOR_URL").unwrap();
-        let fallback_processor_url = std::env::var("FALLBACK_PROCESSOR_URL").unwrap();
+        let memory_backend = match std::env::var("STORE_BACKEND").as_deref() {
+            Ok("memory") => Some(MemoryBackendConfig {
+                snapshot_path: std::env::var("MEMORY_SNAPSHOT_PATH").ok().map(Into::into),
+                snapshot_interval: Duration::from_millis(env_or("MEMORY_SNAPSHOT_INTERVAL_MS", 1_000)),
+            }),
+            Ok("postgres") | Err(_) => None,
+            Ok(other) => panic!("Invalid STORE_BACKEND: {}", other),
+        };
+        let postgres_url = match std::env::var("POSTGRES_URL") {
+            Ok(url) => url,
+            Err(_) if memory_backend.is_some() => String::new(),
+            Err(e) => panic!("POSTGRES_URL: {}", e),
+        };
+        let processors = processors_from_env();
+
+        let shard_count: u32 = env_or("WORKER_SHARD_COUNT", 1);
+        let shard = (shard_count > 1)
+            .then(|| worker_pool::Shard::new(env_or("WORKER_SHARD_INDEX", 0), shard_count).unwrap());
+
+        let probe_offset = match std::env::var("PROBE_PHASE_OFFSET_MS").ok().and_then(|ms| ms.parse().ok()) {
+            Some(ms) => Duration::from_millis(ms),
+            None => {
+                let replica = shard.as_ref().map(|shard| (shard.index, shard.count));
+                health_monitor::probe_phase(replica, std::env::var("HOSTNAME").ok().as_deref())
+                    + health_monitor::random_delay(Duration::from_millis(env_or("PROBE_JITTER_MS", 500)))
+            }
+        };
+
+        // Without Postgres there is nowhere to spill retries to.
+        let retry_capacity = Some(env_or("RETRY_HEAP_CAPACITY", 16 * 1024usize))
+            .filter(|cap| *cap > 0 && memory_backend.is_none());
+
+        let settings_file = std::env::var("WORKER_SETTINGS_FILE").ok();
+        let settings = RuntimeSettings::load(settings_file.as_deref()).unwrap();
 
         WorkerConfig {
             listen_path,
-            num_workers: usize::from_str_radix(&num_workers, 10).unwrap(),
+            max_message_size: env_or("MAX_MESSAGE_BYTES", receiver::DEFAULT_MAX_MESSAGE_SIZE),
+            receiver_credits: Some(env_or("RECEIVER_CREDITS", 0usize)).filter(|credits| *credits > 0),
+            num_workers: num_workers.parse().unwrap(),
             postgres_url,
-            default_processor_url,
-            fallback_processor_url,
+            memory_backend,
+            processors,
+            dns: dns_cache::DnsConfig {
+                ttl: Duration::from_millis(env_or("PROCESSOR_DNS_TTL_MS", 30_000)),
+                pinned: dns_cache::parse_pinned(&std::env::var("PROCESSOR_ADDRS").unwrap_or_default()).unwrap(),
+            },
+            settings_file,
+            settings,
+            shutdown_timeout: Duration::from_millis(env_or("SHUTDOWN_TIMEOUT_MS", 5_000)),
+            startup_db_timeout: Duration::from_millis(env_or("STARTUP_DB_TIMEOUT_MS", 30_000)),
+            admin_socket: std::env::var("ADMIN_SOCKET").ok().map(|addr| addr.parse().unwrap()),
+            admin_allow_purge: env_or("ADMIN_ALLOW_PURGE", false),
+            message_budget: std::env::var("MESSAGE_BUDGET_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            batch_size: env_or("PROCESSOR_BATCH_SIZE", 1usize).max(1),
+            slow_start: Some(env_or("SLOW_START_RPS", 0.0f64))
+                .filter(|rps| *rps > 0.0)
+                .map(|initial_rate| slow_start::SlowStartConfig {
+                    initial_rate,
+                    max_rate: env_or("SLOW_START_MAX_RPS", 2_000.0),
+                    step: Duration::from_millis(env_or("SLOW_START_STEP_MS", 1_000).max(1)),
+                    max_error_rate: env_or("SLOW_START_MAX_ERROR_RATE", 0.05),
+                }),
+            dual_write_percent: env_or("DUAL_WRITE_PERCENT", 0),
+            shard,
+            processor_timeout_floor: Some(env_or("PROCESSOR_TIMEOUT_FLOOR_MS", 0u64))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            probe_offset,
+            imbalance_threshold: Some(env_or("WORKER_IMBALANCE_THRESHOLD", 1.5f64)).filter(|ratio| *ratio > 0.0),
+            retry_capacity,
+            flush_pipelines: env_or("STORE_FLUSH_PIPELINES", 1usize).max(1),
+            durability: store::Durability {
+                unlogged: env_or("STORE_UNLOGGED", false),
+                synchronous_commit: env_or("STORE_SYNCHRONOUS_COMMIT", true),
+            },
+            transactional_summary: env_or("STORE_TRANSACTIONAL_SUMMARY", true),
+            summary_table: env_or("STORE_SUMMARY_TABLE", false),
+            redis_url: std::env::var("REDIS_URL").ok(),
+            #[cfg(feature = "shm-transport")]
+            shm_ring: std::env::var("SHM_RING_PATH").ok().map(|path| ShmRingConfig {
+                path,
+                capacity: env_or("SHM_RING_CAPACITY", 16 * 1024),
+                slot_size: env_or("SHM_RING_SLOT_SIZE", 256),
+            }),
         }
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Initialize tracing with default level WARN, overridable via RUST_LOG
-    {
-        use tracing_subscriber::{EnvFilter, fmt};
-        let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn"));
-        let _ = fmt().with_env_filter(env_filter).try_init();
+    if std::env::args().nth(1).as_deref() == Some("verify") {
+        logging::init("warn");
+        let consistent = verify::run(&verify::VerifyConfig::from_env()).await?;
+        std::process::exit(if consistent { 0 } else { 1 });
+    }
+    if std::env::args().nth(1).as_deref() == Some("report") {
+        logging::init("warn");
+        let json = std::env::args().skip(2).any(|arg| arg == "--json");
+        return report::run(&report::ReportConfig::from_env(), json).await;
     }
 
+    build_info::mark_started();
+    logging::init("warn");
+    tokio::spawn(logging::watch_sigusr2());
+
+    startup::enter(startup::Phase::ConnectingDb);
     let config = WorkerConfig::from_env();
 
-    let pg_config = config.postgres_url
+    let mut pg_config = config.postgres_url
         .parse::<tokio_postgres::Config>()
         .expect("Invalid DATABASE_URL");
+    if let Some(options) = config.durability.connection_options() {
+        pg_config.options(options);
+    }
 
     let mgr = Manager::from_config(
         pg_config,
@@ -63,29 +290,162 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     );
 
     let pool = deadpool_postgres::Pool::builder(mgr)
-        .max_size(config.num_workers)
+        .max_size(config.num_workers.max(config.flush_pipelines + 1))
         .build()
         .unwrap();
+    if config.memory_backend.is_none() && !startup::wait_for_database(&pool, config.startup_db_timeout).await {
+        return Err("database did not answer before STARTUP_DB_TIMEOUT_MS".into());
+    }
+
+    // Health probes, store setup and parked retries.
+    startup::enter(startup::Phase::Warming);
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+
+    metrics::METRICS.fees.configure(&config.processors);
+    dns_cache::install(config.dns.clone());
+    let hosts: Vec<String> = config
+        .processors
+        .iter()
+        .filter_map(|processor| processor.url.as_deref()?.parse::<hyper::Uri>().ok()?.host().map(str::to_owned))
+        .collect();
+    dns_cache::resolver().prime(hosts.iter().map(String::as_str)).await;
 
     let health_monitor = HealthMonitor::new(
-        config.default_processor_url.clone().as_str(),
-        config.default_processor_url.clone().as_str()
-    );
+        &config.processors,
+        config.settings.routing.clone(),
+        clock.clone(),
+    )
+    .with_deadline_floor(config.processor_timeout_floor)
+    .with_probe_offset(config.probe_offset);
     health_monitor.start().await;
     let health_monitor = Arc::new(health_monitor);
 
-    let default_processor = Arc::new(payment_processor::PaymentProcessor::new(config.default_processor_url.clone()));
-    let fallback_processor = Arc::new(payment_processor::PaymentProcessor::new(config.fallback_processor_url.clone()));
+    let processors: Vec<_> = config
+        .processors
+        .iter()
+        .map(|processor| {
+            let latency = health_monitor.latency_tracker(processor.processor_type);
+            let deadline = health_monitor.request_deadline(processor.processor_type);
+            Arc::new(
+                payment_processor::PaymentProcessor::new(processor)
+                    .with_latency_tracker(latency)
+                    .with_request_deadline(deadline),
+            )
+        })
+        .collect();
+
+    let summary = match &config.redis_url {
+        Some(url) => Some(redis_summary::RedisSummary::connect(url).await?),
+        None => None,
+    };
+
+    let store: Arc<dyn PaymentStore> = match config.memory_backend {
+        Some(memory) => {
+            let store = Arc::new(memory_store::MemoryStore::new(memory.snapshot_path));
+            store.init(memory.snapshot_interval).await;
+            store
+        }
+        None => {
+            let mut store = store::PostgresStore::new(pool, summary)
+                .with_transactional_summary(config.transactional_summary)
+                .with_summary_table(config.summary_table)
+                .with_flush_pipelines(config.flush_pipelines)
+                .with_durability(config.durability);
+            store.init(config.startup_db_timeout).await?;
+            store.register_processor_types(config.processors.iter().map(|p| p.processor_type)).await;
+            Arc::new(store)
+        }
+    };
 
-    let mut store = store::Store::new(pool);
-    store.init().await;
-    let store = Arc::new(store);
+    let slow_start = config.slow_start.zip(config.processors.first()).map(|(slow_start, preferred)| {
+        let slow_start = Arc::new(slow_start::SlowStart::new(slow_start, preferred.processor_type, clock.clone()));
+        tokio::spawn(slow_start.clone().follow(health_monitor.watch()));
+        slow_start
+    });
 
-    let mut worker_pool = worker_pool::WorkerPool::new(config.num_workers, health_monitor, default_processor, fallback_processor, store);
+    let mut worker_pool = worker_pool::WorkerPool::new(
+        config.num_workers,
+        health_monitor.clone(),
+        processors.clone(),
+        store.clone(),
+        config.settings.retry.clone(),
+        clock,
+    )
+    .with_message_budget(config.message_budget)
+    .with_batch_size(config.batch_size)
+    .with_slow_start(slow_start)
+    .with_dual_write((config.dual_write_percent > 0).then(|| {
+        tracing::warn!(percent = config.dual_write_percent, "Dual writes on, mirrored payments reach a second processor");
+        Arc::new(dual_write::DualWrite::new(config.dual_write_percent))
+    }))
+    .with_retry_capacity(config.retry_capacity)
+    .with_shard(config.shard)
+    .with_imbalance_threshold(config.imbalance_threshold);
     worker_pool.start().await;
     let worker_pool = Arc::new(worker_pool);
 
-    let mut receiver = Receiver::new(config.listen_path, worker_pool);
+    let reloader = Arc::new(SettingsReloader::new(
+        config.settings_file,
+        health_monitor,
+        worker_pool.clone(),
+        processors,
+    ));
+    reloader.apply(&config.settings);
+    tokio::spawn(reloader.clone().watch_sighup());
+
+    if let Some(admin_socket) = config.admin_socket {
+        let admin = admin::AdminServer::new(admin_socket, reloader.clone(), worker_pool.clone())
+            .with_purge(config.admin_allow_purge);
+        tokio::spawn(async move {
+            if let Err(e) = admin.start().await {
+                tracing::error!(error = %e, "Admin server stopped");
+            }
+        });
+    }
+
+    #[cfg(feature = "shm-transport")]
+    if let Some(ring) = config.shm_ring {
+        let shm_receiver = shm_transport::ShmReceiver::new(
+            ring.path,
+            ring.capacity,
+            ring.slot_size,
+            worker_pool.clone(),
+        )?;
+        tokio::spawn(async move {
+            if let Err(e) = shm_receiver.start().await {
+                tracing::error!(error = %e, "Shared memory receiver stopped");
+            }
+        });
+    }
 
-    Ok(receiver.start().await?)
+    let receiver = Receiver::new(config.listen_path, worker_pool.clone())
+        .with_max_message_size(config.max_message_size)
+        .with_credits(config.receiver_credits);
+    // Bound only now, so producers never reach a worker that cannot take
+    // payments yet.
+    let listener = receiver.bind()?;
+    startup::enter(startup::Phase::Ready);
+
+    tokio::select! {
+        _ = receiver.serve(listener) => {}
+        _ = shutdown_signal() => tracing::info!("Shutdown requested, no longer accepting payments"),
+    }
+
+    // Order matters: in-flight processor calls must land in the store before
+    // it is flushed, otherwise a processed payment would never be recorded.
+    worker_pool.shutdown(config.shutdown_timeout).await;
+    store.flush().await;
+
+    Ok(())
+}
+
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
 }
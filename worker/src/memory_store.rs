@@ -0,0 +1,390 @@
+use crate::error::WorkerError;
+use crate::payment::Payment;
+use crate::processor_type::ProcessorType;
+use crate::store::{PaymentStore, ProcessorTotals, StoreError, StoreFuture, Summary, SummaryFilter};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Payments are spread over this many locks by correlationId.
+const SHARDS: usize = 16;
+
+/// Payments held in memory instead of Postgres (`STORE_BACKEND=memory`).
+///
+/// Amounts are kept in cents, matching `DECIMAL(10, 2)`, and every processor
+/// has running totals so an unfiltered summary needs no scan. With a
+/// snapshot path the payments are written there periodically and read back
+/// at startup. Retries are never parked: there is nowhere to spill them to.
+pub struct MemoryStore {
+    shards: Box<[Mutex<Shard>]>,
+    totals: Mutex<Vec<(ProcessorType, Arc<Totals>)>>,
+    /// Bumped on every change, so unchanged contents are not snapshotted again.
+    version: AtomicU64,
+    snapshot_path: Option<PathBuf>,
+    shutdown: watch::Sender<bool>,
+    snapshots: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Default)]
+struct Shard {
+    payments: Vec<StoredPayment>,
+    ids: HashSet<uuid::Uuid>,
+}
+
+struct StoredPayment {
+    correlation_id: uuid::Uuid,
+    processor: ProcessorType,
+    cents: i64,
+    requested_at_ms: i64,
+    run_id: Option<Arc<str>>,
+}
+
+#[derive(Default)]
+struct Totals {
+    requests: AtomicU64,
+    cents: AtomicI64,
+}
+
+impl StoredPayment {
+    fn matches(&self, filter: &SummaryFilter) -> bool {
+        filter.from.is_none_or(|from| self.requested_at_ms >= from)
+            && filter.to.is_none_or(|to| self.requested_at_ms <= to)
+            && filter
+                .run_id
+                .as_deref()
+                .is_none_or(|run_id| self.run_id.as_deref() == Some(run_id))
+    }
+}
+
+impl MemoryStore {
+    pub fn new(snapshot_path: Option<PathBuf>) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(Shard::default())).collect(),
+            totals: Mutex::new(Vec::new()),
+            version: AtomicU64::new(0),
+            snapshot_path,
+            shutdown: watch::channel(false).0,
+            snapshots: Mutex::new(None),
+        }
+    }
+
+    /// Restores the snapshot, then writes one every `snapshot_interval`
+    /// until the store is flushed.
+    pub async fn init(self: &Arc<Self>, snapshot_interval: Duration) {
+        self.restore().await;
+        // Read before spawning, so payments recorded before the loop first
+        // runs still count as changes.
+        let written = self.version.load(Ordering::Relaxed);
+        let (store, shutdown) = (self.clone(), self.shutdown.subscribe());
+        let snapshots = tokio::spawn(async move { store.snapshot_loop(snapshot_interval, shutdown, written).await });
+        *self.snapshots.lock().unwrap() = Some(snapshots);
+    }
+
+    /// Stores `payment` unless one with the same correlationId already is.
+    /// Returns whether it was added.
+    pub fn record(&self, payment: &Payment) -> bool {
+        let cents = (payment.amount * Decimal::ONE_HUNDRED).round().to_i64().unwrap_or_default();
+        self.insert(StoredPayment {
+            correlation_id: payment.correlation_id,
+            processor: payment.processor,
+            cents,
+            requested_at_ms: (payment.requested_at.unix_timestamp_nanos() / 1_000_000) as i64,
+            run_id: payment.run_id.clone(),
+        })
+    }
+
+    fn insert(&self, payment: StoredPayment) -> bool {
+        let (processor, cents) = (payment.processor, payment.cents);
+        {
+            let mut shard = self.shard(&payment.correlation_id).lock().unwrap();
+            if !shard.ids.insert(payment.correlation_id) {
+                return false;
+            }
+            shard.payments.push(payment);
+        }
+
+        let totals = self.totals_for(processor);
+        totals.requests.fetch_add(1, Ordering::Relaxed);
+        totals.cents.fetch_add(cents, Ordering::Relaxed);
+        self.version.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    fn shard(&self, correlation_id: &uuid::Uuid) -> &Mutex<Shard> {
+        &self.shards[(correlation_id.as_u128() % SHARDS as u128) as usize]
+    }
+
+    fn totals_for(&self, processor: ProcessorType) -> Arc<Totals> {
+        let mut totals = self.totals.lock().unwrap();
+        if let Some((_, found)) = totals.iter().find(|(p, _)| *p == processor) {
+            return found.clone();
+        }
+        let created = Arc::new(Totals::default());
+        totals.push((processor, created.clone()));
+        created
+    }
+
+    /// Totals per processor name. Without a filter they come from the
+    /// running counters; otherwise every payment is scanned.
+    pub fn summary(&self, filter: &SummaryFilter) -> Summary {
+        let mut summary: Summary = self
+            .totals
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(processor, totals)| {
+                let totals = if filter.is_empty() {
+                    ProcessorTotals {
+                        requests: totals.requests.load(Ordering::Relaxed),
+                        cents: totals.cents.load(Ordering::Relaxed),
+                    }
+                } else {
+                    ProcessorTotals::default()
+                };
+                (processor.to_string(), totals)
+            })
+            .collect();
+        if filter.is_empty() {
+            return summary;
+        }
+
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            for payment in shard.payments.iter().filter(|payment| payment.matches(filter)) {
+                let totals = summary.entry(payment.processor.to_string()).or_default();
+                totals.requests += 1;
+                totals.cents += payment.cents;
+            }
+        }
+        summary
+    }
+
+    /// Forgets every payment.
+    pub fn purge(&self) {
+        for shard in self.shards.iter() {
+            *shard.lock().unwrap() = Shard::default();
+        }
+        for (_, totals) in self.totals.lock().unwrap().iter() {
+            totals.requests.store(0, Ordering::Relaxed);
+            totals.cents.store(0, Ordering::Relaxed);
+        }
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Reads the snapshot back, if there is one. Run before any payment is
+    /// recorded.
+    pub async fn restore(&self) {
+        let Some(path) = &self.snapshot_path else {
+            return;
+        };
+        let contents = match tokio::fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                tracing::error!(path = %path.display(), error = %e, "Failed to read the payments snapshot");
+                return;
+            }
+        };
+
+        let mut restored = 0;
+        for line in contents.lines().filter(|line| !line.is_empty()) {
+            match parse_line(line) {
+                Some(payment) => restored += usize::from(self.insert(payment)),
+                None => tracing::warn!(line, "Skipping malformed snapshot line"),
+            }
+        }
+        tracing::warn!(payments = restored, path = %path.display(), "Restored payments from snapshot");
+    }
+
+    /// Writes a snapshot every `interval` while payments change from
+    /// version `written`, and a last one once `shutdown` is set.
+    async fn snapshot_loop(&self, interval: Duration, mut shutdown: watch::Receiver<bool>, mut written: u64) {
+        let Some(path) = &self.snapshot_path else {
+            return;
+        };
+
+        loop {
+            let stopping = tokio::select! {
+                _ = tokio::time::sleep(interval) => false,
+                _ = shutdown.wait_for(|stop| *stop) => true,
+            };
+
+            let version = self.version.load(Ordering::Relaxed);
+            if version != written {
+                match self.write_snapshot(path).await {
+                    Ok(()) => written = version,
+                    Err(e) => tracing::error!(path = %path.display(), error = %e, "Failed to write the payments snapshot"),
+                }
+            }
+            if stopping {
+                return;
+            }
+        }
+    }
+
+    /// Writes to a temporary file renamed over the snapshot, so a crash
+    /// mid-write leaves the previous snapshot intact.
+    async fn write_snapshot(&self, path: &Path) -> std::io::Result<()> {
+        let mut contents = String::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            for payment in &shard.payments {
+                write_line(&mut contents, payment);
+            }
+        }
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, contents).await?;
+        tokio::fs::rename(&tmp, path).await
+    }
+}
+
+impl PaymentStore for MemoryStore {
+    fn push_payment(&self, payment: Payment) -> Result<(), WorkerError> {
+        self.record(&payment);
+        Ok(())
+    }
+
+    /// Writes the last snapshot.
+    fn flush(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async {
+            let _ = self.shutdown.send(true);
+            let snapshots = self.snapshots.lock().unwrap().take();
+            if let Some(snapshots) = snapshots {
+                let _ = snapshots.await;
+            }
+        })
+    }
+
+    fn summary(&self, filter: SummaryFilter) -> StoreFuture<'_, Result<Summary, StoreError>> {
+        Box::pin(async move { Ok(MemoryStore::summary(self, &filter)) })
+    }
+
+    fn purge(&self) -> StoreFuture<'_, Result<(), StoreError>> {
+        Box::pin(async {
+            MemoryStore::purge(self);
+            Ok(())
+        })
+    }
+}
+
+/// `<correlationId> <processor> <cents> <requested_at_ms> <run_id or ->`.
+fn write_line(out: &mut String, payment: &StoredPayment) {
+    use std::fmt::Write;
+    let _ = writeln!(
+        out,
+        "{} {} {} {} {}",
+        payment.correlation_id,
+        payment.processor,
+        payment.cents,
+        payment.requested_at_ms,
+        payment.run_id.as_deref().unwrap_or("-"),
+    );
+}
+
+fn parse_line(line: &str) -> Option<StoredPayment> {
+    let mut fields = line.split(' ');
+    let payment = StoredPayment {
+        correlation_id: fields.next()?.parse().ok()?,
+        processor: ProcessorType::new(fields.next()?),
+        cents: fields.next()?.parse().ok()?,
+        requested_at_ms: fields.next()?.parse().ok()?,
+        run_id: match fields.next()? {
+            "-" => None,
+            run_id => Some(Arc::from(run_id)),
+        },
+    };
+    fields.next().is_none().then_some(payment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::OffsetDateTime;
+
+    fn payment(id: u128, processor: ProcessorType, cents: i64, at_ms: i64, run_id: Option<&str>) -> Payment {
+        Payment::new(
+            Decimal::new(cents, 2),
+            uuid::Uuid::from_u128(id),
+            processor,
+            OffsetDateTime::from_unix_timestamp_nanos(at_ms as i128 * 1_000_000).unwrap(),
+        )
+        .with_run_id(run_id.map(Arc::from))
+    }
+
+    fn totals(requests: u64, cents: i64) -> ProcessorTotals {
+        ProcessorTotals { requests, cents }
+    }
+
+    #[test]
+    fn summarizes_with_and_without_filters() {
+        let store = MemoryStore::new(None);
+        assert!(store.record(&payment(1, ProcessorType::DEFAULT, 1990, 1_000, Some("a"))));
+        assert!(store.record(&payment(2, ProcessorType::DEFAULT, 1000, 2_000, Some("b"))));
+        assert!(store.record(&payment(3, ProcessorType::FALLBACK, 500, 3_000, Some("a"))));
+        // Already stored.
+        assert!(!store.record(&payment(1, ProcessorType::DEFAULT, 1990, 1_000, Some("a"))));
+
+        let all = store.summary(&SummaryFilter::default());
+        assert_eq!(all["default"], totals(2, 2990));
+        assert_eq!(all["fallback"], totals(1, 500));
+
+        let window = store.summary(&SummaryFilter { from: Some(2_000), to: Some(3_000), run_id: None });
+        assert_eq!(window["default"], totals(1, 1000));
+        assert_eq!(window["fallback"], totals(1, 500));
+
+        let run = store.summary(&SummaryFilter { run_id: Some("a".to_string()), ..Default::default() });
+        assert_eq!(run["default"], totals(1, 1990));
+
+        store.purge();
+        assert_eq!(store.summary(&SummaryFilter::default())["default"], totals(0, 0));
+        assert_eq!(store.summary(&SummaryFilter { from: Some(0), ..Default::default() })["default"], totals(0, 0));
+    }
+
+    #[tokio::test]
+    async fn snapshot_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("memory-store-{}.snapshot", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let store = MemoryStore::new(Some(path.clone()));
+        store.record(&payment(1, ProcessorType::DEFAULT, 1990, 1_000, Some("a")));
+        store.record(&payment(2, ProcessorType::FALLBACK, 500, 2_000, None));
+        store.write_snapshot(&path).await.unwrap();
+
+        let restarted = MemoryStore::new(Some(path.clone()));
+        restarted.restore().await;
+        let _ = std::fs::remove_file(&path);
+
+        let all = restarted.summary(&SummaryFilter::default());
+        assert_eq!(all["default"], totals(1, 1990));
+        assert_eq!(all["fallback"], totals(1, 500));
+        let run = restarted.summary(&SummaryFilter { run_id: Some("a".to_string()), ..Default::default() });
+        assert_eq!(run["default"], totals(1, 1990));
+        assert_eq!(run["fallback"], totals(0, 0));
+    }
+
+    #[tokio::test]
+    async fn flush_writes_the_last_snapshot() {
+        let path = std::env::temp_dir().join(format!("memory-store-flush-{}.snapshot", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let store = Arc::new(MemoryStore::new(Some(path.clone())));
+        store.init(Duration::from_secs(3600)).await;
+        let backend: Arc<dyn PaymentStore> = store;
+        backend.push_payment(payment(1, ProcessorType::DEFAULT, 1990, 1_000, None)).unwrap();
+        backend.flush().await;
+
+        let restarted = MemoryStore::new(Some(path.clone()));
+        restarted.restore().await;
+        let _ = std::fs::remove_file(&path);
+        let summary = PaymentStore::summary(&restarted, SummaryFilter::default()).await.unwrap();
+        assert_eq!(summary["default"], totals(1, 1990));
+    }
+}
@@ -0,0 +1,354 @@
+use crate::processor_chain::ProcessorConfig;
+use crate::processor_type::ProcessorType;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, RwLock};
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+const PAYLOAD_SIZE_BOUNDS: &[u64] = &[64, 128, 256, 512, 1024, 2048, 4096, 8192, 16384];
+const LATENCY_MS_BOUNDS: &[u64] = &[1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000];
+const AMOUNT_BOUNDS: &[u64] = &[1, 5, 10, 20, 50, 100, 200, 500, 1000, 5000];
+
+/// Process-wide counters, rendered in the Prometheus text format.
+pub struct Metrics {
+    pub payload_size: Histogram,
+    pub pipeline_latency: Histogram,
+    /// Amounts of accepted payments, rounded up to whole units.
+    pub payment_amount: Histogram,
+    /// Payments another replica's shard owns, processed here anyway.
+    pub foreign_shard: AtomicU64,
+    /// Processor requests abandoned at their health-derived deadline.
+    pub processor_timeouts: AtomicU64,
+    /// Batch requests a processor accepted whole.
+    pub processor_batches: AtomicU64,
+    /// Batches a processor did not accept whole, their payments retried.
+    pub processor_batch_fallbacks: AtomicU64,
+    /// Payments per second the slow start lets through, `0` when not pacing.
+    pub slow_start_rate: AtomicU64,
+    pub outcomes: PaymentOutcomes,
+    pub fees: FeeTotals,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            payload_size: Histogram::new(PAYLOAD_SIZE_BOUNDS),
+            pipeline_latency: Histogram::new(LATENCY_MS_BOUNDS),
+            payment_amount: Histogram::new(AMOUNT_BOUNDS),
+            foreign_shard: AtomicU64::new(0),
+            processor_timeouts: AtomicU64::new(0),
+            processor_batches: AtomicU64::new(0),
+            processor_batch_fallbacks: AtomicU64::new(0),
+            slow_start_rate: AtomicU64::new(0),
+            outcomes: PaymentOutcomes::default(),
+            fees: FeeTotals::default(),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::with_capacity(2048);
+        self.payload_size.render(
+            &mut out,
+            "worker_payload_size_bytes",
+            "Size of messages read from producers",
+        );
+        self.pipeline_latency.render(
+            &mut out,
+            "worker_pipeline_latency_ms",
+            "Time from gateway ingest to processor acceptance",
+        );
+        self.payment_amount.render(
+            &mut out,
+            "worker_payment_amount",
+            "Amounts of payments processors accepted, rounded up",
+        );
+        let _ = writeln!(out, "# HELP worker_foreign_shard_total Payments owned by another replica, processed anyway");
+        let _ = writeln!(out, "# TYPE worker_foreign_shard_total counter");
+        let _ = writeln!(out, "worker_foreign_shard_total {}", self.foreign_shard.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP worker_processor_timeouts_total Processor requests that hit their deadline");
+        let _ = writeln!(out, "# TYPE worker_processor_timeouts_total counter");
+        let _ = writeln!(
+            out,
+            "worker_processor_timeouts_total {}",
+            self.processor_timeouts.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# HELP worker_processor_batches_total Batch requests processors accepted whole");
+        let _ = writeln!(out, "# TYPE worker_processor_batches_total counter");
+        let _ = writeln!(out, "worker_processor_batches_total {}", self.processor_batches.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP worker_processor_batch_fallbacks_total Batches not accepted whole, their payments retried");
+        let _ = writeln!(out, "# TYPE worker_processor_batch_fallbacks_total counter");
+        let _ = writeln!(
+            out,
+            "worker_processor_batch_fallbacks_total {}",
+            self.processor_batch_fallbacks.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(out, "# HELP worker_slow_start_rate Payments per second paced to the recovering processor, 0 when not pacing");
+        let _ = writeln!(out, "# TYPE worker_slow_start_rate gauge");
+        let _ = writeln!(out, "worker_slow_start_rate {}", self.slow_start_rate.load(Ordering::Relaxed));
+        self.outcomes.render(&mut out);
+        self.fees.render(&mut out);
+        out
+    }
+}
+
+/// Amounts processors accepted and what they charge for them at the fee
+/// each is configured with, so the net of a run, which is what the test
+/// scores, can be followed live while routing strategies are tried.
+#[derive(Default)]
+pub struct FeeTotals {
+    processors: RwLock<Vec<ProcessorAmounts>>,
+}
+
+struct ProcessorAmounts {
+    processor: ProcessorType,
+    fee: Decimal,
+    cents: AtomicU64,
+}
+
+impl FeeTotals {
+    /// Sets the processors counted and their fees.
+    pub fn configure(&self, processors: &[ProcessorConfig]) {
+        *self.processors.write().unwrap() = processors
+            .iter()
+            .map(|config| ProcessorAmounts {
+                processor: config.processor_type,
+                fee: config.fee,
+                cents: AtomicU64::new(0),
+            })
+            .collect();
+    }
+
+    /// A payment of `amount` accepted by `processor`.
+    pub fn record(&self, processor: ProcessorType, amount: Decimal) {
+        let cents = (amount * Decimal::ONE_HUNDRED).round().to_u64().unwrap_or_default();
+        if let Some(amounts) = self.processors.read().unwrap().iter().find(|p| p.processor == processor) {
+            amounts.cents.fetch_add(cents, Ordering::Relaxed);
+        }
+    }
+
+    /// Amount and fees per processor.
+    fn totals(&self) -> Vec<(ProcessorType, Decimal, Decimal)> {
+        self.processors
+            .read()
+            .unwrap()
+            .iter()
+            .map(|p| {
+                let amount = Decimal::new(p.cents.load(Ordering::Relaxed) as i64, 2);
+                (p.processor, amount, (amount * p.fee).round_dp(2))
+            })
+            .collect()
+    }
+
+    fn render(&self, out: &mut String) {
+        let totals = self.totals();
+        let _ = writeln!(out, "# HELP worker_processed_amount_total Amount of payments processors accepted");
+        let _ = writeln!(out, "# TYPE worker_processed_amount_total counter");
+        for (processor, amount, _) in &totals {
+            let _ = writeln!(out, "worker_processed_amount_total{{processor=\"{}\"}} {}", processor, amount);
+        }
+        let _ = writeln!(out, "# HELP worker_processed_fees_total Fees processors charge for the payments they accepted");
+        let _ = writeln!(out, "# TYPE worker_processed_fees_total counter");
+        for (processor, _, fee) in &totals {
+            let _ = writeln!(out, "worker_processed_fees_total{{processor=\"{}\"}} {}", processor, fee);
+        }
+        let net: Decimal = totals.iter().map(|(_, amount, fee)| amount - fee).sum();
+        let _ = writeln!(out, "# HELP worker_estimated_net Accepted amounts minus fees, across processors");
+        let _ = writeln!(out, "# TYPE worker_estimated_net gauge");
+        let _ = writeln!(out, "worker_estimated_net {}", net);
+    }
+}
+
+/// Attempt ranges successes are counted in: `1`, `2-5` and `6+`.
+const ATTEMPT_RANGES: [(u32, &str); 3] = [(1, "1"), (5, "2-5"), (u32::MAX, "6+")];
+
+/// How payments ended: on which attempt they succeeded, or why they were
+/// given up. Tells whether the backoff gives payments enough attempts
+/// without holding on to ones that will never go through.
+#[derive(Default)]
+pub struct PaymentOutcomes {
+    succeeded: [AtomicU64; ATTEMPT_RANGES.len()],
+    /// Still failing after the retry policy's `max_retries`.
+    exhausted: AtomicU64,
+    /// Failed in a way a retry cannot fix, such as a rejected payload.
+    rejected: AtomicU64,
+    /// Answered 422 by the processor and written to the quarantine.
+    quarantined: AtomicU64,
+    /// Needed a retry but the retry queue was full.
+    queue_full: AtomicU64,
+}
+
+pub enum Dropped {
+    Exhausted,
+    Rejected,
+    Quarantined,
+    QueueFull,
+}
+
+impl PaymentOutcomes {
+    /// A payment accepted on attempt `retry_count + 1`.
+    pub fn succeeded(&self, retry_count: u32) {
+        let attempt = retry_count.saturating_add(1);
+        let range = ATTEMPT_RANGES
+            .iter()
+            .position(|(max, _)| attempt <= *max)
+            .unwrap_or(ATTEMPT_RANGES.len() - 1);
+        self.succeeded[range].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, reason: Dropped) {
+        let counter = match reason {
+            Dropped::Exhausted => &self.exhausted,
+            Dropped::Rejected => &self.rejected,
+            Dropped::Quarantined => &self.quarantined,
+            Dropped::QueueFull => &self.queue_full,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        let name = "worker_payment_outcomes_total";
+        let _ = writeln!(out, "# HELP {} Payments by final outcome and, for successes, attempt", name);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for ((_, attempts), count) in ATTEMPT_RANGES.iter().zip(&self.succeeded) {
+            let _ = writeln!(
+                out,
+                "{}{{outcome=\"succeeded\",attempts=\"{}\"}} {}",
+                name,
+                attempts,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        for (outcome, count) in [
+            ("exhausted", &self.exhausted),
+            ("rejected", &self.rejected),
+            ("quarantined", &self.quarantined),
+            ("queue_full", &self.queue_full),
+        ] {
+            let _ = writeln!(out, "{}{{outcome=\"dropped_{}\"}} {}", name, outcome, count.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Fixed-bucket histogram with lock-free recording.
+pub struct Histogram {
+    bounds: &'static [u64],
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [u64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let index = self.bounds.partition_point(|bound| *bound < value);
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Upper bound of the bucket containing the given quantile, or `None`
+    /// when nothing was recorded or the quantile falls in the overflow bucket.
+    pub fn quantile_bound(&self, quantile: f64) -> Option<u64> {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return None;
+        }
+
+        let target = (count as f64 * quantile).ceil() as u64;
+        let mut seen = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= target {
+                return self.bounds.get(index).copied();
+            }
+        }
+        None
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        let mut cumulative = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            match self.bounds.get(index) {
+                Some(bound) => {
+                    let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+                }
+                None => {
+                    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+                }
+            }
+        }
+
+        let _ = writeln!(out, "{}_sum {}", name, self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}_count {}", name, self.count.load(Ordering::Relaxed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn net_is_what_processors_keep_after_fees() {
+        let fees = FeeTotals::default();
+        fees.configure(&[
+            ProcessorConfig::new(ProcessorType::DEFAULT, "http://default", Decimal::new(5, 2)),
+            ProcessorConfig::new(ProcessorType::FALLBACK, "http://fallback", Decimal::new(15, 2)),
+        ]);
+        fees.record(ProcessorType::DEFAULT, Decimal::new(1990, 2));
+        fees.record(ProcessorType::DEFAULT, Decimal::new(1000, 2));
+        fees.record(ProcessorType::FALLBACK, Decimal::new(1000, 2));
+
+        let mut out = String::new();
+        fees.render(&mut out);
+        assert!(out.contains("worker_processed_amount_total{processor=\"default\"} 29.90\n"));
+        assert!(out.contains("worker_processed_fees_total{processor=\"default\"} 1.50\n"));
+        assert!(out.contains("worker_processed_fees_total{processor=\"fallback\"} 1.50\n"));
+        assert!(out.contains("worker_estimated_net 36.90\n"));
+    }
+
+    #[test]
+    fn successes_are_counted_by_attempt_range() {
+        let outcomes = PaymentOutcomes::default();
+        for retry_count in [0, 0, 1, 4, 5, 30, u32::MAX] {
+            outcomes.succeeded(retry_count);
+        }
+
+        let mut out = String::new();
+        outcomes.render(&mut out);
+        assert!(out.contains("worker_payment_outcomes_total{outcome=\"succeeded\",attempts=\"1\"} 2\n"));
+        assert!(out.contains("worker_payment_outcomes_total{outcome=\"succeeded\",attempts=\"2-5\"} 2\n"));
+        assert!(out.contains("worker_payment_outcomes_total{outcome=\"succeeded\",attempts=\"6+\"} 3\n"));
+    }
+
+    #[test]
+    fn drops_are_counted_by_reason() {
+        let outcomes = PaymentOutcomes::default();
+        outcomes.dropped(Dropped::Exhausted);
+        outcomes.dropped(Dropped::Exhausted);
+        outcomes.dropped(Dropped::Quarantined);
+        outcomes.dropped(Dropped::QueueFull);
+
+        let mut out = String::new();
+        outcomes.render(&mut out);
+        assert!(out.contains("worker_payment_outcomes_total{outcome=\"dropped_exhausted\"} 2\n"));
+        assert!(out.contains("worker_payment_outcomes_total{outcome=\"dropped_rejected\"} 0\n"));
+        assert!(out.contains("worker_payment_outcomes_total{outcome=\"dropped_quarantined\"} 1\n"));
+        assert!(out.contains("worker_payment_outcomes_total{outcome=\"dropped_queue_full\"} 1\n"));
+        assert!(out.contains("worker_payment_outcomes_total{outcome=\"succeeded\",attempts=\"1\"} 0\n"));
+    }
+}
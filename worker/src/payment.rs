@@ -1,5 +1,6 @@
 ﻿use crate::processor_type::ProcessorType;
 use rust_decimal::Decimal;
+use std::sync::Arc;
 use time::OffsetDateTime;
 
 #[derive(Debug, Clone)]
@@ -8,6 +9,8 @@ pub struct Payment {
     pub correlation_id: uuid::Uuid,
     pub requested_at: OffsetDateTime,
     pub processor: ProcessorType,
+    /// Test run the payment belongs to, stored as `payments.run_id`.
+    pub run_id: Option<Arc<str>>,
 }
 
 impl Payment {
@@ -22,6 +25,12 @@ impl Payment {
             correlation_id,
             processor,
             requested_at: now,
+            run_id: None,
         }
     }
+
+    pub fn with_run_id(mut self, run_id: Option<Arc<str>>) -> Self {
+        self.run_id = run_id;
+        self
+    }
 }
@@ -1,21 +1,168 @@
-﻿use rust_decimal::Decimal;
+﻿use crate::error::WorkerError;
+use crate::processor_type::ProcessorType;
+use rust_decimal::Decimal;
 use serde::Deserialize;
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+/// Largest amount the `payments.amount` column (`DECIMAL(10, 2)`) holds.
+fn max_amount() -> Decimal {
+    Decimal::new(9_999_999_999, 2)
+}
+
+/// A payment as the gateway publishes it. This is the only type bound to
+/// the wire format; [`PaymentMessage`] is what the worker queues.
 #[derive(Debug, Deserialize)]
+pub struct WirePayment {
+    amount: Decimal,
+    #[serde(rename = "correlationId")]
+    correlation_id: uuid::Uuid,
+    /// Gateway receive time in microseconds since the unix epoch.
+    #[serde(rename = "ingestTs", default)]
+    ingest_ts: Option<u64>,
+    /// Test run the gateway tagged the payment with.
+    #[serde(rename = "runId", default)]
+    run_id: Option<String>,
+    /// `X-Request-Id` of the HTTP request that carried the payment.
+    #[serde(rename = "requestId", default)]
+    request_id: Option<String>,
+    /// Unix epoch microseconds after which the payment is treated as late.
+    #[serde(rename = "deadlineTs", default)]
+    deadline_ts: Option<u64>,
+}
+
+#[derive(Debug)]
 pub struct PaymentMessage {
     pub amount: Decimal,
-    #[serde(rename = "correlationId")]
     pub correlation_id: uuid::Uuid,
-    #[serde(default)]
     pub retry_count: u32,
+    /// Gateway receive time in microseconds since the unix epoch.
+    pub ingest_ts: Option<u64>,
+    pub run_id: Option<Arc<str>>,
+    /// Logged with the payment, to follow it from the load balancer on. Not
+    /// kept for retries spilled to Postgres.
+    pub request_id: Option<Box<str>>,
+    /// Microseconds since the unix epoch after which the payment is late
+    /// (`GATEWAY_REQUEST_BUDGET_MS`). Like `request_id`, not kept for
+    /// retries spilled to Postgres.
+    pub deadline_ts: Option<u64>,
+    /// Processor an attempt timed out on. Every later attempt goes there
+    /// too, so the payment cannot end up taken by two processors.
+    pub pinned: Option<ProcessorType>,
 }
 
-impl PaymentMessage {
-    pub fn new(amount: Decimal, correlation_id: uuid::Uuid) -> Self {
-        Self {
-            amount,
-            correlation_id,
-            retry_count: 0,
+impl TryFrom<WirePayment> for PaymentMessage {
+    type Error = WorkerError;
+
+    fn try_from(wire: WirePayment) -> Result<Self, Self::Error> {
+        if wire.amount <= Decimal::ZERO {
+            return Err(WorkerError::RejectedMessage("amount must be positive"));
+        }
+        if wire.amount > max_amount() {
+            return Err(WorkerError::RejectedMessage("amount is too large"));
         }
+        if wire.amount.normalize().scale() > 2 {
+            return Err(WorkerError::RejectedMessage("amount is finer than a cent"));
+        }
+        if wire.correlation_id.is_nil() {
+            return Err(WorkerError::RejectedMessage("correlationId is nil"));
+        }
+
+        Ok(Self {
+            amount: wire.amount,
+            correlation_id: wire.correlation_id,
+            retry_count: 0,
+            ingest_ts: wire.ingest_ts,
+            run_id: wire.run_id.map(intern_run_id),
+            request_id: wire.request_id.map(String::into_boxed_str),
+            deadline_ts: wire.deadline_ts,
+            pinned: None,
+        })
+    }
+}
+
+thread_local! {
+    static LAST_RUN_ID: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+/// Consecutive messages almost always share a run id, so the last one seen
+/// is reused rather than allocating an `Arc` per payment.
+fn intern_run_id(run_id: String) -> Arc<str> {
+    LAST_RUN_ID.with_borrow_mut(|last| match last {
+        Some(last) if **last == *run_id => last.clone(),
+        _ => last.insert(Arc::from(run_id)).clone(),
+    })
+}
+
+impl PaymentMessage {
+    /// Decodes and validates a single published payment.
+    pub fn decode(frame: &[u8]) -> Result<Self, WorkerError> {
+        serde_json::from_slice::<WirePayment>(frame)
+            .map_err(WorkerError::InvalidMessage)?
+            .try_into()
+    }
+
+    /// Decodes a batched frame, a JSON array of published payments. Entries
+    /// that fail validation are returned as errors in place.
+    pub fn decode_batch(frame: &[u8]) -> Result<Vec<Result<Self, WorkerError>>, WorkerError> {
+        let batch = serde_json::from_slice::<Vec<WirePayment>>(frame).map_err(WorkerError::InvalidMessage)?;
+        Ok(batch.into_iter().map(Self::try_from).collect())
+    }
+
+    /// Whether more than `budget` has passed since the gateway received the
+    /// message. Messages without an ingest timestamp never expire.
+    pub fn is_past_deadline(&self, budget: Duration) -> bool {
+        let Some(ingest_ts) = self.ingest_ts else {
+            return false;
+        };
+        unix_micros() > ingest_ts.saturating_add(budget.as_micros() as u64)
+    }
+
+    /// Whether the deadline the gateway set has passed. The gateway answered
+    /// 202 before publishing, so the payment is still processed, only routed
+    /// like one past the worker's own budget.
+    pub fn is_expired(&self) -> bool {
+        self.deadline_ts.is_some_and(|deadline_ts| unix_micros() > deadline_ts)
+    }
+}
+
+fn unix_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_amount(amount: &str) -> Result<PaymentMessage, WorkerError> {
+        let frame = format!(r#"{{"amount":{amount},"correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b6"}}"#);
+        PaymentMessage::decode(frame.as_bytes())
+    }
+
+    #[test]
+    fn decode_accepts_the_largest_amount_the_column_holds() {
+        let message = decode_amount("99999999.99").unwrap();
+        assert_eq!(message.amount, Decimal::new(9_999_999_999, 2));
+    }
+
+    #[test]
+    fn decode_rejects_amounts_over_the_column_limit() {
+        assert!(matches!(
+            decode_amount("100000000.00"),
+            Err(WorkerError::RejectedMessage("amount is too large"))
+        ));
+    }
+
+    #[test]
+    fn decode_rejects_amounts_finer_than_a_cent() {
+        assert!(matches!(
+            decode_amount("19.901"),
+            Err(WorkerError::RejectedMessage("amount is finer than a cent"))
+        ));
+        assert_eq!(decode_amount("19.900").unwrap().amount, Decimal::new(1990, 2));
     }
 }
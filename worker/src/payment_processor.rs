@@ -1,36 +1,91 @@
-﻿use crate::payment::Payment;
-use bytes::Bytes;
-use http_body_util::Full;
+﻿use crate::error::WorkerError;
+use crate::health_monitor::{LatencyEwma, RequestDeadline};
+use crate::http_client::HttpClient;
+use crate::metrics::METRICS;
+use crate::payment::Payment;
+use crate::processor_chain::ProcessorConfig;
+use crate::processor_type::ProcessorType;
+#[cfg(feature = "fast-json")]
+use crate::request_body;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Body;
 use hyper::{Method, Request, StatusCode};
-use hyper_util::client::legacy::{connect::HttpConnector, Client};
 use rust_decimal::Decimal;
 use serde::Serialize;
+use std::cell::RefCell;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use time::OffsetDateTime;
 
-pub struct PaymentProcessor {
-    url: String,
-    client: Client<HttpConnector, Full<Bytes>>,
+/// Headroom reserved before serializing a request; a payment body is ~150 bytes.
+const BODY_RESERVE: usize = 256;
+const BODY_POOL_CAPACITY: usize = 16 * 1024;
+/// Most of a 422 response body kept for the quarantine; longer bodies are
+/// cut to this prefix.
+const REJECTION_BODY_LIMIT: usize = 1024;
+/// Longest a 422 response body is waited for.
+const REJECTION_BODY_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+thread_local! {
+    // Bodies are split off this buffer; once the request drops its `Bytes`
+    // the next `reserve` reclaims the space instead of allocating.
+    static BODY_POOL: RefCell<BytesMut> = RefCell::new(BytesMut::with_capacity(BODY_POOL_CAPACITY));
 }
 
-#[derive(Debug)]
-pub enum PaymentProcessorError {
-    InvalidPayment,
-    Unavailable,
+/// What the worker pool needs from a processor: implemented by
+/// [`PaymentProcessor`] and, in tests, by doubles that need no HTTP.
+pub trait Processor: Send + Sync + 'static {
+    fn processor_type(&self) -> ProcessorType;
+
+    /// Hands `payment` to the processor. The error tells whether trying
+    /// again may help, see [`WorkerError::is_retryable`].
+    fn process(&self, payment: Payment) -> impl Future<Output = Result<(), WorkerError>> + Send;
+
+    /// Hands several payments over at once, answering one result per
+    /// payment in order. Without a batch endpoint they are sent one by one.
+    fn process_batch(&self, payments: Vec<Payment>) -> impl Future<Output = Vec<Result<(), WorkerError>>> + Send {
+        process_each(self, payments)
+    }
 }
 
-impl std::fmt::Display for PaymentProcessorError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            PaymentProcessorError::Unavailable => write!(f, "processors is unavailable"),
-            PaymentProcessorError::InvalidPayment => write!(f, "invalid payment"),
-        }
+async fn process_each<P: Processor + ?Sized>(processor: &P, payments: Vec<Payment>) -> Vec<Result<(), WorkerError>> {
+    let mut results = Vec::with_capacity(payments.len());
+    for payment in payments {
+        results.push(processor.process(payment).await);
     }
+    results
+}
+
+pub struct PaymentProcessor {
+    processor_type: ProcessorType,
+    /// Payments endpoint, `None` for the no-op processor which accepts
+    /// every payment without a request.
+    url: Option<String>,
+    /// Batch endpoint, when the processor has one.
+    batch_url: Option<String>,
+    client: HttpClient<Full<Bytes>>,
+    /// Cap on concurrent requests, `0` meaning unlimited.
+    max_concurrency: AtomicUsize,
+    in_flight: Arc<AtomicUsize>,
+    /// Receives the round trip of every request that got a response.
+    latency: Option<Arc<LatencyEwma>>,
+    /// Limits each attempt, following the processor's probed health.
+    deadline: Option<Arc<RequestDeadline>>,
 }
 
-impl std::error::Error for PaymentProcessorError {}
+/// Releases an in-flight slot when the request completes or is dropped.
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
 #[derive(Debug, Serialize)]
-struct PaymentRequest {
+pub(crate) struct PaymentRequest {
     pub amount: Decimal,
     #[serde(rename = "correlationId")]
     pub correlation_id: uuid::Uuid,
@@ -49,48 +104,234 @@ impl From<Payment> for PaymentRequest {
 }
 
 impl PaymentProcessor {
-    pub fn new(url: String) -> Self {
-        let client =
-            Client::builder(hyper_util::rt::TokioExecutor::new()).build(HttpConnector::new());
-
+    pub fn new(config: &ProcessorConfig) -> Self {
         Self {
-            url: format!("{}/payments", url),
-            client,
+            processor_type: config.processor_type,
+            url: config.url.as_ref().map(|url| format!("{}/payments", url)),
+            batch_url: config.url.as_ref().filter(|_| config.batch).map(|url| format!("{}/payments/batch", url)),
+            client: HttpClient::for_url(config.url.as_deref().unwrap_or_default(), config.http2),
+            max_concurrency: AtomicUsize::new(0),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            latency: None,
+            deadline: None,
+        }
+    }
+
+    pub fn with_latency_tracker(mut self, latency: Option<Arc<LatencyEwma>>) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn with_request_deadline(mut self, deadline: Option<Arc<RequestDeadline>>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    pub fn set_max_concurrency(&self, max_concurrency: usize) {
+        self.max_concurrency.store(max_concurrency, Ordering::Relaxed);
+    }
+
+    /// Claims an in-flight slot, failing when the concurrency cap is reached.
+    fn acquire_slot(&self) -> Result<InFlightGuard, WorkerError> {
+        let previous = self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let guard = InFlightGuard(self.in_flight.clone());
+
+        let max_concurrency = self.max_concurrency.load(Ordering::Relaxed);
+        if max_concurrency > 0 && previous >= max_concurrency {
+            return Err(WorkerError::ProcessorUnavailable);
         }
+
+        Ok(guard)
     }
 
-    pub async fn process(&self, payment: Payment) -> Result<(), PaymentProcessorError> {
-        let data = PaymentRequest::from(payment);
-        let json_bytes =
-            serde_json::to_vec(&data).map_err(|_| PaymentProcessorError::InvalidPayment)?;
+    fn serialize(data: &PaymentRequest) -> Result<Bytes, WorkerError> {
+        BODY_POOL.with_borrow_mut(|pool| {
+            pool.reserve(BODY_RESERVE);
+            #[cfg(feature = "fast-json")]
+            if request_body::write_payment_request(pool, &data.amount, &data.correlation_id, data.requested_at) {
+                return Ok(pool.split().freeze());
+            }
+            if serde_json::to_writer((&mut *pool).writer(), data).is_err() {
+                pool.clear();
+                return Err(WorkerError::InvalidPayment);
+            }
+            Ok(pool.split().freeze())
+        })
+    }
 
-        let body = Full::new(Bytes::from(json_bytes));
+    /// Posts `payments` as one JSON array, succeeding only when the
+    /// processor accepts all of them. The round trip is not fed to the
+    /// latency tracker, which follows single payments.
+    async fn send_batch(&self, url: &str, payments: &[Payment]) -> Result<(), WorkerError> {
+        let _slot = self.acquire_slot()?;
+        let mut body = BytesMut::with_capacity(payments.len() * BODY_RESERVE);
+        body.put_u8(b'[');
+        for (i, payment) in payments.iter().enumerate() {
+            if i > 0 {
+                body.put_u8(b',');
+            }
+            body.put_slice(&Self::serialize(&PaymentRequest::from(payment.clone()))?);
+        }
+        body.put_u8(b']');
 
         let req = Request::builder()
             .method(Method::POST)
-            .uri(&self.url)
+            .uri(url)
+            .header("content-type", "application/json")
+            .body(Full::new(body.freeze()))
+            .map_err(|_| WorkerError::InvalidPayment)?;
+
+        let request = self.client.request(req);
+        let response = match self.deadline.as_deref().and_then(RequestDeadline::current) {
+            Some(deadline) => tokio::time::timeout(deadline, request).await.map_err(|_| {
+                METRICS.processor_timeouts.fetch_add(1, Ordering::Relaxed);
+                WorkerError::ProcessorTimeout(self.processor_type)
+            })?,
+            None => request.await,
+        }
+        .map_err(|_| WorkerError::ProcessorUnavailable)?;
+
+        if !response.status().is_success() {
+            return Err(WorkerError::ProcessorUnavailable);
+        }
+        Ok(())
+    }
+}
+
+impl Processor for PaymentProcessor {
+    fn processor_type(&self) -> ProcessorType {
+        self.processor_type
+    }
+
+    async fn process(&self, payment: Payment) -> Result<(), WorkerError> {
+        let _slot = self.acquire_slot()?;
+        let Some(url) = &self.url else {
+            return Ok(());
+        };
+        let body = Full::new(Self::serialize(&PaymentRequest::from(payment))?);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url)
             .header("content-type", "application/json")
             .body(body)
-            .map_err(|_| PaymentProcessorError::InvalidPayment)?;
+            .map_err(|_| WorkerError::InvalidPayment)?;
 
-        let response = self
-            .client
-            .request(req)
-            .await
-            .map_err(|_| PaymentProcessorError::Unavailable)?;
+        let started = std::time::Instant::now();
+        let request = self.client.request(req);
+        let response = match self.deadline.as_deref().and_then(RequestDeadline::current) {
+            Some(deadline) => tokio::time::timeout(deadline, request).await.map_err(|_| {
+                METRICS.processor_timeouts.fetch_add(1, Ordering::Relaxed);
+                WorkerError::ProcessorTimeout(self.processor_type)
+            })?,
+            None => request.await,
+        }
+        .map_err(|_| WorkerError::ProcessorUnavailable)?;
+        if let Some(latency) = &self.latency {
+            latency.observe(started.elapsed());
+        }
         let status = response.status();
 
+        if status == StatusCode::CONFLICT {
+            return Err(WorkerError::AlreadyProcessed);
+        }
+
         if status == StatusCode::UNPROCESSABLE_ENTITY {
-            return Err(PaymentProcessorError::InvalidPayment);
+            // The body says what the processor disliked; without it a schema
+            // mismatch is hard to tell from a bad payment.
+            let body = read_prefix(response.into_body(), REJECTION_BODY_LIMIT);
+            let body = tokio::time::timeout(REJECTION_BODY_TIMEOUT, body).await.unwrap_or_default();
+            return Err(WorkerError::Unprocessable(body));
         }
 
-        if (status >= StatusCode::INTERNAL_SERVER_ERROR
+        if status >= StatusCode::INTERNAL_SERVER_ERROR
             || status == StatusCode::TOO_MANY_REQUESTS
-            || status == StatusCode::REQUEST_TIMEOUT)
+            || status == StatusCode::REQUEST_TIMEOUT
         {
-            return Err(PaymentProcessorError::Unavailable);
+            return Err(WorkerError::ProcessorUnavailable);
         }
 
         Ok(())
     }
+
+    /// One request for the whole batch when the processor has a batch
+    /// endpoint. Anything short of accepting all of it fails every payment
+    /// with the batch's error, handing them to the retry and failover path
+    /// like a single payment that failed; after a timeout they are retried
+    /// on this processor, which answers the ones it did take as duplicates.
+    async fn process_batch(&self, payments: Vec<Payment>) -> Vec<Result<(), WorkerError>> {
+        let Some(batch_url) = self.batch_url.as_ref().filter(|_| payments.len() > 1) else {
+            return process_each(self, payments).await;
+        };
+
+        match self.send_batch(batch_url, &payments).await {
+            Ok(()) => {
+                METRICS.processor_batches.fetch_add(1, Ordering::Relaxed);
+                payments.iter().map(|_| Ok(())).collect()
+            }
+            Err(e) => {
+                METRICS.processor_batch_fallbacks.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!(processor = %self.processor_type, error = %e, size = payments.len(), "Batch not accepted, retrying its payments");
+                payments
+                    .iter()
+                    .map(|_| match e {
+                        WorkerError::ProcessorTimeout(processor) => Err(WorkerError::ProcessorTimeout(processor)),
+                        _ => Err(WorkerError::ProcessorUnavailable),
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Reads at most `limit` bytes of `body`, dropping the rest. A body that
+/// fails midway keeps what arrived before the error.
+async fn read_prefix<B>(mut body: B, limit: usize) -> Bytes
+where
+    B: Body + Unpin,
+    B::Data: Buf,
+{
+    let mut prefix = BytesMut::new();
+    while prefix.len() < limit {
+        let Some(Ok(frame)) = body.frame().await else { break };
+        if let Ok(mut data) = frame.into_data() {
+            let take = data.remaining().min(limit - prefix.len());
+            prefix.put(data.copy_to_bytes(take));
+        }
+    }
+    prefix.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use http_body_util::StreamBody;
+    use hyper::body::Frame;
+
+    fn chunked(chunks: Vec<Result<&'static [u8], std::io::Error>>) -> impl Body<Data = Bytes, Error = std::io::Error> + Unpin {
+        StreamBody::new(stream::iter(chunks.into_iter().map(|c| c.map(|c| Frame::data(Bytes::from_static(c))))))
+    }
+
+    #[tokio::test]
+    async fn read_prefix_keeps_short_bodies_whole() {
+        let body = read_prefix(Full::new(Bytes::from_static(b"amount: invalid")), REJECTION_BODY_LIMIT).await;
+        assert_eq!(&body[..], b"amount: invalid");
+    }
+
+    #[tokio::test]
+    async fn read_prefix_cuts_long_bodies_to_the_limit() {
+        let long = vec![b'x'; 3 * REJECTION_BODY_LIMIT];
+        let body = read_prefix(Full::new(Bytes::from(long)), REJECTION_BODY_LIMIT).await;
+        assert_eq!(body.len(), REJECTION_BODY_LIMIT);
+
+        let body = read_prefix(chunked(vec![Ok(b"abcd"), Ok(b"efgh"), Ok(b"ijkl")]), 6).await;
+        assert_eq!(&body[..], b"abcdef");
+    }
+
+    #[tokio::test]
+    async fn read_prefix_keeps_what_arrived_before_an_error() {
+        let body = chunked(vec![Ok(b"abc"), Err(std::io::Error::other("reset")), Ok(b"def")]);
+        assert_eq!(&read_prefix(body, 16).await[..], b"abc");
+    }
 }
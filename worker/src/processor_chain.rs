@@ -0,0 +1,126 @@
+use crate::processor_type::ProcessorType;
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+/// Url that selects the internal no-op processor, which accepts every
+/// payment without a network call. Useful for load testing the worker.
+const NOOP_URL: &str = "noop";
+
+/// One entry of the failover chain.
+#[derive(Debug, Clone)]
+pub struct ProcessorConfig {
+    pub processor_type: ProcessorType,
+    /// Base url of the processor, `None` for the no-op processor.
+    pub url: Option<String>,
+    /// Fraction of each payment the processor charges.
+    pub fee: Decimal,
+    /// Whether `/payments/service-health` is polled. Unprobed processors are
+    /// always considered healthy.
+    pub probe_health: bool,
+    /// Speak h2c (HTTP/2 with prior knowledge) to the processor, which must
+    /// support it. Ignored for `https://` urls.
+    pub http2: bool,
+    /// The processor takes several payments per request on
+    /// `/payments/batch`; see [`crate::payment_processor::Processor::process_batch`].
+    pub batch: bool,
+}
+
+impl ProcessorConfig {
+    pub fn new(processor_type: ProcessorType, url: &str, fee: Decimal) -> Self {
+        let url = (url != NOOP_URL).then(|| url.to_string());
+        Self {
+            processor_type,
+            probe_health: url.is_some(),
+            url,
+            fee,
+            http2: false,
+            batch: false,
+        }
+    }
+}
+
+/// Parses `PROCESSORS`, a comma separated list of `name=url` entries with
+/// optional `;fee=<fraction>`, `;health=off`, `;http2=on` and `;batch=on`
+/// options, e.g.
+/// `default=http://pp-default:8080;fee=0.05,fallback=http://pp-fallback:8080;fee=0.15`.
+///
+/// The chain is ordered cheapest first, keeping the configured order for
+/// equal fees; routing prefers earlier processors.
+pub fn parse_chain(spec: &str) -> Result<Vec<ProcessorConfig>, String> {
+    let mut chain = spec
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(parse_entry)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if chain.is_empty() {
+        return Err("PROCESSORS lists no processors".to_string());
+    }
+
+    for (i, config) in chain.iter().enumerate() {
+        if chain[..i].iter().any(|c| c.processor_type == config.processor_type) {
+            return Err(format!("Processor {} is listed twice", config.processor_type));
+        }
+    }
+
+    chain.sort_by_key(|config| config.fee);
+    Ok(chain)
+}
+
+fn parse_entry(entry: &str) -> Result<ProcessorConfig, String> {
+    let mut parts = entry.split(';');
+    let (name, url) = parts
+        .next()
+        .and_then(|head| head.split_once('='))
+        .ok_or_else(|| format!("Invalid processor entry: {}", entry))?;
+
+    // The name becomes a `service_type` label, which the store adds with
+    // unquoted DDL.
+    let name = name.trim();
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_') {
+        return Err(format!("Invalid processor name: {}", name));
+    }
+
+    let mut config = ProcessorConfig::new(ProcessorType::new(name), url.trim(), Decimal::ZERO);
+    for option in parts {
+        match option.trim().split_once('=') {
+            Some(("fee", fee)) => {
+                config.fee = Decimal::from_str(fee.trim()).map_err(|e| format!("Invalid fee for {}: {}", name, e))?;
+            }
+            Some(("health", "off")) => config.probe_health = false,
+            Some(("health", "on")) => config.probe_health = config.url.is_some(),
+            Some(("http2", "on")) => config.http2 = true,
+            Some(("http2", "off")) => config.http2 = false,
+            Some(("batch", "on")) => config.batch = true,
+            Some(("batch", "off")) => config.batch = false,
+            _ => return Err(format!("Invalid option for {}: {}", name, option)),
+        }
+    }
+
+    Ok(config)
+}
+
+/// The classic two-processor chain, used when `PROCESSORS` is not set.
+pub fn default_and_fallback(default_url: &str, fallback_url: &str) -> Vec<ProcessorConfig> {
+    vec![
+        ProcessorConfig::new(ProcessorType::DEFAULT, default_url, Decimal::ZERO),
+        ProcessorConfig::new(ProcessorType::FALLBACK, fallback_url, Decimal::ZERO),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http2_is_opted_into_per_processor() {
+        let chain = parse_chain("default=http://pp-default:8080;http2=on,fallback=http://pp-fallback:8080").unwrap();
+        assert!(chain[0].http2);
+        assert!(!chain[1].http2);
+
+        let chain = parse_chain("default=http://pp-default:8080;http2=on;http2=off").unwrap();
+        assert!(!chain[0].http2);
+        assert!(parse_chain("default=http://pp-default:8080;http2=yes").is_err());
+    }
+}
@@ -1,20 +1,50 @@
 ﻿use bytes::BytesMut;
 use std::error::Error;
 use std::fmt;
+use std::sync::Mutex;
 use tokio_postgres::types::{IsNull, ToSql, Type};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub enum ProcessorType {
-    Default,
-    Fallback,
+/// Name a processor's payments are recorded under, matching a label of the
+/// `service_type` enum. Names are interned when the processor chain is
+/// configured so the type stays `Copy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProcessorType(&'static str);
+
+/// Names interned so far, besides the built-in ones.
+static INTERNED: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+impl ProcessorType {
+    pub const DEFAULT: ProcessorType = ProcessorType("default");
+    pub const FALLBACK: ProcessorType = ProcessorType("fallback");
+
+    /// Interns `name`, leaking it the first time it is seen unless it is
+    /// one of the built-in names.
+    pub fn new(name: &str) -> Self {
+        match name {
+            "default" => Self::DEFAULT,
+            "fallback" => Self::FALLBACK,
+            other => {
+                let mut interned = INTERNED.lock().unwrap();
+                match interned.iter().find(|known| **known == other) {
+                    Some(known) => ProcessorType(known),
+                    None => {
+                        let leaked: &'static str = Box::leak(other.to_string().into_boxed_str());
+                        interned.push(leaked);
+                        ProcessorType(leaked)
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
 }
 
 impl fmt::Display for ProcessorType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            ProcessorType::Default => write!(f, "default"),
-            ProcessorType::Fallback => write!(f, "fallback"),
-        }
+        f.write_str(self.0)
     }
 }
 
@@ -23,11 +53,7 @@ impl ToSql for ProcessorType {
     where
         Self: Sized,
     {
-        let s = match self {
-            ProcessorType::Default => "default",
-            ProcessorType::Fallback => "fallback",
-        };
-        s.to_sql(ty, out)
+        self.0.to_sql(ty, out)
     }
 
     fn accepts(ty: &Type) -> bool
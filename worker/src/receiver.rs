@@ -1,48 +1,102 @@
-﻿use crate::worker_pool::WorkerPool;
+﻿use crate::error::WorkerError;
+use common::lz4::{self, Lz4Error};
+use crate::payment_message::PaymentMessage;
+use crate::worker_pool::WorkerPool;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use bytes::Bytes;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use crate::metrics::METRICS;
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::OwnedWriteHalf;
 use tokio::net::{UnixListener, UnixStream};
 use tokio::sync::Semaphore;
 
+const DEFAULT_READ_CAPACITY: usize = 8192;
+const MIN_READ_SPACE: usize = 512;
+const READ_BATCH_MESSAGES: u64 = 32;
+/// Default cap on a single line, batched frames included.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 256 * 1024;
+/// Byte written back to a producer per message it may send.
+const CREDIT: u8 = b'+';
+/// First byte of a producer that sends LZ4 frames instead of plain lines.
+/// No JSON line starts with it, so plain producers are told apart by their
+/// first byte. Every write that follows is a little-endian `u32` length and
+/// one LZ4 frame holding newline-terminated lines, which count as a single
+/// message against the size limit once decompressed.
+pub const LZ4_HELLO: u8 = 0x04;
+/// Written back once compression is accepted, after any opening credits.
+const LZ4_ACK: u8 = b'z';
+/// How often credits held back by full worker queues are reconsidered.
+const CREDIT_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+
 pub struct Receiver {
     socket_path: String,
     workers: Arc<WorkerPool>,
-    conn_sem: Arc<Semaphore>
+    conn_sem: Arc<Semaphore>,
+    max_message_size: usize,
+    credits: Option<usize>,
+    /// Producer connections under flow control, which split the queue
+    /// headroom between them.
+    producers: Arc<AtomicUsize>,
 }
 
-#[derive(Debug)]
-pub enum ReceiverError {
-    SocketError(std::io::Error),
-}
+/// Counts a producer connection under flow control while it lasts.
+struct ProducerGuard(Arc<AtomicUsize>);
 
-impl std::fmt::Display for ReceiverError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ReceiverError::SocketError(e) => write!(f, "Socket error: {}", e),
-        }
+impl ProducerGuard {
+    fn new(producers: &Arc<AtomicUsize>) -> Self {
+        producers.fetch_add(1, Ordering::Relaxed);
+        Self(producers.clone())
     }
 }
 
-impl std::error::Error for ReceiverError {}
+impl Drop for ProducerGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
 impl Receiver {
     pub fn new(socket_path: String, workers: Arc<WorkerPool>) -> Self {
         Self {
             socket_path,
             workers,
-            conn_sem: Arc::new(Semaphore::new(512))
+            conn_sem: Arc::new(Semaphore::new(512)),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            credits: None,
+            producers: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    pub async fn start(&mut self) -> Result<(), ReceiverError> {
+    /// Producers sending a line longer than `max_message_size` bytes are
+    /// disconnected rather than buffered without bound.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Grants each producer connection `credits` lines up front, one
+    /// [`CREDIT`] byte each, and hands a credit back per line only once the
+    /// worker queues have room for it. A connection never holds more than
+    /// its share of that room, split evenly between the connections.
+    /// Producers must stop sending when they run out, so a burst backs up
+    /// into the gateway instead of overflowing the queues.
+    pub fn with_credits(mut self, credits: Option<usize>) -> Self {
+        self.credits = credits;
+        self
+    }
+
+    /// Binds the producer socket. Producers can connect from here on, so
+    /// this comes once the pool and store are running; [`Self::serve`]
+    /// then accepts them.
+    pub fn bind(&self) -> Result<UnixListener, WorkerError> {
         tracing::info!("Starting receiver");
         if std::fs::metadata(&self.socket_path).is_ok() {
             let _ = std::fs::remove_file(&self.socket_path);
         }
 
-        let listener = UnixListener::bind(&self.socket_path).map_err(ReceiverError::SocketError)?;
+        let listener = UnixListener::bind(&self.socket_path).map_err(WorkerError::Socket)?;
 
         if let Err(e) = std::fs::set_permissions(
             &self.socket_path,
@@ -51,13 +105,14 @@ impl Receiver {
             tracing::warn!(error = %e, "Failed to set permissions on socket");
         }
 
-        self.accept_loop(listener).await;
-
-        Ok(())
+        Ok(listener)
     }
 
-    async fn accept_loop(&self, listener: UnixListener) {
+    pub async fn serve(&self, listener: UnixListener) {
         let workers = Arc::clone(&self.workers);
+        let max_message_size = self.max_message_size;
+        let credits = self.credits;
+        let producers = &self.producers;
 
         tracing::info!("Listening on {}", self.socket_path);
 
@@ -69,10 +124,11 @@ impl Receiver {
 
                     let workers_clone = Arc::clone(&workers);
                     let semaphore = Arc::clone(&self.conn_sem);
+                    let producers = Arc::clone(producers);
 
                     tokio::task::spawn(async move {
                         let _permit = semaphore.acquire().await.unwrap();
-                        Self::read_producer(stream, workers_clone).await;
+                        Self::read_producer(stream, workers_clone, max_message_size, credits, producers).await;
                     });
                 }
                 Err(e) => {
@@ -83,29 +139,103 @@ impl Receiver {
         }
     }
 
-    async fn read_producer(stream: UnixStream, workers: Arc<WorkerPool>) {
-        let mut reader = BufReader::with_capacity(8192, stream);
-        let mut buffer = Vec::with_capacity(1024);
+    /// Reads newline-terminated frames. A frame may arrive over any number
+    /// of reads; `scanned` remembers how much of the pending partial line
+    /// was already searched so each byte is scanned once. Producers opening
+    /// with [`LZ4_HELLO`] fill `compressed` instead, and their lines are
+    /// added to `buffer` a whole LZ4 frame at a time.
+    async fn read_producer(
+        stream: UnixStream,
+        workers: Arc<WorkerPool>,
+        max_message_size: usize,
+        credits: Option<usize>,
+        producers: Arc<AtomicUsize>,
+    ) {
+        let capacity = Self::suggested_capacity();
+        let mut buffer = BytesMut::with_capacity(capacity);
+        let mut scanned = 0;
+        let (mut reader, mut writer) = stream.into_split();
+        // Credits spent by the producer and not yet handed back.
+        let mut owed = 0;
+        let mut compressed: Option<BytesMut> = None;
+        let mut first_read = true;
+
+        let _producer = credits.map(|_| ProducerGuard::new(&producers));
+        let window = credits.unwrap_or_default();
+        if credits.is_some()
+            && let Err(e) = Self::grant(&mut writer, window).await
+        {
+            tracing::warn!(error = %e, "Failed to grant credits to producer");
+            return;
+        }
 
         loop {
-            match reader.read_until(b'\n', &mut buffer).await {
+            let target = compressed.as_mut().unwrap_or(&mut buffer);
+            if target.capacity() - target.len() < MIN_READ_SPACE {
+                target.reserve(capacity);
+            }
+
+            let read = tokio::select! {
+                read = reader.read_buf(target) => read,
+                _ = tokio::time::sleep(CREDIT_RETRY_INTERVAL), if owed > 0 => {
+                    owed = Self::repay(&mut writer, &workers, owed, window, &producers).await;
+                    continue;
+                }
+            };
+
+            match read {
                 Ok(0) => {
                     tracing::info!("Read producer disconnected");
                     return;
                 }
                 Ok(_) => {
-                    if buffer.last() == Some(&b'\n') {
-                        buffer.pop();
+                    if std::mem::take(&mut first_read) && buffer.first() == Some(&LZ4_HELLO) {
+                        buffer.advance(1);
+                        compressed = Some(buffer.split());
+                        if let Err(e) = writer.write_all(&[LZ4_ACK]).await {
+                            tracing::warn!(error = %e, "Failed to accept compression from producer");
+                            return;
+                        }
+                        tracing::info!("Producer sends LZ4 frames");
                     }
+                    if let Some(raw) = compressed.as_mut()
+                        && let Err(e) = Self::inflate(raw, &mut buffer, max_message_size)
+                    {
+                        tracing::error!(error = %e, "Bad compressed frame, closing producer connection");
+                        return;
+                    }
+
+                    while let Some(offset) = buffer[scanned..].iter().position(|b| *b == b'\n') {
+                        let end = scanned + offset;
+                        if end > max_message_size {
+                            tracing::error!(size = end, max_message_size, "Message too large, closing producer connection");
+                            return;
+                        }
+                        let frame = buffer.split_to(end + 1).freeze().slice(..end);
+                        scanned = 0;
 
-                    if !buffer.is_empty() {
-                        let bytes = Bytes::copy_from_slice(&buffer);
-                        if let Err(e) = workers.submit(bytes).await {
-                            tracing::warn!(error = %e, "Failed to submit message to worker pool");
+                        METRICS.payload_size.observe(frame.len() as u64);
+                        Self::dispatch(frame, &workers).await;
+                        // The producer spent a credit on the line whatever
+                        // it held, so it is owed back even when it did not
+                        // decode.
+                        if credits.is_some() {
+                            owed += 1;
                         }
                     }
+                    if owed > 0 {
+                        owed = Self::repay(&mut writer, &workers, owed, window, &producers).await;
+                    }
+                    scanned = buffer.len();
 
-                    buffer.clear();
+                    if scanned > max_message_size {
+                        tracing::error!(
+                            buffered = scanned,
+                            max_message_size,
+                            "No message delimiter within the size limit, closing producer connection"
+                        );
+                        return;
+                    }
                 }
                 Err(e) => {
                     tracing::warn!(error = %e, "Error reading from connection");
@@ -114,4 +244,181 @@ impl Receiver {
             }
         }
     }
+
+    /// Moves the lines of every complete LZ4 frame in `raw` to `lines`.
+    fn inflate(raw: &mut BytesMut, lines: &mut BytesMut, max_message_size: usize) -> Result<(), Lz4Error> {
+        while raw.len() >= 4 {
+            let len = u32::from_le_bytes(raw[..4].try_into().unwrap()) as usize;
+            if len > max_message_size {
+                return Err(Lz4Error::TooLarge);
+            }
+            if raw.len() < 4 + len {
+                break;
+            }
+            let frame = raw.split_to(4 + len);
+            let mut decoded = Vec::with_capacity(len * 4);
+            lz4::decompress(&frame[4..], max_message_size, &mut decoded)?;
+            lines.extend_from_slice(&decoded);
+        }
+        Ok(())
+    }
+
+    /// Hands back as many of the `owed` credits as this connection's share
+    /// of the worker queue headroom has room for, counting the credits of
+    /// `window` the producer still holds, and returns how many are still
+    /// owed.
+    async fn repay(writer: &mut OwnedWriteHalf, workers: &WorkerPool, owed: usize, window: usize, producers: &AtomicUsize) -> usize {
+        let held = window.saturating_sub(owed);
+        let share = workers.headroom() / producers.load(Ordering::Relaxed).max(1);
+        let granted = owed.min(share.saturating_sub(held));
+        if granted == 0 {
+            return owed;
+        }
+        if let Err(e) = Self::grant(writer, granted).await {
+            // The read side notices the producer going away.
+            tracing::debug!(error = %e, "Failed to return credits to producer");
+        }
+        owed - granted
+    }
+
+    async fn grant(writer: &mut OwnedWriteHalf, credits: usize) -> std::io::Result<()> {
+        writer.write_all(&vec![CREDIT; credits]).await
+    }
+
+    /// Sizes new connection buffers from the observed payload distribution so
+    /// a read typically holds many messages without over-allocating.
+    fn suggested_capacity() -> usize {
+        let p99 = METRICS
+            .payload_size
+            .quantile_bound(0.99)
+            .unwrap_or(DEFAULT_READ_CAPACITY as u64 / READ_BATCH_MESSAGES);
+        (p99 as usize * READ_BATCH_MESSAGES as usize).clamp(MIN_READ_SPACE, DEFAULT_READ_CAPACITY)
+    }
+
+    /// Hands a single frame, without its trailing newline, to the worker pool.
+    pub(crate) async fn dispatch(frame: Bytes, workers: &WorkerPool) {
+        if frame.first() == Some(&b'[') {
+            return Self::submit_batch(&frame, workers).await;
+        }
+        if frame.is_empty() {
+            return;
+        }
+        if let Err(e) = workers.submit(frame).await {
+            tracing::warn!(error = %e, "Failed to submit message to worker pool");
+        }
+    }
+
+    /// A batched frame is a single line holding a JSON array of messages.
+    async fn submit_batch(frame: &[u8], workers: &WorkerPool) {
+        match PaymentMessage::decode_batch(frame) {
+            Ok(decoded) => {
+                let msgs = decoded
+                    .into_iter()
+                    .filter_map(|msg| {
+                        msg.inspect_err(|e| tracing::warn!(error = %e, "Dropping invalid message from batch"))
+                            .ok()
+                    })
+                    .collect();
+                if let Err(e) = workers.submit_batch(msgs).await {
+                    tracing::warn!(error = %e, "Failed to submit batch to worker pool");
+                }
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to decode batched frame");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    const PAYMENT: &[u8] = b"{\"amount\":1,\"correlationId\":\"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b6\"}\n";
+
+    /// A receiver granting `credits` per connection over a single queue of
+    /// `capacity` messages.
+    async fn serve(name: &str, credits: usize, capacity: usize) -> (String, mpsc::Receiver<PaymentMessage>) {
+        let path = std::env::temp_dir()
+            .join(format!("receiver-{}-{}.sock", name, std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let (pool, queue) = WorkerPool::queue_only(capacity);
+        let receiver = Receiver::new(path.clone(), Arc::new(pool)).with_credits(Some(credits));
+        let listener = receiver.bind().unwrap();
+        tokio::spawn(async move { receiver.serve(listener).await });
+        (path, queue)
+    }
+
+    /// Credits the worker sent within a short wait.
+    async fn credits(stream: &mut UnixStream) -> usize {
+        let mut received = 0;
+        let mut buf = [0; 64];
+        while let Ok(Ok(n)) = tokio::time::timeout(Duration::from_millis(50), stream.read(&mut buf)).await {
+            if n == 0 {
+                break;
+            }
+            received += buf[..n].iter().filter(|b| **b == CREDIT).count();
+        }
+        received
+    }
+
+    /// `lines` as the gateway sends them to a producer that negotiated LZ4.
+    fn compressed(lines: &[u8]) -> Vec<u8> {
+        let frame = lz4::compress(lines);
+        [&(frame.len() as u32).to_le_bytes()[..], &frame].concat()
+    }
+
+    #[test]
+    fn compressed_frames_are_inflated_once_whole() {
+        let first = compressed(&PAYMENT.repeat(3));
+        let second = compressed(PAYMENT);
+        let (head, tail) = second.split_at(second.len() - 1);
+        let mut raw = BytesMut::from(&[&first[..], head].concat()[..]);
+        let mut lines = BytesMut::new();
+
+        Receiver::inflate(&mut raw, &mut lines, 1024).unwrap();
+        assert_eq!(lines[..], PAYMENT.repeat(3)[..]);
+        assert_eq!(raw[..], head[..]);
+
+        raw.extend_from_slice(tail);
+        Receiver::inflate(&mut raw, &mut lines, 1024).unwrap();
+        assert_eq!(lines[..], PAYMENT.repeat(4)[..]);
+        assert!(raw.is_empty());
+
+        let mut raw = BytesMut::from(&first[..]);
+        let err = Receiver::inflate(&mut raw, &mut BytesMut::new(), 2 * PAYMENT.len());
+        assert!(matches!(err, Err(Lz4Error::TooLarge)));
+    }
+
+    #[tokio::test]
+    async fn lines_that_do_not_decode_are_repaid() {
+        let (path, _queue) = serve("undecodable", 2, 16).await;
+        let mut producer = UnixStream::connect(&path).await.unwrap();
+        assert_eq!(credits(&mut producer).await, 2);
+
+        producer.write_all(b"[not a batch\nnot a payment\n").await.unwrap();
+        assert_eq!(credits(&mut producer).await, 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn connections_split_the_queue_headroom() {
+        let (path, _queue) = serve("shared", 4, 8).await;
+        let mut first = UnixStream::connect(&path).await.unwrap();
+        let mut second = UnixStream::connect(&path).await.unwrap();
+        assert_eq!(credits(&mut first).await, 4);
+        assert_eq!(credits(&mut second).await, 4);
+
+        // Four of the eight slots are left, two for each connection: the
+        // first gets two of its four credits back, not all of them.
+        first.write_all(&PAYMENT.repeat(4)).await.unwrap();
+        assert_eq!(credits(&mut first).await, 2);
+
+        second.write_all(&PAYMENT.repeat(4)).await.unwrap();
+        assert_eq!(credits(&mut second).await, 0);
+        let _ = std::fs::remove_file(&path);
+    }
 }
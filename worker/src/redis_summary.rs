@@ -0,0 +1,142 @@
+use crate::payment::Payment;
+use common::redis_summary::{self, KEY_PREFIX, RECORDED_KEY, RECORD_SCRIPT};
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, Script};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::sync::{Arc, Mutex};
+
+/// Most payments kept for another attempt while Redis is unreachable.
+const PENDING_LIMIT: usize = 100_000;
+
+/// Per-processor counters mirrored into Redis so the gateway can serve
+/// `/payments-summary` without querying Postgres, laid out as described in
+/// [`common::redis_summary`] so `from`/`to` filters can still be answered.
+///
+/// Redis cannot join the Postgres transaction, so the counters are kept in
+/// step afterwards instead: every payment is counted at most once by
+/// correlationId, and payments whose update failed are sent again by
+/// [`RedisSummary::retry_pending`].
+#[derive(Clone)]
+pub struct RedisSummary {
+    conn: ConnectionManager,
+    script: Arc<Script>,
+    pending: Arc<Mutex<Vec<Payment>>>,
+}
+
+impl RedisSummary {
+    pub async fn connect(url: &str) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let conn = ConnectionManager::new(client).await?;
+        Ok(Self {
+            conn,
+            script: Arc::new(Script::new(RECORD_SCRIPT)),
+            pending: Arc::default(),
+        })
+    }
+
+    /// Counts the payments of `payments` not counted yet, in one script.
+    /// When that fails they are kept for [`RedisSummary::retry_pending`].
+    pub async fn record(&self, payments: &[Payment]) -> redis::RedisResult<()> {
+        if payments.is_empty() {
+            return Ok(());
+        }
+        let result = self.invoke(payments).await;
+        if result.is_err() {
+            self.keep_pending(payments);
+        }
+        result
+    }
+
+    /// Sends again the payments whose update failed.
+    pub async fn retry_pending(&self) -> redis::RedisResult<()> {
+        let payments = std::mem::take(&mut *self.pending.lock().unwrap());
+        if payments.is_empty() {
+            return Ok(());
+        }
+        let result = self.invoke(&payments).await;
+        if result.is_err() {
+            self.keep_pending(&payments);
+        }
+        result
+    }
+
+    async fn invoke(&self, payments: &[Payment]) -> redis::RedisResult<()> {
+        let (keys, args) = script_args(payments);
+        let mut invocation = self.script.prepare_invoke();
+        invocation.key(keys).arg(args);
+        let mut conn = self.conn.clone();
+        let _counted: i64 = invocation.invoke_async(&mut conn).await?;
+        Ok(())
+    }
+
+    fn keep_pending(&self, payments: &[Payment]) {
+        let mut pending = self.pending.lock().unwrap();
+        let kept = payments.len().min(PENDING_LIMIT.saturating_sub(pending.len()));
+        pending.extend_from_slice(&payments[..kept]);
+        if kept < payments.len() {
+            tracing::error!(payments = payments.len() - kept, "redis summary is behind, dropping counter updates");
+        }
+    }
+
+    /// Deletes every processor's counters and the correlationIds counted,
+    /// along with the updates still pending.
+    pub async fn purge(&self) -> redis::RedisResult<()> {
+        self.pending.lock().unwrap().clear();
+        let mut conn = self.conn.clone();
+        let keys: Vec<String> = {
+            let mut iter = conn.scan_match::<_, String>(format!("{}*", KEY_PREFIX)).await?;
+            let mut keys = vec![RECORDED_KEY.to_string()];
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+            keys
+        };
+        conn.del(keys).await
+    }
+}
+
+/// `KEYS` and `ARGV` of [`RECORD_SCRIPT`] for `payments`.
+fn script_args(payments: &[Payment]) -> (Vec<String>, Vec<String>) {
+    redis_summary::record_args(payments.iter().map(|payment| {
+        let millis = (payment.requested_at.unix_timestamp_nanos() / 1_000_000) as i64;
+        let cents = (payment.amount * Decimal::ONE_HUNDRED)
+            .round()
+            .to_i64()
+            .unwrap_or_default();
+        (payment.correlation_id, payment.processor, millis, cents)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor_type::ProcessorType;
+    use time::OffsetDateTime;
+
+    fn payment(id: u128, processor: ProcessorType, amount: Decimal) -> Payment {
+        Payment {
+            amount,
+            correlation_id: uuid::Uuid::from_u128(id),
+            requested_at: OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap(),
+            processor,
+            run_id: None,
+        }
+    }
+
+    #[test]
+    fn script_args_name_each_processor_key_once() {
+        let payments = [
+            payment(1, ProcessorType::DEFAULT, Decimal::new(1990, 2)),
+            payment(2, ProcessorType::FALLBACK, Decimal::new(5, 1)),
+            payment(3, ProcessorType::DEFAULT, Decimal::ONE),
+        ];
+        let (keys, args) = script_args(&payments);
+
+        assert_eq!(keys, [RECORDED_KEY, "payments-summary:default", "payments-summary:fallback"]);
+        assert_eq!(args.len(), 12);
+        assert_eq!(args[..4], [uuid::Uuid::from_u128(1).to_string(), "2".into(), "1700000000000".into(), "1990".into()]);
+        assert_eq!(args[5..8], ["3", "1700000000000", "50"]);
+        assert_eq!(args[9], "2");
+    }
+}
@@ -0,0 +1,320 @@
+//! `worker report`: sums up a finished run for post-run analysis. Totals
+//! per processor, quarantined payments and retries still pending come from
+//! the database; attempts, drops and stage latencies from `/metrics`
+//! snapshots saved from each worker's admin socket before it stopped
+//! (`REPORT_METRICS`), added up across workers. Prints a table, or one JSON
+//! object with `--json`.
+
+use crate::verify::Totals;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio_postgres::NoTls;
+
+const OUTCOMES: &str = "worker_payment_outcomes_total";
+
+pub struct ReportConfig {
+    pub postgres_url: String,
+    /// Saved `/metrics` outputs (`REPORT_METRICS`, comma separated paths).
+    pub metrics: Vec<String>,
+    /// Only payments of this run when set (`REPORT_RUN_ID`).
+    pub run_id: Option<String>,
+    /// Window reported, inclusive (`REPORT_FROM`, `REPORT_TO`, RFC 3339);
+    /// unbounded when unset.
+    pub from: Option<OffsetDateTime>,
+    pub to: Option<OffsetDateTime>,
+}
+
+impl ReportConfig {
+    pub fn from_env() -> Self {
+        let instant = |name| {
+            std::env::var(name)
+                .ok()
+                .map(|value| OffsetDateTime::parse(&value, &Rfc3339).unwrap_or_else(|e| panic!("{}: {}", name, e)))
+        };
+        Self {
+            postgres_url: std::env::var("POSTGRES_URL").unwrap(),
+            metrics: std::env::var("REPORT_METRICS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|path| !path.is_empty())
+                .map(String::from)
+                .collect(),
+            run_id: std::env::var("REPORT_RUN_ID").ok(),
+            from: instant("REPORT_FROM"),
+            to: instant("REPORT_TO"),
+        }
+    }
+}
+
+/// One series of a metrics snapshot: its name and labels.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Series {
+    name: String,
+    labels: Vec<(String, String)>,
+}
+
+impl Series {
+    fn label(&self, key: &str) -> Option<&str> {
+        self.labels.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Samples of `/metrics` snapshots, with the series several workers
+/// report added up.
+#[derive(Debug, Default)]
+pub struct MetricsSnapshot {
+    samples: BTreeMap<Series, f64>,
+}
+
+impl MetricsSnapshot {
+    /// Adds the samples of one snapshot in the text format the admin
+    /// socket serves. Lines that do not parse are skipped.
+    pub fn add(&mut self, text: &str) {
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((series, value)) = line.rsplit_once(' ') else {
+                continue;
+            };
+            let Ok(value) = value.parse::<f64>() else {
+                continue;
+            };
+            let series = match series.split_once('{') {
+                Some((name, labels)) => Series {
+                    name: name.to_string(),
+                    labels: labels
+                        .trim_end_matches('}')
+                        .split(',')
+                        .filter_map(|pair| pair.split_once('='))
+                        .map(|(k, v)| (k.to_string(), v.trim_matches('"').to_string()))
+                        .collect(),
+                },
+                None => Series { name: series.to_string(), labels: Vec::new() },
+            };
+            *self.samples.entry(series).or_default() += value;
+        }
+    }
+
+    /// `(label value, count)` of each series of `name`.
+    fn by_label<'a>(&'a self, name: &'a str, key: &'a str) -> impl Iterator<Item = (&'a str, u64)> + 'a {
+        self.samples
+            .iter()
+            .filter(move |(series, _)| series.name == name)
+            .filter_map(move |(series, value)| Some((series.label(key)?, *value as u64)))
+    }
+
+    /// Every histogram's sample count and the upper bounds of the buckets
+    /// holding its median and 99th percentile, `None` when in the overflow
+    /// bucket or nothing was recorded.
+    fn stages(&self) -> BTreeMap<String, StageLatency> {
+        let mut buckets: BTreeMap<&str, Vec<(f64, u64)>> = BTreeMap::new();
+        for (series, value) in &self.samples {
+            let (Some(name), Some(le)) = (series.name.strip_suffix("_bucket"), series.label("le")) else {
+                continue;
+            };
+            let bound = if le == "+Inf" { f64::INFINITY } else { le.parse().unwrap_or(f64::INFINITY) };
+            buckets.entry(name).or_default().push((bound, *value as u64));
+        }
+
+        buckets
+            .into_iter()
+            .map(|(name, mut buckets)| {
+                buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+                let count = buckets.last().map_or(0, |(_, cumulative)| *cumulative);
+                let quantile = |q: f64| {
+                    let target = (count as f64 * q).ceil() as u64;
+                    buckets
+                        .iter()
+                        .find(|(_, cumulative)| count > 0 && *cumulative >= target)
+                        .map(|(bound, _)| *bound)
+                        .filter(|bound| bound.is_finite())
+                };
+                (name.to_string(), StageLatency { count, p50: quantile(0.5), p99: quantile(0.99) })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct StageLatency {
+    pub count: u64,
+    pub p50: Option<f64>,
+    pub p99: Option<f64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    /// Stored payments per processor.
+    pub processors: BTreeMap<String, Totals>,
+    /// Rows of `quarantined_payments` per processor.
+    pub quarantined: BTreeMap<String, i64>,
+    /// Rows of `scheduled_retries` never retried.
+    pub pending_retries: i64,
+    /// Successful payments by attempt range.
+    pub attempts: BTreeMap<String, u64>,
+    /// Payments given up, by reason.
+    pub dropped: BTreeMap<String, u64>,
+    /// Every histogram of the snapshots, by metric name.
+    pub stages: BTreeMap<String, StageLatency>,
+}
+
+impl Report {
+    /// Fills in what the metrics snapshots say.
+    pub fn with_metrics(mut self, metrics: &MetricsSnapshot) -> Self {
+        for (outcome, count) in metrics.by_label(OUTCOMES, "outcome") {
+            if let Some(reason) = outcome.strip_prefix("dropped_") {
+                self.dropped.insert(reason.to_string(), count);
+            }
+        }
+        self.attempts = metrics.by_label(OUTCOMES, "attempts").map(|(a, count)| (a.to_string(), count)).collect();
+        self.stages = metrics.stages();
+        self
+    }
+
+    pub fn table(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "{:<12} {:>10} {:>14} {:>12}", "processor", "requests", "amount", "quarantined");
+        let names = self.processors.keys().chain(self.quarantined.keys().filter(|n| !self.processors.contains_key(*n)));
+        for name in names {
+            let totals = self.processors.get(name).copied().unwrap_or_default();
+            let quarantined = self.quarantined.get(name).copied().unwrap_or_default();
+            let _ = writeln!(out, "{:<12} {:>10} {:>14} {:>12}", name, totals.requests, totals.amount, quarantined);
+        }
+        let _ = writeln!(out, "\npending retries: {}", self.pending_retries);
+
+        let _ = writeln!(out, "\n{:<12} {:>10}", "attempts", "succeeded");
+        for (attempts, count) in &self.attempts {
+            let _ = writeln!(out, "{:<12} {:>10}", attempts, count);
+        }
+        let _ = writeln!(out, "\n{:<12} {:>10}", "dropped", "payments");
+        for (reason, count) in &self.dropped {
+            let _ = writeln!(out, "{:<12} {:>10}", reason, count);
+        }
+
+        let bound = |b: Option<f64>| b.map_or_else(|| "-".to_string(), |b| format!("<={}", b));
+        let _ = writeln!(out, "\n{:<28} {:>10} {:>10} {:>10}", "stage", "count", "p50", "p99");
+        for (name, stage) in &self.stages {
+            let _ = writeln!(out, "{:<28} {:>10} {:>10} {:>10}", name, stage.count, bound(stage.p50), bound(stage.p99));
+        }
+        out
+    }
+}
+
+/// Prints the report for `config`, as JSON when `json` is set.
+pub async fn run(config: &ReportConfig, json: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut metrics = MetricsSnapshot::default();
+    for path in &config.metrics {
+        metrics.add(&std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?);
+    }
+
+    let (client, connection) = tokio_postgres::connect(&config.postgres_url, NoTls).await?;
+    tokio::spawn(connection);
+    let params: [&(dyn tokio_postgres::types::ToSql + Sync); 3] = [&config.from, &config.to, &config.run_id];
+
+    let processors = client
+        .query(
+            "SELECT service_used::text, COUNT(*), COALESCE(SUM(amount), 0)
+             FROM payments
+             WHERE ($1::timestamptz IS NULL OR requested_at >= $1)
+               AND ($2::timestamptz IS NULL OR requested_at <= $2)
+               AND ($3::text IS NULL OR run_id = $3)
+             GROUP BY service_used",
+            &params,
+        )
+        .await?
+        .iter()
+        .map(|row| Ok((row.try_get(0)?, Totals { requests: row.try_get(1)?, amount: row.try_get(2)? })))
+        .collect::<Result<_, tokio_postgres::Error>>()?;
+    let quarantined = client
+        .query(
+            "SELECT processor, COUNT(*)
+             FROM quarantined_payments
+             WHERE ($1::timestamptz IS NULL OR quarantined_at >= $1)
+               AND ($2::timestamptz IS NULL OR quarantined_at <= $2)
+               AND ($3::text IS NULL OR run_id = $3)
+             GROUP BY processor",
+            &params,
+        )
+        .await?
+        .iter()
+        .map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+        .collect::<Result<_, tokio_postgres::Error>>()?;
+    let pending_retries = client
+        .query_one(
+            "SELECT COUNT(*) FROM scheduled_retries WHERE ($1::text IS NULL OR run_id = $1)",
+            &[&config.run_id],
+        )
+        .await?
+        .try_get(0)?;
+
+    let report = Report { processors, quarantined, pending_retries, ..Report::default() }.with_metrics(&metrics);
+    if json {
+        println!("{}", serde_json::to_string(&report)?);
+    } else {
+        print!("{}", report.table());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    const WORKER_A: &str = "\
+# TYPE worker_pipeline_latency_ms histogram
+worker_pipeline_latency_ms_bucket{le=\"1\"} 90
+worker_pipeline_latency_ms_bucket{le=\"2\"} 98
+worker_pipeline_latency_ms_bucket{le=\"5\"} 100
+worker_pipeline_latency_ms_bucket{le=\"+Inf\"} 100
+worker_pipeline_latency_ms_count 100
+worker_payment_outcomes_total{outcome=\"succeeded\",attempts=\"1\"} 95
+worker_payment_outcomes_total{outcome=\"succeeded\",attempts=\"2-5\"} 4
+worker_payment_outcomes_total{outcome=\"dropped_expired\"} 1
+";
+    const WORKER_B: &str = "\
+worker_pipeline_latency_ms_bucket{le=\"1\"} 0
+worker_pipeline_latency_ms_bucket{le=\"2\"} 0
+worker_pipeline_latency_ms_bucket{le=\"5\"} 0
+worker_pipeline_latency_ms_bucket{le=\"+Inf\"} 100
+worker_payment_outcomes_total{outcome=\"succeeded\",attempts=\"1\"} 5
+worker_payment_outcomes_total{outcome=\"dropped_expired\"} 2
+";
+
+    #[test]
+    fn adds_up_snapshots_of_several_workers() {
+        let mut metrics = MetricsSnapshot::default();
+        metrics.add(WORKER_A);
+        metrics.add(WORKER_B);
+        let report = Report::default().with_metrics(&metrics);
+
+        assert_eq!(report.attempts, BTreeMap::from([("1".to_string(), 100), ("2-5".to_string(), 4)]));
+        assert_eq!(report.dropped, BTreeMap::from([("expired".to_string(), 3)]));
+        // Half the latencies are past the last bound.
+        assert_eq!(
+            report.stages["worker_pipeline_latency_ms"],
+            StageLatency { count: 200, p50: Some(5.0), p99: None }
+        );
+    }
+
+    #[test]
+    fn table_lists_every_processor() {
+        let report = Report {
+            processors: BTreeMap::from([(
+                "default".to_string(),
+                Totals { requests: 2, amount: Decimal::new(3980, 2) },
+            )]),
+            quarantined: BTreeMap::from([("fallback".to_string(), 1)]),
+            ..Report::default()
+        };
+        let table = report.table();
+        assert!(table.lines().any(|line| line.split_whitespace().eq(["default", "2", "39.80", "0"])));
+        assert!(table.lines().any(|line| line.split_whitespace().eq(["fallback", "0", "0", "1"])));
+    }
+}
@@ -0,0 +1,167 @@
+//! Hand-rolled processor request body. The shape is fixed and tiny, so this
+//! writes it straight from a template instead of going through serde, while
+//! producing byte for byte what `serde_json` would: the amount as a string,
+//! the id hyphenated and lowercase, and `requestedAt` as RFC 3339 with
+//! trailing zeros of the fraction dropped.
+
+use bytes::{BufMut, BytesMut};
+use rust_decimal::Decimal;
+use time::{OffsetDateTime, UtcOffset};
+
+/// Appends `{"amount":…,"correlationId":…,"requestedAt":…}` to `out`.
+/// Returns `false`, leaving `out` untouched, when `requested_at` has no RFC
+/// 3339 form (a year outside 0..=9999 or an offset with seconds).
+pub fn write_payment_request(
+    out: &mut BytesMut,
+    amount: &Decimal,
+    correlation_id: &uuid::Uuid,
+    requested_at: OffsetDateTime,
+) -> bool {
+    let offset = requested_at.offset();
+    if !(0..10_000).contains(&requested_at.year()) || offset.seconds_past_minute() != 0 {
+        return false;
+    }
+
+    out.put_slice(b"{\"amount\":\"");
+    write_decimal(out, amount);
+    out.put_slice(b"\",\"correlationId\":\"");
+    out.put_slice(correlation_id.hyphenated().encode_lower(&mut uuid::Uuid::encode_buffer()).as_bytes());
+    out.put_slice(b"\",\"requestedAt\":\"");
+    write_rfc3339(out, requested_at, offset);
+    out.put_slice(b"\"}");
+    true
+}
+
+/// Same digits as `Decimal`'s `Display`: every digit of the scale is kept.
+fn write_decimal(out: &mut BytesMut, value: &Decimal) {
+    if value.is_sign_negative() {
+        out.put_u8(b'-');
+    }
+
+    let mut buffer = itoa::Buffer::new();
+    let mantissa = value.mantissa().unsigned_abs();
+    let digits = if mantissa == 0 { &[][..] } else { buffer.format(mantissa).as_bytes() };
+    let scale = value.scale() as usize;
+
+    if scale == 0 {
+        out.put_slice(if digits.is_empty() { b"0" } else { digits });
+    } else if digits.len() > scale {
+        let (whole, fraction) = digits.split_at(digits.len() - scale);
+        out.put_slice(whole);
+        out.put_u8(b'.');
+        out.put_slice(fraction);
+    } else {
+        out.put_slice(b"0.");
+        out.put_bytes(b'0', scale - digits.len());
+        out.put_slice(digits);
+    }
+}
+
+fn write_rfc3339(out: &mut BytesMut, at: OffsetDateTime, offset: UtcOffset) {
+    put_padded(out, at.year() as u32, 4);
+    out.put_u8(b'-');
+    put_padded(out, u8::from(at.month()) as u32, 2);
+    out.put_u8(b'-');
+    put_padded(out, at.day() as u32, 2);
+    out.put_u8(b'T');
+    put_padded(out, at.hour() as u32, 2);
+    out.put_u8(b':');
+    put_padded(out, at.minute() as u32, 2);
+    out.put_u8(b':');
+    put_padded(out, at.second() as u32, 2);
+
+    let mut nanos = at.nanosecond();
+    if nanos != 0 {
+        let mut width = 9;
+        while nanos.is_multiple_of(10) {
+            nanos /= 10;
+            width -= 1;
+        }
+        out.put_u8(b'.');
+        put_padded(out, nanos, width);
+    }
+
+    if offset == UtcOffset::UTC {
+        out.put_u8(b'Z');
+        return;
+    }
+    out.put_u8(if offset.is_negative() { b'-' } else { b'+' });
+    put_padded(out, offset.whole_hours().unsigned_abs() as u32, 2);
+    out.put_u8(b':');
+    put_padded(out, offset.minutes_past_hour().unsigned_abs() as u32, 2);
+}
+
+/// Writes `value` zero-padded to `width` digits; `value` must fit.
+fn put_padded(out: &mut BytesMut, value: u32, width: usize) {
+    let mut digits = [b'0'; 9];
+    let mut rest = value;
+    for digit in digits[..width].iter_mut().rev() {
+        *digit = b'0' + (rest % 10) as u8;
+        rest /= 10;
+    }
+    out.put_slice(&digits[..width]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payment_processor::PaymentRequest;
+    use proptest::prelude::*;
+
+    fn fast(request: &PaymentRequest) -> Option<String> {
+        let mut out = BytesMut::new();
+        write_payment_request(&mut out, &request.amount, &request.correlation_id, request.requested_at)
+            .then(|| String::from_utf8(out.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn matches_serde_for_a_typical_payment() {
+        let request = PaymentRequest {
+            amount: Decimal::new(1990, 2),
+            correlation_id: uuid::Uuid::parse_str("4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3").unwrap(),
+            requested_at: OffsetDateTime::from_unix_timestamp_nanos(1_752_000_000_123_000_000).unwrap(),
+        };
+        assert_eq!(
+            fast(&request).unwrap(),
+            r#"{"amount":"19.90","correlationId":"4a7901b8-7d26-4d9d-aa19-4dc1c7cf60b3","requestedAt":"2025-07-08T18:40:00.123Z"}"#
+        );
+        assert_eq!(fast(&request).unwrap(), serde_json::to_string(&request).unwrap());
+    }
+
+    #[test]
+    fn leaves_unrepresentable_dates_to_serde() {
+        let request = PaymentRequest {
+            amount: Decimal::ONE,
+            correlation_id: uuid::Uuid::nil(),
+            requested_at: OffsetDateTime::from_unix_timestamp(-70_000_000_000).unwrap(),
+        };
+        assert_eq!(fast(&request), None);
+        assert!(serde_json::to_string(&request).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn matches_serde(
+            mantissa in any::<i64>(),
+            scale in 0u32..=12,
+            id in any::<u128>(),
+            seconds in 0i64..253_400_000_000,
+            nanos in prop_oneof![Just(0u32), 0u32..1_000_000_000, (0u32..1_000).prop_map(|ms| ms * 1_000_000)],
+            offset_minutes in prop_oneof![Just(0i32), -1439i32..=1439],
+        ) {
+            let offset = UtcOffset::from_whole_seconds(offset_minutes * 60).unwrap();
+            let requested_at = OffsetDateTime::from_unix_timestamp(seconds).unwrap()
+                .replace_nanosecond(nanos).unwrap()
+                .to_offset(offset);
+            let request = PaymentRequest {
+                amount: Decimal::new(mantissa, scale),
+                correlation_id: uuid::Uuid::from_u128(id),
+                requested_at,
+            };
+            match serde_json::to_string(&request) {
+                Ok(json) => prop_assert_eq!(fast(&request), Some(json)),
+                Err(_) => prop_assert_eq!(fast(&request), None),
+            }
+        }
+    }
+}
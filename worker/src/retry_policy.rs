@@ -0,0 +1,133 @@
+/// How failed payments are rescheduled.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub jitter_fraction: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 50,
+            base_backoff_ms: 500,
+            max_backoff_ms: 2_000,
+            jitter_fraction: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff capped at `max_backoff_ms`, moved by up to
+    /// `jitter_fraction` of the delay in either direction.
+    pub fn backoff_ms(&self, retry_count: u32) -> u64 {
+        let delay = self.base_backoff_ms.saturating_mul(1_u64 << retry_count.min(10)); // Cap the exponential growth
+        let delay = delay.min(self.max_backoff_ms);
+
+        let jitter_range = (delay as f64 * self.jitter_fraction()) as u64;
+        let pseudo = retry_count.wrapping_mul(1103515245).wrapping_add(12345) as u64;
+        let jitter = pseudo % jitter_range.saturating_mul(2).max(1);
+
+        delay.saturating_sub(jitter_range).saturating_add(jitter)
+    }
+
+    /// The configured fraction limited to `[0, 1]`; anything else would let
+    /// the jitter swallow the whole delay or more than double it.
+    fn jitter_fraction(&self) -> f64 {
+        if self.jitter_fraction.is_nan() {
+            0.0
+        } else {
+            self.jitter_fraction.clamp(0.0, 1.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_until_capped_within_jitter() {
+        let policy = RetryPolicy::default();
+
+        for retry_count in 1..=12 {
+            let base = (policy.base_backoff_ms << retry_count.min(10)).min(policy.max_backoff_ms);
+            let jitter = (base as f64 * policy.jitter_fraction) as u64;
+            let delay = policy.backoff_ms(retry_count);
+
+            assert!(delay >= base - jitter, "retry {} delay {} below {}", retry_count, delay, base - jitter);
+            assert!(delay <= base + jitter, "retry {} delay {} above {}", retry_count, delay, base + jitter);
+        }
+    }
+
+    #[test]
+    fn backoff_is_deterministic_and_unjittered_without_fraction() {
+        let policy = RetryPolicy {
+            base_backoff_ms: 10,
+            max_backoff_ms: 1_000,
+            jitter_fraction: 0.0,
+            ..RetryPolicy::default()
+        };
+
+        let sequence: Vec<u64> = (0..8).map(|n| policy.backoff_ms(n)).collect();
+        assert_eq!(sequence, vec![10, 20, 40, 80, 160, 320, 640, 1_000]);
+        assert_eq!(policy.backoff_ms(5), policy.backoff_ms(5));
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn policies() -> impl Strategy<Value = RetryPolicy> {
+            (any::<u64>(), any::<u64>(), prop_oneof![-1.0..2.0f64, Just(0.0), Just(1.0), Just(f64::NAN)]).prop_map(
+                |(base_backoff_ms, max_backoff_ms, jitter_fraction)| RetryPolicy {
+                    max_retries: 50,
+                    base_backoff_ms,
+                    max_backoff_ms,
+                    jitter_fraction,
+                },
+            )
+        }
+
+        proptest! {
+            #[test]
+            fn backoff_stays_within_the_jittered_cap(policy in policies(), retry_count in any::<u32>()) {
+                let cap = policy
+                    .base_backoff_ms
+                    .saturating_mul(1 << retry_count.min(10))
+                    .min(policy.max_backoff_ms);
+                let jitter_range = (cap as f64 * policy.jitter_fraction()) as u64;
+
+                let delay = policy.backoff_ms(retry_count);
+                prop_assert!(delay >= cap.saturating_sub(jitter_range));
+                prop_assert!(delay <= cap.saturating_add(jitter_range));
+            }
+
+            #[test]
+            fn jitter_is_at_most_the_configured_fraction(policy in policies(), retry_count in any::<u32>()) {
+                let unjittered = RetryPolicy { jitter_fraction: 0.0, ..policy.clone() }.backoff_ms(retry_count);
+                let spread = policy.backoff_ms(retry_count).abs_diff(unjittered);
+                prop_assert!(spread as f64 <= unjittered as f64 * policy.jitter_fraction());
+            }
+
+            #[test]
+            fn zero_jitter_range_gives_the_exact_delay(base in 0..1_000u64, retry_count in any::<u32>()) {
+                // A fraction this small truncates the range to zero.
+                let policy = RetryPolicy {
+                    base_backoff_ms: base,
+                    max_backoff_ms: u64::MAX,
+                    jitter_fraction: 1e-9,
+                    ..RetryPolicy::default()
+                };
+                prop_assert_eq!(policy.backoff_ms(retry_count), base << retry_count.min(10));
+            }
+
+            #[test]
+            fn backoff_never_decreases_before_jitter(policy in policies(), retry_count in 0..64u32) {
+                let policy = RetryPolicy { jitter_fraction: 0.0, ..policy };
+                prop_assert!(policy.backoff_ms(retry_count) <= policy.backoff_ms(retry_count + 1));
+            }
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use crate::error::WorkerError;
+use crate::health_monitor::ProcessorHealth;
+use serde::Serialize;
+
+/// Decides which processor of the failover chain should receive the next
+/// payment based on the last probed health of each.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoutingStrategy {
+    /// A processor is preferred over the ones after it in the chain while its
+    /// min response time is at most `latency_multiplier` times theirs.
+    pub latency_multiplier: u16,
+    /// A processor slower than this (in ms, probed or observed) is treated
+    /// as failing.
+    pub max_response_time: u16,
+    /// Whether the `failing` flag reported by the processor is honored.
+    pub respect_failing: bool,
+    /// When disabled every payment goes to the first processor of the chain.
+    pub fallback_enabled: bool,
+    /// A payment that failed this many attempts moves one step down the
+    /// chain from the routed processor, and another step every as many
+    /// further failures. `0` disables escalation.
+    pub escalate_after_retries: u32,
+}
+
+impl Default for RoutingStrategy {
+    fn default() -> Self {
+        Self {
+            latency_multiplier: 3,
+            max_response_time: 50,
+            respect_failing: true,
+            fallback_enabled: false,
+            escalate_after_retries: 0,
+        }
+    }
+}
+
+/// What [`RoutingStrategy::decide`] made of one processor of the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Reason {
+    Chosen,
+    /// Reports `failing` and the flag is respected.
+    Failing,
+    /// Slower than `max_response_time`, probed or observed.
+    TooSlow,
+    /// A healthy processor after it is more than `latency_multiplier` times
+    /// faster.
+    Outpaced,
+    /// After the chosen processor, so not considered.
+    NotReached,
+    /// Past the first processor while fallback is disabled.
+    FallbackDisabled,
+}
+
+impl RoutingStrategy {
+    /// Index into `chain` of the processor to use. Walking the chain in
+    /// order, a healthy processor is picked unless some healthy processor
+    /// after it is more than `latency_multiplier` times faster.
+    pub fn decide(&self, chain: &[ProcessorHealth]) -> Result<usize, WorkerError> {
+        self.explain(chain)
+            .iter()
+            .position(|reason| *reason == Reason::Chosen)
+            .ok_or(WorkerError::AllProcessorsFailing)
+    }
+
+    /// The [`Reason`] behind the decision for every processor of `chain`.
+    pub fn explain(&self, chain: &[ProcessorHealth]) -> Vec<Reason> {
+        let usable = if self.fallback_enabled { chain.len() } else { chain.len().min(1) };
+        let mut reasons = vec![Reason::FallbackDisabled; chain.len()];
+        let chain = &chain[..usable];
+
+        for (index, health) in chain.iter().enumerate() {
+            if let Some(reason) = self.unhealthy(health) {
+                reasons[index] = reason;
+                continue;
+            }
+
+            let fastest_after = chain[index + 1..]
+                .iter()
+                .filter(|later| !self.is_failing(later))
+                .map(|later| u32::from(later.latency()))
+                .min();
+
+            match fastest_after {
+                Some(fastest) if u32::from(health.latency()) > u32::from(self.latency_multiplier) * fastest => {
+                    reasons[index] = Reason::Outpaced;
+                }
+                _ => {
+                    reasons[index] = Reason::Chosen;
+                    reasons[index + 1..usable].fill(Reason::NotReached);
+                    break;
+                }
+            }
+        }
+        reasons
+    }
+
+    /// Processors after `routed` a repeatedly failing payment may escalate
+    /// to, in chain order. Failing processors are never escalated to.
+    pub fn escalation(&self, chain: &[ProcessorHealth], routed: usize) -> Vec<usize> {
+        if !self.fallback_enabled || self.escalate_after_retries == 0 {
+            return Vec::new();
+        }
+
+        (routed + 1..chain.len())
+            .filter(|index| !self.is_failing(&chain[*index]))
+            .collect()
+    }
+
+    /// Where a payment past its budget goes: the last processor after
+    /// `routed` that is not failing, so a backlog skips the slow head of the
+    /// chain. Stays on `routed` when fallback is disabled or nothing after
+    /// it is healthy.
+    pub fn late_route(&self, chain: &[ProcessorHealth], routed: usize) -> usize {
+        if !self.fallback_enabled {
+            return routed;
+        }
+
+        (routed + 1..chain.len())
+            .rev()
+            .find(|index| !self.is_failing(&chain[*index]))
+            .unwrap_or(routed)
+    }
+
+    fn is_failing(&self, health: &ProcessorHealth) -> bool {
+        self.unhealthy(health).is_some()
+    }
+
+    fn unhealthy(&self, health: &ProcessorHealth) -> Option<Reason> {
+        if self.respect_failing && health.failing {
+            Some(Reason::Failing)
+        } else if health.latency() > self.max_response_time {
+            Some(Reason::TooSlow)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT: usize = 0;
+    const FALLBACK: usize = 1;
+
+    fn health(failing: bool, min_response_time: u16) -> ProcessorHealth {
+        ProcessorHealth {
+            failing,
+            min_response_time,
+            updated_at: None,
+            observed_latency: None,
+        }
+    }
+
+    fn strategy() -> RoutingStrategy {
+        RoutingStrategy {
+            fallback_enabled: true,
+            ..RoutingStrategy::default()
+        }
+    }
+
+    #[test]
+    fn failing_matrix() {
+        let s = strategy();
+        let ok = health(false, 10);
+        let bad = health(true, 10);
+
+        assert_eq!(s.decide(&[ok, ok]).unwrap(), DEFAULT);
+        assert_eq!(s.decide(&[bad, ok]).unwrap(), FALLBACK);
+        assert_eq!(s.decide(&[ok, bad]).unwrap(), DEFAULT);
+        assert!(s.decide(&[bad, bad]).is_err());
+    }
+
+    #[test]
+    fn slow_processor_counts_as_failing() {
+        let s = strategy();
+        let slow = health(false, s.max_response_time + 1);
+        let ok = health(false, 10);
+
+        assert_eq!(s.decide(&[slow, ok]).unwrap(), FALLBACK);
+        assert!(s.decide(&[slow, slow]).is_err());
+    }
+
+    #[test]
+    fn observed_latency_counts_when_worse_than_probed() {
+        let s = strategy();
+        let observed = |min_response_time, observed| ProcessorHealth {
+            observed_latency: Some(observed),
+            ..health(false, min_response_time)
+        };
+
+        assert_eq!(observed(10, 5).latency(), 10);
+        assert_eq!(observed(10, 50).latency(), 50);
+        assert_eq!(s.decide(&[observed(10, 31), health(false, 10)]).unwrap(), FALLBACK);
+        assert_eq!(s.decide(&[observed(10, 30), health(false, 10)]).unwrap(), DEFAULT);
+        assert_eq!(s.decide(&[observed(10, s.max_response_time + 1), observed(50, 10)]).unwrap(), FALLBACK);
+    }
+
+    #[test]
+    fn latency_multiplier_sets_switch_point() {
+        let s = strategy();
+
+        assert_eq!(s.decide(&[health(false, 30), health(false, 10)]).unwrap(), DEFAULT);
+        assert_eq!(s.decide(&[health(false, 31), health(false, 10)]).unwrap(), FALLBACK);
+        assert_eq!(s.decide(&[health(false, 0), health(false, 0)]).unwrap(), DEFAULT);
+    }
+
+    #[test]
+    fn ignores_failing_flag_when_not_respected() {
+        let s = RoutingStrategy {
+            respect_failing: false,
+            ..strategy()
+        };
+
+        assert_eq!(s.decide(&[health(true, 10), health(false, 10)]).unwrap(), DEFAULT);
+    }
+
+    #[test]
+    fn default_only_when_fallback_disabled() {
+        let s = RoutingStrategy::default();
+
+        assert_eq!(s.decide(&[health(false, 40), health(false, 1)]).unwrap(), DEFAULT);
+        assert!(s.decide(&[health(true, 1), health(false, 1)]).is_err());
+    }
+
+    #[test]
+    fn walks_longer_chains_in_order() {
+        let s = strategy();
+
+        assert_eq!(s.decide(&[health(true, 10), health(true, 10), health(false, 10)]).unwrap(), 2);
+        assert_eq!(s.decide(&[health(false, 30), health(false, 20), health(false, 10)]).unwrap(), 0);
+        assert_eq!(s.decide(&[health(false, 40), health(false, 20), health(false, 1)]).unwrap(), 2);
+        assert_eq!(s.decide(&[health(false, 40), health(true, 1), health(false, 12)]).unwrap(), 2);
+        assert_eq!(s.decide(&[health(false, 40), health(false, 10), health(false, 4)]).unwrap(), 1);
+    }
+
+    #[test]
+    fn explains_every_processor_of_the_chain() {
+        let s = strategy();
+        let reasons = s.explain(&[health(true, 10), health(false, 60), health(false, 40), health(false, 10), health(false, 5)]);
+        assert_eq!(
+            reasons,
+            [Reason::Failing, Reason::TooSlow, Reason::Outpaced, Reason::Chosen, Reason::NotReached]
+        );
+
+        let reasons = RoutingStrategy::default().explain(&[health(true, 10), health(false, 10)]);
+        assert_eq!(reasons, [Reason::Failing, Reason::FallbackDisabled]);
+    }
+
+    #[test]
+    fn escalation_skips_failing_processors() {
+        let s = RoutingStrategy {
+            escalate_after_retries: 3,
+            ..strategy()
+        };
+        let chain = [health(false, 10), health(true, 10), health(false, 10)];
+
+        assert_eq!(s.escalation(&chain, DEFAULT), vec![2]);
+        assert!(s.escalation(&chain, 2).is_empty());
+        assert!(RoutingStrategy { escalate_after_retries: 0, ..s.clone() }.escalation(&chain, DEFAULT).is_empty());
+        assert!(RoutingStrategy { fallback_enabled: false, ..s }.escalation(&chain, DEFAULT).is_empty());
+    }
+
+    #[test]
+    fn late_payments_take_the_last_healthy_processor() {
+        let s = strategy();
+
+        assert_eq!(s.late_route(&[health(false, 10), health(false, 10), health(false, 10)], DEFAULT), 2);
+        assert_eq!(s.late_route(&[health(false, 10), health(false, 10), health(true, 10)], DEFAULT), 1);
+        assert_eq!(s.late_route(&[health(false, 10), health(true, 10)], DEFAULT), DEFAULT);
+        assert_eq!(RoutingStrategy::default().late_route(&[health(false, 10), health(false, 10)], DEFAULT), DEFAULT);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::health_monitor::HealthMonitor;
+use crate::payment_processor::{PaymentProcessor, Processor};
+use crate::processor_type::ProcessorType;
+use crate::retry_policy::RetryPolicy;
+use crate::routing_strategy::RoutingStrategy;
+use crate::worker_pool::WorkerPool;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Tunables that can be changed while the worker is running.
+#[derive(Debug, Clone)]
+pub struct RuntimeSettings {
+    pub routing: RoutingStrategy,
+    pub retry: RetryPolicy,
+    /// In-flight request caps for the default and fallback processors, `0`
+    /// meaning unlimited. Other processors of the chain are not capped.
+    pub default_max_concurrency: usize,
+    pub fallback_max_concurrency: usize,
+}
+
+impl RuntimeSettings {
+    /// Reads the settings from the environment, overridden by `KEY=VALUE`
+    /// lines in `file` when one is given.
+    pub fn load(file: Option<&str>) -> std::io::Result<Self> {
+        let source = match file {
+            Some(path) => Source::from_file(path)?,
+            None => Source::default(),
+        };
+
+        let routing = RoutingStrategy::default();
+        let retry = RetryPolicy::default();
+
+        Ok(Self {
+            routing: RoutingStrategy {
+                latency_multiplier: source.get("ROUTING_LATENCY_MULTIPLIER", routing.latency_multiplier),
+                max_response_time: source.get("ROUTING_MAX_RESPONSE_TIME_MS", routing.max_response_time),
+                respect_failing: source.get("ROUTING_RESPECT_FAILING", routing.respect_failing),
+                fallback_enabled: source.get("ROUTING_FALLBACK_ENABLED", routing.fallback_enabled),
+                escalate_after_retries: source.get("ROUTING_ESCALATE_AFTER_RETRIES", routing.escalate_after_retries),
+            },
+            retry: RetryPolicy {
+                max_retries: source.get("RETRY_MAX_RETRIES", retry.max_retries),
+                base_backoff_ms: source.get("RETRY_BASE_BACKOFF_MS", retry.base_backoff_ms),
+                max_backoff_ms: source.get("RETRY_MAX_BACKOFF_MS", retry.max_backoff_ms),
+                jitter_fraction: source.get("RETRY_JITTER_FRACTION", retry.jitter_fraction),
+            },
+            default_max_concurrency: source.get("DEFAULT_PROCESSOR_MAX_CONCURRENCY", 0),
+            fallback_max_concurrency: source.get("FALLBACK_PROCESSOR_MAX_CONCURRENCY", 0),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Source {
+    overrides: HashMap<String, String>,
+}
+
+impl Source {
+    fn from_file(path: &str) -> std::io::Result<Self> {
+        let overrides = std::fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        Ok(Self { overrides })
+    }
+
+    fn get<T: FromStr>(&self, key: &str, default: T) -> T {
+        self.overrides
+            .get(key)
+            .cloned()
+            .or_else(|| std::env::var(key).ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    }
+}
+
+/// Re-reads [`RuntimeSettings`] and pushes them into the running components
+/// without touching their queues.
+pub struct SettingsReloader {
+    file: Option<String>,
+    health_monitor: Arc<HealthMonitor>,
+    worker_pool: Arc<WorkerPool>,
+    processors: Vec<Arc<PaymentProcessor>>,
+}
+
+impl SettingsReloader {
+    pub fn new(
+        file: Option<String>,
+        health_monitor: Arc<HealthMonitor>,
+        worker_pool: Arc<WorkerPool>,
+        processors: Vec<Arc<PaymentProcessor>>,
+    ) -> Self {
+        Self {
+            file,
+            health_monitor,
+            worker_pool,
+            processors,
+        }
+    }
+
+    pub fn reload(&self) -> std::io::Result<RuntimeSettings> {
+        let settings = RuntimeSettings::load(self.file.as_deref())?;
+        self.apply(&settings);
+        tracing::info!(?settings, "Reloaded runtime settings");
+        Ok(settings)
+    }
+
+    pub fn apply(&self, settings: &RuntimeSettings) {
+        self.health_monitor.set_strategy(settings.routing.clone());
+        self.worker_pool.set_retry_policy(settings.retry.clone());
+        for processor in &self.processors {
+            match processor.processor_type() {
+                ProcessorType::DEFAULT => processor.set_max_concurrency(settings.default_max_concurrency),
+                ProcessorType::FALLBACK => processor.set_max_concurrency(settings.fallback_max_concurrency),
+                _ => {}
+            }
+        }
+    }
+
+    /// Reloads every time the process receives SIGHUP.
+    pub async fn watch_sighup(self: Arc<Self>) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to install SIGHUP handler");
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            if let Err(e) = self.reload() {
+                tracing::error!(error = %e, "Failed to reload runtime settings");
+            }
+        }
+    }
+}
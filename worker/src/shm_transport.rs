@@ -0,0 +1,245 @@
+//! Experimental gateway -> worker transport over a memory-mapped ring buffer.
+//!
+//! The worker owns the ring file and an eventfd. Producers map the same file
+//! and obtain the eventfd over `<ring path>.sock` (passed with SCM_RIGHTS), so
+//! a wakeup is only signalled while the consumer is parked.
+//!
+//! Layout (all offsets in bytes, little endian, must match the gateway):
+//! - header (256): magic @0, capacity @8, slot size @16, consumer waiting @64,
+//!   enqueue position @128, dequeue position @192
+//! - slots: sequence (u64) @0, length (u32) @8, payload @16
+
+use crate::receiver::Receiver;
+use crate::worker_pool::WorkerPool;
+use bytes::Bytes;
+use memmap2::MmapMut;
+use std::fs::OpenOptions;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::unix::AsyncFd;
+use tokio::net::UnixListener;
+
+const MAGIC: u64 = 0x5249_4e47_4841_3235;
+const HEADER_SIZE: usize = 256;
+const SLOT_HEADER_SIZE: usize = 16;
+
+const OFF_MAGIC: usize = 0;
+const OFF_CAPACITY: usize = 8;
+const OFF_SLOT_SIZE: usize = 16;
+const OFF_WAITING: usize = 64;
+const OFF_ENQUEUE: usize = 128;
+const OFF_DEQUEUE: usize = 192;
+
+pub struct ShmRing {
+    map: MmapMut,
+    capacity: u64,
+    slot_size: usize,
+    stride: usize,
+}
+
+impl ShmRing {
+    /// Creates (or truncates) the ring file and initializes every slot.
+    /// `capacity` must be a power of two.
+    pub fn create(path: &str, capacity: u64, slot_size: usize) -> std::io::Result<Self> {
+        if !capacity.is_power_of_two() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "ring capacity must be a power of two",
+            ));
+        }
+
+        let stride = (SLOT_HEADER_SIZE + slot_size).next_multiple_of(64);
+        let len = HEADER_SIZE + stride * capacity as usize;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(len as u64)?;
+        std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(0o666))?;
+
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        let ring = Self {
+            map,
+            capacity,
+            slot_size,
+            stride,
+        };
+
+        ring.header(OFF_CAPACITY).store(capacity, Ordering::Relaxed);
+        ring.header(OFF_SLOT_SIZE).store(slot_size as u64, Ordering::Relaxed);
+        ring.header(OFF_ENQUEUE).store(0, Ordering::Relaxed);
+        ring.header(OFF_DEQUEUE).store(0, Ordering::Relaxed);
+        ring.waiting().store(0, Ordering::Relaxed);
+        for pos in 0..capacity {
+            ring.slot_seq(pos).store(pos, Ordering::Relaxed);
+        }
+        ring.header(OFF_MAGIC).store(MAGIC, Ordering::Release);
+
+        Ok(ring)
+    }
+
+    fn header(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.map.as_ptr().add(offset) as *const AtomicU64) }
+    }
+
+    fn waiting(&self) -> &AtomicU32 {
+        unsafe { &*(self.map.as_ptr().add(OFF_WAITING) as *const AtomicU32) }
+    }
+
+    fn slot_ptr(&self, pos: u64) -> *const u8 {
+        let index = (pos & (self.capacity - 1)) as usize;
+        unsafe { self.map.as_ptr().add(HEADER_SIZE + index * self.stride) }
+    }
+
+    fn slot_seq(&self, pos: u64) -> &AtomicU64 {
+        unsafe { &*(self.slot_ptr(pos) as *const AtomicU64) }
+    }
+
+    /// Pops the next message into `out`. Only one consumer may call this.
+    fn pop(&self, out: &mut Vec<u8>) -> bool {
+        let dequeue = self.header(OFF_DEQUEUE);
+        let pos = dequeue.load(Ordering::Relaxed);
+
+        if self.slot_seq(pos).load(Ordering::Acquire) != pos + 1 {
+            return false;
+        }
+
+        let slot = self.slot_ptr(pos);
+        let len = unsafe { (slot.add(8) as *const u32).read() } as usize;
+        let len = len.min(self.slot_size);
+        out.clear();
+        out.extend_from_slice(unsafe {
+            std::slice::from_raw_parts(slot.add(SLOT_HEADER_SIZE), len)
+        });
+
+        self.slot_seq(pos).store(pos + self.capacity, Ordering::Release);
+        dequeue.store(pos + 1, Ordering::Relaxed);
+        true
+    }
+}
+
+pub struct ShmReceiver {
+    ring_path: String,
+    ring: ShmRing,
+    workers: Arc<WorkerPool>,
+}
+
+impl ShmReceiver {
+    pub fn new(
+        ring_path: String,
+        capacity: u64,
+        slot_size: usize,
+        workers: Arc<WorkerPool>,
+    ) -> std::io::Result<Self> {
+        let ring = ShmRing::create(&ring_path, capacity, slot_size)?;
+        Ok(Self {
+            ring_path,
+            ring,
+            workers,
+        })
+    }
+
+    pub async fn start(self) -> std::io::Result<()> {
+        let event_fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if event_fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let event_fd = unsafe { OwnedFd::from_raw_fd(event_fd) };
+
+        let control_path = format!("{}.sock", self.ring_path);
+        if std::fs::metadata(&control_path).is_ok() {
+            let _ = std::fs::remove_file(&control_path);
+        }
+        let control = UnixListener::bind(&control_path)?;
+        std::fs::set_permissions(
+            &control_path,
+            std::os::unix::fs::PermissionsExt::from_mode(0o666),
+        )?;
+
+        let shared_fd = event_fd.as_raw_fd();
+        tokio::spawn(async move {
+            loop {
+                match control.accept().await {
+                    Ok((stream, _)) => {
+                        if let Err(e) = send_fd(stream.as_raw_fd(), shared_fd) {
+                            tracing::warn!(error = %e, "Failed to hand eventfd to producer");
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to accept shm control connection");
+                    }
+                }
+            }
+        });
+
+        tracing::info!("Consuming shared memory ring at {}", self.ring_path);
+        self.consume_loop(AsyncFd::new(event_fd)?).await
+    }
+
+    async fn consume_loop(&self, event_fd: AsyncFd<OwnedFd>) -> std::io::Result<()> {
+        let mut buffer = Vec::with_capacity(self.ring.slot_size);
+
+        loop {
+            while self.ring.pop(&mut buffer) {
+                Receiver::dispatch(Bytes::copy_from_slice(&buffer), &self.workers).await;
+            }
+
+            // Advertise that we are about to park, then re-check so a message
+            // pushed before the producer saw the flag is not missed.
+            self.ring.waiting().store(1, Ordering::Relaxed);
+            fence(Ordering::SeqCst);
+            if self.ring.pop(&mut buffer) {
+                self.ring.waiting().store(0, Ordering::Relaxed);
+                Receiver::dispatch(Bytes::copy_from_slice(&buffer), &self.workers).await;
+                continue;
+            }
+
+            let mut guard = event_fd.readable().await?;
+            let mut counter = 0u64;
+            unsafe {
+                libc::read(
+                    guard.get_inner().as_raw_fd(),
+                    &mut counter as *mut u64 as *mut libc::c_void,
+                    std::mem::size_of::<u64>(),
+                );
+            }
+            guard.clear_ready();
+            self.ring.waiting().store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+fn send_fd(socket: RawFd, fd: RawFd) -> std::io::Result<()> {
+    let mut payload = [b'E'];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+        (libc::CMSG_DATA(cmsg) as *mut RawFd).write_unaligned(fd);
+
+        if libc::sendmsg(socket, &msg, 0) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,218 @@
+//! Slow start toward the default processor. When routing comes back to it
+//! after a failing window, the backlog that built up meanwhile would all be
+//! sent at once and could knock it over again. Instead payments are paced:
+//! the rate starts low and doubles every step whose error share stays under
+//! the limit, halving after one that does not, until it reaches the ceiling
+//! and pacing stops. A step without payments says nothing about the
+//! processor and keeps the rate.
+
+use crate::clock::Clock;
+use crate::health_monitor::HealthSnapshot;
+use crate::metrics::METRICS;
+use crate::processor_type::ProcessorType;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlowStartConfig {
+    /// Payments per second right after recovery (`SLOW_START_RPS`).
+    pub initial_rate: f64,
+    /// Rate at which pacing stops (`SLOW_START_MAX_RPS`).
+    pub max_rate: f64,
+    /// How long each rate is held (`SLOW_START_STEP_MS`).
+    pub step: Duration,
+    /// Share of failed payments in a step above which the rate is halved
+    /// rather than doubled (`SLOW_START_MAX_ERROR_RATE`).
+    pub max_error_rate: f64,
+}
+
+struct Ramp {
+    /// Payments per second, `None` when not pacing.
+    rate: Option<f64>,
+    step_ends: Instant,
+    /// When the next payment may be sent.
+    next_slot: Instant,
+    succeeded: u32,
+    failed: u32,
+}
+
+pub struct SlowStart {
+    config: SlowStartConfig,
+    processor: ProcessorType,
+    clock: Arc<dyn Clock>,
+    ramp: Mutex<Ramp>,
+}
+
+impl SlowStart {
+    pub fn new(config: SlowStartConfig, processor: ProcessorType, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
+        Self {
+            config,
+            processor,
+            clock,
+            ramp: Mutex::new(Ramp { rate: None, step_ends: now, next_slot: now, succeeded: 0, failed: 0 }),
+        }
+    }
+
+    /// The processor being paced.
+    pub fn processor(&self) -> ProcessorType {
+        self.processor
+    }
+
+    /// Starts pacing from the initial rate, as after a recovery.
+    pub fn begin(&self) {
+        let now = self.clock.now();
+        let mut ramp = self.ramp.lock().unwrap();
+        *ramp = Ramp {
+            rate: Some(self.config.initial_rate),
+            step_ends: now + self.config.step,
+            next_slot: now,
+            succeeded: 0,
+            failed: 0,
+        };
+        Self::publish(ramp.rate);
+        tracing::warn!(processor = %self.processor, rate = self.config.initial_rate, "Slow start began");
+    }
+
+    /// Current rate, `None` when not pacing.
+    pub fn rate(&self) -> Option<f64> {
+        let mut ramp = self.ramp.lock().unwrap();
+        self.advance(&mut ramp, self.clock.now());
+        ramp.rate
+    }
+
+    /// Waits until `payments` more may be sent.
+    pub async fn pace(&self, payments: usize) {
+        if let Some(slot) = self.reserve(payments) {
+            self.clock.sleep_until(slot).await;
+        }
+    }
+
+    /// Takes the next `payments` slots, returning when they start, or `None`
+    /// when they can be sent right away.
+    fn reserve(&self, payments: usize) -> Option<Instant> {
+        let now = self.clock.now();
+        let mut ramp = self.ramp.lock().unwrap();
+        self.advance(&mut ramp, now);
+        let rate = ramp.rate?;
+        let slot = ramp.next_slot.max(now);
+        ramp.next_slot = slot + Duration::from_secs_f64(payments as f64 / rate);
+        (slot > now).then_some(slot)
+    }
+
+    /// Counts what a paced payment's attempt came to.
+    pub fn record(&self, succeeded: bool) {
+        let mut ramp = self.ramp.lock().unwrap();
+        if ramp.rate.is_none() {
+            return;
+        }
+        if succeeded {
+            ramp.succeeded += 1;
+        } else {
+            ramp.failed += 1;
+        }
+    }
+
+    /// Settles every step that ended by `now`.
+    fn advance(&self, ramp: &mut Ramp, now: Instant) {
+        while let Some(rate) = ramp.rate
+            && now >= ramp.step_ends
+        {
+            let attempts = ramp.succeeded + ramp.failed;
+            if attempts == 0 {
+                ramp.step_ends = now + self.config.step;
+                continue;
+            }
+            ramp.step_ends += self.config.step;
+            let error_rate = f64::from(ramp.failed) / f64::from(attempts);
+            let next = if error_rate > self.config.max_error_rate {
+                (rate / 2.0).max(self.config.initial_rate)
+            } else {
+                rate * 2.0
+            };
+            ramp.rate = (next < self.config.max_rate).then_some(next);
+            ramp.succeeded = 0;
+            ramp.failed = 0;
+            Self::publish(ramp.rate);
+            match ramp.rate {
+                Some(rate) => tracing::info!(processor = %self.processor, rate, error_rate, "Slow start step"),
+                None => tracing::warn!(processor = %self.processor, "Slow start finished"),
+            }
+        }
+    }
+
+    fn publish(rate: Option<f64>) {
+        METRICS.slow_start_rate.store(rate.unwrap_or(0.0) as u64, Ordering::Relaxed);
+    }
+
+    /// Begins a ramp every time routing comes back to the paced processor.
+    pub async fn follow(self: Arc<Self>, mut healths: watch::Receiver<HealthSnapshot>) {
+        let mut routed = healths.borrow_and_update().route == Some(self.processor);
+        while healths.changed().await.is_ok() {
+            let now_routed = healths.borrow_and_update().route == Some(self.processor);
+            if now_routed && !routed {
+                self.begin();
+            }
+            routed = now_routed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn slow_start(clock: Arc<ManualClock>) -> SlowStart {
+        let config = SlowStartConfig {
+            initial_rate: 10.0,
+            max_rate: 80.0,
+            step: Duration::from_secs(1),
+            max_error_rate: 0.1,
+        };
+        SlowStart::new(config, ProcessorType::DEFAULT, clock)
+    }
+
+    #[test]
+    fn spaces_payments_at_the_current_rate() {
+        let clock = Arc::new(ManualClock::new());
+        let slow_start = slow_start(clock.clone());
+        assert_eq!(slow_start.reserve(5), None);
+
+        slow_start.begin();
+        let start = clock.now();
+        assert_eq!(slow_start.reserve(1), None);
+        assert_eq!(slow_start.reserve(2), Some(start + Duration::from_millis(100)));
+        assert_eq!(slow_start.reserve(1), Some(start + Duration::from_millis(300)));
+    }
+
+    #[test]
+    fn rate_doubles_while_errors_stay_low_and_halves_otherwise() {
+        let clock = Arc::new(ManualClock::new());
+        let slow_start = slow_start(clock.clone());
+        slow_start.begin();
+
+        slow_start.record(true);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(slow_start.rate(), Some(20.0));
+
+        slow_start.record(true);
+        slow_start.record(false);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(slow_start.rate(), Some(10.0));
+
+        // Quiet steps hold the rate.
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(slow_start.rate(), Some(10.0));
+
+        for expected in [Some(20.0), Some(40.0), None] {
+            slow_start.record(true);
+            clock.advance(Duration::from_secs(1));
+            assert_eq!(slow_start.rate(), expected);
+        }
+        assert_eq!(slow_start.reserve(100), None);
+    }
+}
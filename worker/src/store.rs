@@ -1,58 +1,741 @@
-﻿use crate::payment::Payment;
+﻿use crate::error::WorkerError;
+use crate::ledger::Ledger;
+use crate::payment::Payment;
+use crate::payment_message::PaymentMessage;
+use crate::processor_type::ProcessorType;
+use crate::redis_summary::RedisSummary;
+use bytes::Bytes;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::GenericClient;
 use futures_util::pin_mut;
-use std::fmt::Display;
-use std::sync::Arc;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{mpsc, watch, RwLock};
+use tokio::task::JoinHandle;
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+use time::OffsetDateTime;
 use tokio_postgres::binary_copy::BinaryCopyInWriter;
-use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::types::Type;
+use tokio_postgres::CopyInSink;
+
+/// How often payments whose write failed are written again.
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(1);
+const RECONCILE_BATCH_SIZE: usize = 512;
+/// Longest a purge waits for the payments queued before it to be written.
+const PURGE_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Pause between checks while waiting for Postgres at startup.
+const DATABASE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Longest pause between attempts to create the schema at startup.
+const SCHEMA_RETRY_MAX_INTERVAL: Duration = Duration::from_secs(2);
+/// Transaction-level advisory lock held while the schema is created, so
+/// replicas starting together do not race on `CREATE TYPE`; `payments` in
+/// ASCII.
+const SCHEMA_LOCK_KEY: i64 = 0x7061_796d_656e_7473;
+
+/// Everything the store writes to. Each statement is a no-op once its
+/// object exists, so it runs on every start; the `ALTER TABLE`s bring
+/// tables created by older versions up to date.
+const SCHEMA: &str = "
+DO $$ BEGIN
+    CREATE TYPE service_type AS ENUM ('default', 'fallback');
+EXCEPTION WHEN duplicate_object THEN NULL;
+END $$;
+
+CREATE TABLE IF NOT EXISTS payments (
+    id SERIAL PRIMARY KEY,
+    amount DECIMAL(10, 2) NOT NULL,
+    requested_at TIMESTAMPTZ NOT NULL,
+    service_used service_type NOT NULL,
+    correlation_id UUID NOT NULL,
+    -- Set by the gateway (RUN_ID) so summaries can be filtered per test run.
+    run_id TEXT
+);
+
+-- Retries the worker could not keep in memory, loaded back once due.
+CREATE TABLE IF NOT EXISTS scheduled_retries (
+    correlation_id UUID PRIMARY KEY,
+    amount DECIMAL(10, 2) NOT NULL,
+    retry_count INTEGER NOT NULL,
+    ingest_ts BIGINT,
+    next_attempt TIMESTAMPTZ NOT NULL,
+    run_id TEXT,
+    processor TEXT
+);
+
+-- Payments a processor answered 422, with its response, for diagnosing
+-- requests it cannot parse. They are not retried.
+CREATE TABLE IF NOT EXISTS quarantined_payments (
+    correlation_id UUID PRIMARY KEY,
+    amount DECIMAL(10, 2) NOT NULL,
+    processor TEXT NOT NULL,
+    response TEXT NOT NULL,
+    quarantined_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    run_id TEXT
+);
+
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS run_id TEXT;
+ALTER TABLE scheduled_retries ADD COLUMN IF NOT EXISTS ingest_ts BIGINT;
+ALTER TABLE scheduled_retries ADD COLUMN IF NOT EXISTS run_id TEXT;
+ALTER TABLE scheduled_retries ADD COLUMN IF NOT EXISTS processor TEXT;
+ALTER TABLE quarantined_payments ADD COLUMN IF NOT EXISTS run_id TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_payments_requested_at_service_used ON payments(requested_at, service_used);
+CREATE UNIQUE INDEX IF NOT EXISTS uq_correlation_id ON payments(correlation_id);
+";
+
+/// `payments_summary` (see [`SummaryTable`]), filled with the payments
+/// already stored. `payments` is locked meanwhile so none slips in between.
+const SUMMARY_SCHEMA: &str = "
+LOCK TABLE payments IN SHARE MODE;
+
+CREATE TABLE payments_summary (
+    service_used service_type NOT NULL,
+    requested_at TIMESTAMPTZ NOT NULL,
+    total_requests BIGINT NOT NULL,
+    total_amount NUMERIC NOT NULL,
+    PRIMARY KEY (service_used, requested_at)
+);
+
+INSERT INTO payments_summary (service_used, requested_at, total_requests, total_amount)
+SELECT service_used, requested_at, count(*), sum(amount)
+FROM payments
+GROUP BY service_used, requested_at;
+";
+
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Where processed payments end up: implemented by [`PostgresStore`] and by
+/// [`crate::memory_store::MemoryStore`], and open to other backends such as
+/// Redis or SQLite. The worker pool and admin server only see this trait.
+///
+/// Parking retries and quarantining payments are optional; a backend without
+/// them keeps retries in the worker's memory and only logs quarantines.
+pub trait PaymentStore: Send + Sync {
+    /// Queues a processed payment for writing. Never waits on the backend,
+    /// so a slow database does not hold up the workers.
+    fn push_payment(&self, payment: Payment) -> Result<(), WorkerError>;
+
+    /// Stops accepting payments and writes everything still buffered.
+    fn flush(&self) -> StoreFuture<'_, ()>;
+
+    /// Totals per processor name over the payments `filter` covers.
+    fn summary(&self, filter: SummaryFilter) -> StoreFuture<'_, Result<Summary, StoreError>>;
+
+    /// Forgets every stored payment.
+    fn purge(&self) -> StoreFuture<'_, Result<(), StoreError>>;
+
+    /// Payments accepted by a processor that are not stored yet, for
+    /// backends that track them.
+    fn ledger(&self) -> Option<&Ledger> {
+        None
+    }
+
+    /// Parks retries that do not fit in the worker's memory. Returns whether
+    /// they were written.
+    fn spill_retries<'a>(&'a self, _retries: &'a [ScheduledRetry]) -> StoreFuture<'a, bool> {
+        Box::pin(async { false })
+    }
+
+    /// Removes and returns up to `limit` parked retries that are due.
+    fn take_due_retries(&self, _limit: usize) -> StoreFuture<'_, Vec<PaymentMessage>> {
+        Box::pin(async { Vec::new() })
+    }
+
+    /// Removes and returns up to `limit` parked retries, due or not, earliest
+    /// first. Used to recover everything a previous run left behind.
+    fn take_parked_retries(&self, _limit: usize) -> StoreFuture<'_, Vec<ScheduledRetry>> {
+        Box::pin(async { Vec::new() })
+    }
+
+    /// Records a payment the processor answered 422 together with its
+    /// response body, so requests the processor cannot parse are diagnosed
+    /// rather than resent.
+    fn quarantine<'a>(&'a self, msg: &'a PaymentMessage, processor: ProcessorType, response: &'a [u8]) -> StoreFuture<'a, ()> {
+        log_quarantine(msg, processor, response);
+        Box::pin(async {})
+    }
+}
+
+/// Totals per processor name.
+pub type Summary = BTreeMap<String, ProcessorTotals>;
+
+/// One processor's share of a summary.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ProcessorTotals {
+    pub requests: u64,
+    pub cents: i64,
+}
+
+/// Which payments a summary covers; `from` and `to` are inclusive unix
+/// milliseconds.
+#[derive(Debug, Default, Clone)]
+pub struct SummaryFilter {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub run_id: Option<String>,
+}
+
+impl SummaryFilter {
+    pub fn is_empty(&self) -> bool {
+        self.from.is_none() && self.to.is_none() && self.run_id.is_none()
+    }
+}
 
 #[derive(Debug)]
 pub enum StoreError {
-    PushPaymentError,
+    /// No connection could be taken from the pool.
+    Unavailable,
+    Database(tokio_postgres::Error),
 }
 
-impl Display for StoreError {
+impl std::fmt::Display for StoreError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            StoreError::PushPaymentError => write!(f, "push payment into the store failed"),
+            StoreError::Unavailable => write!(f, "Store unavailable"),
+            StoreError::Database(e) => write!(f, "Database error: {}", e),
         }
     }
 }
 
 impl std::error::Error for StoreError {}
 
-pub struct Store {
+impl From<tokio_postgres::Error> for StoreError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        StoreError::Database(e)
+    }
+}
+
+fn log_quarantine(msg: &PaymentMessage, processor: ProcessorType, response: &[u8]) {
+    tracing::warn!(
+        correlation_id = %msg.correlation_id,
+        request_id = msg.request_id.as_deref(),
+        amount = %msg.amount,
+        %processor,
+        response = %String::from_utf8_lossy(response),
+        "Quarantining payment the processor cannot process"
+    );
+}
+
+/// Durability the store may give up for write throughput. Both are off by
+/// default.
+///
+/// - `unlogged` turns `payments` and `payments_summary` into `UNLOGGED`
+///   tables: writes skip the WAL, but Postgres empties both tables after a
+///   crash. `scheduled_retries` stays logged so parked work survives. When
+///   unset, both tables are set back to `LOGGED`.
+/// - `synchronous_commit: false` acknowledges a commit before its WAL reaches
+///   disk, so a crash can lose the most recent commits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Durability {
+    pub unlogged: bool,
+    pub synchronous_commit: bool,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Self {
+            unlogged: false,
+            synchronous_commit: true,
+        }
+    }
+}
+
+impl Durability {
+    /// Startup options for the store's connections, `None` when nothing is
+    /// relaxed.
+    pub fn connection_options(&self) -> Option<&'static str> {
+        (!self.synchronous_commit).then_some("-c synchronous_commit=off")
+    }
+
+    /// Statements run by [`PostgresStore::init`]. Postgres skips the rewrite
+    /// when a table already has the requested persistence.
+    fn table_statements(&self, summary_table: SummaryTable) -> Vec<&'static str> {
+        let mut statements = Vec::new();
+        if self.unlogged {
+            statements.push("ALTER TABLE payments SET UNLOGGED");
+            if summary_table != SummaryTable::Absent {
+                statements.push("ALTER TABLE payments_summary SET UNLOGGED");
+            }
+        } else {
+            statements.push("ALTER TABLE payments SET LOGGED");
+            if summary_table != SummaryTable::Absent {
+                statements.push("ALTER TABLE payments_summary SET LOGGED");
+            }
+        }
+        statements
+    }
+}
+
+/// A retry parked in `scheduled_retries` until `next_attempt`.
+pub struct ScheduledRetry {
+    pub msg: PaymentMessage,
+    pub next_attempt: OffsetDateTime,
+}
+
+/// How far an insert loop got, so a purge can wait for the payments queued
+/// before it.
+#[derive(Default)]
+struct PipelineProgress {
+    queued: AtomicU64,
+    settled: AtomicU64,
+}
+
+/// Payments written to Postgres through buffered insert loops, with failed
+/// writes tracked in a [`Ledger`] until reconciliation stores them.
+pub struct PostgresStore {
     dbpool: Arc<deadpool_postgres::Pool>,
-    sender: Option<mpsc::Sender<Payment>>,
+    /// Counters updated after every successful write, when configured.
+    summary: Option<RedisSummary>,
+    transactional_summary: bool,
+    flush_pipelines: usize,
+    /// One channel per flush pipeline, empty until [`PostgresStore::init`].
+    senders: Vec<mpsc::Sender<Payment>>,
+    progress: Vec<Arc<PipelineProgress>>,
+    /// Held shared while payments are written and settled, and exclusively
+    /// by a purge, so no write lands halfway through one.
+    purge_lock: Arc<RwLock<()>>,
+    shutdown: watch::Sender<bool>,
+    insert_handles: Mutex<Vec<JoinHandle<()>>>,
+    ledger: Arc<Ledger>,
+    summary_table: SummaryTable,
+    create_summary_table: bool,
+    durability: Durability,
 }
 
-impl Store {
-    pub fn new(dbpool: deadpool_postgres::Pool) -> Self {
+impl PostgresStore {
+    pub fn new(dbpool: deadpool_postgres::Pool, summary: Option<RedisSummary>) -> Self {
         Self {
             dbpool: Arc::new(dbpool),
-            sender: None,
+            summary,
+            transactional_summary: true,
+            flush_pipelines: 1,
+            senders: Vec::new(),
+            progress: Vec::new(),
+            purge_lock: Arc::new(RwLock::new(())),
+            shutdown: watch::channel(false).0,
+            insert_handles: Mutex::new(Vec::new()),
+            ledger: Arc::new(Ledger::default()),
+            summary_table: SummaryTable::Absent,
+            create_summary_table: false,
+            durability: Durability::default(),
         }
     }
 
-    pub async fn init(&mut self) {
-        let (sender, receiver) = mpsc::channel(16 * 1024);
+    /// Number of insert loops writing in parallel, each with its own buffer
+    /// and pooled connection.
+    ///
+    /// Payments are sharded by correlation id, so all writes for one payment
+    /// go through the same pipeline in the order they were pushed. There is
+    /// no ordering between pipelines: two payments pushed one after the other
+    /// may be committed in either order.
+    pub fn with_flush_pipelines(mut self, flush_pipelines: usize) -> Self {
+        self.flush_pipelines = flush_pipelines.max(1);
+        self
+    }
+
+    /// With `transactional_summary` unset, summary counters are written after
+    /// the payments rather than in the same transaction.
+    pub fn with_transactional_summary(mut self, transactional_summary: bool) -> Self {
+        self.transactional_summary = transactional_summary;
+        self
+    }
+
+    /// Has [`PostgresStore::init`] create `payments_summary` when it is
+    /// missing. Replicas already running keep writing without it until they
+    /// restart, so enable it on all of them at once.
+    pub fn with_summary_table(mut self, create_summary_table: bool) -> Self {
+        self.create_summary_table = create_summary_table;
+        self
+    }
+
+    /// Table persistence applied by [`PostgresStore::init`]. Session settings are
+    /// part of the pool's connection config, see
+    /// [`Durability::connection_options`].
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Creates the schema where it is missing, then starts the insert and
+    /// reconcile loops. Fails when the schema still cannot be created after
+    /// `timeout`, rather than starting on tables that may not exist.
+    pub async fn init(&mut self, timeout: Duration) -> Result<(), StoreError> {
+        self.create_schema(timeout).await?;
+        let summary_table = self.detect_summary_table().await;
+        self.summary_table = summary_table;
+        self.apply_durability(summary_table).await;
+
+        let mut handles = Vec::with_capacity(self.flush_pipelines + 1);
+        for _ in 0..self.flush_pipelines {
+            let (sender, receiver) = mpsc::channel(16 * 1024);
+            self.senders.push(sender);
+            let progress = Arc::new(PipelineProgress::default());
+            self.progress.push(progress.clone());
+
+            let dbpool_clone = self.dbpool.clone();
+            let summary = self.summary.clone();
+            let ledger = self.ledger.clone();
+            let purge_lock = self.purge_lock.clone();
+            let shutdown = self.shutdown.subscribe();
+            handles.push(tokio::spawn(async move {
+                Self::insert_loop(receiver, dbpool_clone, summary, ledger, summary_table, progress, purge_lock, shutdown)
+                    .await;
+            }));
+        }
 
-        self.sender = Some(sender);
         let dbpool_clone = self.dbpool.clone();
-        tokio::spawn(async move {
-            Self::insert_loop(receiver, dbpool_clone).await;
-        });
+        let summary = self.summary.clone();
+        let ledger = self.ledger.clone();
+        let purge_lock = self.purge_lock.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        handles.push(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(RECONCILE_INTERVAL) => {}
+                    _ = shutdown.changed() => return,
+                }
+                let _writing = purge_lock.read().await;
+                Self::reconcile(&dbpool_clone, &summary, &ledger, summary_table).await;
+            }
+        }));
+        *self.insert_handles.lock().unwrap() = handles;
+        Ok(())
+    }
+
+    /// Runs [`SCHEMA`] and, when asked for, [`SUMMARY_SCHEMA`] in one
+    /// transaction under [`SCHEMA_LOCK_KEY`], retrying with backoff until
+    /// `timeout`.
+    async fn create_schema(&self, timeout: Duration) -> Result<(), StoreError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut interval = DATABASE_POLL_INTERVAL;
+        loop {
+            let error = match self.try_create_schema().await {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            if tokio::time::Instant::now() + interval >= deadline {
+                tracing::error!(error = %error, "failed to create the schema");
+                return Err(error);
+            }
+            tracing::warn!(error = %error, retry_in_ms = interval.as_millis() as u64, "failed to create the schema, retrying");
+            tokio::time::sleep(interval).await;
+            interval = (interval * 2).min(SCHEMA_RETRY_MAX_INTERVAL);
+        }
     }
 
+    async fn try_create_schema(&self) -> Result<(), StoreError> {
+        let mut client = self.dbpool.get().await.map_err(|_| StoreError::Unavailable)?;
+        let transaction = client.transaction().await?;
+        transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&SCHEMA_LOCK_KEY]).await?;
+        transaction.batch_execute(SCHEMA).await?;
+        if self.create_summary_table {
+            let exists: bool = transaction
+                .query_one("SELECT to_regclass('payments_summary') IS NOT NULL", &[])
+                .await?
+                .get(0);
+            if !exists {
+                transaction.batch_execute(SUMMARY_SCHEMA).await?;
+                tracing::warn!("Created payments_summary");
+            }
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Adds a `service_type` label for every processor outside the built-in
+    /// default/fallback pair, so extending the chain needs no migration.
+    /// Run after [`PostgresStore::init`], which creates the type.
+    pub async fn register_processor_types(&self, processor_types: impl Iterator<Item = ProcessorType>) {
+        let extra: Vec<_> = processor_types
+            .filter(|t| *t != ProcessorType::DEFAULT && *t != ProcessorType::FALLBACK)
+            .collect();
+        if extra.is_empty() {
+            return;
+        }
+
+        let client = match self.dbpool.get().await {
+            Ok(client) => client,
+            Err(_) => {
+                tracing::error!("failed to get a client from the pool");
+                return;
+            }
+        };
+
+        // Names are validated to `[a-z0-9_]` when the chain is parsed.
+        for processor_type in extra {
+            let ddl = format!("ALTER TYPE service_type ADD VALUE IF NOT EXISTS '{}'", processor_type);
+            if let Err(e) = client.batch_execute(&ddl).await {
+                tracing::error!("failed to register processor {}: {}", processor_type, e);
+            }
+        }
+    }
+
+    async fn spill(&self, retries: &[ScheduledRetry]) -> bool {
+        let client = match self.dbpool.get().await {
+            Ok(client) => client,
+            Err(_) => {
+                tracing::error!("failed to get a client from the pool");
+                return false;
+            }
+        };
+
+        let mut correlation_ids = Vec::with_capacity(retries.len());
+        let mut amounts = Vec::with_capacity(retries.len());
+        let mut retry_counts = Vec::with_capacity(retries.len());
+        let mut ingest_ts = Vec::with_capacity(retries.len());
+        let mut next_attempts = Vec::with_capacity(retries.len());
+        let mut run_ids = Vec::with_capacity(retries.len());
+        let mut processors = Vec::with_capacity(retries.len());
+        for retry in retries {
+            correlation_ids.push(retry.msg.correlation_id);
+            amounts.push(retry.msg.amount);
+            retry_counts.push(retry.msg.retry_count as i32);
+            ingest_ts.push(retry.msg.ingest_ts.map(|ts| ts as i64));
+            next_attempts.push(retry.next_attempt);
+            run_ids.push(retry.msg.run_id.as_deref());
+            processors.push(retry.msg.pinned.map(|processor| processor.as_str()));
+        }
+
+        let result = client
+            .execute(
+                "INSERT INTO scheduled_retries (correlation_id, amount, retry_count, ingest_ts, next_attempt, run_id, processor)
+                 SELECT * FROM UNNEST($1::uuid[], $2::numeric[], $3::int4[], $4::int8[], $5::timestamptz[], $6::text[], $7::text[])
+                 ON CONFLICT (correlation_id) DO NOTHING",
+                &[&correlation_ids, &amounts, &retry_counts, &ingest_ts, &next_attempts, &run_ids, &processors],
+            )
+            .await;
+
+        if let Err(e) = &result {
+            tracing::error!("failed to spill {} retries: {}", retries.len(), e);
+        }
+        result.is_ok()
+    }
+
+    /// Keeps a quarantined payment in `quarantined_payments`.
+    async fn insert_quarantined(&self, msg: &PaymentMessage, processor: ProcessorType, response: &[u8]) {
+        let response = String::from_utf8_lossy(response);
+        let client = match self.dbpool.get().await {
+            Ok(client) => client,
+            Err(_) => {
+                tracing::error!("failed to get a client from the pool");
+                return;
+            }
+        };
+        let result = client
+            .execute(
+                "INSERT INTO quarantined_payments (correlation_id, amount, processor, response, run_id)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (correlation_id) DO UPDATE
+                 SET processor = EXCLUDED.processor, response = EXCLUDED.response, quarantined_at = now()",
+                &[&msg.correlation_id, &msg.amount, &processor.as_str(), &response.as_ref(), &msg.run_id.as_deref()],
+            )
+            .await;
+        if let Err(e) = result {
+            tracing::error!("failed to quarantine payment {}: {}", msg.correlation_id, e);
+        }
+    }
+
+    /// `SKIP LOCKED` lets several workers poll the table without handing out
+    /// the same retry twice.
+    async fn take_retries(&self, limit: usize, due_only: bool) -> Vec<ScheduledRetry> {
+        let client = match self.dbpool.get().await {
+            Ok(client) => client,
+            Err(_) => {
+                tracing::error!("failed to get a client from the pool");
+                return Vec::new();
+            }
+        };
+
+        let rows = client
+            .query(
+                "DELETE FROM scheduled_retries WHERE correlation_id IN (
+                     SELECT correlation_id FROM scheduled_retries
+                     WHERE next_attempt <= now() OR NOT $2
+                     ORDER BY next_attempt
+                     LIMIT $1
+                     FOR UPDATE SKIP LOCKED
+                 )
+                 RETURNING correlation_id, amount, retry_count, ingest_ts, run_id, next_attempt, processor",
+                &[&(limit as i64), &due_only],
+            )
+            .await;
+
+        match rows {
+            Ok(rows) => rows
+                .iter()
+                .map(|row| ScheduledRetry {
+                    msg: PaymentMessage {
+                        correlation_id: row.get(0),
+                        amount: row.get(1),
+                        retry_count: row.get::<_, i32>(2) as u32,
+                        ingest_ts: row.get::<_, Option<i64>>(3).map(|ts| ts as u64),
+                        run_id: row.get::<_, Option<&str>>(4).map(Arc::from),
+                        request_id: None,
+                        deadline_ts: None,
+                        pinned: row.get::<_, Option<&str>>(6).map(ProcessorType::new),
+                    },
+                    next_attempt: row.get(5),
+                })
+                .collect(),
+            Err(e) => {
+                tracing::error!("failed to load scheduled retries: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    async fn apply_durability(&self, summary_table: SummaryTable) {
+        let statements = self.durability.table_statements(summary_table);
+        let client = match self.dbpool.get().await {
+            Ok(client) => client,
+            Err(_) => {
+                tracing::error!("failed to get a client from the pool");
+                return;
+            }
+        };
+
+        for statement in statements {
+            match client.batch_execute(statement).await {
+                Ok(()) if self.durability.unlogged => tracing::warn!(statement, "Relaxed table durability"),
+                Ok(()) => tracing::debug!(statement, "Applied table durability"),
+                Err(e) => tracing::error!("failed to run {}: {}", statement, e),
+            }
+        }
+    }
+
+    async fn detect_summary_table(&self) -> SummaryTable {
+        let exists = match self.dbpool.get().await {
+            Ok(client) => client
+                .query_one("SELECT to_regclass('payments_summary') IS NOT NULL", &[])
+                .await
+                .map(|row| row.get::<_, bool>(0)),
+            Err(_) => {
+                tracing::error!("failed to get a client from the pool");
+                return SummaryTable::Absent;
+            }
+        };
+
+        match exists {
+            Ok(true) if self.transactional_summary => SummaryTable::Transactional,
+            Ok(true) => SummaryTable::AfterWrite,
+            Ok(false) => SummaryTable::Absent,
+            Err(e) => {
+                tracing::error!("failed to look up payments_summary: {}", e);
+                SummaryTable::Absent
+            }
+        }
+    }
+
+    /// Waits for the insert loops to write what they hold and exit. Failed
+    /// writes get one last reconcile pass; whatever is still missing after it
+    /// is logged.
+    async fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+
+        let handles = std::mem::take(&mut *self.insert_handles.lock().unwrap());
+        futures_util::future::join_all(handles).await;
+
+        Self::reconcile(&self.dbpool, &self.summary, &self.ledger, self.summary_table).await;
+        let report = self.ledger.report();
+        if report.failed > 0 {
+            tracing::error!(
+                payments = report.failed,
+                amount = %report.failed_amount,
+                "payments accepted by processors were never stored"
+            );
+            for payment in self.ledger.failed(usize::MAX) {
+                tracing::warn!(correlation_id = %payment.correlation_id, processor = %payment.processor, amount = %payment.amount, "unstored payment");
+            }
+        }
+    }
+
+    /// Writes payments whose write failed again. The failed write may have
+    /// committed without the worker hearing back, so payments already in
+    /// `payments` are only cleared from the ledger and counted in Redis,
+    /// which counts each payment once. Redis updates that failed are sent
+    /// again first.
+    async fn reconcile(
+        dbpool: &Arc<deadpool_postgres::Pool>,
+        summary: &Option<RedisSummary>,
+        ledger: &Ledger,
+        summary_table: SummaryTable,
+    ) {
+        if let Some(summary) = summary
+            && let Err(e) = summary.retry_pending().await
+        {
+            tracing::error!("failed to catch up the redis summary: {}", e);
+        }
+
+        let failed = ledger.failed(RECONCILE_BATCH_SIZE);
+        if failed.is_empty() {
+            return;
+        }
+
+        let stored: Vec<uuid::Uuid> = match dbpool.get().await {
+            Ok(client) => {
+                let ids: Vec<_> = failed.iter().map(|payment| payment.correlation_id).collect();
+                match client
+                    .query("SELECT correlation_id FROM payments WHERE correlation_id = ANY($1)", &[&ids])
+                    .await
+                {
+                    Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+                    Err(e) => {
+                        tracing::error!("failed to look up unstored payments: {}", e);
+                        return;
+                    }
+                }
+            }
+            Err(_) => {
+                tracing::error!("failed to get a client from the pool");
+                return;
+            }
+        };
+
+        let (stored, missing): (Vec<_>, Vec<_>) = failed
+            .into_iter()
+            .partition(|payment| stored.contains(&payment.correlation_id));
+        ledger.persisted(&stored);
+        Self::record_summary(summary, &stored).await;
+
+        if !missing.is_empty() {
+            let written = Self::write_payments(dbpool, &missing, summary_table).await;
+            let stored = Self::settle(summary, ledger, &missing, written).await;
+            if stored > 0 {
+                tracing::warn!(payments = stored, "stored payments after a failed write");
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn insert_loop(
         mut receiver: mpsc::Receiver<Payment>,
         dbpool: Arc<deadpool_postgres::Pool>,
+        summary: Option<RedisSummary>,
+        ledger: Arc<Ledger>,
+        summary_table: SummaryTable,
+        progress: Arc<PipelineProgress>,
+        purge_lock: Arc<RwLock<()>>,
+        shutdown: watch::Receiver<bool>,
     ) {
         let mut buffer = Vec::<Payment>::with_capacity(256);
 
         loop {
+            // Once closed no new payment can arrive, so the drain below
+            // empties the channel for good. `try_recv` keeps reporting
+            // `Empty` rather than `Disconnected` while the store holds the
+            // sender, hence the explicit exit after the final flush.
+            let stopping = *shutdown.borrow();
+            if stopping {
+                receiver.close();
+            }
+
             loop {
                 match receiver.try_recv() {
                     Ok(item) => buffer.push(item),
@@ -60,93 +743,637 @@ impl Store {
                     Err(TryRecvError::Disconnected) => {
                         // Channel closed, maybe flush and exit loop
                         if !buffer.is_empty() {
-                            _ = Self::batch_payments(&dbpool, &buffer).await;
+                            let _writing = purge_lock.read().await;
+                            Self::flush(&dbpool, &summary, &ledger, &buffer, summary_table).await;
+                            progress.settled.fetch_add(buffer.len() as u64, Ordering::Release);
                         }
                         return;
                     }
                 }
             }
 
-            if buffer.len() == 1 {
-                let payment = buffer.pop().unwrap();
-                _ = Self::insert_payment(&dbpool, &payment).await;
-            } else if buffer.len() > 1 {
+            if !buffer.is_empty() {
                 let payments = std::mem::take(&mut buffer);
-                _ = Self::batch_payments(&dbpool, &payments).await;
+                let _writing = purge_lock.read().await;
+                Self::flush(&dbpool, &summary, &ledger, &payments, summary_table).await;
+                progress.settled.fetch_add(payments.len() as u64, Ordering::Release);
+            }
+
+            if stopping {
+                return;
             }
 
             tokio::time::sleep(Duration::from_millis(1)).await;
         }
     }
 
-    pub async fn push_payment(&self, payment: Payment) -> Result<(), StoreError> {
-        match &self.sender {
-            Some(sender) => {
-                sender
-                    .try_send(payment)
-                    .map_err(|_| StoreError::PushPaymentError)?;
-                Ok(())
+    /// Pipeline that writes `payment`; see [`PostgresStore::with_flush_pipelines`].
+    fn pipeline_for(&self, payment: &Payment) -> usize {
+        (payment.correlation_id.as_u128() % self.senders.len().max(1) as u128) as usize
+    }
+
+    async fn flush(
+        dbpool: &Arc<deadpool_postgres::Pool>,
+        summary: &Option<RedisSummary>,
+        ledger: &Ledger,
+        payments: &[Payment],
+        summary_table: SummaryTable,
+    ) {
+        let written = Self::write_payments(dbpool, payments, summary_table).await;
+        Self::settle(summary, ledger, payments, written).await;
+    }
+
+    /// Updates the ledger and Redis counters with what became of
+    /// `payments`. Returns how many are stored now.
+    async fn settle(
+        summary: &Option<RedisSummary>,
+        ledger: &Ledger,
+        payments: &[Payment],
+        written: Written,
+    ) -> usize {
+        match written {
+            Written::All => {
+                ledger.persisted(payments);
+                Self::record_summary(summary, payments).await;
+                payments.len()
+            }
+            Written::Nothing => {
+                ledger.write_failed(payments);
+                0
+            }
+            Written::Isolated { inserted, duplicates, rejected } => {
+                ledger.persisted(&inserted);
+                ledger.persisted(&duplicates);
+                ledger.rejected(&rejected);
+                Self::record_summary(summary, &inserted).await;
+                Self::record_summary(summary, &duplicates).await;
+                inserted.len() + duplicates.len()
             }
-            None => Err(StoreError::PushPaymentError),
         }
     }
 
-    async fn batch_payments(dbpool: &Arc<deadpool_postgres::Pool>, payments: &[Payment]) {
-        if let Ok(client) = dbpool.get().await {
-            if let Ok(sink) = client
-                .copy_in("COPY payments (amount, requested_at, service_used, correlation_id) FROM STDIN BINARY")
+    /// Sums `payments` per processor; `payments_summary` only has totals
+    /// for every payment, not for a window or a run.
+    async fn query_summary(&self, filter: &SummaryFilter) -> Result<Summary, StoreError> {
+        let client = self.dbpool.get().await.map_err(|_| StoreError::Unavailable)?;
+        let millis = |ms: Option<i64>| {
+            ms.and_then(|ms| OffsetDateTime::from_unix_timestamp_nanos(ms as i128 * 1_000_000).ok())
+        };
+        let rows = client
+            .query(
+                "SELECT service_used::text, count(*), COALESCE(sum(amount), 0)
+                 FROM payments
+                 WHERE ($1::timestamptz IS NULL OR requested_at >= $1)
+                   AND ($2::timestamptz IS NULL OR requested_at <= $2)
+                   AND ($3::text IS NULL OR run_id = $3)
+                 GROUP BY service_used",
+                &[&millis(filter.from), &millis(filter.to), &filter.run_id],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let amount: Decimal = row.get(2);
+                let totals = ProcessorTotals {
+                    requests: row.get::<_, i64>(1) as u64,
+                    cents: (amount * Decimal::ONE_HUNDRED).round().to_i64().unwrap_or_default(),
+                };
+                (row.get(0), totals)
+            })
+            .collect())
+    }
+
+    /// Empties every table the store writes and the Redis counters, and
+    /// forgets the payments the ledger was still reconciling. Payments
+    /// queued before the purge are written first, so none of them lands
+    /// afterwards; fails when they are not within [`PURGE_DRAIN_TIMEOUT`].
+    async fn truncate(&self) -> Result<(), StoreError> {
+        self.drain_pipelines().await?;
+        let _purging = self.purge_lock.write().await;
+
+        let client = self.dbpool.get().await.map_err(|_| StoreError::Unavailable)?;
+        let statement = match self.summary_table {
+            SummaryTable::Absent => "TRUNCATE TABLE payments, scheduled_retries, quarantined_payments",
+            SummaryTable::Transactional | SummaryTable::AfterWrite => {
+                "TRUNCATE TABLE payments, payments_summary, scheduled_retries, quarantined_payments"
+            }
+        };
+        client.batch_execute(statement).await?;
+        self.ledger.clear();
+        if let Some(summary) = &self.summary
+            && let Err(e) = summary.purge().await
+        {
+            tracing::error!("failed to purge redis summary: {}", e);
+            return Err(StoreError::Unavailable);
+        }
+        Ok(())
+    }
+
+    /// Waits until every insert loop has settled the payments queued so far.
+    async fn drain_pipelines(&self) -> Result<(), StoreError> {
+        let targets: Vec<u64> = self.progress.iter().map(|p| p.queued.load(Ordering::Acquire)).collect();
+        let deadline = tokio::time::Instant::now() + PURGE_DRAIN_TIMEOUT;
+        while !self
+            .progress
+            .iter()
+            .zip(&targets)
+            .all(|(progress, target)| progress.settled.load(Ordering::Acquire) >= *target)
+        {
+            if tokio::time::Instant::now() >= deadline {
+                tracing::error!("queued payments were not written in time, not purging");
+                return Err(StoreError::Unavailable);
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        Ok(())
+    }
+
+    async fn record_summary(summary: &Option<RedisSummary>, payments: &[Payment]) {
+        if let Some(summary) = summary
+            && let Err(e) = summary.record(payments).await
+        {
+            tracing::error!("failed to update redis summary: {}", e);
+        }
+    }
+
+    /// Writes `payments` (a single row INSERT or a binary COPY) and, when the
+    /// summary table exists, their counters. When Postgres refuses the batch
+    /// itself, e.g. over a correlationId already stored, its payments are
+    /// written again one by one so a single bad row does not hold back the
+    /// rest.
+    async fn write_payments(
+        dbpool: &Arc<deadpool_postgres::Pool>,
+        payments: &[Payment],
+        summary_table: SummaryTable,
+    ) -> Written {
+        let mut client = match dbpool.get().await {
+            Ok(client) => client,
+            Err(_) => {
+                tracing::error!("failed to get a client from the pool");
+                return Written::Nothing;
+            }
+        };
+
+        let result = match summary_table {
+            SummaryTable::Absent => Self::insert_rows(&**client, payments).await,
+            SummaryTable::Transactional => {
+                async {
+                    let transaction = client.transaction().await?;
+                    Self::insert_rows(&*transaction, payments).await?;
+                    Self::upsert_summary(&*transaction, payments).await?;
+                    transaction.commit().await
+                }
                 .await
-            {
-                let writer = BinaryCopyInWriter::new(
-                    sink,
-                    &[Type::NUMERIC, Type::TIMESTAMPTZ, Type::ANYENUM, Type::UUID],
-                );
-                pin_mut!(writer);
-
-                for payment in payments {
-                    if let Err(e) = writer.as_mut()
-                        .write(&[
-                            &payment.amount,
-                            &payment.requested_at,
-                            &payment.processor,
-                            &payment.correlation_id,
-                        ])
-                        .await {
-                        tracing::error!("failed to write payments batch: {}", e);
+            }
+            SummaryTable::AfterWrite => {
+                let result = Self::insert_rows(&**client, payments).await;
+                if result.is_ok()
+                    && let Err(e) = Self::upsert_summary(&**client, payments).await
+                {
+                    tracing::error!("failed to update payments summary: {}", e);
+                }
+                result
+            }
+        };
+
+        match result {
+            Ok(()) => Written::All,
+            // A refusal is about the rows; anything else, such as a lost
+            // connection or a cancelled statement, is left to reconciliation.
+            Err(e) if refuses_rows(e.code()) => {
+                tracing::warn!(error = %e, payments = payments.len(), "Batch refused, writing its payments one by one");
+                match Self::write_each(&mut client, payments, summary_table).await {
+                    Ok(written) => written,
+                    Err(e) => {
+                        tracing::error!("failed to write payments one by one: {}", e);
+                        Written::Nothing
                     }
                 }
+            }
+            Err(e) => {
+                tracing::error!("failed to write payments batch: {}", e);
+                Written::Nothing
+            }
+        }
+    }
+
+    /// Writes each payment under its own savepoint, setting aside the ones
+    /// Postgres refuses. Payments already stored are skipped, and only the
+    /// newly inserted ones are counted in `payments_summary`. Any other
+    /// failure fails the whole batch, for reconciliation to write again.
+    async fn write_each(
+        client: &mut deadpool_postgres::Object,
+        payments: &[Payment],
+        summary_table: SummaryTable,
+    ) -> Result<Written, tokio_postgres::Error> {
+        let mut transaction = client.transaction().await?;
+        let insert = transaction
+            .prepare(
+                "INSERT INTO payments (amount, requested_at, service_used, correlation_id, run_id)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT DO NOTHING",
+            )
+            .await?;
 
-                if let Err(e) = writer.finish().await {
-                    tracing::error!("failed to finish payments batch: {}", e);
+        let (mut inserted, mut duplicates, mut rejected) = (Vec::new(), Vec::new(), Vec::new());
+        for payment in payments {
+            let savepoint = transaction.savepoint("payment").await?;
+            let result = savepoint
+                .execute(
+                    &insert,
+                    &[
+                        &payment.amount,
+                        &payment.requested_at,
+                        &payment.processor,
+                        &payment.correlation_id,
+                        &payment.run_id.as_deref(),
+                    ],
+                )
+                .await;
+            match result {
+                Ok(rows) => {
+                    savepoint.commit().await?;
+                    if rows == 1 {
+                        inserted.push(payment.clone());
+                    } else {
+                        duplicates.push(payment.clone());
+                    }
+                }
+                Err(e) if refuses_rows(e.code()) => {
+                    savepoint.rollback().await?;
+                    tracing::error!(correlation_id = %payment.correlation_id, amount = %payment.amount, error = %e, "Payment refused by the database");
+                    rejected.push(payment.clone());
                 }
+                Err(e) => return Err(e),
             }
-        } else {
-            tracing::error!("failed to get a client from the pool");
         }
+
+        if summary_table != SummaryTable::Absent && !inserted.is_empty() {
+            Self::upsert_summary(&*transaction, &inserted).await?;
+        }
+        transaction.commit().await?;
+        Ok(Written::Isolated { inserted, duplicates, rejected })
     }
 
-    async fn insert_payment(
-        dbpool: &Arc<deadpool_postgres::Pool>,
-        payment: &Payment,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let conn = dbpool.get().await?;
+    async fn insert_rows<W: PaymentWriter>(
+        client: &W,
+        payments: &[Payment],
+    ) -> Result<(), tokio_postgres::Error> {
+        if let [payment] = payments {
+            return Self::insert_payment(client, payment).await;
+        }
 
-        let stmt = conn.prepare(
-            "INSERT INTO payments (amount, requested_at, service_used, correlation_id) VALUES ($1, $2, $3, $4)"
+        let sink = client.copy_in_payments().await?;
+        let writer = BinaryCopyInWriter::new(
+            sink,
+            &[Type::NUMERIC, Type::TIMESTAMPTZ, Type::ANYENUM, Type::UUID, Type::TEXT],
+        );
+        pin_mut!(writer);
+
+        for payment in payments {
+            writer
+                .as_mut()
+                .write(&[
+                    &payment.amount,
+                    &payment.requested_at,
+                    &payment.processor,
+                    &payment.correlation_id,
+                    &payment.run_id.as_deref(),
+                ])
+                .await?;
+        }
+
+        writer.finish().await?;
+        Ok(())
+    }
+
+    async fn insert_payment<W: PaymentWriter>(
+        client: &W,
+        payment: &Payment,
+    ) -> Result<(), tokio_postgres::Error> {
+        let stmt = client.prepare(
+            "INSERT INTO payments (amount, requested_at, service_used, correlation_id, run_id) VALUES ($1, $2, $3, $4, $5)"
         )
             .await?;
 
-        conn.execute(
+        client.execute(
             &stmt,
             &[
                 &payment.amount,
                 &payment.requested_at,
                 &payment.processor,
                 &payment.correlation_id,
+                &payment.run_id.as_deref(),
             ],
         )
         .await?;
 
         Ok(())
     }
+
+    /// Adds `payments` to `payments_summary`, one row per processor and
+    /// `requested_at`. Rows are aggregated first since a single upsert may
+    /// not touch the same key twice, and sorted so that pipelines upserting
+    /// overlapping keys lock them in the same order instead of deadlocking.
+    async fn upsert_summary<W: PaymentWriter>(
+        client: &W,
+        payments: &[Payment],
+    ) -> Result<(), tokio_postgres::Error> {
+        let mut totals: HashMap<(&ProcessorType, OffsetDateTime), (i64, Decimal)> = HashMap::new();
+        for payment in payments {
+            let total = totals
+                .entry((&payment.processor, payment.requested_at))
+                .or_default();
+            total.0 += 1;
+            total.1 += payment.amount;
+        }
+
+        let mut totals: Vec<_> = totals.into_iter().collect();
+        totals.sort_by_key(|((processor, at), _)| (processor.as_str(), *at));
+
+        let mut processors = Vec::with_capacity(totals.len());
+        let mut requested_at = Vec::with_capacity(totals.len());
+        let mut requests = Vec::with_capacity(totals.len());
+        let mut amounts = Vec::with_capacity(totals.len());
+        for ((processor, at), (count, amount)) in totals {
+            processors.push(*processor);
+            requested_at.push(at);
+            requests.push(count);
+            amounts.push(amount);
+        }
+
+        let stmt = client
+            .prepare(
+                "INSERT INTO payments_summary (service_used, requested_at, total_requests, total_amount)
+                 SELECT * FROM UNNEST($1::service_type[], $2::timestamptz[], $3::bigint[], $4::numeric[])
+                 ON CONFLICT (service_used, requested_at) DO UPDATE
+                 SET total_requests = payments_summary.total_requests + EXCLUDED.total_requests,
+                     total_amount = payments_summary.total_amount + EXCLUDED.total_amount",
+            )
+            .await?;
+
+        client
+            .execute(&stmt, &[&processors, &requested_at, &requests, &amounts])
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// What became of a batch handed to [`PostgresStore::write_payments`].
+enum Written {
+    All,
+    /// Nothing was stored; reconciliation writes the batch again.
+    Nothing,
+    /// The batch was refused and its payments written one by one.
+    Isolated {
+        inserted: Vec<Payment>,
+        /// Already stored under the same correlationId.
+        duplicates: Vec<Payment>,
+        /// Refused on their own; writing them again cannot help.
+        rejected: Vec<Payment>,
+    },
+}
+
+/// How `payments_summary` is maintained, decided once at [`PostgresStore::init`].
+///
+/// The table is optional; when present it is expected to look like
+/// `(service_used service_type, requested_at timestamptz, total_requests
+/// bigint, total_amount numeric, PRIMARY KEY (service_used, requested_at))`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SummaryTable {
+    Absent,
+    /// Counters are upserted in the same transaction as the payments, so
+    /// summary reads never observe a partial batch.
+    Transactional,
+    /// Counters are upserted after the payments are written, trading that
+    /// guarantee for throughput.
+    AfterWrite,
+}
+
+impl PaymentStore for PostgresStore {
+    /// A payment that cannot be queued is kept in the [`Ledger`] and written
+    /// by reconciliation.
+    fn push_payment(&self, payment: Payment) -> Result<(), WorkerError> {
+        self.ledger.accepted(&payment);
+        let pipeline = self.pipeline_for(&payment);
+        match self.senders.get(pipeline) {
+            Some(sender) => match sender.try_send(payment) {
+                Ok(()) => {
+                    self.progress[pipeline].queued.fetch_add(1, Ordering::Release);
+                    Ok(())
+                }
+                Err(TrySendError::Full(payment) | TrySendError::Closed(payment)) => {
+                    self.ledger.write_failed(&[payment]);
+                    Err(WorkerError::StoreUnavailable)
+                }
+            },
+            None => {
+                self.ledger.write_failed(&[payment]);
+                Err(WorkerError::StoreUnavailable)
+            }
+        }
+    }
+
+    fn flush(&self) -> StoreFuture<'_, ()> {
+        Box::pin(self.shutdown())
+    }
+
+    fn summary(&self, filter: SummaryFilter) -> StoreFuture<'_, Result<Summary, StoreError>> {
+        Box::pin(async move { self.query_summary(&filter).await })
+    }
+
+    fn purge(&self) -> StoreFuture<'_, Result<(), StoreError>> {
+        Box::pin(self.truncate())
+    }
+
+    fn ledger(&self) -> Option<&Ledger> {
+        Some(&self.ledger)
+    }
+
+    fn spill_retries<'a>(&'a self, retries: &'a [ScheduledRetry]) -> StoreFuture<'a, bool> {
+        Box::pin(self.spill(retries))
+    }
+
+    fn take_due_retries(&self, limit: usize) -> StoreFuture<'_, Vec<PaymentMessage>> {
+        Box::pin(async move {
+            self.take_retries(limit, true)
+                .await
+                .into_iter()
+                .map(|retry| retry.msg)
+                .collect()
+        })
+    }
+
+    fn take_parked_retries(&self, limit: usize) -> StoreFuture<'_, Vec<ScheduledRetry>> {
+        Box::pin(self.take_retries(limit, false))
+    }
+
+    /// Also keeps the payment in `quarantined_payments`.
+    fn quarantine<'a>(&'a self, msg: &'a PaymentMessage, processor: ProcessorType, response: &'a [u8]) -> StoreFuture<'a, ()> {
+        log_quarantine(msg, processor, response);
+        Box::pin(self.insert_quarantined(msg, processor, response))
+    }
+}
+
+/// A pooled client or an open transaction, both able to write payments.
+trait PaymentWriter: GenericClient {
+    async fn copy_in_payments(&self) -> Result<CopyInSink<Bytes>, tokio_postgres::Error>;
+}
+
+const COPY_PAYMENTS: &str =
+    "COPY payments (amount, requested_at, service_used, correlation_id, run_id) FROM STDIN BINARY";
+
+impl PaymentWriter for tokio_postgres::Client {
+    async fn copy_in_payments(&self) -> Result<CopyInSink<Bytes>, tokio_postgres::Error> {
+        self.copy_in(COPY_PAYMENTS).await
+    }
+}
+
+impl PaymentWriter for tokio_postgres::Transaction<'_> {
+    async fn copy_in_payments(&self) -> Result<CopyInSink<Bytes>, tokio_postgres::Error> {
+        self.copy_in(COPY_PAYMENTS).await
+    }
+}
+
+/// Whether an error with SQLSTATE `code` is about the rows written: a data
+/// exception (class 22) or an integrity constraint violation (class 23).
+/// Writing those rows again cannot help; anything else may pass next time.
+fn refuses_rows(code: Option<&SqlState>) -> bool {
+    code.is_some_and(|code| matches!(&code.code()[..2], "22" | "23"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deadpool_postgres::{Manager, ManagerConfig, RecyclingMethod};
+    use tokio_postgres::NoTls;
+
+    fn test_store(pipelines: usize) -> (PostgresStore, Vec<mpsc::Receiver<Payment>>) {
+        let pg_config = "postgres://postgres@localhost/test".parse().unwrap();
+        let mgr = Manager::from_config(pg_config, NoTls, ManagerConfig { recycling_method: RecyclingMethod::Fast });
+        let dbpool = deadpool_postgres::Pool::builder(mgr).build().unwrap();
+
+        let mut store = PostgresStore::new(dbpool, None).with_flush_pipelines(pipelines);
+        let (senders, receivers) = (0..pipelines).map(|_| mpsc::channel(64)).unzip();
+        store.senders = senders;
+        store.progress = (0..pipelines).map(|_| Arc::default()).collect();
+        (store, receivers)
+    }
+
+    fn payment(correlation_id: uuid::Uuid, cents: i64) -> Payment {
+        Payment::new(
+            Decimal::new(cents, 2),
+            correlation_id,
+            ProcessorType::DEFAULT,
+            OffsetDateTime::now_utc(),
+        )
+    }
+
+    #[tokio::test]
+    async fn one_payment_always_goes_through_the_same_pipeline_in_order() {
+        let (store, mut receivers) = test_store(4);
+        let correlation_id = uuid::Uuid::new_v4();
+
+        for cents in [100, 200, 300] {
+            store.push_payment(payment(correlation_id, cents)).unwrap();
+        }
+
+        let received: Vec<Vec<i64>> = receivers
+            .iter_mut()
+            .map(|receiver| {
+                std::iter::from_fn(|| receiver.try_recv().ok())
+                    .map(|payment| payment.amount.mantissa() as i64)
+                    .collect()
+            })
+            .collect();
+        let used: Vec<_> = received.iter().filter(|amounts| !amounts.is_empty()).collect();
+        assert_eq!(used, [&vec![100, 200, 300]]);
+    }
+
+    #[tokio::test]
+    async fn init_fails_when_the_schema_cannot_be_created() {
+        let pg_config = "postgres://postgres@127.0.0.1:1/test".parse().unwrap();
+        let mgr = Manager::from_config(pg_config, NoTls, ManagerConfig { recycling_method: RecyclingMethod::Fast });
+        let mut store = PostgresStore::new(deadpool_postgres::Pool::builder(mgr).build().unwrap(), None);
+
+        let started = tokio::time::Instant::now();
+        assert!(store.init(Duration::from_millis(600)).await.is_err());
+        // Retried rather than given up on the first refusal.
+        assert!(started.elapsed() >= DATABASE_POLL_INTERVAL);
+        assert!(store.senders.is_empty());
+    }
+
+    #[test]
+    fn only_data_and_constraint_errors_refuse_rows() {
+        assert!(refuses_rows(Some(&SqlState::NUMERIC_VALUE_OUT_OF_RANGE)));
+        assert!(refuses_rows(Some(&SqlState::UNIQUE_VIOLATION)));
+        assert!(!refuses_rows(Some(&SqlState::T_R_SERIALIZATION_FAILURE)));
+        assert!(!refuses_rows(Some(&SqlState::QUERY_CANCELED)));
+        assert!(!refuses_rows(Some(&SqlState::ADMIN_SHUTDOWN)));
+        assert!(!refuses_rows(None));
+    }
+
+    #[test]
+    fn durability_is_only_relaxed_when_asked() {
+        let durable = Durability::default();
+        assert_eq!(durable.connection_options(), None);
+        assert_eq!(
+            durable.table_statements(SummaryTable::Transactional),
+            ["ALTER TABLE payments SET LOGGED", "ALTER TABLE payments_summary SET LOGGED"]
+        );
+        assert_eq!(durable.table_statements(SummaryTable::Absent), ["ALTER TABLE payments SET LOGGED"]);
+
+        let relaxed = Durability {
+            unlogged: true,
+            synchronous_commit: false,
+        };
+        assert_eq!(relaxed.connection_options(), Some("-c synchronous_commit=off"));
+        assert_eq!(relaxed.table_statements(SummaryTable::Absent), ["ALTER TABLE payments SET UNLOGGED"]);
+        assert_eq!(
+            relaxed.table_statements(SummaryTable::AfterWrite),
+            ["ALTER TABLE payments SET UNLOGGED", "ALTER TABLE payments_summary SET UNLOGGED"]
+        );
+    }
+
+    #[tokio::test]
+    async fn payments_spread_across_pipelines() {
+        let (store, mut receivers) = test_store(4);
+
+        for _ in 0..64 {
+            store.push_payment(payment(uuid::Uuid::new_v4(), 100)).unwrap();
+        }
+
+        let busy = receivers
+            .iter_mut()
+            .filter_map(|receiver| receiver.try_recv().ok())
+            .count();
+        assert!(busy > 1);
+    }
+
+    #[tokio::test]
+    async fn purge_waits_for_the_payments_queued_before_it() {
+        let (store, mut receivers) = test_store(1);
+        store.push_payment(payment(uuid::Uuid::new_v4(), 100)).unwrap();
+        store.push_payment(payment(uuid::Uuid::new_v4(), 200)).unwrap();
+
+        let progress = store.progress[0].clone();
+        let mut receiver = receivers.remove(0);
+        let insert_loop = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            let mut written = 0;
+            while receiver.try_recv().is_ok() {
+                written += 1;
+            }
+            progress.settled.fetch_add(written, Ordering::Release);
+        });
+
+        let started = tokio::time::Instant::now();
+        store.drain_pipelines().await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        insert_loop.await.unwrap();
+        // Nothing queued since, so the next purge does not wait.
+        store.drain_pipelines().await.unwrap();
+    }
 }
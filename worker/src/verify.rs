@@ -0,0 +1,288 @@
+//! `worker verify`: checks a finished run for lost or double-counted
+//! payments. Totals are recomputed from the `payments` rows and compared
+//! with the `payments_summary` counters and with what each processor's
+//! `/admin/payments-summary` says it charged. The report is one JSON object
+//! on stdout; the exit status is `1` when anything differs.
+
+use crate::env_or;
+use crate::http_client::HttpClient;
+use crate::processor_chain::ProcessorConfig;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::{Method, Request, StatusCode};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio_postgres::NoTls;
+
+/// Longest a processor may take to answer its admin summary.
+const PROCESSOR_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct VerifyConfig {
+    pub postgres_url: String,
+    pub processors: Vec<ProcessorConfig>,
+    /// `X-Rinha-Token` for the processors' admin endpoints
+    /// (`PROCESSOR_ADMIN_TOKEN`).
+    pub admin_token: String,
+    /// Window checked, inclusive (`VERIFY_FROM`, `VERIFY_TO`, RFC 3339);
+    /// unbounded when unset.
+    pub from: Option<OffsetDateTime>,
+    pub to: Option<OffsetDateTime>,
+}
+
+impl VerifyConfig {
+    pub fn from_env() -> Self {
+        let instant = |name| {
+            std::env::var(name)
+                .ok()
+                .map(|value| OffsetDateTime::parse(&value, &Rfc3339).unwrap_or_else(|e| panic!("{}: {}", name, e)))
+        };
+        Self {
+            postgres_url: std::env::var("POSTGRES_URL").unwrap(),
+            processors: crate::processors_from_env(),
+            admin_token: env_or("PROCESSOR_ADMIN_TOKEN", "123".to_string()),
+            from: instant("VERIFY_FROM"),
+            to: instant("VERIFY_TO"),
+        }
+    }
+}
+
+/// Requests and amount one source accounts a processor.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Totals {
+    #[serde(alias = "totalRequests")]
+    pub requests: i64,
+    #[serde(alias = "totalAmount")]
+    pub amount: Decimal,
+}
+
+/// What one source says about a processor, next to the `payments` rows.
+#[derive(Debug)]
+pub enum Reading {
+    /// Nothing to compare, e.g. no `payments_summary` table or a no-op
+    /// processor.
+    Absent,
+    Totals(Totals),
+    /// The source could not be read, which fails the check.
+    Failed(String),
+}
+
+impl Reading {
+    /// The reading as reported, with its difference from `payments`.
+    fn report(&self, payments: Totals) -> (Value, bool) {
+        match self {
+            Reading::Absent => (Value::Null, true),
+            Reading::Totals(totals) => (
+                json!({
+                    "requests": totals.requests,
+                    "amount": totals.amount,
+                    "requestsDiff": totals.requests - payments.requests,
+                    "amountDiff": totals.amount - payments.amount,
+                }),
+                *totals == payments,
+            ),
+            Reading::Failed(error) => (json!({ "error": error }), false),
+        }
+    }
+}
+
+/// The three accounts of one processor.
+#[derive(Debug)]
+pub struct ProcessorAudit {
+    pub name: String,
+    pub payments: Totals,
+    pub summary_table: Reading,
+    pub processor: Reading,
+}
+
+/// `{"consistent":…,"processors":{"<name>":{"consistent":…,"payments":{…},
+/// "summaryTable":{…},"processor":{…}}}}`. Amounts are decimal strings, and
+/// every other source carries its difference from `payments`.
+pub fn report(audits: &[ProcessorAudit]) -> (Value, bool) {
+    let mut processors = Map::new();
+    let mut all_consistent = true;
+    for audit in audits {
+        let (summary_table, table_matches) = audit.summary_table.report(audit.payments);
+        let (processor, processor_matches) = audit.processor.report(audit.payments);
+        let consistent = table_matches && processor_matches;
+        all_consistent &= consistent;
+        processors.insert(
+            audit.name.clone(),
+            json!({
+                "consistent": consistent,
+                "payments": audit.payments,
+                "summaryTable": summary_table,
+                "processor": processor,
+            }),
+        );
+    }
+    (json!({ "consistent": all_consistent, "processors": processors }), all_consistent)
+}
+
+/// Prints the report for `config` and returns whether every source agrees.
+pub async fn run(config: &VerifyConfig) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let (client, connection) = tokio_postgres::connect(&config.postgres_url, NoTls).await?;
+    tokio::spawn(connection);
+
+    let payments = grouped_totals(
+        &client,
+        "SELECT service_used::text, COUNT(*), COALESCE(SUM(amount), 0)
+         FROM payments
+         WHERE ($1::timestamptz IS NULL OR requested_at >= $1)
+           AND ($2::timestamptz IS NULL OR requested_at <= $2)
+         GROUP BY service_used",
+        config,
+    )
+    .await?;
+
+    let summary_table: bool = client
+        .query_one("SELECT to_regclass('payments_summary') IS NOT NULL", &[])
+        .await?
+        .get(0);
+    let summary = if summary_table {
+        Some(
+            grouped_totals(
+                &client,
+                "SELECT service_used::text, COALESCE(SUM(total_requests), 0)::bigint, COALESCE(SUM(total_amount), 0)
+                 FROM payments_summary
+                 WHERE ($1::timestamptz IS NULL OR requested_at >= $1)
+                   AND ($2::timestamptz IS NULL OR requested_at <= $2)
+                 GROUP BY service_used",
+                config,
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+
+    let mut audits = Vec::with_capacity(config.processors.len());
+    for processor in &config.processors {
+        let name = processor.processor_type.as_str();
+        let processor_totals = match &processor.url {
+            Some(url) => match processor_summary(url, processor.http2, config).await {
+                Ok(totals) => Reading::Totals(totals),
+                Err(e) => Reading::Failed(e.to_string()),
+            },
+            None => Reading::Absent,
+        };
+        audits.push(ProcessorAudit {
+            name: name.to_string(),
+            payments: payments.get(name).copied().unwrap_or_default(),
+            summary_table: match &summary {
+                Some(summary) => Reading::Totals(summary.get(name).copied().unwrap_or_default()),
+                None => Reading::Absent,
+            },
+            processor: processor_totals,
+        });
+    }
+
+    let (report, consistent) = report(&audits);
+    println!("{}", report);
+    Ok(consistent)
+}
+
+/// Runs `query`, which selects name, requests and amount per processor
+/// within the configured window.
+async fn grouped_totals(
+    client: &tokio_postgres::Client,
+    query: &str,
+    config: &VerifyConfig,
+) -> Result<BTreeMap<String, Totals>, tokio_postgres::Error> {
+    let rows = client.query(query, &[&config.from, &config.to]).await?;
+    rows.iter()
+        .map(|row| Ok((row.try_get(0)?, Totals { requests: row.try_get(1)?, amount: row.try_get(2)? })))
+        .collect()
+}
+
+/// The processor's own count of what it charged in the window.
+async fn processor_summary(
+    url: &str,
+    http2: bool,
+    config: &VerifyConfig,
+) -> Result<Totals, Box<dyn std::error::Error + Send + Sync>> {
+    let mut query = Vec::new();
+    if let Some(from) = config.from {
+        query.push(format!("from={}", from.format(&Rfc3339)?));
+    }
+    if let Some(to) = config.to {
+        query.push(format!("to={}", to.format(&Rfc3339)?));
+    }
+    let req = Request::builder()
+        .uri(format!("{}/admin/payments-summary?{}", url, query.join("&")))
+        .method(Method::GET)
+        .header("X-Rinha-Token", &config.admin_token)
+        .body(Empty::<Bytes>::new())?;
+
+    let client = HttpClient::for_url(url, http2);
+    let res = tokio::time::timeout(PROCESSOR_TIMEOUT, client.request(req))
+        .await
+        .map_err(|_| "Processor did not answer in time")??;
+    if res.status() != StatusCode::OK {
+        return Err(format!("Processor answered {}", res.status()).into());
+    }
+    let body = res.into_body().collect().await?.to_bytes();
+    Ok(serde_json::from_slice(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn totals(requests: i64, cents: i64) -> Totals {
+        Totals { requests, amount: Decimal::new(cents, 2) }
+    }
+
+    #[test]
+    fn report_flags_sources_that_differ_from_the_rows() {
+        let audits = [
+            ProcessorAudit {
+                name: "default".to_string(),
+                payments: totals(2, 3980),
+                summary_table: Reading::Totals(totals(2, 3980)),
+                processor: Reading::Totals(totals(3, 5970)),
+            },
+            ProcessorAudit {
+                name: "fallback".to_string(),
+                payments: totals(1, 1990),
+                summary_table: Reading::Absent,
+                processor: Reading::Totals(totals(1, 1990)),
+            },
+        ];
+
+        let (report, consistent) = report(&audits);
+        assert!(!consistent);
+        assert_eq!(report["processors"]["default"]["consistent"], false);
+        assert_eq!(report["processors"]["default"]["processor"]["requestsDiff"], 1);
+        assert_eq!(report["processors"]["default"]["processor"]["amountDiff"], "19.90");
+        assert_eq!(report["processors"]["default"]["summaryTable"]["requestsDiff"], 0);
+        assert_eq!(report["processors"]["fallback"]["consistent"], true);
+        assert_eq!(report["processors"]["fallback"]["summaryTable"], Value::Null);
+    }
+
+    #[test]
+    fn unreadable_source_is_inconsistent() {
+        let audit = ProcessorAudit {
+            name: "default".to_string(),
+            payments: totals(1, 100),
+            summary_table: Reading::Failed("timeout".to_string()),
+            processor: Reading::Absent,
+        };
+        let (report, consistent) = report(&[audit]);
+        assert!(!consistent);
+        assert_eq!(report["processors"]["default"]["summaryTable"]["error"], "timeout");
+    }
+
+    #[test]
+    fn reads_processor_admin_totals() {
+        let totals: Totals =
+            serde_json::from_str(r#"{"totalRequests":3,"totalAmount":59.7,"totalFee":2.98,"feePerTransaction":0.05}"#)
+                .unwrap();
+        assert_eq!(totals, Totals { requests: 3, amount: Decimal::new(5970, 2) });
+    }
+}
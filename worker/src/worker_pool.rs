@@ -1,46 +1,38 @@
-﻿use crate::health_monitor::HealthMonitor;
+﻿use crate::clock::Clock;
+use crate::dual_write::{DualWrite, DualWriteReport, Verdict};
+use crate::health_monitor::{HealthMonitor, HealthSubscription, RoutingReport};
+use crate::metrics::{Dropped, METRICS};
 use crate::payment::Payment;
 use crate::payment_message::PaymentMessage;
-use crate::payment_processor::{PaymentProcessor, PaymentProcessorError};
-use crate::processor_type::ProcessorType;
-use crate::store::Store;
+use crate::error::WorkerError;
+use crate::payment_processor::{PaymentProcessor, Processor};
+use crate::retry_policy::RetryPolicy;
+use crate::slow_start::SlowStart;
+use crate::store::{PaymentStore, ScheduledRetry};
+use crate::worker_stats::{WorkerStats, WorkerStatsReport};
 use bytes::Bytes;
+use rust_decimal::prelude::ToPrimitive;
 use std::collections::BinaryHeap;
 
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use time::{UtcDateTime, UtcOffset};
-use tokio::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use time::{OffsetDateTime, UtcDateTime, UtcOffset};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::sync::mpsc::error::TrySendError;
 
 use tokio::time::Instant;
 
-#[derive(Debug)]
-pub enum WorkerPoolError {
-    JsonParseError(serde_json::Error),
-    QueueClosed,
-    QueueFull,
-    PaymentFailed(PaymentProcessorError),
-    ProcessorsUnavailable,
-}
-impl std::fmt::Display for WorkerPoolError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            WorkerPoolError::JsonParseError(e) => write!(f, "JSON parse error: {}", e),
-            WorkerPoolError::QueueClosed => write!(f, "Queue closed"),
-            WorkerPoolError::QueueFull => write!(f, "Queue full"),
-            WorkerPoolError::PaymentFailed(e) => write!(f, "Payment failed: {}", e),
-            WorkerPoolError::ProcessorsUnavailable => write!(f, "No processors available"),
-        }
-    }
-}
+const BUFFER_SIZE: usize = 32768;
 
-impl std::error::Error for WorkerPoolError {}
+/// How often parked retries are looked for in `scheduled_retries`.
+const SPILL_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
-const BUFFER_SIZE: usize = 32768;
-const MAX_RETRIES: u32 = 50;
-const BASE_BACKOFF_MS: u64 = 500;
-const MAX_BACKOFF_MS: u64 = 2_000;
-const JITTER_FRACTION: f64 = 0.2;
+/// Most retries spilled in one write or loaded back in one poll.
+const SPILL_BATCH_SIZE: usize = 512;
+
+/// How often the spread of work across workers is checked.
+const BALANCE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
 
 struct RetryItem {
     msg: PaymentMessage,
@@ -67,73 +59,300 @@ impl Ord for RetryItem {
     }
 }
 
-#[derive(Clone)]
-pub struct WorkerDependencies {
+/// The slice of the correlationId space a replica owns when several consume
+/// the same payments: ids with `id % count == index`. The gateway's `hash`
+/// dispatch uses the same split, so listing the worker sockets in index
+/// order sends every payment straight to its owner. A payment that reaches
+/// another replica was still answered 202, so it is processed there too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: u32,
+    pub count: u32,
+}
+
+impl Shard {
+    pub fn new(index: u32, count: u32) -> Result<Self, String> {
+        if count == 0 || index >= count {
+            return Err(format!("shard index {} is out of range for {} shards", index, count));
+        }
+        Ok(Self { index, count })
+    }
+
+    pub fn owns(&self, correlation_id: &uuid::Uuid) -> bool {
+        correlation_id.as_u128() % self.count as u128 == self.index as u128
+    }
+}
+
+pub struct WorkerDependencies<P = PaymentProcessor> {
     health_monitor: Arc<HealthMonitor>,
-    default_processor: Arc<PaymentProcessor>,
-    fallback_processor: Arc<PaymentProcessor>,
-    store: Arc<Store>,
+    /// The failover chain, most preferred first.
+    processors: Arc<[Arc<P>]>,
+    store: Arc<dyn PaymentStore>,
+    retry_policy: Arc<RwLock<RetryPolicy>>,
+    clock: Arc<dyn Clock>,
+    /// Messages older than this take the strategy's late route, the last
+    /// healthy processor of the chain when fallback is enabled, so a backlog
+    /// does not queue up behind a slow default.
+    message_budget: Option<Duration>,
+    /// Most messages a worker takes off its queue at once and hands to
+    /// [`Processor::process_batch`]; `1` processes them one at a time.
+    batch_size: usize,
+    /// Paces payments to a processor recovering from a failing window.
+    slow_start: Option<Arc<SlowStart>>,
+    /// Mirrors sampled payments to a second processor, for conformance
+    /// testing.
+    dual_write: Option<Arc<DualWrite>>,
+}
+
+// Derived `Clone` would require `P: Clone`; only the `Arc`s are cloned.
+impl<P> Clone for WorkerDependencies<P> {
+    fn clone(&self) -> Self {
+        Self {
+            health_monitor: self.health_monitor.clone(),
+            processors: self.processors.clone(),
+            store: self.store.clone(),
+            retry_policy: self.retry_policy.clone(),
+            clock: self.clock.clone(),
+            message_budget: self.message_budget,
+            batch_size: self.batch_size,
+            slow_start: self.slow_start.clone(),
+            dual_write: self.dual_write.clone(),
+        }
+    }
 }
 
-#[derive(Clone)]
-pub struct WorkerPool {
+/// Workers pulling payments off their queues and sending them to `P`,
+/// the real [`PaymentProcessor`] outside of tests.
+pub struct WorkerPool<P = PaymentProcessor> {
     senders: Vec<mpsc::Sender<PaymentMessage>>,
     num_workers: usize,
-    deps: WorkerDependencies,
-    next_worker: Arc<AtomicUsize>,
+    deps: WorkerDependencies<P>,
+    shutdown: Arc<watch::Sender<bool>>,
+    /// While set, workers stop pulling messages; submissions still queue up.
+    paused: Arc<watch::Sender<bool>>,
+    handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    retry_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Retries held in memory at most; the rest wait in `scheduled_retries`.
+    retry_capacity: Option<usize>,
+    shard: Option<Shard>,
+    stats: Arc<WorkerStats>,
+    /// Busiest worker's share over the mean above which a warning is logged.
+    imbalance_threshold: Option<f64>,
 }
 
-impl WorkerPool {
+impl<P> Clone for WorkerPool<P> {
+    fn clone(&self) -> Self {
+        Self {
+            senders: self.senders.clone(),
+            num_workers: self.num_workers,
+            deps: self.deps.clone(),
+            shutdown: self.shutdown.clone(),
+            paused: self.paused.clone(),
+            handles: self.handles.clone(),
+            retry_handle: self.retry_handle.clone(),
+            retry_capacity: self.retry_capacity,
+            shard: self.shard,
+            stats: self.stats.clone(),
+            imbalance_threshold: self.imbalance_threshold,
+        }
+    }
+}
+
+impl<P: Processor> WorkerPool<P> {
     pub fn new(
         num_workers: usize,
         health_monitor: Arc<HealthMonitor>,
-        default_processor: Arc<PaymentProcessor>,
-        fallback_processor: Arc<PaymentProcessor>,
-        store: Arc<Store>,
+        processors: Vec<Arc<P>>,
+        store: Arc<dyn PaymentStore>,
+        retry_policy: RetryPolicy,
+        clock: Arc<dyn Clock>,
     ) -> Self {
         Self {
             senders: Vec::with_capacity(num_workers),
             num_workers,
-            next_worker: Arc::new(AtomicUsize::new(0)),
+            shutdown: Arc::new(watch::channel(false).0),
+            paused: Arc::new(watch::channel(false).0),
+            handles: Arc::new(Mutex::new(Vec::with_capacity(num_workers))),
+            retry_handle: Arc::new(Mutex::new(None)),
+            retry_capacity: None,
+            shard: None,
+            stats: Arc::new(WorkerStats::new(num_workers)),
+            imbalance_threshold: None,
             deps: WorkerDependencies {
                 health_monitor,
-                default_processor,
-                fallback_processor,
+                processors: processors.into(),
                 store,
+                retry_policy: Arc::new(RwLock::new(retry_policy)),
+                clock,
+                message_budget: None,
+                batch_size: 1,
+                slow_start: None,
+                dual_write: None,
             },
         }
     }
 
-    pub async fn submit(&self, msg: Bytes) -> Result<(), WorkerPoolError> {
-        if let Ok(msg) = serde_json::from_slice::<PaymentMessage>(&msg) {
-            return self.submit_internal(msg).await;
+    pub fn with_message_budget(mut self, message_budget: Option<Duration>) -> Self {
+        self.deps.message_budget = message_budget;
+        self
+    }
+
+    /// Lets each worker take up to `batch_size` queued messages at once,
+    /// without waiting for more to arrive, and send those bound for the same
+    /// processor together.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.deps.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Ramps traffic back up to [`SlowStart::processor`] whenever routing
+    /// returns to it.
+    pub fn with_slow_start(mut self, slow_start: Option<Arc<SlowStart>>) -> Self {
+        self.deps.slow_start = slow_start;
+        self
+    }
+
+    /// Sends a sample of the payments to another processor as well, see
+    /// [`DualWrite`].
+    pub fn with_dual_write(mut self, dual_write: Option<Arc<DualWrite>>) -> Self {
+        self.deps.dual_write = dual_write;
+        self
+    }
+
+    /// Bounds the in-memory retry heap, spilling retries beyond `capacity`
+    /// to Postgres. Unbounded when `None`.
+    pub fn with_retry_capacity(mut self, capacity: Option<usize>) -> Self {
+        self.retry_capacity = capacity;
+        self
+    }
+
+    /// Payments outside `shard` are counted and logged as misrouted, but
+    /// still processed.
+    pub fn with_shard(mut self, shard: Option<Shard>) -> Self {
+        self.shard = shard;
+        self
+    }
+
+    /// Warns when, over a check interval, the busiest worker handled more
+    /// than `threshold` times the mean.
+    pub fn with_imbalance_threshold(mut self, threshold: Option<f64>) -> Self {
+        self.imbalance_threshold = threshold;
+        self
+    }
+
+    pub fn stats(&self) -> WorkerStatsReport {
+        self.stats.report()
+    }
+
+    /// Where payments go right now and why, with the slow start pace.
+    pub fn routing_report(&self) -> RoutingReport {
+        let mut report = self.deps.health_monitor.routing_report();
+        if let Some(slow_start) = &self.deps.slow_start {
+            let paced = slow_start.processor().to_string();
+            for processor in report.processors.iter_mut().filter(|p| p.processor == paced) {
+                processor.pacing_rps = slow_start.rate();
+            }
         }
-        Ok(())
+        report
     }
 
-    async fn submit_internal(&self, msg: PaymentMessage) -> Result<(), WorkerPoolError> {
+    /// Answers compared so far, `None` without dual writes.
+    pub fn dual_write_report(&self) -> Option<DualWriteReport> {
+        self.deps.dual_write.as_ref().map(|dual_write| dual_write.report())
+    }
+
+    /// Counts `msg` when another shard owns it, warning once per process:
+    /// the gateway is not dispatching by hash.
+    fn note_foreign(&self, msg: &PaymentMessage) {
+        static WARNED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+        let Some(shard) = self.shard.filter(|shard| !shard.owns(&msg.correlation_id)) else {
+            return;
+        };
+        METRICS.foreign_shard.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if !WARNED.swap(true, std::sync::atomic::Ordering::Relaxed) {
+            tracing::warn!(
+                shard = shard.index,
+                shards = shard.count,
+                "Processing payments owned by other shards, set GATEWAY_PUBLISH_DISPATCH=hash on the gateway"
+            );
+        }
+        tracing::debug!(correlation_id = %msg.correlation_id, "Processing payment owned by another shard");
+    }
+
+    pub async fn submit(&self, msg: Bytes) -> Result<(), WorkerError> {
+        let msg = PaymentMessage::decode(&msg)?;
+        self.note_foreign(&msg);
+        self.submit_internal(msg).await
+    }
+
+    /// Distributes a decoded batch across the workers in a single pass,
+    /// reserving consecutive round-robin slots up front. Messages that do not
+    /// fit are dropped and the first error is reported.
+    pub async fn submit_batch(&self, msgs: Vec<PaymentMessage>) -> Result<(), WorkerError> {
         if self.senders.is_empty() {
-            return Err(WorkerPoolError::QueueClosed);
+            return Err(WorkerError::QueueClosed);
         }
 
+        if self.shard.is_some() {
+            msgs.iter().for_each(|msg| self.note_foreign(msg));
+        }
+
+        let start = self.reserve_workers(msgs.len());
+        let mut first_error = None;
+
+        for (offset, msg) in msgs.into_iter().enumerate() {
+            let worker_index = (start + offset) % self.senders.len();
+            if let Err(e) = self.send_to(worker_index, msg) {
+                first_error.get_or_insert(e);
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    async fn submit_internal(&self, msg: PaymentMessage) -> Result<(), WorkerError> {
+        if self.senders.is_empty() {
+            return Err(WorkerError::QueueClosed);
+        }
+
+        let worker_index = self.reserve_workers(1);
+        self.send_to(worker_index, msg)?;
+
+        tracing::debug!("Submitted message to worker {}", worker_index);
+        Ok(())
+    }
+
+    /// Advances the per-thread round-robin cursor by `count` slots and
+    /// returns the first reserved worker index.
+    fn reserve_workers(&self, count: usize) -> usize {
         thread_local! {
-            static COUNTER: std::cell::Cell<usize> = std::cell::Cell::new(0);
+            static COUNTER: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
         }
 
-        let worker_index = COUNTER.with(|c| {
-            let current = c.get();
-            let next = (current + 1) % self.senders.len();
-            c.set(next);
+        COUNTER.with(|c| {
+            let current = c.get() % self.senders.len();
+            c.set((current + count) % self.senders.len());
             current
-        });
+        })
+    }
 
+    /// Messages that fit in the worker queues right now. Round-robin spreads
+    /// them evenly, so the fullest queue sets the bound.
+    pub fn headroom(&self) -> usize {
+        self.senders.iter().map(|sender| sender.capacity()).min().unwrap_or(0) * self.senders.len()
+    }
 
+    fn send_to(&self, worker_index: usize, msg: PaymentMessage) -> Result<(), WorkerError> {
         self.senders[worker_index]
             .try_send(msg)
-            .map_err(|_| WorkerPoolError::QueueClosed)?;
-
-        tracing::debug!("Submitted message to worker {}", worker_index);
-        Ok(())
+            .map_err(|e| match e {
+                TrySendError::Full(_) => WorkerError::QueueFull,
+                TrySendError::Closed(_) => WorkerError::QueueClosed,
+            })
     }
 
     pub async fn start(&mut self) {
@@ -146,9 +365,12 @@ impl WorkerPool {
             let (sender, receiver) = mpsc::channel(worker_channel_size);
             let deps = self.deps.clone();
             let retry_sender_clone = retry_sender.clone();
+            let shutdown = self.shutdown.subscribe();
+            let paused = self.paused.subscribe();
+            let stats = self.stats.clone();
 
             let handle = tokio::spawn(async move {
-                Self::worker_loop(worker_id, receiver, retry_sender_clone, deps).await;
+                Self::worker_loop(worker_id, receiver, retry_sender_clone, deps, stats, shutdown, paused).await;
             });
 
             handles.push(handle);
@@ -156,24 +378,91 @@ impl WorkerPool {
         }
 
         self.senders = senders;
+        *self.handles.lock().unwrap() = handles;
+
+        // With a bounded heap the poller picks up parked retries as they come
+        // due. Without one nothing reads the table, so whatever an earlier
+        // run parked is taken back once at startup.
+        if self.retry_capacity.is_some() {
+            tokio::spawn(self.clone().load_spilled_retries(retry_sender.clone(), self.shutdown.subscribe()));
+        } else {
+            tokio::spawn(self.clone().recover_parked_retries(retry_sender.clone()));
+        }
 
         let self_clone = self.clone();
-        tokio::spawn(async move {
-            Self::retry_loop(self_clone, retry_receiver).await;
+        let shutdown = self.shutdown.subscribe();
+        let retry_handle = tokio::spawn(async move {
+            Self::retry_loop(self_clone, retry_receiver, shutdown).await;
         });
+        *self.retry_handle.lock().unwrap() = Some(retry_handle);
+
+        if let Some(threshold) = self.imbalance_threshold {
+            tokio::spawn(Self::watch_balance(self.stats.clone(), threshold, self.shutdown.subscribe()));
+        }
 
         tracing::info!("Started {} workers", self.num_workers);
     }
 
-    async fn retry_loop(self, mut retry_receiver: mpsc::Receiver<RetryItem>) {
+    /// Stops workers from pulling new messages and waits, up to `deadline`,
+    /// for the payments they are currently processing to finish so their
+    /// results reach the store before it is flushed, and for the retries
+    /// still pending to be parked in `scheduled_retries`.
+    pub async fn shutdown(&self, deadline: Duration) {
+        let deadline = tokio::time::Instant::now() + deadline;
+        let _ = self.shutdown.send(true);
+
+        let handles = std::mem::take(&mut *self.handles.lock().unwrap());
+        let in_flight = futures_util::future::join_all(handles);
+
+        if tokio::time::timeout_at(deadline, in_flight).await.is_err() {
+            tracing::warn!("Timed out waiting for in-flight payments to finish");
+        } else {
+            tracing::info!("All workers finished in-flight payments");
+        }
+
+        // The retry loop finishes once the workers, and with them the last
+        // retries they hand back, are gone.
+        let retry_handle = self.retry_handle.lock().unwrap().take();
+        if let Some(retry_handle) = retry_handle
+            && tokio::time::timeout_at(deadline, retry_handle).await.is_err()
+        {
+            tracing::warn!("Timed out parking pending retries");
+        }
+    }
+
+    /// Stops workers from taking new messages once their current payment is
+    /// done. Returns whether the pool was running.
+    pub fn pause(&self) -> bool {
+        !self.paused.send_replace(true)
+    }
+
+    /// Returns whether the pool was paused.
+    pub fn resume(&self) -> bool {
+        self.paused.send_replace(false)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    pub fn store(&self) -> &dyn PaymentStore {
+        &*self.deps.store
+    }
+
+    async fn retry_loop(
+        self,
+        mut retry_receiver: mpsc::Receiver<RetryItem>,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
         let mut heap: BinaryHeap<RetryItem> = BinaryHeap::with_capacity(8 * 1024);
 
         loop {
-            let now = Instant::now();
+            let now = self.deps.clock.now();
             while let Some(item) = heap.peek() {
                 if item.next_attempt <= now {
                     let item = heap.pop().unwrap();
                     if let Err(e) = self.submit_internal(item.msg).await {
+                        METRICS.outcomes.dropped(Dropped::QueueFull);
                         tracing::error!("Failed to resubmit retry message: {}", e);
                     }
                 } else {
@@ -183,131 +472,1160 @@ impl WorkerPool {
 
             let next_timer = heap
                 .peek()
-                .map(|item| tokio::time::sleep_until(item.next_attempt));
+                .map(|item| self.deps.clock.sleep_until(item.next_attempt));
 
-            tokio::select! {
-                item = retry_receiver.recv() => {
-                    if let Some(item) = item {
-                        heap.push(item);
-                    }
-                }
+            let received = tokio::select! {
+                _ = shutdown.wait_for(|stop| *stop) => break,
+                item = retry_receiver.recv() => item,
                 _ = async {
                     match next_timer {
                         Some(timer) => timer.await,
                         None => std::future::pending().await,
                     }
-                } => {}
+                } => None,
+            };
+            let Some(item) = received else { continue };
+            let capacity = self.retry_capacity.unwrap_or(usize::MAX);
+
+            let mut overflow = Vec::new();
+            let mut next = Some(item);
+            while let Some(item) = next {
+                if heap.len() < capacity {
+                    heap.push(item);
+                } else {
+                    overflow.push(item);
+                }
+
+                next = if overflow.len() < SPILL_BATCH_SIZE {
+                    retry_receiver.try_recv().ok()
+                } else {
+                    None
+                };
+            }
+
+            if !overflow.is_empty()
+                && let Err(kept) = self.spill(overflow).await
+            {
+                tracing::warn!(count = kept.len(), "Keeping retries that could not be spilled in memory");
+                heap.extend(kept);
             }
         }
+
+        self.park_pending_retries(heap, retry_receiver).await;
     }
 
-    async fn retry(mut msg: PaymentMessage, retry_sender: &mpsc::Sender<RetryItem>) {
-        if msg.retry_count >= MAX_RETRIES {
-            tracing::warn!(
-                "Max retries exceeded, dropping message: {}",
-                msg.correlation_id
-            );
-            return;
+    /// Writes the retries in `heap`, and those still arriving on
+    /// `retry_receiver` until its senders are gone, to `scheduled_retries`
+    /// for the next run: each is a payment the gateway already answered 202.
+    async fn park_pending_retries(&self, heap: BinaryHeap<RetryItem>, mut retry_receiver: mpsc::Receiver<RetryItem>) {
+        let mut pending = heap.into_vec();
+        let mut parked = 0;
+
+        loop {
+            if !pending.is_empty() {
+                let count = pending.len();
+                match self.spill(std::mem::take(&mut pending)).await {
+                    Ok(()) => parked += count,
+                    Err(kept) => {
+                        METRICS.outcomes.dropped(Dropped::QueueFull);
+                        tracing::error!(count = kept.len(), "Could not park pending retries, dropping them");
+                    }
+                }
+            }
+            if retry_receiver.recv_many(&mut pending, SPILL_BATCH_SIZE).await == 0 {
+                break;
+            }
+        }
+
+        if parked > 0 {
+            tracing::info!(parked, "Parked pending retries for the next run");
+        }
+    }
+
+    /// Writes retries the heap has no room for to `scheduled_retries`. If
+    /// the write fails they are handed back, for the heap to keep past its
+    /// capacity: every retry is a payment the gateway already answered 202.
+    async fn spill(&self, items: Vec<RetryItem>) -> Result<(), Vec<RetryItem>> {
+        let now = self.deps.clock.now();
+        let wall_now = OffsetDateTime::now_utc();
+        let retries: Vec<_> = items
+            .into_iter()
+            .map(|item| ScheduledRetry {
+                next_attempt: wall_now + item.next_attempt.saturating_duration_since(now),
+                msg: item.msg,
+            })
+            .collect();
+
+        if self.deps.store.spill_retries(&retries).await {
+            tracing::debug!(count = retries.len(), "Spilled retries to the database");
+            return Ok(());
+        }
+        Err(retries
+            .into_iter()
+            .map(|retry| RetryItem {
+                next_attempt: now + (retry.next_attempt - wall_now).try_into().unwrap_or(Duration::ZERO),
+                msg: retry.msg,
+            })
+            .collect())
+    }
+
+    /// Moves due retries from `scheduled_retries` back into the retry queue,
+    /// never taking more than the queue has room for. Rows are deleted as
+    /// they are taken, so anything the queue no longer accepts is written
+    /// back.
+    async fn load_spilled_retries(self, retry_sender: mpsc::Sender<RetryItem>, mut shutdown: watch::Receiver<bool>) {
+        let mut interval = tokio::time::interval(SPILL_POLL_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.wait_for(|stop| *stop) => return,
+                _ = interval.tick() => {}
+            }
+
+            let room = retry_sender.capacity().min(SPILL_BATCH_SIZE);
+            if room == 0 {
+                continue;
+            }
+
+            let now = self.deps.clock.now();
+            let mut due = self.deps.store.take_due_retries(room).await.into_iter();
+            while let Some(msg) = due.next() {
+                if let Err(mpsc::error::SendError(item)) = retry_sender.send(RetryItem { msg, next_attempt: now }).await {
+                    let wall_now = OffsetDateTime::now_utc();
+                    let unsent: Vec<_> = std::iter::once(item.msg)
+                        .chain(due)
+                        .map(|msg| ScheduledRetry { next_attempt: wall_now, msg })
+                        .collect();
+                    if !self.deps.store.spill_retries(&unsent).await {
+                        METRICS.outcomes.dropped(Dropped::QueueFull);
+                        tracing::error!(count = unsent.len(), "Retry queue closed, dropping retries taken from the database");
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Moves every retry in `scheduled_retries` into the retry queue, keeping
+    /// its schedule, so work parked by a run that crashed or was restarted
+    /// mid-outage is not orphaned. Rows are deleted as they are taken, so
+    /// anything the queue no longer accepts is written back.
+    async fn recover_parked_retries(self, retry_sender: mpsc::Sender<RetryItem>) {
+        let mut recovered = 0;
+
+        loop {
+            let batch = self.deps.store.take_parked_retries(SPILL_BATCH_SIZE).await;
+            let last_batch = batch.len() < SPILL_BATCH_SIZE;
+
+            let now = self.deps.clock.now();
+            let wall_now = OffsetDateTime::now_utc();
+            let mut batch = batch.into_iter();
+            while let Some(ScheduledRetry { msg, next_attempt }) = batch.next() {
+                let delay = (next_attempt - wall_now).try_into().unwrap_or(Duration::ZERO);
+                if let Err(mpsc::error::SendError(item)) = retry_sender.send(RetryItem { msg, next_attempt: now + delay }).await {
+                    let unsent: Vec<_> = std::iter::once(ScheduledRetry { msg: item.msg, next_attempt })
+                        .chain(batch)
+                        .collect();
+                    if !self.deps.store.spill_retries(&unsent).await {
+                        METRICS.outcomes.dropped(Dropped::QueueFull);
+                        tracing::error!(count = unsent.len(), "Retry queue closed, dropping retries taken from the database");
+                    }
+                    return;
+                }
+                recovered += 1;
+            }
+
+            if last_batch {
+                break;
+            }
         }
 
+        if recovered > 0 {
+            tracing::info!(recovered, "Recovered parked retries from a previous run");
+        }
+    }
+
+    async fn watch_balance(stats: Arc<WorkerStats>, threshold: f64, mut shutdown: watch::Receiver<bool>) {
+        let mut interval = tokio::time::interval(BALANCE_CHECK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.wait_for(|stop| *stop) => return,
+                _ = interval.tick() => {}
+            }
+
+            if let Some(imbalance) = stats.check_balance()
+                && imbalance > threshold
+            {
+                tracing::warn!(imbalance, threshold, workers = ?stats.report().workers, "Work is unevenly spread across workers");
+            }
+        }
+    }
+
+    pub fn set_retry_policy(&self, retry_policy: RetryPolicy) {
+        *self.deps.retry_policy.write().unwrap() = retry_policy;
+    }
+
+    async fn retry(mut msg: PaymentMessage, retry_sender: &mpsc::Sender<RetryItem>, deps: &WorkerDependencies<P>) {
+        let delay = {
+            let policy = deps.retry_policy.read().unwrap();
+            if msg.retry_count >= policy.max_retries {
+                METRICS.outcomes.dropped(Dropped::Exhausted);
+                tracing::warn!(
+                    request_id = msg.request_id.as_deref(),
+                    "Max retries exceeded, dropping message: {}",
+                    msg.correlation_id
+                );
+                return;
+            }
+            policy.backoff_ms(msg.retry_count + 1)
+        };
+
         msg.retry_count += 1;
-        let delay = Self::calc_backoff(msg.retry_count);
         let item = RetryItem {
             msg,
-            next_attempt: Instant::now() + std::time::Duration::from_millis(delay),
+            next_attempt: deps.clock.now() + Duration::from_millis(delay),
         };
 
-        if let Err(_) = retry_sender.try_send(item) {
+        if retry_sender.try_send(item).is_err() {
+            METRICS.outcomes.dropped(Dropped::QueueFull);
             tracing::warn!("Retry queue is full, dropping message");
         }
     }
 
-    fn calc_backoff(retry_count: u32) -> u64 {
-        let delay = BASE_BACKOFF_MS * (1_u64 << retry_count.min(10)); // Cap the exponential growth
-        let delay = delay.min(MAX_BACKOFF_MS);
-
-        let jitter_range = (delay as f64 * JITTER_FRACTION) as u64;
-        let pseudo = retry_count.wrapping_mul(1103515245).wrapping_add(12345) as u64;
-        let jitter = pseudo % (2 * jitter_range).max(1);
-
-        delay.saturating_sub(jitter_range).saturating_add(jitter)
-    }
-
     async fn worker_loop(
         id: usize,
         mut receiver: mpsc::Receiver<PaymentMessage>,
         retry_sender: mpsc::Sender<RetryItem>,
-        deps: WorkerDependencies,
+        deps: WorkerDependencies<P>,
+        stats: Arc<WorkerStats>,
+        mut shutdown: watch::Receiver<bool>,
+        mut paused: watch::Receiver<bool>,
     ) {
-        while let Some(msg) = receiver.recv().await {
-            if let Err(e) = Self::process_message(id, &msg, &deps).await {
-                tracing::info!(worker_id = id, error = %e, "Worker failed to process message retrying");
-                Self::retry(msg, &retry_sender).await
+        let mut health = deps.health_monitor.subscribe();
+
+        loop {
+            let msg = tokio::select! {
+                biased;
+                _ = shutdown.wait_for(|stop| *stop) => {
+                    tracing::info!(worker_id = id, "Worker shutting down - stop requested");
+                    return;
+                }
+                msg = async {
+                    let _ = paused.wait_for(|paused| !*paused).await;
+                    receiver.recv().await
+                } => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+            };
+
+            // A worker already parked in `recv` when the pool was paused
+            // holds on to the message it got until processing resumes.
+            if *paused.borrow() {
+                tokio::select! {
+                    biased;
+                    _ = shutdown.wait_for(|stop| *stop) => {
+                        tracing::info!(worker_id = id, "Worker shutting down while paused");
+                        return;
+                    }
+                    _ = paused.wait_for(|paused| !*paused) => {}
+                }
+            }
+
+            let mut batch = vec![msg];
+            while batch.len() < deps.batch_size
+                && let Ok(msg) = receiver.try_recv()
+            {
+                batch.push(msg);
+            }
+
+            let started = std::time::Instant::now();
+            if batch.len() == 1 {
+                let msg = batch.pop().expect("one message");
+                let result = Self::process_message(id, &msg, &deps, &mut health).await;
+                stats.record(id, result.is_ok(), started.elapsed());
+                Self::conclude(id, msg, result, &retry_sender, &deps).await;
+                continue;
+            }
+
+            let results = Self::process_batch(&batch, &deps, &mut health).await;
+            let elapsed = started.elapsed();
+            for (msg, result) in batch.into_iter().zip(results) {
+                stats.record(id, result.is_ok(), elapsed);
+                Self::conclude(id, msg, result, &retry_sender, &deps).await;
             }
         }
         tracing::info!(worker_id = id, "Worker shutting down - channel closed");
     }
 
+    /// Counts the outcome of processing `msg` and schedules its retry when
+    /// trying again may help.
+    async fn conclude(
+        id: usize,
+        msg: PaymentMessage,
+        result: Result<(), WorkerError>,
+        retry_sender: &mpsc::Sender<RetryItem>,
+        deps: &WorkerDependencies<P>,
+    ) {
+        match result {
+            Err(e) if e.is_retryable() => {
+                tracing::info!(worker_id = id, error = %e, request_id = msg.request_id.as_deref(), "Worker failed to process message retrying");
+                let mut msg = msg;
+                if let WorkerError::ProcessorTimeout(processor) = e {
+                    msg.pinned = Some(processor);
+                }
+                Self::retry(msg, retry_sender, deps).await
+            }
+            Err(WorkerError::Unprocessable(_)) => METRICS.outcomes.dropped(Dropped::Quarantined),
+            Err(e) => {
+                METRICS.outcomes.dropped(Dropped::Rejected);
+                tracing::warn!(
+                    worker_id = id,
+                    error = %e,
+                    correlation_id = %msg.correlation_id,
+                    request_id = msg.request_id.as_deref(),
+                    "Dropping payment that cannot succeed"
+                );
+            }
+            Ok(()) => METRICS.outcomes.succeeded(msg.retry_count),
+        }
+    }
+
     async fn process_message(
         _id: usize,
         msg: &PaymentMessage,
-        deps: &WorkerDependencies,
-    ) -> Result<(), WorkerPoolError> {
-        match deps.health_monitor.next_processor().await {
-            Ok(processor_type) => match processor_type {
-                ProcessorType::Default  => Self::process_default(msg, deps).await,
-                ProcessorType::Fallback => Self::process_fallback(msg, deps).await,
-            },
-            Err(_) => Err(WorkerPoolError::ProcessorsUnavailable),
+        deps: &WorkerDependencies<P>,
+        health: &mut HealthSubscription,
+    ) -> Result<(), WorkerError> {
+        let processor = Self::choose_processor(msg, deps, health)?;
+        Self::process_with(processor, msg, deps).await
+    }
+
+    /// Processes `msgs` grouped by the processor each is routed to, one
+    /// [`Processor::process_batch`] call per group. Results are in the order
+    /// of `msgs`.
+    async fn process_batch(
+        msgs: &[PaymentMessage],
+        deps: &WorkerDependencies<P>,
+        health: &mut HealthSubscription,
+    ) -> Vec<Result<(), WorkerError>> {
+        let mut results: Vec<Option<Result<(), WorkerError>>> = msgs.iter().map(|_| None).collect();
+        let mut groups: Vec<(&Arc<P>, Vec<usize>)> = Vec::new();
+        for (i, msg) in msgs.iter().enumerate() {
+            match Self::choose_processor(msg, deps, health) {
+                Ok(processor) => match groups.iter_mut().find(|(p, _)| Arc::ptr_eq(p, processor)) {
+                    Some((_, members)) => members.push(i),
+                    None => groups.push((processor, vec![i])),
+                },
+                Err(e) => results[i] = Some(Err(e)),
+            }
+        }
+
+        for (processor, members) in groups {
+            if let Some(slow_start) = Self::slow_start_for(processor, deps) {
+                slow_start.pace(members.len()).await;
+            }
+            let payments: Vec<Payment> = members.iter().map(|&i| Self::payment_for(processor, &msgs[i])).collect();
+            let started = std::time::Instant::now();
+            let outcomes = processor.process_batch(payments.clone()).await;
+            let elapsed = started.elapsed();
+            for ((i, payment), outcome) in members.into_iter().zip(payments).zip(outcomes) {
+                Self::mirror(processor, &msgs[i], &outcome, elapsed, deps);
+                results[i] = Some(Self::settle(processor, &msgs[i], payment, outcome, deps).await);
+            }
         }
+
+        // A processor answering fewer results than payments leaves the rest
+        // to be retried.
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or(Err(WorkerError::ProcessorUnavailable)))
+            .collect()
     }
 
-    async fn process_default(
+    /// The processor `msg` is pinned to after a timeout, else the one health
+    /// routing picks, for late payments once `msg` is past its budget or the
+    /// gateway's deadline.
+    fn choose_processor<'a>(
         msg: &PaymentMessage,
-        deps: &WorkerDependencies,
-    ) -> Result<(), WorkerPoolError> {
-        let payment = Payment::new(
+        deps: &'a WorkerDependencies<P>,
+        health: &mut HealthSubscription,
+    ) -> Result<&'a Arc<P>, WorkerError> {
+        if let Some(pinned) = msg.pinned {
+            return deps
+                .processors
+                .iter()
+                .find(|processor| processor.processor_type() == pinned)
+                .ok_or(WorkerError::ProcessorUnavailable);
+        }
+        let late = msg.is_expired() || deps.message_budget.is_some_and(|budget| msg.is_past_deadline(budget));
+        let processor_type = if late {
+            tracing::debug!(
+                correlation_id = %msg.correlation_id,
+                request_id = msg.request_id.as_deref(),
+                "Message past its deadline, using the late route"
+            );
+            health.late_processor()?
+        } else {
+            health.next_processor(msg.retry_count)?
+        };
+        deps.processors
+            .iter()
+            .find(|processor| processor.processor_type() == processor_type)
+            .ok_or(WorkerError::ProcessorUnavailable)
+    }
+
+    /// The slow start pacing `processor`, if any.
+    fn slow_start_for<'a>(processor: &P, deps: &'a WorkerDependencies<P>) -> Option<&'a SlowStart> {
+        deps.slow_start
+            .as_deref()
+            .filter(|slow_start| slow_start.processor() == processor.processor_type())
+    }
+
+    fn payment_for(processor: &P, msg: &PaymentMessage) -> Payment {
+        Payment::new(
             msg.amount,
             msg.correlation_id,
-            ProcessorType::Default,
+            processor.processor_type(),
             UtcDateTime::now().to_offset(UtcOffset::UTC),
-        );
+        )
+        .with_run_id(msg.run_id.clone())
+    }
+
+    async fn process_with(
+        processor: &P,
+        msg: &PaymentMessage,
+        deps: &WorkerDependencies<P>,
+    ) -> Result<(), WorkerError> {
+        if let Some(slow_start) = Self::slow_start_for(processor, deps) {
+            slow_start.pace(1).await;
+        }
+        let payment = Self::payment_for(processor, msg);
+        let started = std::time::Instant::now();
+        let result = processor.process(payment.clone()).await;
+        Self::mirror(processor, msg, &result, started.elapsed(), deps);
+        Self::settle(processor, msg, payment, result, deps).await
+    }
+
+    /// Sends a sampled payment `processor` answered for to the next other
+    /// processor of the chain too, in the background. Failed attempts are
+    /// left to their retry.
+    fn mirror(
+        processor: &P,
+        msg: &PaymentMessage,
+        result: &Result<(), WorkerError>,
+        latency: Duration,
+        deps: &WorkerDependencies<P>,
+    ) {
+        let Some(dual_write) = &deps.dual_write else {
+            return;
+        };
+        let verdict = Verdict::of(result);
+        if verdict == Verdict::Failed || !dual_write.samples(&msg.correlation_id) {
+            return;
+        }
+        let Some(shadow) = deps
+            .processors
+            .iter()
+            .find(|other| other.processor_type() != processor.processor_type())
+        else {
+            return;
+        };
+        tokio::spawn(dual_write.clone().mirror(
+            processor.processor_type(),
+            verdict,
+            latency,
+            shadow.clone(),
+            Self::payment_for(shadow, msg),
+        ));
+    }
 
-        match deps.default_processor.process(payment.clone()).await {
-            Ok(_) => {
-                if let Err(e) = deps.store.push_payment(payment).await {
+    /// Stores or quarantines `payment` according to what the processor
+    /// answered.
+    async fn settle(
+        processor: &P,
+        msg: &PaymentMessage,
+        payment: Payment,
+        result: Result<(), WorkerError>,
+        deps: &WorkerDependencies<P>,
+    ) -> Result<(), WorkerError> {
+        // Any answer about the payment itself shows the processor is up.
+        if let Some(slow_start) = Self::slow_start_for(processor, deps) {
+            slow_start.record(matches!(
+                result,
+                Ok(_) | Err(WorkerError::AlreadyProcessed | WorkerError::Unprocessable(_))
+            ));
+        }
+
+        // A duplicate means the processor already has the payment, so it is
+        // recorded rather than retried.
+        match result {
+            Ok(_) | Err(WorkerError::AlreadyProcessed) => {
+                Self::record_pipeline_latency(msg);
+                METRICS.fees.record(payment.processor, payment.amount);
+                METRICS
+                    .payment_amount
+                    .observe(payment.amount.ceil().to_u64().unwrap_or_default());
+                if let Err(e) = deps.store.push_payment(payment) {
                     tracing::error!("Failed to insert payment into database: {}", e);
                 }
                 Ok(())
             }
+            Err(WorkerError::Unprocessable(body)) => {
+                deps.store.quarantine(msg, processor.processor_type(), &body).await;
+                Err(WorkerError::Unprocessable(body))
+            }
             Err(e) => {
-                tracing::info!("Payment failed to process");
-                Err(WorkerPoolError::PaymentFailed(e))
+                tracing::info!(processor = %processor.processor_type(), "Payment failed to process");
+                Err(e)
             }
         }
     }
 
-    async fn process_fallback(
-        msg: &PaymentMessage,
-        deps: &WorkerDependencies,
-    ) -> Result<(), WorkerPoolError> {
-        let payment = Payment::new(
-            msg.amount,
-            msg.correlation_id,
-            ProcessorType::Fallback,
-            UtcDateTime::now().to_offset(UtcOffset::UTC),
+    /// Time from the gateway receiving the payment to the processor accepting it.
+    fn record_pipeline_latency(msg: &PaymentMessage) {
+        if let Some(ingest_ts) = msg.ingest_ts {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_micros() as u64)
+                .unwrap_or_default();
+            METRICS
+                .pipeline_latency
+                .observe(now.saturating_sub(ingest_ts) / 1_000);
+        }
+    }
+}
+
+#[cfg(test)]
+impl WorkerPool {
+    /// A pool whose single queue holds `capacity` messages and is read by
+    /// the caller rather than a worker.
+    pub(crate) fn queue_only(capacity: usize) -> (Self, mpsc::Receiver<PaymentMessage>) {
+        let clock = Arc::new(crate::clock::ManualClock::new());
+        let chain = crate::processor_chain::default_and_fallback("http://default", "http://fallback");
+        let mut pool = WorkerPool::new(
+            1,
+            Arc::new(HealthMonitor::new(&chain, Default::default(), clock.clone())),
+            chain.iter().map(|config| Arc::new(PaymentProcessor::new(config))).collect(),
+            Arc::new(crate::memory_store::MemoryStore::new(None)),
+            RetryPolicy::default(),
+            clock,
         );
+        let (sender, receiver) = mpsc::channel(capacity);
+        pool.senders = vec![sender];
+        (pool, receiver)
+    }
+}
 
-        match deps.fallback_processor.process(payment.clone()).await {
-            Ok(_) => {
-                if let Err(e) = deps.store.push_payment(payment).await {
-                    tracing::error!("Failed to insert payment into database: {}", e);
-                }
-                Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::health_monitor::ProcessorHealth;
+    use crate::memory_store::MemoryStore;
+    use crate::processor_chain::default_and_fallback;
+    use crate::processor_type::ProcessorType;
+    use crate::routing_strategy::RoutingStrategy;
+    use crate::store::{PostgresStore, StoreError, StoreFuture, Summary, SummaryFilter};
+    use deadpool_postgres::{Manager, ManagerConfig, RecyclingMethod};
+    use rust_decimal::Decimal;
+    use std::collections::VecDeque;
+    use tokio_postgres::NoTls;
+
+    fn test_pool(clock: Arc<ManualClock>, retry_policy: RetryPolicy) -> (WorkerPool, mpsc::Receiver<PaymentMessage>) {
+        let pg_config = "postgres://postgres@localhost/test".parse().unwrap();
+        let mgr = Manager::from_config(pg_config, NoTls, ManagerConfig { recycling_method: RecyclingMethod::Fast });
+        let dbpool = deadpool_postgres::Pool::builder(mgr).build().unwrap();
+
+        let chain = default_and_fallback("http://default", "http://fallback");
+
+        let mut pool = WorkerPool::new(
+            1,
+            Arc::new(HealthMonitor::new(&chain, RoutingStrategy::default(), clock.clone())),
+            chain.iter().map(|config| Arc::new(PaymentProcessor::new(config))).collect(),
+            Arc::new(PostgresStore::new(dbpool, None)),
+            retry_policy,
+            clock,
+        );
+
+        let (sender, receiver) = mpsc::channel(16);
+        pool.senders = vec![sender];
+        (pool, receiver)
+    }
+
+    fn message(retry_count: u32) -> PaymentMessage {
+        PaymentMessage {
+            amount: Decimal::new(1990, 2),
+            correlation_id: uuid::Uuid::new_v4(),
+            retry_count,
+            ingest_ts: None,
+            run_id: None,
+            request_id: None,
+            deadline_ts: None,
+            pinned: None,
+        }
+    }
+
+    async fn settle() {
+        for _ in 0..16 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_schedules_backoff_and_respects_cap() {
+        let clock = Arc::new(ManualClock::new());
+        let policy = RetryPolicy {
+            max_retries: 2,
+            ..RetryPolicy::default()
+        };
+        let (pool, _) = test_pool(clock.clone(), policy.clone());
+        let (retry_sender, mut retry_receiver) = mpsc::channel(16);
+
+        WorkerPool::retry(message(1), &retry_sender, &pool.deps).await;
+        let item = retry_receiver.try_recv().unwrap();
+        assert_eq!(item.msg.retry_count, 2);
+        assert_eq!(item.next_attempt, clock.now() + Duration::from_millis(policy.backoff_ms(2)));
+
+        WorkerPool::retry(message(2), &retry_sender, &pool.deps).await;
+        assert!(retry_receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn retry_loop_resubmits_only_when_due() {
+        let clock = Arc::new(ManualClock::new());
+        let (pool, mut worker_receiver) = test_pool(clock.clone(), RetryPolicy::default());
+        let (retry_sender, retry_receiver) = mpsc::channel(16);
+
+        tokio::spawn(pool.clone().retry_loop(retry_receiver, pool.shutdown.subscribe()));
+
+        retry_sender
+            .send(RetryItem {
+                msg: message(1),
+                next_attempt: clock.now() + Duration::from_millis(500),
+            })
+            .await
+            .unwrap();
+        settle().await;
+
+        clock.advance(Duration::from_millis(499));
+        settle().await;
+        assert!(worker_receiver.try_recv().is_err());
+
+        clock.advance(Duration::from_millis(1));
+        settle().await;
+        assert_eq!(worker_receiver.try_recv().unwrap().retry_count, 1);
+    }
+
+    #[tokio::test]
+    async fn retry_loop_keeps_retries_it_cannot_spill() {
+        let clock = Arc::new(ManualClock::new());
+        let (pool, mut worker_receiver) = test_pool(clock.clone(), RetryPolicy::default());
+        let pool = pool.with_retry_capacity(Some(2));
+        let (retry_sender, retry_receiver) = mpsc::channel(16);
+
+        tokio::spawn(pool.clone().retry_loop(retry_receiver, pool.shutdown.subscribe()));
+
+        for retry_count in 1..=4 {
+            retry_sender
+                .send(RetryItem {
+                    msg: message(retry_count),
+                    next_attempt: clock.now() + Duration::from_millis(100 + retry_count as u64),
+                })
+                .await
+                .unwrap();
+        }
+        // Long enough for the spill to the unreachable database to fail.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The two past the capacity stay in memory rather than being
+        // dropped.
+        clock.advance(Duration::from_millis(110));
+        settle().await;
+        for retry_count in 1..=4 {
+            assert_eq!(worker_receiver.try_recv().unwrap().retry_count, retry_count);
+        }
+        assert!(worker_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn every_id_belongs_to_exactly_one_shard() {
+        assert!(Shard::new(3, 3).is_err());
+        assert!(Shard::new(0, 0).is_err());
+
+        let shards: Vec<_> = (0..3).map(|index| Shard::new(index, 3).unwrap()).collect();
+        for _ in 0..256 {
+            let id = uuid::Uuid::new_v4();
+            assert_eq!(shards.iter().filter(|shard| shard.owns(&id)).count(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn submit_batch_keeps_payments_of_other_shards() {
+        let clock = Arc::new(ManualClock::new());
+        let (pool, mut receiver) = test_pool(clock, RetryPolicy::default());
+        let shard = Shard::new(1, 2).unwrap();
+        let pool = pool.with_shard(Some(shard));
+
+        // Every payment was answered 202, whichever replica it reached.
+        let msgs: Vec<_> = (0..16).map(|_| message(0)).collect();
+        let ids: Vec<_> = msgs.iter().map(|msg| msg.correlation_id).collect();
+        pool.submit_batch(msgs).await.unwrap();
+
+        let received: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok()).map(|msg| msg.correlation_id).collect();
+        assert_eq!(received, ids);
+    }
+
+    /// Answers with the queued results, `Ok` once they run out, and keeps
+    /// the ids it was sent and the size of every batch.
+    struct ScriptedProcessor {
+        processor_type: ProcessorType,
+        results: Mutex<VecDeque<Result<(), WorkerError>>>,
+        sent: Mutex<Vec<uuid::Uuid>>,
+        batches: Mutex<Vec<usize>>,
+    }
+
+    impl ScriptedProcessor {
+        fn new(processor_type: ProcessorType) -> Arc<Self> {
+            Arc::new(Self {
+                processor_type,
+                results: Mutex::new(VecDeque::new()),
+                sent: Mutex::new(Vec::new()),
+                batches: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn answer(&self, result: Result<(), WorkerError>) {
+            self.results.lock().unwrap().push_back(result);
+        }
+
+        fn sent(&self) -> usize {
+            self.sent.lock().unwrap().len()
+        }
+
+        fn batches(&self) -> Vec<usize> {
+            self.batches.lock().unwrap().clone()
+        }
+    }
+
+    impl Processor for ScriptedProcessor {
+        fn processor_type(&self) -> ProcessorType {
+            self.processor_type
+        }
+
+        async fn process(&self, payment: Payment) -> Result<(), WorkerError> {
+            self.sent.lock().unwrap().push(payment.correlation_id);
+            self.results.lock().unwrap().pop_front().unwrap_or(Ok(()))
+        }
+
+        async fn process_batch(&self, payments: Vec<Payment>) -> Vec<Result<(), WorkerError>> {
+            self.batches.lock().unwrap().push(payments.len());
+            let mut results = Vec::with_capacity(payments.len());
+            for payment in payments {
+                results.push(self.process(payment).await);
             }
-            Err(e) => {
-                tracing::info!("Payment failed to process");
-                Err(WorkerPoolError::PaymentFailed(e))
+            results
+        }
+    }
+
+    /// Default and fallback doubles over a store that keeps payments in
+    /// memory, so nothing needs HTTP or Postgres.
+    struct Scripted {
+        pool: WorkerPool<ScriptedProcessor>,
+        default: Arc<ScriptedProcessor>,
+        fallback: Arc<ScriptedProcessor>,
+        store: Arc<MemoryStore>,
+    }
+
+    impl Scripted {
+        fn new(strategy: RoutingStrategy) -> Self {
+            let store = Arc::new(MemoryStore::new(None));
+
+            let clock = Arc::new(ManualClock::new());
+            let chain = default_and_fallback("http://default", "http://fallback");
+            let (default, fallback) = (ScriptedProcessor::new(ProcessorType::DEFAULT), ScriptedProcessor::new(ProcessorType::FALLBACK));
+            let pool = WorkerPool::new(
+                1,
+                Arc::new(HealthMonitor::new(&chain, strategy, clock.clone())),
+                vec![default.clone(), fallback.clone()],
+                store.clone(),
+                RetryPolicy::default(),
+                clock,
+            );
+            Self { pool, default, fallback, store }
+        }
+
+        async fn process(&self, msg: &PaymentMessage) -> Result<(), WorkerError> {
+            let mut health = self.pool.deps.health_monitor.subscribe();
+            WorkerPool::process_message(0, msg, &self.pool.deps, &mut health).await
+        }
+
+        /// Requests stored per processor.
+        fn stored(&self) -> (u64, u64) {
+            let summary = self.store.summary(&SummaryFilter::default());
+            let requests = |name| summary.get(name).map_or(0, |totals| totals.requests);
+            (requests("default"), requests("fallback"))
+        }
+    }
+
+    fn failing() -> ProcessorHealth {
+        ProcessorHealth { failing: true, min_response_time: 10, updated_at: None, observed_latency: None }
+    }
+
+    #[tokio::test]
+    async fn process_message_routes_by_health() {
+        let strategy = RoutingStrategy { fallback_enabled: true, ..RoutingStrategy::default() };
+        let scripted = Scripted::new(strategy);
+
+        scripted.process(&message(0)).await.unwrap();
+        assert_eq!((scripted.default.sent(), scripted.fallback.sent()), (1, 0));
+
+        scripted.pool.deps.health_monitor.record_probe(&ProcessorType::DEFAULT, failing());
+        scripted.process(&message(0)).await.unwrap();
+        assert_eq!((scripted.default.sent(), scripted.fallback.sent()), (1, 1));
+        assert_eq!(scripted.stored(), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn process_message_sends_late_messages_along_the_late_route() {
+        let late = || {
+            let mut late = message(0);
+            late.ingest_ts = Some(1);
+            late
+        };
+
+        let mut scripted = Scripted::new(RoutingStrategy { fallback_enabled: true, ..RoutingStrategy::default() });
+        scripted.pool = scripted.pool.with_message_budget(Some(Duration::from_millis(1)));
+        scripted.process(&late()).await.unwrap();
+        assert_eq!((scripted.default.sent(), scripted.fallback.sent()), (0, 1));
+
+        // A failing fallback is not shed to, and neither is one routing
+        // may not use.
+        scripted.pool.deps.health_monitor.record_probe(&ProcessorType::FALLBACK, failing());
+        scripted.process(&late()).await.unwrap();
+        assert_eq!((scripted.default.sent(), scripted.fallback.sent()), (1, 1));
+
+        let mut disabled = Scripted::new(RoutingStrategy::default());
+        disabled.pool = disabled.pool.with_message_budget(Some(Duration::from_millis(1)));
+        disabled.process(&late()).await.unwrap();
+        assert_eq!((disabled.default.sent(), disabled.fallback.sent()), (1, 0));
+
+        // Nor does a late payment stay on the fallback once it stops being
+        // healthy.
+        let mut retried = Scripted::new(RoutingStrategy { fallback_enabled: true, ..RoutingStrategy::default() });
+        retried.pool = retried.pool.with_message_budget(Some(Duration::from_millis(1)));
+        retried.fallback.answer(Err(WorkerError::ProcessorUnavailable));
+        let mut retry = late();
+        assert!(retried.process(&retry).await.unwrap_err().is_retryable());
+        retried.pool.deps.health_monitor.record_probe(&ProcessorType::FALLBACK, failing());
+        retry.retry_count = 1;
+        retried.process(&retry).await.unwrap();
+        assert_eq!((retried.default.sent(), retried.fallback.sent()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn process_message_still_processes_expired_messages() {
+        let scripted = Scripted::new(RoutingStrategy { fallback_enabled: true, ..RoutingStrategy::default() });
+
+        // The gateway already answered 202 for it, so it is late, not lost.
+        let mut expired = message(0);
+        expired.deadline_ts = Some(1);
+        scripted.process(&expired).await.unwrap();
+        assert_eq!(scripted.stored(), (0, 1));
+
+        let mut current = message(0);
+        current.deadline_ts = Some(u64::MAX);
+        scripted.process(&current).await.unwrap();
+        assert_eq!(scripted.stored(), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn process_batch_groups_messages_by_processor() {
+        let mut scripted = Scripted::new(RoutingStrategy { fallback_enabled: true, ..RoutingStrategy::default() });
+        scripted.pool = scripted.pool.with_message_budget(Some(Duration::from_millis(1)));
+        scripted.default.answer(Ok(()));
+        scripted.default.answer(Err(WorkerError::ProcessorUnavailable));
+
+        let mut late = message(0);
+        late.ingest_ts = Some(1);
+        let msgs = [message(0), late, message(0)];
+        let mut health = scripted.pool.deps.health_monitor.subscribe();
+        let results = WorkerPool::process_batch(&msgs, &scripted.pool.deps, &mut health).await;
+
+        assert!(results[0].is_ok());
+        assert!(results[1].is_ok());
+        assert!(results[2].as_ref().unwrap_err().is_retryable());
+        assert_eq!(scripted.default.batches(), vec![2]);
+        assert_eq!(scripted.fallback.batches(), vec![1]);
+        assert_eq!(scripted.stored(), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn process_message_classifies_failures() {
+        let scripted = Scripted::new(RoutingStrategy::default());
+
+        scripted.default.answer(Err(WorkerError::ProcessorUnavailable));
+        assert!(scripted.process(&message(0)).await.unwrap_err().is_retryable());
+
+        scripted.default.answer(Err(WorkerError::Unprocessable(Bytes::from_static(b"{}"))));
+        assert!(!scripted.process(&message(0)).await.unwrap_err().is_retryable());
+        assert_eq!(scripted.stored(), (0, 0));
+
+        // The processor has it already, so it is stored as a success.
+        scripted.default.answer(Err(WorkerError::AlreadyProcessed));
+        scripted.process(&message(0)).await.unwrap();
+        assert_eq!(scripted.stored(), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn timed_out_payments_are_retried_on_the_same_processor() {
+        let strategy = RoutingStrategy { fallback_enabled: true, ..RoutingStrategy::default() };
+        let scripted = Scripted::new(strategy);
+        let (retry_sender, mut retry_receiver) = mpsc::channel(1);
+
+        scripted.default.answer(Err(WorkerError::ProcessorTimeout(ProcessorType::DEFAULT)));
+        let msg = message(0);
+        let result = scripted.process(&msg).await;
+        WorkerPool::conclude(0, msg, result, &retry_sender, &scripted.pool.deps).await;
+        let retry = retry_receiver.try_recv().unwrap().msg;
+        assert_eq!(retry.pinned, Some(ProcessorType::DEFAULT));
+
+        // The default may hold the payment, so even once it is failing the
+        // retry does not move to the fallback.
+        scripted.pool.deps.health_monitor.record_probe(&ProcessorType::DEFAULT, failing());
+        scripted.default.answer(Err(WorkerError::AlreadyProcessed));
+        scripted.process(&retry).await.unwrap();
+        assert_eq!((scripted.default.sent(), scripted.fallback.sent()), (2, 0));
+        assert_eq!(scripted.stored(), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn process_message_fails_retryably_without_a_healthy_processor() {
+        let scripted = Scripted::new(RoutingStrategy::default());
+        scripted.pool.deps.health_monitor.record_probe(&ProcessorType::DEFAULT, failing());
+
+        let error = scripted.process(&message(0)).await.unwrap_err();
+        assert!(matches!(error, WorkerError::AllProcessorsFailing));
+        assert!(error.is_retryable());
+        assert_eq!(scripted.default.sent() + scripted.fallback.sent(), 0);
+    }
+
+    #[tokio::test]
+    async fn dual_write_mirrors_sampled_payments_without_storing_them() {
+        let mut scripted = Scripted::new(RoutingStrategy::default());
+        scripted.pool = scripted.pool.with_dual_write(Some(Arc::new(DualWrite::new(100))));
+        scripted.fallback.answer(Ok(()));
+        scripted.fallback.answer(Err(WorkerError::Unprocessable(Bytes::from_static(b"{}"))));
+
+        let msg = message(0);
+        scripted.process(&msg).await.unwrap();
+        scripted.process(&message(0)).await.unwrap();
+        settle().await;
+
+        assert_eq!(scripted.fallback.sent.lock().unwrap()[0], crate::dual_write::shadow_id(&msg.correlation_id));
+        assert_eq!(scripted.stored(), (2, 0));
+        let report = scripted.pool.dual_write_report().unwrap();
+        let pair = &report.pairs["default->fallback"];
+        assert_eq!((pair.mirrored, pair.agreed, pair.disagreed), (2, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn paused_workers_hold_payments_until_resumed() {
+        let mut scripted = Scripted::new(RoutingStrategy::default());
+        scripted.pool.start().await;
+        assert!(scripted.pool.pause());
+        assert!(!scripted.pool.pause());
+        assert!(scripted.pool.is_paused());
+
+        scripted.pool.submit_internal(message(0)).await.unwrap();
+        scripted.pool.submit_internal(message(0)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(scripted.default.sent(), 0);
+
+        assert!(scripted.pool.resume());
+        assert!(!scripted.pool.resume());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(scripted.default.sent(), 2);
+
+        // Shutting down does not wait for a resume.
+        scripted.pool.pause();
+        scripted.pool.submit_internal(message(0)).await.unwrap();
+        let started = std::time::Instant::now();
+        scripted.pool.shutdown(Duration::from_secs(5)).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(scripted.default.sent(), 2);
+    }
+
+    /// Keeps payments in memory and parks retries, by retry count and next
+    /// attempt, in a list standing in for `scheduled_retries`.
+    struct ParkingStore {
+        payments: MemoryStore,
+        parked: Mutex<Vec<(u32, OffsetDateTime)>>,
+    }
+
+    impl ParkingStore {
+        fn new(parked: Vec<(u32, OffsetDateTime)>) -> Arc<Self> {
+            Arc::new(Self { payments: MemoryStore::new(None), parked: Mutex::new(parked) })
+        }
+
+        fn parked_retry_counts(&self) -> Vec<u32> {
+            let mut counts: Vec<_> = self.parked.lock().unwrap().iter().map(|(retry_count, _)| *retry_count).collect();
+            counts.sort();
+            counts
+        }
+    }
+
+    impl PaymentStore for ParkingStore {
+        fn push_payment(&self, payment: Payment) -> Result<(), WorkerError> {
+            self.payments.push_payment(payment)
+        }
+
+        fn flush(&self) -> StoreFuture<'_, ()> {
+            self.payments.flush()
+        }
+
+        fn summary(&self, filter: SummaryFilter) -> StoreFuture<'_, Result<Summary, StoreError>> {
+            PaymentStore::summary(&self.payments, filter)
+        }
+
+        fn purge(&self) -> StoreFuture<'_, Result<(), StoreError>> {
+            PaymentStore::purge(&self.payments)
+        }
+
+        fn spill_retries<'a>(&'a self, retries: &'a [ScheduledRetry]) -> StoreFuture<'a, bool> {
+            let mut parked = self.parked.lock().unwrap();
+            parked.extend(retries.iter().map(|retry| (retry.msg.retry_count, retry.next_attempt)));
+            Box::pin(async { true })
+        }
+
+        fn take_parked_retries(&self, limit: usize) -> StoreFuture<'_, Vec<ScheduledRetry>> {
+            let mut parked = self.parked.lock().unwrap();
+            let count = limit.min(parked.len());
+            let taken = parked
+                .drain(..count)
+                .map(|(retry_count, next_attempt)| ScheduledRetry { msg: message(retry_count), next_attempt })
+                .collect();
+            Box::pin(async { taken })
+        }
+    }
+
+    fn parking_pool(store: Arc<ParkingStore>) -> (WorkerPool<ScriptedProcessor>, Arc<ScriptedProcessor>) {
+        let chain = default_and_fallback("http://default", "http://fallback");
+        let clock = Arc::new(ManualClock::new());
+        let default = ScriptedProcessor::new(ProcessorType::DEFAULT);
+        let pool = WorkerPool::new(
+            1,
+            Arc::new(HealthMonitor::new(&chain, RoutingStrategy::default(), clock.clone())),
+            vec![default.clone(), ScriptedProcessor::new(ProcessorType::FALLBACK)],
+            store,
+            RetryPolicy::default(),
+            clock,
+        );
+        (pool, default)
+    }
+
+    #[tokio::test]
+    async fn shutdown_parks_pending_retries() {
+        let store = ParkingStore::new(Vec::new());
+        let (mut pool, default) = parking_pool(store.clone());
+        pool.start().await;
+
+        default.answer(Err(WorkerError::ProcessorUnavailable));
+        default.answer(Err(WorkerError::ProcessorUnavailable));
+        pool.submit_internal(message(0)).await.unwrap();
+        pool.submit_internal(message(3)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(default.sent(), 2);
+        assert!(store.parked_retry_counts().is_empty());
+
+        // Neither retry is due on the manual clock: both are still in the
+        // heap when the pool shuts down.
+        pool.shutdown(Duration::from_secs(5)).await;
+        assert_eq!(store.parked_retry_counts(), [1, 4]);
+    }
+
+    #[tokio::test]
+    async fn retries_handed_back_while_stopping_are_parked() {
+        let store = ParkingStore::new(Vec::new());
+        let (pool, _) = parking_pool(store.clone());
+        let (retry_sender, retry_receiver) = mpsc::channel(16);
+        let retry_loop = tokio::spawn(pool.clone().retry_loop(retry_receiver, pool.shutdown.subscribe()));
+
+        let later = pool.deps.clock.now() + Duration::from_secs(60);
+        retry_sender.send(RetryItem { msg: message(1), next_attempt: later }).await.unwrap();
+        settle().await;
+        let _ = pool.shutdown.send(true);
+        settle().await;
+        assert!(!retry_loop.is_finished());
+
+        // A worker finishing its last payment.
+        retry_sender.send(RetryItem { msg: message(2), next_attempt: later }).await.unwrap();
+        drop(retry_sender);
+        retry_loop.await.unwrap();
+
+        assert_eq!(store.parked_retry_counts(), [1, 2]);
+        let earliest = OffsetDateTime::now_utc() + Duration::from_secs(50);
+        assert!(store.parked.lock().unwrap().iter().all(|(_, next_attempt)| *next_attempt > earliest));
+    }
+
+    #[tokio::test]
+    async fn parked_retries_the_queue_refuses_are_written_back() {
+        let parked = (1..=3)
+            .map(|retry_count| (retry_count, OffsetDateTime::now_utc()))
+            .collect();
+        let store = ParkingStore::new(parked);
+        let (pool, _) = parking_pool(store.clone());
+
+        let (retry_sender, retry_receiver) = mpsc::channel(16);
+        drop(retry_receiver);
+        pool.recover_parked_retries(retry_sender).await;
+
+        assert_eq!(store.parked_retry_counts(), [1, 2, 3]);
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::prelude::*;
+
+        proptest! {
+            #[test]
+            fn retry_heap_pops_earliest_attempt_first(offsets in proptest::collection::vec(0..10_000u64, 0..64)) {
+                let start = Instant::now();
+                let mut heap: BinaryHeap<RetryItem> = offsets
+                    .iter()
+                    .map(|offset| RetryItem {
+                        msg: message(0),
+                        next_attempt: start + Duration::from_millis(*offset),
+                    })
+                    .collect();
+
+                let popped: Vec<_> = std::iter::from_fn(|| heap.pop()).map(|item| item.next_attempt).collect();
+                let mut expected: Vec<_> = offsets.iter().map(|offset| start + Duration::from_millis(*offset)).collect();
+                expected.sort();
+                prop_assert_eq!(popped, expected);
+            }
+
+            #[test]
+            fn retry_count_never_passes_max_retries(retry_count in any::<u32>(), max_retries in any::<u32>()) {
+                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+                let clock = Arc::new(ManualClock::new());
+                let policy = RetryPolicy { max_retries, ..RetryPolicy::default() };
+
+                let rescheduled = rt.block_on(async {
+                    let (pool, _) = test_pool(clock, policy);
+                    let (retry_sender, mut retry_receiver) = mpsc::channel(1);
+                    WorkerPool::retry(message(retry_count), &retry_sender, &pool.deps).await;
+                    retry_receiver.try_recv().ok()
+                });
+
+                match rescheduled {
+                    Some(item) => {
+                        prop_assert_eq!(item.msg.retry_count, retry_count + 1);
+                        prop_assert!(item.msg.retry_count <= max_retries);
+                    }
+                    None => prop_assert!(retry_count >= max_retries),
+                }
             }
         }
     }
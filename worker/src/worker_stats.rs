@@ -0,0 +1,156 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Handled messages needed in an interval before imbalance is judged, per
+/// worker; a near-idle pool is always lopsided.
+const MIN_SAMPLES_PER_WORKER: u64 = 50;
+
+/// Per-worker counters, so the spread of work across workers can be checked
+/// rather than assumed.
+pub struct WorkerStats {
+    workers: Box<[WorkerCounters]>,
+    /// Handled counts at the previous [`WorkerStats::check_balance`].
+    last_handled: Mutex<Vec<u64>>,
+}
+
+#[derive(Default)]
+struct WorkerCounters {
+    processed: AtomicU64,
+    errors: AtomicU64,
+    busy_us: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkerReport {
+    pub id: usize,
+    pub processed: u64,
+    pub errors: u64,
+    /// Mean time spent on a message, successful or not.
+    pub avg_handling_us: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkerStatsReport {
+    pub workers: Vec<WorkerReport>,
+    /// Busiest worker's share over the mean, since start.
+    pub imbalance: Option<f64>,
+}
+
+impl WorkerStats {
+    pub fn new(num_workers: usize) -> Self {
+        Self {
+            workers: (0..num_workers).map(|_| WorkerCounters::default()).collect(),
+            last_handled: Mutex::new(vec![0; num_workers]),
+        }
+    }
+
+    pub fn record(&self, worker_id: usize, succeeded: bool, elapsed: Duration) {
+        let Some(worker) = self.workers.get(worker_id) else {
+            return;
+        };
+        let counter = if succeeded { &worker.processed } else { &worker.errors };
+        counter.fetch_add(1, Ordering::Relaxed);
+        worker
+            .busy_us
+            .fetch_add(u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    fn handled(&self) -> Vec<u64> {
+        self.workers
+            .iter()
+            .map(|worker| worker.processed.load(Ordering::Relaxed) + worker.errors.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Imbalance of the messages handled since the previous call, `None`
+    /// while there were too few to tell.
+    pub fn check_balance(&self) -> Option<f64> {
+        let handled = self.handled();
+        let mut last = self.last_handled.lock().unwrap();
+        let delta: Vec<u64> = handled.iter().zip(last.iter()).map(|(now, then)| now - then).collect();
+        *last = handled;
+
+        if delta.iter().sum::<u64>() < MIN_SAMPLES_PER_WORKER * delta.len() as u64 {
+            return None;
+        }
+        imbalance(&delta)
+    }
+
+    pub fn report(&self) -> WorkerStatsReport {
+        let workers = self
+            .workers
+            .iter()
+            .enumerate()
+            .map(|(id, worker)| {
+                let processed = worker.processed.load(Ordering::Relaxed);
+                let errors = worker.errors.load(Ordering::Relaxed);
+                let handled = processed + errors;
+                WorkerReport {
+                    id,
+                    processed,
+                    errors,
+                    avg_handling_us: worker.busy_us.load(Ordering::Relaxed).checked_div(handled).unwrap_or(0),
+                }
+            })
+            .collect();
+
+        WorkerStatsReport {
+            workers,
+            imbalance: imbalance(&self.handled()),
+        }
+    }
+}
+
+/// Busiest worker's count over the mean: `1.0` is a perfect spread, `n`
+/// means one of `n` workers did everything. `None` when nothing was handled.
+fn imbalance(handled: &[u64]) -> Option<f64> {
+    let total: u64 = handled.iter().sum();
+    let max = *handled.iter().max()?;
+    if total == 0 {
+        return None;
+    }
+    Some(max as f64 * handled.len() as f64 / total as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imbalance_compares_busiest_worker_to_mean() {
+        assert_eq!(imbalance(&[]), None);
+        assert_eq!(imbalance(&[0, 0]), None);
+        assert_eq!(imbalance(&[10, 10, 10, 10]), Some(1.0));
+        assert_eq!(imbalance(&[40, 0, 0, 0]), Some(4.0));
+        assert_eq!(imbalance(&[30, 10]), Some(1.5));
+    }
+
+    #[test]
+    fn balance_is_checked_per_interval() {
+        let stats = WorkerStats::new(2);
+        for _ in 0..150 {
+            stats.record(0, true, Duration::from_micros(100));
+        }
+        stats.record(1, false, Duration::from_micros(300));
+        assert!(stats.check_balance().unwrap() > 1.9);
+
+        // Too few messages since the last check to judge.
+        stats.record(1, true, Duration::from_micros(100));
+        assert_eq!(stats.check_balance(), None);
+
+        for worker in [0, 1] {
+            for _ in 0..100 {
+                stats.record(worker, true, Duration::from_micros(100));
+            }
+        }
+        assert_eq!(stats.check_balance(), Some(1.0));
+
+        let report = stats.report();
+        assert_eq!((report.workers[1].processed, report.workers[1].errors), (101, 1));
+        assert_eq!(report.workers[1].avg_handling_us, 101);
+    }
+}